@@ -0,0 +1,153 @@
+//! Benchmarks comparing the tree backends on the operations a wallet or
+//! node performs most often.
+//!
+//! Every backend is measured at tree sizes from 1e3 up to 1e7 leaves. Building
+//! the larger trees (especially the persistent ones) takes a long time, so the
+//! largest size can be capped with the `MERKLE_BENCH_MAX_SIZE` environment
+//! variable:
+//!
+//! ```text
+//! MERKLE_BENCH_MAX_SIZE=100000 cargo bench --all-features
+//! ```
+//!
+//! The trees hold `notes::Note`s, so the benchmarks need the `notes`
+//! feature. The sled and RocksDB backends are only benchmarked when their
+//! features (`sledder` and `rocker`) are enabled.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use merkle_notes::linked::LinkedMerkleTree;
+use merkle_notes::notes::{Note, NoteHasher};
+use merkle_notes::vector::VectorMerkleTree;
+use merkle_notes::MerkleTree;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const SIZES: [usize; 5] = [1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+/// A note whose content starts with index.
+fn note(index: usize) -> Note {
+    let mut bytes = [0; 32];
+    (&mut bytes[..])
+        .write_u64::<LittleEndian>(index as u64)
+        .unwrap();
+    Note(bytes)
+}
+
+/// The sizes to benchmark, capped by `MERKLE_BENCH_MAX_SIZE` if it is set.
+fn sizes() -> Vec<usize> {
+    let max_size = std::env::var("MERKLE_BENCH_MAX_SIZE")
+        .ok()
+        .map(|max| max.parse().expect("MERKLE_BENCH_MAX_SIZE must be a number"))
+        .unwrap_or(usize::MAX);
+    SIZES
+        .iter()
+        .cloned()
+        .filter(|size| *size <= max_size)
+        .collect()
+}
+
+/// Run the benchmarks for one backend. `new_tree` is called once per size,
+/// and the tree it returns is filled to that size before being measured.
+///
+/// Operations that change the size of the tree are undone outside of the
+/// timed section, so every measurement is taken against a tree of the
+/// advertised size.
+fn bench_backend<M, F>(c: &mut Criterion, name: &str, mut new_tree: F)
+where
+    M: MerkleTree<Hasher = NoteHasher>,
+    F: FnMut() -> Box<M>,
+{
+    let mut group = c.benchmark_group(name);
+    group.sample_size(10);
+
+    for size in sizes() {
+        let mut tree = new_tree();
+        for index in 0..size {
+            tree.add(note(index));
+        }
+        let middle = note(size / 2);
+
+        group.bench_with_input(BenchmarkId::new("add", size), &size, |b, &size| {
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                for index in 0..iters {
+                    tree.add(note(size + index as usize));
+                }
+                let elapsed = start.elapsed();
+                tree.truncate(size).unwrap();
+                elapsed
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("witness", size), &size, |b, &size| {
+            b.iter(|| tree.witness(black_box(size / 2)))
+        });
+
+        group.bench_with_input(BenchmarkId::new("past_root", size), &size, |b, &size| {
+            b.iter(|| tree.past_root(black_box(size / 2 + 1)))
+        });
+
+        group.bench_with_input(BenchmarkId::new("truncate", size), &size, |b, &size| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::new(0, 0);
+                for _ in 0..iters {
+                    let start = Instant::now();
+                    tree.truncate(black_box(size - 1)).unwrap();
+                    elapsed += start.elapsed();
+                    tree.add(note(size - 1));
+                }
+                elapsed
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("contains", size), &size, |b, _| {
+            b.iter(|| tree.contains(black_box(&middle)))
+        });
+    }
+
+    group.finish();
+}
+
+fn in_memory(c: &mut Criterion) {
+    let hasher = Arc::new(NoteHasher {});
+    bench_backend(c, "linked", || LinkedMerkleTree::new(hasher.clone()));
+    bench_backend(c, "vector", || VectorMerkleTree::new(hasher.clone()));
+}
+
+#[cfg(feature = "sledder")]
+fn sled(c: &mut Criterion) {
+    use merkle_notes::sled::SledMerkleTree;
+
+    let hasher = Arc::new(NoteHasher {});
+    let mut directories = vec![];
+    bench_backend(c, "sled", || {
+        let directory = tempfile::tempdir().unwrap();
+        let tree = SledMerkleTree::new(hasher.clone(), directory.path());
+        directories.push(directory);
+        Box::new(tree)
+    });
+}
+
+#[cfg(not(feature = "sledder"))]
+fn sled(_c: &mut Criterion) {}
+
+#[cfg(feature = "rocker")]
+fn rocks(c: &mut Criterion) {
+    use merkle_notes::rocks::RocksMerkleTree;
+
+    let hasher = Arc::new(NoteHasher {});
+    let mut directories = vec![];
+    bench_backend(c, "rocks", || {
+        let directory = tempfile::tempdir().unwrap();
+        let tree = RocksMerkleTree::new(hasher.clone(), directory.path());
+        directories.push(directory);
+        Box::new(tree)
+    });
+}
+
+#[cfg(not(feature = "rocker"))]
+fn rocks(_c: &mut Criterion) {}
+
+criterion_group!(benches, in_memory, sled, rocks);
+criterion_main!(benches);
@@ -0,0 +1,27 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use merkle_notes::vector::VectorMerkleTree;
+use merkle_notes::{HashableElement, MerkleTree};
+use merkle_notes_fuzz::FuzzHasher;
+use std::sync::Arc;
+
+fuzz_target!(|data: &[u8]| {
+    let hasher = Arc::new(FuzzHasher);
+    if let Ok(tree) = VectorMerkleTree::read(hasher.clone(), &mut &data[..]) {
+        // Whatever was accepted must serialize back to the bytes it came from
+        let mut bytes = vec![];
+        tree.write(&mut bytes).expect("can write to a vec");
+        assert_eq!(&bytes[..], &data[..bytes.len()]);
+
+        if let Some(last) = tree.len().checked_sub(1) {
+            let witness = tree.witness(last).expect("last leaf has a witness");
+            let hash = tree
+                .get(last)
+                .unwrap()
+                .expect("last leaf exists")
+                .merkle_hash();
+            assert!(witness.verify(&hasher, &hash));
+            assert_eq!(tree.past_root(tree.len()), tree.root_hash());
+        }
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use merkle_notes::Witness;
+use merkle_notes_fuzz::FuzzHasher;
+
+fuzz_target!(|data: &[u8]| {
+    let hasher = FuzzHasher;
+    if let Ok(witness) = Witness::<FuzzHasher>::read(&hasher, &mut &data[..]) {
+        let mut bytes = vec![];
+        witness.write(&hasher, &mut bytes).expect("can write to a vec");
+        assert_eq!(&bytes[..], &data[..bytes.len()]);
+
+        // Verifying a garbage witness must fail quietly, not panic
+        witness.verify(&hasher, &0);
+    }
+});
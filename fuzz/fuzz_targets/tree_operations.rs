@@ -0,0 +1,68 @@
+#![no_main]
+//! Apply a random sequence of operations to the linked and vector trees and
+//! check that they always agree with each other. Each operation is three
+//! bytes: an opcode and a little endian u16 argument.
+use libfuzzer_sys::fuzz_target;
+use merkle_notes::linked::LinkedMerkleTree;
+use merkle_notes::vector::VectorMerkleTree;
+use merkle_notes::{HashableElement, MerkleTree};
+use merkle_notes_fuzz::{FuzzHasher, Note};
+use std::sync::Arc;
+
+fuzz_target!(|data: &[u8]| {
+    let hasher = Arc::new(FuzzHasher);
+    let mut linked = LinkedMerkleTree::new(hasher.clone());
+    let mut vector = VectorMerkleTree::new(hasher.clone());
+
+    for operation in data.chunks_exact(3) {
+        let argument = u16::from_le_bytes([operation[1], operation[2]]) as usize;
+        match operation[0] % 7 {
+            // adding is the only way to grow the tree, so give it two opcodes
+            0 | 1 => {
+                linked.add(Note(argument as u64));
+                vector.add(Note(argument as u64));
+            }
+            2 => {
+                linked.truncate(argument).unwrap();
+                vector.truncate(argument).unwrap();
+            }
+            3 => assert_eq!(linked.past_root(argument), vector.past_root(argument)),
+            4 => {
+                let witness = linked.witness(argument);
+                assert_eq!(witness, vector.witness(argument));
+                if let Some(witness) = witness {
+                    let hash = linked
+                        .get(argument)
+                        .unwrap()
+                        .expect("leaf exists")
+                        .merkle_hash();
+                    assert!(witness.verify(&hasher, &hash));
+                }
+            }
+            5 => {
+                // the argument bytes are a position and a past size
+                let (position, past_size) = (operation[1] as usize, operation[2] as usize);
+                let witness = linked.past_witness(position, past_size);
+                assert_eq!(witness, vector.past_witness(position, past_size));
+                if let Some(witness) = witness {
+                    let hash = linked
+                        .get(position)
+                        .unwrap()
+                        .expect("leaf exists")
+                        .merkle_hash();
+                    assert!(witness.verify(&hasher, &hash));
+                    assert_eq!(Some(witness.root_hash), linked.past_root(past_size));
+                }
+            }
+            _ => {
+                assert_eq!(linked.get(argument), vector.get(argument));
+                assert_eq!(
+                    linked.contains(&Note(argument as u64)),
+                    vector.contains(&Note(argument as u64))
+                );
+            }
+        }
+        assert_eq!(linked.len(), vector.len());
+        assert_eq!(linked.root_hash(), vector.root_hash());
+    }
+});
@@ -0,0 +1,51 @@
+//! Element and hasher shared by the fuzz targets.
+//!
+//! The fuzzers are hunting for panics and inconsistencies in the tree
+//! bookkeeping, not for weaknesses in the hash, so hashing is a cheap
+//! integer mix. It only needs to be deterministic and sensitive to the
+//! order of its arguments and to the depth.
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use merkle_notes::{HashableElement, MerkleHasher};
+use std::io;
+
+/// A note is just a number that hashes to itself. It is serialized as eight
+/// little endian bytes, so fuzzer input maps directly onto elements.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Note(pub u64);
+
+impl HashableElement for Note {
+    type Hash = u64;
+
+    fn merkle_hash(&self) -> u64 {
+        self.0
+    }
+
+    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(self.0)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FuzzHasher;
+
+impl MerkleHasher for FuzzHasher {
+    type Element = Note;
+
+    fn read_element<R: io::Read>(&self, reader: &mut R) -> io::Result<Note> {
+        Ok(Note(reader.read_u64::<LittleEndian>()?))
+    }
+
+    fn read_hash<R: io::Read>(&self, reader: &mut R) -> io::Result<u64> {
+        reader.read_u64::<LittleEndian>()
+    }
+
+    fn write_hash<W: io::Write>(&self, hash: &u64, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(*hash)
+    }
+
+    fn combine_hash(&self, depth: usize, left: &u64, right: &u64) -> u64 {
+        left.rotate_left(17)
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            .wrapping_add(*right ^ (depth as u64))
+    }
+}
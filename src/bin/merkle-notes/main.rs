@@ -0,0 +1,345 @@
+//! `merkle-notes`: look inside a `SledMerkleTree` (or, with the `rocker`
+//! feature, a `RocksMerkleTree`) directory.
+//!
+//! The trees are opened with `notes::NoteHasher`, for 32 byte notes and 32
+//! byte Blake2b hashes, which is the shape of the trees our nodes keep.
+//! Elements, hashes, witnesses and exported states are printed with the
+//! `display` encodings, hex unless `--encoding base64` is given.
+//!
+//! ```text
+//! merkle-notes /path/to/tree info
+//! merkle-notes /path/to/tree witness 1234
+//! merkle-notes /path/to/tree export > state.txt
+//! merkle-notes --depth 33 /path/to/new-tree import "$(cat state.txt)"
+//! ```
+//!
+//! Trees are opened at the depth they were created with, and only `import`
+//! creates one; the other commands fail on a directory without a tree. Only
+//! `truncate` and `import` write to the tree. Neither sled 0.30 nor our
+//! RocksDB version can open a database read-only, though, so nothing else
+//! can have the tree open while it is inspected.
+
+use clap::{value_t, App, AppSettings, Arg, ArgMatches, SubCommand};
+use merkle_notes::config::TreeConfig;
+use merkle_notes::display::{encode_hash, encode_witness, Encoding};
+use merkle_notes::integrity::IntegrityReport;
+use merkle_notes::notes::NoteHasher;
+use merkle_notes::sled::SledMerkleTree;
+use merkle_notes::MerkleTree;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+/// What the inspector needs from a backend beyond the `MerkleTree` trait.
+trait Backend: MerkleTree<Hasher = NoteHasher> {
+    fn tree_depth(&self) -> u32;
+    fn num_nodes(&self) -> usize;
+    fn import_state(&mut self, state: &[u8]) -> io::Result<()>;
+    fn verify_integrity(&self) -> io::Result<IntegrityReport>;
+}
+
+impl Backend for SledMerkleTree<NoteHasher> {
+    fn tree_depth(&self) -> u32 {
+        SledMerkleTree::tree_depth(self)
+    }
+
+    fn num_nodes(&self) -> usize {
+        SledMerkleTree::num_nodes(self)
+    }
+
+    fn import_state(&mut self, state: &[u8]) -> io::Result<()> {
+        SledMerkleTree::import_state(self, &mut &state[..])
+    }
+
+    fn verify_integrity(&self) -> io::Result<IntegrityReport> {
+        SledMerkleTree::verify_integrity(self)
+    }
+}
+
+#[cfg(feature = "rocker")]
+impl Backend for merkle_notes::rocks::RocksMerkleTree<NoteHasher> {
+    fn tree_depth(&self) -> u32 {
+        merkle_notes::rocks::RocksMerkleTree::tree_depth(self)
+    }
+
+    fn num_nodes(&self) -> usize {
+        merkle_notes::rocks::RocksMerkleTree::num_nodes(self)
+    }
+
+    fn import_state(&mut self, state: &[u8]) -> io::Result<()> {
+        merkle_notes::rocks::RocksMerkleTree::import_state(self, &mut &state[..])
+    }
+
+    fn verify_integrity(&self) -> io::Result<IntegrityReport> {
+        merkle_notes::rocks::RocksMerkleTree::verify_integrity(self)
+    }
+}
+
+fn app() -> App<'static, 'static> {
+    let position = || Arg::with_name("position").required(true);
+    let size = || Arg::with_name("size").required(true);
+    App::new("merkle-notes")
+        .about("Inspect a merkle tree stored on disk")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("directory")
+                .required(true)
+                .help("The directory the tree is stored in"),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .possible_values(&["sled", "rocks"])
+                .default_value("sled"),
+        )
+        .arg(
+            Arg::with_name("depth")
+                .long("depth")
+                .takes_value(true)
+                .help(
+                    "The depth of a tree created by import, 33 if not given. \
+             An existing tree must have been created with it.",
+                ),
+        )
+        .arg(
+            Arg::with_name("encoding")
+                .long("encoding")
+                .possible_values(&["hex", "base64"])
+                .default_value("hex"),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Print the depth, leaf count, node count and root hash"),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Print the element at a position")
+                .arg(position()),
+        )
+        .subcommand(
+            SubCommand::with_name("witness")
+                .about("Print the witness of a position")
+                .arg(position()),
+        )
+        .subcommand(
+            SubCommand::with_name("past-root")
+                .about("Print the root hash at an earlier size")
+                .arg(size()),
+        )
+        .subcommand(
+            SubCommand::with_name("truncate")
+                .about("Remove the leaves from a size on")
+                .arg(size()),
+        )
+        .subcommand(
+            SubCommand::with_name("export").about("Print the state another tree can import"),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Create a tree from an exported state")
+                .arg(Arg::with_name("state").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check the stored rows, then every witness against the root"),
+        )
+}
+
+fn main() {
+    let matches = app().get_matches();
+    let directory = Path::new(matches.value_of("directory").unwrap());
+    let depth = if matches.is_present("depth") {
+        Some(value_t!(matches, "depth", u32).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
+    let command = matches.subcommand_name().unwrap();
+    let hasher = Arc::new(NoteHasher {});
+    let stdout = io::stdout();
+    let result = match matches.value_of("backend").unwrap() {
+        "sled" => open_sled(hasher, directory, command, depth)
+            .and_then(|mut tree| run(&mut tree, depth, &matches, &mut stdout.lock())),
+        #[cfg(feature = "rocker")]
+        "rocks" => open_rocks(hasher, directory, command, depth)
+            .and_then(|mut tree| run(&mut tree, depth, &matches, &mut stdout.lock())),
+        backend => Err(io::Error::other(format!(
+            "Built without support for {} trees",
+            backend
+        ))),
+    };
+    if let Err(error) = result {
+        eprintln!("merkle-notes: {}", error);
+        process::exit(1);
+    }
+}
+
+/// The configuration of the tree `import` creates in a new directory.
+fn new_tree_config(depth: Option<u32>) -> io::Result<TreeConfig> {
+    let config = TreeConfig {
+        depth: depth.map_or(33, |depth| depth as usize),
+        ..TreeConfig::default()
+    };
+    config
+        .validate()
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error.to_string()))?;
+    Ok(config)
+}
+
+/// Open the sled tree the command works on, which only `import` creates.
+fn open_sled(
+    hasher: Arc<NoteHasher>,
+    directory: &Path,
+    command: &str,
+    depth: Option<u32>,
+) -> io::Result<SledMerkleTree<NoteHasher>> {
+    match command {
+        "import" if !directory.exists() => {
+            SledMerkleTree::with_config(hasher, directory, new_tree_config(depth)?)
+        }
+        _ => SledMerkleTree::open_existing(hasher, directory),
+    }
+}
+
+/// Open the RocksDB tree the command works on, which only `import` creates.
+#[cfg(feature = "rocker")]
+fn open_rocks(
+    hasher: Arc<NoteHasher>,
+    directory: &Path,
+    command: &str,
+    depth: Option<u32>,
+) -> io::Result<merkle_notes::rocks::RocksMerkleTree<NoteHasher>> {
+    use merkle_notes::rocks::RocksMerkleTree;
+    match command {
+        "import" if !directory.exists() => {
+            RocksMerkleTree::with_config(hasher, directory, new_tree_config(depth)?)
+        }
+        _ => RocksMerkleTree::open_existing(hasher, directory),
+    }
+}
+
+fn run<M: Backend, W: Write>(
+    tree: &mut M,
+    depth: Option<u32>,
+    matches: &ArgMatches,
+    out: &mut W,
+) -> io::Result<()> {
+    if let Some(depth) = depth {
+        if depth != tree.tree_depth() {
+            return Err(io::Error::other(format!(
+                "The tree was created with depth {}, not {}",
+                tree.tree_depth(),
+                depth
+            )));
+        }
+    }
+    let encoding = match matches.value_of("encoding").unwrap() {
+        "base64" => Encoding::Base64,
+        _ => Encoding::Hex,
+    };
+    let hasher = tree.hasher();
+    let not_found = |what: String| io::Error::new(io::ErrorKind::NotFound, what);
+    match matches.subcommand() {
+        ("info", _) => {
+            writeln!(out, "depth: {}", tree.tree_depth())?;
+            writeln!(out, "leaves: {}", tree.len())?;
+            writeln!(out, "nodes: {}", tree.num_nodes())?;
+            match tree.root_hash() {
+                Some(root) => writeln!(out, "root: {}", encode_hash(&*hasher, &root, encoding)?)?,
+                None => writeln!(out, "root: none")?,
+            }
+        }
+        ("get", Some(args)) => {
+            let position = value_t!(args, "position", usize).unwrap_or_else(|e| e.exit());
+            let element = tree
+                .get(position)
+                .map_err(|error| io::Error::other(error.to_string()))?
+                .ok_or_else(|| not_found(format!("No element at position {}", position)))?;
+            writeln!(out, "{}", encoding.encode(&element.0))?;
+        }
+        ("witness", Some(args)) => {
+            let position = value_t!(args, "position", usize).unwrap_or_else(|e| e.exit());
+            let witness = tree
+                .witness(position)
+                .ok_or_else(|| not_found(format!("No witness for position {}", position)))?;
+            writeln!(out, "{}", encode_witness(&*hasher, &witness, encoding)?)?;
+        }
+        ("past-root", Some(args)) => {
+            let size = value_t!(args, "size", usize).unwrap_or_else(|e| e.exit());
+            let root = tree
+                .past_root(size)
+                .ok_or_else(|| not_found(format!("No root at size {}", size)))?;
+            writeln!(out, "{}", encode_hash(&*hasher, &root, encoding)?)?;
+        }
+        ("truncate", Some(args)) => {
+            let size = value_t!(args, "size", usize).unwrap_or_else(|e| e.exit());
+            if size > tree.len() {
+                return Err(io::Error::other(format!(
+                    "The tree only has {} leaves",
+                    tree.len()
+                )));
+            }
+            tree.truncate(size)
+                .map_err(|error| io::Error::other(error.to_string()))?;
+            writeln!(out, "leaves: {}", tree.len())?;
+        }
+        ("export", _) => {
+            let mut state = vec![];
+            tree.export_state(&mut state)?;
+            writeln!(out, "{}", encoding.encode(&state))?;
+        }
+        ("import", Some(args)) => {
+            let state = encoding.decode(args.value_of("state").unwrap())?;
+            tree.import_state(&state)?;
+            writeln!(out, "leaves: {}", tree.len())?;
+        }
+        ("verify", _) => {
+            // witnesses of a tree with broken rows may panic, so stop here
+            let report = tree.verify_integrity()?;
+            for problem in &report.problems {
+                writeln!(out, "{}", problem)?;
+            }
+            writeln!(
+                out,
+                "rows: {} leaves, {} nodes",
+                report.leaves_checked, report.nodes_checked
+            )?;
+            if !report.is_ok() {
+                return Err(io::Error::other(format!(
+                    "{} problems with the stored rows",
+                    report.problems.len()
+                )));
+            }
+            let root = tree.root_hash();
+            let mut checked = 0;
+            let mut failed = 0;
+            for leaf in tree.iter_leaves() {
+                let leaf = leaf?;
+                let valid = match tree.witness(leaf.position) {
+                    Some(witness) => {
+                        Some(&witness.root_hash) == root.as_ref()
+                            && witness.verify(&*hasher, &leaf.hash)
+                    }
+                    None => false,
+                };
+                if !valid {
+                    writeln!(out, "invalid witness for position {}", leaf.position)?;
+                    failed += 1;
+                }
+                checked += 1;
+            }
+            writeln!(out, "checked: {}", checked)?;
+            if failed > 0 {
+                return Err(io::Error::other(format!(
+                    "{} of {} witnesses failed",
+                    failed, checked
+                )));
+            }
+        }
+        _ => unreachable!("clap requires a known subcommand"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,186 @@
+use super::{app, open_sled, run};
+use merkle_notes::display::{encode_hash, encode_witness, Encoding};
+use merkle_notes::notes::{Note, NoteHasher};
+use merkle_notes::sled::SledMerkleTree;
+use merkle_notes::MerkleTree;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::tempdir;
+
+/// Run a command on the tree, as if it were opened from directory, and
+/// return what it printed.
+fn run_command(
+    tree: &mut SledMerkleTree<NoteHasher>,
+    directory: &Path,
+    args: &[&str],
+) -> io::Result<String> {
+    let mut command_line = vec!["merkle-notes", directory.to_str().unwrap()];
+    command_line.extend(args);
+    let matches = app().get_matches_from(command_line);
+    let depth = matches
+        .value_of("depth")
+        .map(|depth| depth.parse().unwrap());
+    let mut out = vec![];
+    run(tree, depth, &matches, &mut out)?;
+    Ok(String::from_utf8(out).unwrap())
+}
+
+fn make_tree(directory: &Path) -> SledMerkleTree<NoteHasher> {
+    let mut tree = open_sled(Arc::new(NoteHasher {}), directory, "import", Some(5)).unwrap();
+    for byte in 0..5 {
+        tree.add(Note([byte; 32]));
+    }
+    tree
+}
+
+#[test]
+fn info_witness_verify() {
+    let directory = tempdir().unwrap();
+    let directory = directory.path().join("tree");
+    let mut tree = make_tree(&directory);
+    let hasher = tree.hasher();
+
+    let root = encode_hash(&*hasher, &tree.root_hash().unwrap(), Encoding::Hex).unwrap();
+    assert_eq!(
+        run_command(&mut tree, &directory, &["info"]).unwrap(),
+        format!(
+            "depth: 5\nleaves: 5\nnodes: {}\nroot: {}\n",
+            tree.num_nodes(),
+            root
+        )
+    );
+
+    let witness = encode_witness(&*hasher, &tree.witness(2).unwrap(), Encoding::Hex).unwrap();
+    assert_eq!(
+        run_command(&mut tree, &directory, &["witness", "2"]).unwrap(),
+        format!("{}\n", witness)
+    );
+    assert!(run_command(&mut tree, &directory, &["witness", "5"]).is_err());
+
+    let report = run_command(&mut tree, &directory, &["verify"]).unwrap();
+    assert!(report.ends_with("checked: 5\n"), "{}", report);
+}
+
+#[test]
+fn depth_must_match() {
+    let directory = tempdir().unwrap();
+    let directory = directory.path().join("tree");
+    let mut tree = make_tree(&directory);
+    assert!(run_command(&mut tree, &directory, &["--depth", "5", "info"]).is_ok());
+    let error = run_command(&mut tree, &directory, &["--depth", "33", "info"]).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "The tree was created with depth 5, not 33"
+    );
+}
+
+#[test]
+fn missing_tree() {
+    let directory = tempdir().unwrap();
+    let missing = directory.path().join("missing");
+    for command in &["info", "verify", "truncate"] {
+        let error = open_sled(Arc::new(NoteHasher {}), &missing, command, None)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(!missing.exists());
+    }
+}
+
+#[test]
+fn get_past_root_truncate() {
+    let directory = tempdir().unwrap();
+    let directory = directory.path().join("tree");
+    let mut tree = make_tree(&directory);
+    let hasher = tree.hasher();
+
+    assert_eq!(
+        run_command(&mut tree, &directory, &["get", "2"]).unwrap(),
+        format!("{}\n", Encoding::Hex.encode(&[2; 32]))
+    );
+    assert_eq!(
+        run_command(&mut tree, &directory, &["--encoding", "base64", "get", "4"]).unwrap(),
+        format!("{}\n", Encoding::Base64.encode(&[4; 32]))
+    );
+    let error = run_command(&mut tree, &directory, &["get", "5"]).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+    let past_root = tree.past_root(3).unwrap();
+    let past_root = format!(
+        "{}\n",
+        encode_hash(&*hasher, &past_root, Encoding::Hex).unwrap()
+    );
+    assert_eq!(
+        run_command(&mut tree, &directory, &["past-root", "3"]).unwrap(),
+        past_root
+    );
+
+    // truncating can't add leaves
+    let error = run_command(&mut tree, &directory, &["truncate", "6"]).unwrap_err();
+    assert_eq!(error.to_string(), "The tree only has 5 leaves");
+    assert_eq!(tree.len(), 5);
+
+    assert_eq!(
+        run_command(&mut tree, &directory, &["truncate", "3"]).unwrap(),
+        "leaves: 3\n"
+    );
+    assert_eq!(tree.len(), 3);
+    assert!(run_command(&mut tree, &directory, &["get", "3"]).is_err());
+    assert_eq!(
+        run_command(&mut tree, &directory, &["past-root", "3"]).unwrap(),
+        past_root
+    );
+}
+
+#[test]
+fn export_import() {
+    let directory = tempdir().unwrap();
+    let exported_from = directory.path().join("tree");
+    let mut tree = make_tree(&exported_from);
+    let state = run_command(&mut tree, &exported_from, &["export"]).unwrap();
+
+    let imported_into = directory.path().join("imported");
+    let mut imported =
+        open_sled(Arc::new(NoteHasher {}), &imported_into, "import", Some(5)).unwrap();
+    assert_eq!(
+        run_command(
+            &mut imported,
+            &imported_into,
+            &["--depth", "5", "import", state.trim_end()]
+        )
+        .unwrap(),
+        "leaves: 5\n"
+    );
+
+    // the imported tree only has the nodes on its frontier, so the node
+    // counts differ, but nothing else does
+    let info = |tree: &mut SledMerkleTree<NoteHasher>, directory: &Path| {
+        run_command(tree, directory, &["info"])
+            .unwrap()
+            .lines()
+            .filter(|line| !line.starts_with("nodes: "))
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        info(&mut imported, &imported_into),
+        info(&mut tree, &exported_from)
+    );
+    assert_eq!(
+        run_command(&mut imported, &imported_into, &["past-root", "5"]).unwrap(),
+        run_command(&mut tree, &exported_from, &["past-root", "5"]).unwrap()
+    );
+    // the imported tree can carry on where the exported one left off
+    tree.add(Note([5; 32]));
+    imported.add(Note([5; 32]));
+    assert_eq!(
+        run_command(&mut imported, &imported_into, &["past-root", "6"]).unwrap(),
+        run_command(&mut tree, &exported_from, &["past-root", "6"]).unwrap()
+    );
+
+    // but only an empty tree can import
+    let error =
+        run_command(&mut imported, &imported_into, &["import", state.trim_end()]).unwrap_err();
+    assert_eq!(error.to_string(), "Only an empty tree can import a state");
+}
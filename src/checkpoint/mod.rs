@@ -0,0 +1,167 @@
+//! Bookkeeping for named checkpoints, so that callers handling a chain
+//! reorganization can rewind the tree to "the end of block N" without
+//! having to remember how many notes each block added.
+//!
+//! Every backend keeps a Checkpoints list. The persistent trees store it
+//! in their counters namespace using `Checkpoints::write`, the in-memory
+//! trees keep it alongside the nodes (it is not part of their serialized
+//! format).
+
+use super::MerkleTree;
+#[cfg(any(test, feature = "sledder", feature = "rocker"))]
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::VecDeque;
+use std::io;
+#[cfg(any(test, feature = "sledder", feature = "rocker"))]
+use std::io::Read;
+
+/// A `MerkleTree` that can record checkpoints and be rewound to them. Every
+/// tree in this crate is one, but a tree implemented elsewhere doesn't have
+/// to be.
+///
+/// The persistent trees read their checkpoints from the database, so these
+/// methods fail if they can't be read. The in-memory trees only fail to
+/// rewind.
+pub trait Checkpointed: MerkleTree {
+    /// Record the current size of the tree under a caller-chosen id, such as
+    /// a block hash or height, so that the tree can be rewound to it later.
+    /// Recording an id that already exists replaces the old checkpoint.
+    ///
+    /// Only the most recent checkpoints are retained; see `set_reorg_depth`.
+    fn checkpoint(&mut self, id: &[u8]) -> io::Result<()>;
+
+    /// Truncate the tree to the size it had when the checkpoint `id` was
+    /// recorded, and forget the checkpoints recorded after it.
+    ///
+    /// Returns false, without changing the tree, if there is no such
+    /// checkpoint. Fails without changing the tree if it doesn't keep the
+    /// leaf that would become the last one, because it was skipped by a sync
+    /// or an import; the error wraps a `NotRetained`.
+    fn rewind_to(&mut self, id: &[u8]) -> io::Result<bool>;
+
+    /// List the retained checkpoints as (id, tree size) pairs, oldest first.
+    fn checkpoints(&self) -> io::Result<Vec<(Vec<u8>, usize)>>;
+
+    /// Set how many checkpoints are retained, dropping the oldest ones if
+    /// there are too many. Defaults to `DEFAULT_REORG_DEPTH`.
+    fn set_reorg_depth(&mut self, reorg_depth: usize) -> io::Result<()>;
+}
+
+/// How many checkpoints are retained unless the tree is told otherwise.
+pub const DEFAULT_REORG_DEPTH: usize = 100;
+
+/// An ordered list of (id, tree size) pairs, oldest first. Only the most
+/// recent `reorg_depth` checkpoints are retained; a reorganization deeper than
+/// that has to fall back on `truncate`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Checkpoints {
+    entries: VecDeque<(Vec<u8>, usize)>,
+    reorg_depth: usize,
+}
+
+impl Default for Checkpoints {
+    fn default() -> Self {
+        Checkpoints {
+            entries: VecDeque::new(),
+            reorg_depth: DEFAULT_REORG_DEPTH,
+        }
+    }
+}
+
+impl Checkpoints {
+    /// Record `size` under `id`. If the id was already recorded, the old
+    /// entry is replaced.
+    pub(crate) fn record(&mut self, id: &[u8], size: usize) {
+        self.entries.retain(|(existing, _)| existing[..] != *id);
+        self.entries.push_back((id.to_vec(), size));
+        self.prune();
+    }
+
+    /// The size recorded under id, if there is such a checkpoint.
+    pub(crate) fn find(&self, id: &[u8]) -> Option<usize> {
+        self.entries
+            .iter()
+            .find(|(existing, _)| existing[..] == *id)
+            .map(|(_, size)| *size)
+    }
+
+    /// Find the size recorded under id and forget every checkpoint that was
+    /// recorded after it. Returns None if there is no such checkpoint.
+    pub(crate) fn rewind(&mut self, id: &[u8]) -> Option<usize> {
+        let index = self
+            .entries
+            .iter()
+            .position(|(existing, _)| existing[..] == *id)?;
+        self.entries.truncate(index + 1);
+        Some(self.entries[index].1)
+    }
+
+    /// Forget the checkpoints that refer to leaves the tree no longer has.
+    /// Returns true if any were forgotten.
+    pub(crate) fn truncated(&mut self, past_size: usize) -> bool {
+        let count = self.entries.len();
+        self.entries.retain(|(_, size)| *size <= past_size);
+        self.entries.len() != count
+    }
+
+    /// Change how many checkpoints are retained, dropping the oldest ones if
+    /// there are now too many.
+    pub(crate) fn set_reorg_depth(&mut self, reorg_depth: usize) {
+        self.reorg_depth = reorg_depth;
+        self.prune();
+    }
+
+    /// The (id, size) pairs, oldest first.
+    pub(crate) fn list(&self) -> Vec<(Vec<u8>, usize)> {
+        self.entries.iter().cloned().collect()
+    }
+
+    fn prune(&mut self) {
+        while self.entries.len() > self.reorg_depth {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Serialize the checkpoints. The format is the reorg depth and the
+    /// number of checkpoints as little endian u32s, then for each checkpoint
+    /// the length of its id as a u32, the id, and the tree size as a u64.
+    #[cfg(any(test, feature = "sledder", feature = "rocker"))]
+    pub(crate) fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u32::<LittleEndian>(self.reorg_depth as u32)?;
+        writer.write_u32::<LittleEndian>(self.entries.len() as u32)?;
+        for (id, size) in self.entries.iter() {
+            writer.write_u32::<LittleEndian>(id.len() as u32)?;
+            writer.write_all(id)?;
+            writer.write_u64::<LittleEndian>(*size as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Deserialize checkpoints that were written with `Checkpoints::write`.
+    #[cfg(any(test, feature = "sledder", feature = "rocker"))]
+    pub(crate) fn read<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+        let reorg_depth = reader.read_u32::<LittleEndian>()? as usize;
+        let count = reader.read_u32::<LittleEndian>()?;
+        let mut entries = VecDeque::new();
+        for _ in 0..count {
+            let id_length = reader.read_u32::<LittleEndian>()?;
+            let mut id = vec![];
+            reader
+                .by_ref()
+                .take(u64::from(id_length))
+                .read_to_end(&mut id)?;
+            if id.len() != id_length as usize {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let size = reader.read_u64::<LittleEndian>()? as usize;
+            entries.push_back((id, size));
+        }
+        Ok(Checkpoints {
+            entries,
+            reorg_depth,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests;
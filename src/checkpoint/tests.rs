@@ -0,0 +1,77 @@
+use super::Checkpoints;
+
+#[test]
+fn record_and_rewind() {
+    let mut checkpoints = Checkpoints::default();
+    checkpoints.record(b"one", 1);
+    checkpoints.record(b"two", 3);
+    checkpoints.record(b"three", 6);
+    assert_eq!(checkpoints.rewind(b"four"), None);
+    assert_eq!(checkpoints.list().len(), 3);
+
+    assert_eq!(checkpoints.rewind(b"two"), Some(3));
+    assert_eq!(
+        checkpoints.list(),
+        vec![(b"one".to_vec(), 1), (b"two".to_vec(), 3)]
+    );
+    assert_eq!(checkpoints.rewind(b"three"), None);
+}
+
+#[test]
+fn record_replaces_existing_id() {
+    let mut checkpoints = Checkpoints::default();
+    checkpoints.record(b"one", 1);
+    checkpoints.record(b"two", 2);
+    checkpoints.record(b"one", 4);
+    assert_eq!(
+        checkpoints.list(),
+        vec![(b"two".to_vec(), 2), (b"one".to_vec(), 4)]
+    );
+}
+
+#[test]
+fn truncated() {
+    let mut checkpoints = Checkpoints::default();
+    checkpoints.record(b"one", 1);
+    checkpoints.record(b"two", 3);
+    checkpoints.record(b"three", 6);
+    assert!(!checkpoints.truncated(6));
+    assert!(checkpoints.truncated(4));
+    assert_eq!(
+        checkpoints.list(),
+        vec![(b"one".to_vec(), 1), (b"two".to_vec(), 3)]
+    );
+    assert!(checkpoints.truncated(0));
+    assert!(checkpoints.list().is_empty());
+}
+
+#[test]
+fn reorg_depth() {
+    let mut checkpoints = Checkpoints::default();
+    for size in 0..5u8 {
+        checkpoints.record(&[size], size as usize);
+    }
+    checkpoints.set_reorg_depth(2);
+    assert_eq!(checkpoints.list(), vec![(vec![3], 3), (vec![4], 4)]);
+    checkpoints.record(b"five", 5);
+    assert_eq!(
+        checkpoints.list(),
+        vec![(vec![4], 4), (b"five".to_vec(), 5)]
+    );
+    assert_eq!(checkpoints.rewind(&[3]), None);
+}
+
+#[test]
+fn serialization() {
+    let mut checkpoints = Checkpoints::default();
+    checkpoints.set_reorg_depth(7);
+    checkpoints.record(b"", 0);
+    checkpoints.record(b"block 12", 1234);
+    let mut bytes = vec![];
+    checkpoints.write(&mut bytes).unwrap();
+    let read_back = Checkpoints::read(&mut bytes[..].as_ref()).unwrap();
+    assert_eq!(read_back, checkpoints);
+
+    assert!(Checkpoints::read(&mut bytes[..bytes.len() - 1].as_ref()).is_err());
+    assert!(Checkpoints::read(&mut bytes[..14].as_ref()).is_err());
+}
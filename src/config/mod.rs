@@ -0,0 +1,131 @@
+//! Settings for constructing a tree, accepted by every backend's
+//! `with_config`.
+
+use super::{LeafStorage, MAX_TREE_DEPTH};
+use std::{fmt, io};
+
+/// How to build a tree. Start from the default and override what you need:
+///
+/// ```
+/// # use merkle_notes::config::TreeConfig;
+/// let config = TreeConfig {
+///     depth: 17,
+///     ..TreeConfig::default()
+/// };
+/// assert!(config.validate().is_ok());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeConfig {
+    /// The number of levels in the tree, including the root and the leaves.
+    /// A tree of depth d holds 2^(d-1) leaves, and its witnesses have d - 1
+    /// nodes. Defaults to 33, as sapling uses.
+    ///
+    /// Leaf positions are stored as u64, so the depth can be at most 64.
+    pub depth: usize,
+    /// How many checkpoints to retain; see `Checkpointed::set_reorg_depth`.
+    /// None leaves it as it is: the default for a new tree, or whatever was
+    /// set on an existing persistent tree.
+    pub reorg_depth: Option<usize>,
+    /// Whether a persistent tree stores its elements or only their hashes.
+    /// None keeps the mode an existing tree was created with, and stores
+    /// elements in a new one. In-memory trees always store their elements.
+    pub leaf_storage: Option<LeafStorage>,
+}
+
+impl Default for TreeConfig {
+    fn default() -> Self {
+        TreeConfig {
+            depth: 33,
+            reorg_depth: None,
+            leaf_storage: None,
+        }
+    }
+}
+
+impl TreeConfig {
+    /// Check that a tree can be built with this configuration. The
+    /// backends' `with_config` functions do this first.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.depth < 2 || self.depth > MAX_TREE_DEPTH {
+            return Err(ConfigError::UnsupportedDepth(self.depth));
+        }
+        Ok(())
+    }
+
+    /// Validate the configuration for an in-memory tree, which can't be
+    /// told to store only hashes. Its leaf positions and node indices are
+    /// kept in usize and u64, so every depth `validate` accepts can be
+    /// addressed on a 64-bit platform, but on a narrower one the depth can't
+    /// exceed the width of usize.
+    pub(crate) fn validate_in_memory(&self) -> Result<(), ConfigError> {
+        self.validate()?;
+        if self.depth > usize::BITS as usize {
+            return Err(ConfigError::UnsupportedDepth(self.depth));
+        }
+        match self.leaf_storage {
+            Some(LeafStorage::HashesOnly) => {
+                Err(ConfigError::UnsupportedLeafStorage(LeafStorage::HashesOnly))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returned when a tree can't be built with a TreeConfig, or a persistent
+/// tree can't be opened with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The depth is less than 2, or too deep for u64 leaf positions.
+    UnsupportedDepth(usize),
+    /// The backend can't store its leaves this way.
+    UnsupportedLeafStorage(LeafStorage),
+    /// The directory holds a tree created with another depth.
+    DepthMismatch { stored: usize, requested: usize },
+    /// The directory holds a tree created with another leaf storage mode.
+    LeafStorageMismatch {
+        stored: LeafStorage,
+        requested: LeafStorage,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::UnsupportedDepth(depth) => write!(
+                f,
+                "Unsupported tree depth {}; it must be between 2 and {}",
+                depth, MAX_TREE_DEPTH
+            ),
+            ConfigError::UnsupportedLeafStorage(leaf_storage) => write!(
+                f,
+                "{:?} leaf storage is not supported by this tree",
+                leaf_storage
+            ),
+            ConfigError::DepthMismatch { stored, requested } => write!(
+                f,
+                "Tree was created with depth {}, not {}",
+                stored, requested
+            ),
+            ConfigError::LeafStorageMismatch { stored, requested } => write!(
+                f,
+                "Tree was created with {:?} leaf storage, not {:?}",
+                stored, requested
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The persistent trees' `with_config` can also fail to open or read the
+/// database, so they return a ConfigError as an io::Error of kind
+/// InvalidInput. The ConfigError can be recovered with
+/// `io::Error::into_inner`.
+impl From<ConfigError> for io::Error {
+    fn from(error: ConfigError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+#[cfg(test)]
+mod tests;
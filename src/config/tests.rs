@@ -0,0 +1,79 @@
+use super::{ConfigError, TreeConfig};
+use crate::checkpoint::Checkpointed;
+use crate::linked::LinkedMerkleTree;
+use crate::test_helper::{MixHasher, StringHasher};
+use crate::vector::VectorMerkleTree;
+use crate::{LeafStorage, MerkleTree};
+
+#[test]
+fn validate() {
+    assert_eq!(TreeConfig::default().depth, 33);
+    assert!(TreeConfig::default().validate().is_ok());
+    for depth in [0, 1, 65, 255].iter() {
+        let config = TreeConfig {
+            depth: *depth,
+            ..TreeConfig::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::UnsupportedDepth(*depth))
+        );
+    }
+}
+
+#[test]
+fn in_memory_trees() {
+    let config = TreeConfig {
+        depth: 4,
+        reorg_depth: Some(1),
+        ..TreeConfig::default()
+    };
+    let mut linked = LinkedMerkleTree::with_config(StringHasher::new(), config).unwrap();
+    let mut vector = VectorMerkleTree::with_config(StringHasher::new(), config).unwrap();
+    let mut expected = LinkedMerkleTree::new_with_size(StringHasher::new(), 4);
+    for character in "abcde".chars() {
+        linked.add(character.to_string());
+        vector.add(character.to_string());
+        expected.add(character.to_string());
+    }
+    assert_eq!(linked.root_hash(), expected.root_hash());
+    assert_eq!(vector.root_hash(), expected.root_hash());
+    assert_eq!(linked.witness(0).unwrap().auth_path.len(), 3);
+
+    linked.checkpoint(b"one").unwrap();
+    linked.checkpoint(b"two").unwrap();
+    assert_eq!(linked.checkpoints().unwrap().len(), 1);
+
+    let hashes_only = TreeConfig {
+        leaf_storage: Some(LeafStorage::HashesOnly),
+        ..TreeConfig::default()
+    };
+    assert_eq!(
+        LinkedMerkleTree::with_config(StringHasher::new(), hashes_only).err(),
+        Some(ConfigError::UnsupportedLeafStorage(LeafStorage::HashesOnly))
+    );
+    let too_deep = TreeConfig {
+        depth: 65,
+        ..TreeConfig::default()
+    };
+    assert!(VectorMerkleTree::with_config(StringHasher::new(), too_deep).is_err());
+}
+
+#[test]
+fn deepest_in_memory_trees() {
+    let config = TreeConfig {
+        depth: 64,
+        ..TreeConfig::default()
+    };
+    let mut linked = LinkedMerkleTree::with_config(MixHasher::new(), config).unwrap();
+    let mut vector = VectorMerkleTree::with_config(MixHasher::new(), config).unwrap();
+    for element in 0..100 {
+        linked.add(element);
+        vector.add(element);
+    }
+    assert_eq!(linked.root_hash(), vector.root_hash());
+    let witness = linked.witness(42).unwrap();
+    assert_eq!(witness.auth_path.len(), 63);
+    assert!(witness.verify(&MixHasher {}, &42));
+    assert_eq!(vector.witness(42), Some(witness));
+}
@@ -0,0 +1,188 @@
+//! Text encodings of root hashes and witnesses, for logging them and for
+//! pasting them between services and command line tools.
+//!
+//! Hashes are encoded from the bytes `MerkleHasher::write_hash` writes, and
+//! witnesses from the bytes `Witness::write` writes, so the text is only
+//! meaningful alongside a hasher of the same type. Both hex (lowercase) and
+//! standard, padded base64 are supported.
+
+use super::{invalid_data, HashableElement, MerkleHasher, Witness};
+use std::fmt;
+use std::io;
+
+/// A way of writing bytes as text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Hex,
+    Base64,
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+impl Encoding {
+    /// Write bytes as text.
+    pub fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Hex => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            Encoding::Base64 => {
+                let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+                for chunk in bytes.chunks(3) {
+                    let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| {
+                        group | u32::from(*byte) << (16 - 8 * i)
+                    });
+                    for i in 0..4 {
+                        if i <= chunk.len() {
+                            let sextet = (group >> (18 - 6 * i)) & 0x3f;
+                            text.push(BASE64_ALPHABET[sextet as usize] as char);
+                        } else {
+                            text.push('=');
+                        }
+                    }
+                }
+                text
+            }
+        }
+    }
+
+    /// Read back bytes that were written as text. Hex is accepted in either
+    /// case. Fails if the text isn't valid in this encoding, or for base64,
+    /// isn't the text `encode` writes for those bytes.
+    pub fn decode(self, text: &str) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Hex => {
+                if text.len() % 2 != 0 {
+                    return Err(invalid_data(format!(
+                        "Hex text has an odd length of {}",
+                        text.len()
+                    )));
+                }
+                text.as_bytes()
+                    .chunks(2)
+                    .map(|pair| Ok(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+                    .collect()
+            }
+            Encoding::Base64 => {
+                if text.len() % 4 != 0 {
+                    return Err(invalid_data(format!(
+                        "Base64 text length {} is not a multiple of 4",
+                        text.len()
+                    )));
+                }
+                let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+                let num_chunks = text.len() / 4;
+                for (index, chunk) in text.as_bytes().chunks(4).enumerate() {
+                    let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+                    if padding > 2 || (padding > 0 && index + 1 < num_chunks) {
+                        return Err(invalid_data("Misplaced base64 padding".to_string()));
+                    }
+                    let mut group = 0u32;
+                    for character in &chunk[..4 - padding] {
+                        group = group << 6 | base64_digit(*character)?;
+                    }
+                    group <<= 6 * padding;
+                    // the bits of a padded group after the last byte must be
+                    // zero, so that every byte string has one encoding
+                    if group & ((1 << (8 * padding)) - 1) != 0 {
+                        return Err(invalid_data(
+                            "Base64 text has bits set after its last byte".to_string(),
+                        ));
+                    }
+                    for i in 0..3 - padding {
+                        bytes.push((group >> (16 - 8 * i)) as u8);
+                    }
+                }
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+fn hex_digit(character: u8) -> io::Result<u8> {
+    (character as char)
+        .to_digit(16)
+        .map(|digit| digit as u8)
+        .ok_or_else(|| invalid_data(format!("Invalid hex digit {:?}", character as char)))
+}
+
+fn base64_digit(character: u8) -> io::Result<u32> {
+    BASE64_ALPHABET
+        .iter()
+        .position(|c| *c == character)
+        .map(|digit| digit as u32)
+        .ok_or_else(|| invalid_data(format!("Invalid base64 digit {:?}", character as char)))
+}
+
+/// Fail if decoding didn't use up all of the bytes.
+fn check_consumed(remaining: &[u8]) -> io::Result<()> {
+    if remaining.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid_data(format!(
+            "{} bytes left over after decoding",
+            remaining.len()
+        )))
+    }
+}
+
+/// Encode a hash, such as a root hash, as text.
+pub fn encode_hash<H: MerkleHasher>(
+    hasher: &H,
+    hash: &<H::Element as HashableElement>::Hash,
+    encoding: Encoding,
+) -> io::Result<String> {
+    let mut bytes = vec![];
+    hasher.write_hash(hash, &mut bytes)?;
+    Ok(encoding.encode(&bytes))
+}
+
+/// Decode a hash that was encoded with `encode_hash`.
+pub fn decode_hash<H: MerkleHasher>(
+    hasher: &H,
+    text: &str,
+    encoding: Encoding,
+) -> io::Result<<H::Element as HashableElement>::Hash> {
+    let bytes = encoding.decode(text)?;
+    let mut reader = &bytes[..];
+    let hash = hasher.read_hash(&mut reader)?;
+    check_consumed(reader)?;
+    Ok(hash)
+}
+
+/// Encode a witness as text.
+pub fn encode_witness<H: MerkleHasher>(
+    hasher: &H,
+    witness: &Witness<H>,
+    encoding: Encoding,
+) -> io::Result<String> {
+    let mut bytes = vec![];
+    witness.write(hasher, &mut bytes)?;
+    Ok(encoding.encode(&bytes))
+}
+
+/// Decode a witness that was encoded with `encode_witness`.
+pub fn decode_witness<H: MerkleHasher>(
+    hasher: &H,
+    text: &str,
+    encoding: Encoding,
+) -> io::Result<Witness<H>> {
+    let bytes = encoding.decode(text)?;
+    let mut reader = &bytes[..];
+    let witness = Witness::read(hasher, &mut reader)?;
+    check_consumed(reader)?;
+    Ok(witness)
+}
+
+/// Displays a hash in hex, for use in format strings:
+/// `println!("root {}", HexHash(&*hasher, &root))`.
+pub struct HexHash<'a, H: MerkleHasher>(pub &'a H, pub &'a <H::Element as HashableElement>::Hash);
+
+impl<'a, H: MerkleHasher> fmt::Display for HexHash<'a, H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = encode_hash(self.0, self.1, Encoding::Hex).map_err(|_| fmt::Error)?;
+        f.write_str(&text)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,70 @@
+use super::{decode_hash, decode_witness, encode_hash, encode_witness, Encoding, HexHash};
+use crate::linked::LinkedMerkleTree;
+use crate::test_helper::{MixHasher, StringHasher};
+use crate::MerkleTree;
+
+#[test]
+fn encodings() {
+    let cases: &[(&[u8], &str, &str)] = &[
+        (b"", "", ""),
+        (b"f", "66", "Zg=="),
+        (b"fo", "666f", "Zm8="),
+        (b"foo", "666f6f", "Zm9v"),
+        (b"foob", "666f6f62", "Zm9vYg=="),
+        (&[0, 0xff, 0x10], "00ff10", "AP8Q"),
+    ];
+    for (bytes, hex, base64) in cases {
+        assert_eq!(Encoding::Hex.encode(bytes), *hex);
+        assert_eq!(Encoding::Base64.encode(bytes), *base64);
+        assert_eq!(Encoding::Hex.decode(hex).unwrap(), *bytes);
+        assert_eq!(Encoding::Base64.decode(base64).unwrap(), *bytes);
+    }
+    assert_eq!(Encoding::Hex.decode("00FF10").unwrap(), vec![0, 0xff, 0x10]);
+
+    assert!(Encoding::Hex.decode("abc").is_err());
+    assert!(Encoding::Hex.decode("zz").is_err());
+    assert!(Encoding::Base64.decode("Zg=").is_err());
+    assert!(Encoding::Base64.decode("Z===").is_err());
+    assert!(Encoding::Base64.decode("Zg==Zg==").is_err());
+    assert!(Encoding::Base64.decode("Zm9*").is_err());
+    // "Zg==" and "Zm8=" with bits set after the last byte
+    assert!(Encoding::Base64.decode("Zh==").is_err());
+    assert!(Encoding::Base64.decode("Zm9=").is_err());
+}
+
+#[test]
+fn hashes() {
+    let hasher = MixHasher::new();
+    let mut tree = LinkedMerkleTree::new(hasher.clone());
+    for value in 0..10 {
+        tree.add(value);
+    }
+    let root = tree.root_hash().unwrap();
+    for encoding in [Encoding::Hex, Encoding::Base64] {
+        let text = encode_hash(&*hasher, &root, encoding).unwrap();
+        assert_eq!(decode_hash(&*hasher, &text, encoding).unwrap(), root);
+    }
+    let text = encode_hash(&*hasher, &root, Encoding::Hex).unwrap();
+    assert_eq!(text.len(), 16);
+    assert_eq!(HexHash(&*hasher, &root).to_string(), text);
+    assert!(decode_hash(&*hasher, &text[..14], Encoding::Hex).is_err());
+    assert!(decode_hash(&*hasher, &format!("{}00", text), Encoding::Hex).is_err());
+}
+
+#[test]
+fn witnesses() {
+    let hasher = StringHasher::new();
+    let mut tree = LinkedMerkleTree::new_with_size(hasher.clone(), 5);
+    for character in "abcde".chars() {
+        tree.add(character.to_string());
+    }
+    let witness = tree.witness(2).unwrap();
+    for encoding in [Encoding::Hex, Encoding::Base64] {
+        let text = encode_witness(&*hasher, &witness, encoding).unwrap();
+        let decoded = decode_witness(&*hasher, &text, encoding).unwrap();
+        assert_eq!(decoded, witness);
+        assert!(decoded.verify(&*hasher, &"c".to_string()));
+    }
+    let text = encode_witness(&*hasher, &witness, Encoding::Base64).unwrap();
+    assert!(decode_witness(&*hasher, &text[..text.len() - 4], Encoding::Base64).is_err());
+}
@@ -0,0 +1,284 @@
+//! Trees whose depth is part of their type.
+//!
+//! The backends take their depth at runtime, so nothing stops a witness from
+//! a 20 level tree being checked against the root of a 32 level one.
+//! `FixedDepth<M, DEPTH>` wraps a backend built with DEPTH levels below the
+//! root, and its witnesses are `FixedWitness<H, DEPTH>`, whose authentication
+//! path is an array of exactly DEPTH nodes. Code that expects one depth
+//! won't compile when handed a tree or witness of another.
+//!
+//! DEPTH is the length of an authentication path. The trees built by the
+//! backends' `new` functions (33 levels, including the root) correspond to a
+//! DEPTH of 32.
+
+use super::checkpoint::Checkpointed;
+#[cfg(any(feature = "sledder", feature = "rocker"))]
+use super::config::TreeConfig;
+use super::linked::LinkedMerkleTree;
+#[cfg(feature = "rocker")]
+use super::rocks::RocksMerkleTree;
+#[cfg(feature = "sledder")]
+use super::sled::SledMerkleTree;
+use super::vector::VectorMerkleTree;
+use super::{
+    invalid_data, HashableElement, MerkleHasher, MerkleTree, NotRetained, Witness, WitnessNode,
+    MAX_TREE_DEPTH,
+};
+use byteorder::ReadBytesExt;
+use std::convert::TryFrom;
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::sync::Arc;
+
+type Hash<H> = <<H as MerkleHasher>::Element as HashableElement>::Hash;
+
+/// Commitment that a leaf exists in a tree with DEPTH levels below the root.
+/// The same as a `Witness`, but the length of the path is fixed.
+pub struct FixedWitness<H: MerkleHasher, const DEPTH: usize> {
+    pub tree_size: usize,
+    pub root_hash: Hash<H>,
+    pub auth_path: [WitnessNode<Hash<H>>; DEPTH],
+}
+
+impl<H: MerkleHasher, const DEPTH: usize> FixedWitness<H, DEPTH> {
+    /// Verify that the authentication path leads from my_hash to the root
+    /// hash, as in `Witness::verify`.
+    pub fn verify(&self, hasher: &H, my_hash: &Hash<H>) -> bool {
+        let mut current = my_hash.clone();
+        for (depth, node) in self.auth_path.iter().enumerate() {
+            current = match node {
+                WitnessNode::Left(ref right) => hasher.combine_hash(depth, &current, right),
+                WitnessNode::Right(ref left) => hasher.combine_hash(depth, left, &current),
+            }
+        }
+        current == self.root_hash
+    }
+}
+
+impl<H: MerkleHasher, const DEPTH: usize> From<FixedWitness<H, DEPTH>> for Witness<H> {
+    fn from(witness: FixedWitness<H, DEPTH>) -> Self {
+        Witness {
+            tree_size: witness.tree_size,
+            root_hash: witness.root_hash,
+            auth_path: Vec::from(witness.auth_path),
+        }
+    }
+}
+
+impl<H: MerkleHasher, const DEPTH: usize> TryFrom<Witness<H>> for FixedWitness<H, DEPTH> {
+    type Error = Witness<H>;
+
+    /// Fails, giving the witness back, if its path isn't DEPTH nodes long.
+    fn try_from(witness: Witness<H>) -> Result<Self, Witness<H>> {
+        let Witness {
+            tree_size,
+            root_hash,
+            auth_path,
+        } = witness;
+        match <[WitnessNode<Hash<H>>; DEPTH]>::try_from(auth_path) {
+            Ok(auth_path) => Ok(FixedWitness {
+                tree_size,
+                root_hash,
+                auth_path,
+            }),
+            Err(auth_path) => Err(Witness {
+                tree_size,
+                root_hash,
+                auth_path,
+            }),
+        }
+    }
+}
+
+impl<H: MerkleHasher, const DEPTH: usize> PartialEq for FixedWitness<H, DEPTH> {
+    fn eq(&self, other: &Self) -> bool {
+        self.tree_size == other.tree_size
+            && self.root_hash == other.root_hash
+            && self.auth_path == other.auth_path
+    }
+}
+
+impl<H: MerkleHasher, const DEPTH: usize> std::fmt::Debug for FixedWitness<H, DEPTH> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("FixedWitness")
+            .field("tree_size", &self.tree_size)
+            .field("root_hash", &self.root_hash)
+            .field("auth_path", &self.auth_path)
+            .finish()
+    }
+}
+
+/// A tree with DEPTH levels below the root. Everything but the witnesses is
+/// available through Deref to the wrapped tree. Changes go through the
+/// methods here instead of DerefMut, so the wrapped tree can't be swapped
+/// for one of another depth.
+pub struct FixedDepth<M: MerkleTree, const DEPTH: usize> {
+    tree: Box<M>,
+}
+
+impl<M: MerkleTree, const DEPTH: usize> FixedDepth<M, DEPTH> {
+    /// Fails to compile for a depth the backends can't build.
+    const VALID: () = assert!(
+        DEPTH > 0 && DEPTH < MAX_TREE_DEPTH,
+        "DEPTH must be between 1 and 63"
+    );
+
+    fn wrap(tree: Box<M>) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::VALID;
+        FixedDepth { tree }
+    }
+
+    /// Unwrap the tree.
+    pub fn into_inner(self) -> Box<M> {
+        self.tree
+    }
+
+    /// Construct the proof that the leaf at position exists.
+    pub fn witness(&self, position: usize) -> Option<FixedWitness<M::Hasher, DEPTH>> {
+        self.tree.witness(position).map(fixed)
+    }
+
+    /// Construct the proof that the leaf at position existed when the tree
+    /// had past_size leaves.
+    pub fn past_witness(
+        &self,
+        position: usize,
+        past_size: usize,
+    ) -> Option<FixedWitness<M::Hasher, DEPTH>> {
+        self.tree.past_witness(position, past_size).map(fixed)
+    }
+
+    /// Insert the new leaf element into the tree.
+    pub fn add(&mut self, element: <M::Hasher as MerkleHasher>::Element) {
+        self.tree.add(element)
+    }
+
+    /// Truncate the tree to the values it contained when it contained
+    /// past_size elements.
+    pub fn truncate(&mut self, past_size: usize) -> Result<(), NotRetained> {
+        self.tree.truncate(past_size)
+    }
+}
+
+impl<M: Checkpointed, const DEPTH: usize> FixedDepth<M, DEPTH> {
+    /// Record the current size of the tree under id, as
+    /// `Checkpointed::checkpoint`.
+    pub fn checkpoint(&mut self, id: &[u8]) -> io::Result<()> {
+        self.tree.checkpoint(id)
+    }
+
+    /// Truncate the tree to the size recorded under id, as
+    /// `Checkpointed::rewind_to`.
+    pub fn rewind_to(&mut self, id: &[u8]) -> io::Result<bool> {
+        self.tree.rewind_to(id)
+    }
+
+    /// Set how many checkpoints the tree keeps.
+    pub fn set_reorg_depth(&mut self, reorg_depth: usize) -> io::Result<()> {
+        self.tree.set_reorg_depth(reorg_depth)
+    }
+}
+
+impl<H: MerkleHasher, const DEPTH: usize> FixedDepth<LinkedMerkleTree<H>, DEPTH> {
+    /// Construct a new, empty tree.
+    pub fn new(hasher: Arc<H>) -> Self {
+        Self::wrap(LinkedMerkleTree::new_with_size(hasher, DEPTH + 1))
+    }
+
+    /// Load a tree that was written with `MerkleTree::write`. Fails if it
+    /// was written with a different depth.
+    pub fn read<R: io::Read>(hasher: Arc<H>, reader: &mut R) -> io::Result<Self> {
+        let tree_depth = read_depth::<_, DEPTH>(reader)?;
+        let tree = LinkedMerkleTree::read(hasher, &mut (&[tree_depth][..]).chain(reader))?;
+        Ok(Self::wrap(tree))
+    }
+}
+
+impl<H: MerkleHasher, const DEPTH: usize> FixedDepth<VectorMerkleTree<H>, DEPTH> {
+    /// Construct a new, empty tree.
+    pub fn new(hasher: Arc<H>) -> Self {
+        Self::wrap(VectorMerkleTree::new_with_size(hasher, DEPTH + 1))
+    }
+
+    /// Load a tree that was written with `MerkleTree::write`. Fails if it
+    /// was written with a different depth.
+    pub fn read<R: io::Read>(hasher: Arc<H>, reader: &mut R) -> io::Result<Self> {
+        let tree_depth = read_depth::<_, DEPTH>(reader)?;
+        let tree = VectorMerkleTree::read(hasher, &mut (&[tree_depth][..]).chain(reader))?;
+        Ok(Self::wrap(tree))
+    }
+}
+
+#[cfg(feature = "sledder")]
+impl<H: MerkleHasher, const DEPTH: usize> FixedDepth<SledMerkleTree<H>, DEPTH> {
+    /// Open the tree stored in the given directory, creating it if
+    /// necessary.
+    ///
+    /// Fails with InvalidInput wrapping a ConfigError if the directory holds
+    /// a tree created with another depth, or with the database's error if it
+    /// can't be opened.
+    pub fn open(hasher: Arc<H>, sled_directory: &std::path::Path) -> io::Result<Self> {
+        let config = TreeConfig {
+            depth: DEPTH + 1,
+            ..TreeConfig::default()
+        };
+        Ok(Self::wrap(Box::new(SledMerkleTree::with_config(
+            hasher,
+            sled_directory,
+            config,
+        )?)))
+    }
+}
+
+#[cfg(feature = "rocker")]
+impl<H: MerkleHasher, const DEPTH: usize> FixedDepth<RocksMerkleTree<H>, DEPTH> {
+    /// Open the tree stored in the given directory, creating it if
+    /// necessary.
+    ///
+    /// Fails with InvalidInput wrapping a ConfigError if the directory holds
+    /// a tree created with another depth, or with the database's error if it
+    /// can't be opened.
+    pub fn open(hasher: Arc<H>, rocks_directory: &std::path::Path) -> io::Result<Self> {
+        let config = TreeConfig {
+            depth: DEPTH + 1,
+            ..TreeConfig::default()
+        };
+        Ok(Self::wrap(Box::new(RocksMerkleTree::with_config(
+            hasher,
+            rocks_directory,
+            config,
+        )?)))
+    }
+}
+
+impl<M: MerkleTree, const DEPTH: usize> Deref for FixedDepth<M, DEPTH> {
+    type Target = M;
+
+    fn deref(&self) -> &M {
+        &self.tree
+    }
+}
+
+/// Convert a witness from a tree built with DEPTH levels below the root.
+fn fixed<H: MerkleHasher, const DEPTH: usize>(witness: Witness<H>) -> FixedWitness<H, DEPTH> {
+    FixedWitness::try_from(witness)
+        .unwrap_or_else(|_| panic!("A tree of depth {} made a witness of another depth", DEPTH))
+}
+
+/// Read the depth byte at the start of a serialized in-memory tree and check
+/// that it matches DEPTH.
+fn read_depth<R: io::Read, const DEPTH: usize>(reader: &mut R) -> io::Result<u8> {
+    let tree_depth = reader.read_u8()?;
+    if tree_depth as usize != DEPTH + 1 {
+        return Err(invalid_data(format!(
+            "Tree was written with {} levels below the root, not {}",
+            tree_depth as i32 - 1,
+            DEPTH
+        )));
+    }
+    Ok(tree_depth)
+}
+
+#[cfg(test)]
+mod tests;
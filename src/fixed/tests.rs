@@ -0,0 +1,76 @@
+use super::{FixedDepth, FixedWitness};
+use crate::linked::LinkedMerkleTree;
+use crate::test_helper::StringHasher;
+use crate::vector::VectorMerkleTree;
+use crate::{MerkleTree, Witness, WitnessNode};
+use std::convert::TryFrom;
+
+fn make_tree(characters: &str) -> FixedDepth<LinkedMerkleTree<StringHasher>, 4> {
+    let mut tree = FixedDepth::<LinkedMerkleTree<_>, 4>::new(StringHasher::new());
+    for character in characters.chars() {
+        tree.add(character.to_string());
+    }
+    tree
+}
+
+#[test]
+fn witness_has_fixed_length() {
+    let tree = make_tree("abcde");
+    let mut unfixed = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in "abcde".chars() {
+        unfixed.add(character.to_string());
+    }
+    assert_eq!(tree.root_hash(), unfixed.root_hash());
+
+    let witness: FixedWitness<StringHasher, 4> = tree.witness(2).unwrap();
+    assert_eq!(witness.auth_path.len(), 4);
+    assert_eq!(witness.auth_path[0], WitnessNode::Left("d".to_string()));
+    assert!(witness.verify(&StringHasher {}, &"c".to_string()));
+    assert!(!witness.verify(&StringHasher {}, &"d".to_string()));
+    assert_eq!(Witness::from(witness), unfixed.witness(2).unwrap());
+    assert_eq!(
+        Witness::from(tree.past_witness(1, 3).unwrap()),
+        unfixed.past_witness(1, 3).unwrap()
+    );
+    assert!(tree.witness(5).is_none());
+}
+
+#[test]
+fn witness_conversion_checks_length() {
+    let mut shallow = LinkedMerkleTree::new_with_size(StringHasher::new(), 3);
+    shallow.add("a".to_string());
+    let witness = shallow.witness(0).unwrap();
+    let witness = FixedWitness::<StringHasher, 4>::try_from(witness).unwrap_err();
+    assert_eq!(witness.auth_path.len(), 2);
+    assert!(FixedWitness::<StringHasher, 2>::try_from(witness).is_ok());
+}
+
+#[test]
+fn backends_agree() {
+    let linked = make_tree("abcdefg");
+    let mut vector = FixedDepth::<VectorMerkleTree<_>, 4>::new(StringHasher::new());
+    for character in "abcdefg".chars() {
+        vector.add(character.to_string());
+    }
+    assert_eq!(vector.root_hash(), linked.root_hash());
+    assert_eq!(vector.witness(3), linked.witness(3));
+}
+
+#[test]
+fn read_checks_depth() {
+    let tree = make_tree("abc");
+    let mut bytes = vec![];
+    tree.write(&mut bytes).unwrap();
+
+    let read_back =
+        FixedDepth::<LinkedMerkleTree<_>, 4>::read(StringHasher::new(), &mut &bytes[..]).unwrap();
+    assert_eq!(read_back.root_hash(), tree.root_hash());
+    let vector =
+        FixedDepth::<VectorMerkleTree<_>, 4>::read(StringHasher::new(), &mut &bytes[..]).unwrap();
+    assert_eq!(vector.root_hash(), tree.root_hash());
+
+    assert!(
+        FixedDepth::<LinkedMerkleTree<_>, 5>::read(StringHasher::new(), &mut &bytes[..]).is_err()
+    );
+    assert_eq!(read_back.into_inner().len(), 3);
+}
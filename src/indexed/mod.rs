@@ -0,0 +1,294 @@
+//! An indexed Merkle tree, where the leaves form a linked list sorted by
+//! value.
+//!
+//! Each leaf stores a value along with the position and value of the next
+//! larger leaf. To show that a value is absent, it's enough to witness the
+//! leaf with the largest value below it (the "low leaf") and show that the
+//! next value after it is larger than the value in question. That takes one
+//! ordinary witness, instead of the 256 levels of a `SparseMerkleTree`.
+//!
+//! The tree is stored in a `LinkedMerkleTree`. Leaves are appended in the
+//! order they are inserted; only the low leaf is updated in place to link to
+//! the new one.
+
+use super::linked::LinkedMerkleTree;
+use super::{invalid_data, HashableElement, MerkleHash, MerkleHasher, MerkleTree, Witness};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::io;
+use std::sync::Arc;
+
+/// A value that can be stored in an indexed tree. The tree keeps its leaves
+/// sorted by the ordering on values.
+pub trait IndexedValue: Ord + Clone + Debug {
+    type Hash: MerkleHash;
+
+    /// Hash a leaf. The hash must commit to all three parts of the leaf, or
+    /// a low leaf could be forged for any value.
+    fn hash_leaf(value: &Self, next_index: usize, next_value: &Self) -> Self::Hash;
+
+    /// Write this value to a writer.
+    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()>;
+
+    /// Read a value that was written with `IndexedValue::write`.
+    fn read<R: io::Read>(reader: &mut R) -> io::Result<Self>;
+}
+
+/// A leaf in an indexed tree. A next_index of 0 means this is the leaf with
+/// the largest value; its next_value is meaningless. (Position 0 always holds
+/// the lowest value, so no leaf can link to it.)
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexedLeaf<V: IndexedValue> {
+    pub value: V,
+    pub next_index: usize,
+    pub next_value: V,
+}
+
+impl<V: IndexedValue> IndexedLeaf<V> {
+    /// Read a leaf that was written with `HashableElement::write`. Hashers
+    /// for indexed trees can use this to implement `read_element`.
+    pub fn read<R: io::Read>(reader: &mut R) -> io::Result<Self> {
+        let value = V::read(reader)?;
+        let next_index = usize::try_from(reader.read_u64::<LittleEndian>()?)
+            .map_err(|_| invalid_data("Next index doesn't fit in a usize".to_string()))?;
+        let next_value = V::read(reader)?;
+        Ok(IndexedLeaf {
+            value,
+            next_index,
+            next_value,
+        })
+    }
+
+    /// Determine whether value falls in the gap between this leaf and the
+    /// next one, which proves it isn't in the tree.
+    pub fn is_low_leaf_for(&self, value: &V) -> bool {
+        self.value < *value && (self.next_index == 0 || *value < self.next_value)
+    }
+}
+
+impl<V: IndexedValue> HashableElement for IndexedLeaf<V> {
+    type Hash = V::Hash;
+
+    fn merkle_hash(&self) -> V::Hash {
+        V::hash_leaf(&self.value, self.next_index, &self.next_value)
+    }
+
+    /// The value, the next_index as a little endian u64, and the next value.
+    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.value.write(writer)?;
+        writer.write_u64::<LittleEndian>(self.next_index as u64)?;
+        self.next_value.write(writer)
+    }
+}
+
+/// What changed when a value was inserted: the low leaf, which now links to
+/// the new leaf, and the new leaf itself. Both witnesses are against the
+/// root hash after the insertion.
+pub struct Insertion<H: MerkleHasher> {
+    pub low_position: usize,
+    pub low_leaf: H::Element,
+    pub low_witness: Witness<H>,
+    pub position: usize,
+    pub leaf: H::Element,
+    pub witness: Witness<H>,
+}
+
+/// Proof that a value is not in an indexed tree with the witness' root hash.
+pub struct NonMembershipProof<H: MerkleHasher> {
+    pub low_leaf: H::Element,
+    pub witness: Witness<H>,
+}
+
+impl<V: IndexedValue, H: MerkleHasher<Element = IndexedLeaf<V>>> NonMembershipProof<H> {
+    /// Verify that the low leaf is in the tree and that value falls after
+    /// it. The caller must still check that the witness' root hash is one
+    /// they trust.
+    pub fn verify(&self, hasher: &H, value: &V) -> bool {
+        self.low_leaf.is_low_leaf_for(value)
+            && self.witness.verify(hasher, &self.low_leaf.merkle_hash())
+    }
+}
+
+/// Merkle tree of IndexedLeafs that supports proofs of non-membership.
+///
+/// The tree starts with a single leaf holding the lowest value, which must
+/// be lower than any value that will be inserted.
+pub struct IndexedMerkleTree<V: IndexedValue, H: MerkleHasher<Element = IndexedLeaf<V>>> {
+    tree: Box<LinkedMerkleTree<H>>,
+    /// The position of each value in the tree.
+    positions: BTreeMap<V, usize>,
+}
+
+impl<V: IndexedValue, H: MerkleHasher<Element = IndexedLeaf<V>>> IndexedMerkleTree<V, H> {
+    /// Construct a tree holding only the lowest value.
+    pub fn new(hasher: Arc<H>, lowest_value: V) -> Self {
+        let mut tree = LinkedMerkleTree::new(hasher);
+        tree.add(IndexedLeaf {
+            value: lowest_value.clone(),
+            next_index: 0,
+            next_value: lowest_value.clone(),
+        });
+        let mut positions = BTreeMap::new();
+        positions.insert(lowest_value, 0);
+        IndexedMerkleTree { tree, positions }
+    }
+
+    /// Load a tree that was written with `IndexedMerkleTree::write`.
+    ///
+    /// Fails with InvalidData unless the leaves form a single sorted list:
+    /// starting from the lowest value at position 0, each leaf's next_index
+    /// and next_value must point at the leaf with the next larger value, and
+    /// the last one must be the only leaf with a next_index of 0.
+    pub fn read<R: io::Read>(hasher: Arc<H>, reader: &mut R) -> io::Result<Self> {
+        let tree = LinkedMerkleTree::read(hasher, reader)?;
+        if tree.is_empty() {
+            return Err(invalid_data("Indexed tree has no lowest value".to_string()));
+        }
+        let leaves = tree
+            .iter_notes()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| invalid_data(error.to_string()))?;
+        // every link goes to a larger value, so following len - 1 of them
+        // visits each leaf once
+        let mut position = 0;
+        for _ in 1..leaves.len() {
+            let leaf = &leaves[position];
+            match leaves.get(leaf.next_index) {
+                Some(next)
+                    if leaf.next_index != 0
+                        && next.value == leaf.next_value
+                        && leaf.value < next.value =>
+                {
+                    position = leaf.next_index
+                }
+                _ => {
+                    return Err(invalid_data(format!(
+                        "Leaf at position {} doesn't link to the next larger value",
+                        position
+                    )))
+                }
+            }
+        }
+        if leaves[position].next_index != 0 {
+            return Err(invalid_data(format!(
+                "Leaf at position {} holds the largest value but links to another",
+                position
+            )));
+        }
+        let positions = leaves
+            .into_iter()
+            .enumerate()
+            .map(|(position, leaf)| (leaf.value, position))
+            .collect();
+        Ok(IndexedMerkleTree { tree, positions })
+    }
+
+    /// Write the tree to a writer, in the format of `LinkedMerkleTree`.
+    pub fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.tree.write(writer)
+    }
+
+    /// The underlying tree, for roots, witnesses and leaves.
+    pub fn tree(&self) -> &LinkedMerkleTree<H> {
+        &self.tree
+    }
+
+    /// Get the number of leaves, including the lowest value.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// An indexed tree always holds the lowest value.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// The current root hash of the tree.
+    pub fn root_hash(&self) -> <H::Element as HashableElement>::Hash {
+        self.tree.root_hash().expect("indexed tree is never empty")
+    }
+
+    /// Determine whether value is in the tree.
+    pub fn contains(&self, value: &V) -> bool {
+        self.positions.contains_key(value)
+    }
+
+    /// The position of the leaf holding value.
+    pub fn position(&self, value: &V) -> Option<usize> {
+        self.positions.get(value).cloned()
+    }
+
+    /// Insert value, linking it in after its low leaf. Returns None if value
+    /// was already in the tree.
+    ///
+    /// Panics if value is not larger than the lowest value.
+    pub fn insert(&mut self, value: V) -> Option<Insertion<H>> {
+        if self.contains(&value) {
+            return None;
+        }
+        let low_position = self
+            .low_position(&value)
+            .expect("Inserted values must be larger than the lowest value");
+        let position = self.tree.len();
+        let low_leaf = self
+            .tree
+            .get(low_position)
+            .ok()
+            .flatten()
+            .expect("low leaf exists");
+        let leaf = IndexedLeaf {
+            value: value.clone(),
+            next_index: low_leaf.next_index,
+            next_value: low_leaf.next_value,
+        };
+        let low_leaf = IndexedLeaf {
+            value: low_leaf.value,
+            next_index: position,
+            next_value: value.clone(),
+        };
+        self.tree
+            .update(low_position, low_leaf.clone())
+            .expect("low leaf exists");
+        self.tree.add(leaf.clone());
+        self.positions.insert(value, position);
+        Some(Insertion {
+            low_position,
+            low_leaf,
+            low_witness: self.tree.witness(low_position).expect("low leaf exists"),
+            position,
+            leaf,
+            witness: self.tree.witness(position).expect("leaf was just added"),
+        })
+    }
+
+    /// Prove that value is not in the tree. Returns None if it is, or if it
+    /// is lower than the lowest value.
+    pub fn prove_non_membership(&self, value: &V) -> Option<NonMembershipProof<H>> {
+        if self.contains(value) {
+            return None;
+        }
+        let low_position = self.low_position(value)?;
+        Some(NonMembershipProof {
+            low_leaf: self
+                .tree
+                .get(low_position)
+                .ok()
+                .flatten()
+                .expect("low leaf exists"),
+            witness: self.tree.witness(low_position).expect("low leaf exists"),
+        })
+    }
+
+    /// The position of the leaf with the largest value below value.
+    fn low_position(&self, value: &V) -> Option<usize> {
+        self.positions
+            .range(..value)
+            .next_back()
+            .map(|(_, position)| *position)
+    }
+}
+
+#[cfg(test)]
+mod tests;
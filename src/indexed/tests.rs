@@ -0,0 +1,146 @@
+use super::{IndexedLeaf, IndexedMerkleTree};
+use crate::linked::LinkedMerkleTree;
+use crate::test_helper::IndexedMixHasher;
+use crate::{HashableElement, MerkleTree};
+use std::io;
+
+fn leaf(value: u64, next_index: usize, next_value: u64) -> IndexedLeaf<u64> {
+    IndexedLeaf {
+        value,
+        next_index,
+        next_value,
+    }
+}
+
+#[test]
+fn insert() {
+    let hasher = IndexedMixHasher {};
+    let mut tree = IndexedMerkleTree::new(IndexedMixHasher::new(), 0);
+    assert_eq!(tree.len(), 1);
+    assert_eq!(tree.tree().get(0), Ok(Some(leaf(0, 0, 0))));
+
+    let insertion = tree.insert(30).unwrap();
+    assert_eq!(insertion.low_position, 0);
+    assert_eq!(insertion.low_leaf, leaf(0, 1, 30));
+    assert_eq!(insertion.position, 1);
+    assert_eq!(insertion.leaf, leaf(30, 0, 0));
+
+    tree.insert(10).unwrap();
+    let insertion = tree.insert(20).unwrap();
+    assert_eq!(insertion.low_position, 2);
+    assert_eq!(insertion.low_leaf, leaf(10, 3, 20));
+    assert_eq!(insertion.leaf, leaf(20, 1, 30));
+    assert_eq!(insertion.low_witness.root_hash, tree.root_hash());
+    assert!(insertion
+        .low_witness
+        .verify(&hasher, &insertion.low_leaf.merkle_hash()));
+    assert!(insertion
+        .witness
+        .verify(&hasher, &insertion.leaf.merkle_hash()));
+
+    assert!(tree.insert(20).is_none());
+    assert!(tree.contains(&10));
+    assert_eq!(tree.position(&20), Some(3));
+    assert_eq!(
+        tree.tree().iter_notes().collect::<Result<Vec<_>, _>>(),
+        Ok(vec![
+            leaf(0, 2, 10),
+            leaf(30, 0, 0),
+            leaf(10, 3, 20),
+            leaf(20, 1, 30)
+        ])
+    );
+}
+
+#[test]
+#[should_panic(expected = "lowest value")]
+fn insert_below_lowest() {
+    let mut tree = IndexedMerkleTree::new(IndexedMixHasher::new(), 5);
+    tree.insert(3);
+}
+
+#[test]
+fn non_membership() {
+    let hasher = IndexedMixHasher {};
+    let mut tree = IndexedMerkleTree::new(IndexedMixHasher::new(), 0);
+    for value in [50, 10, 40, 20].iter() {
+        tree.insert(*value);
+    }
+    assert!(tree.prove_non_membership(&40).is_none());
+    assert!(tree.prove_non_membership(&0).is_none());
+
+    let proof = tree.prove_non_membership(&30).unwrap();
+    assert_eq!(proof.low_leaf.value, 20);
+    assert_eq!(proof.witness.root_hash, tree.root_hash());
+    assert!(proof.verify(&hasher, &30));
+    assert!(proof.verify(&hasher, &25));
+    assert!(!proof.verify(&hasher, &40));
+    assert!(!proof.verify(&hasher, &20));
+
+    let above = tree.prove_non_membership(&1000).unwrap();
+    assert_eq!(above.low_leaf.value, 50);
+    assert!(above.verify(&hasher, &1000));
+
+    let mut forged = tree.prove_non_membership(&30).unwrap();
+    forged.low_leaf.next_value = 45;
+    assert!(!forged.verify(&hasher, &40));
+}
+
+#[test]
+fn serialization() {
+    let mut tree = IndexedMerkleTree::new(IndexedMixHasher::new(), 0);
+    for value in [7, 3, 9].iter() {
+        tree.insert(*value);
+    }
+    let mut bytes = vec![];
+    tree.write(&mut bytes).unwrap();
+    let mut read_back = IndexedMerkleTree::read(IndexedMixHasher::new(), &mut &bytes[..]).unwrap();
+    assert_eq!(read_back.root_hash(), tree.root_hash());
+    assert_eq!(read_back.position(&9), Some(3));
+    read_back.insert(8);
+    tree.insert(8);
+    assert_eq!(read_back.root_hash(), tree.root_hash());
+}
+
+#[test]
+fn read_checks_links() {
+    let read = |leaves: Vec<IndexedLeaf<u64>>| {
+        let mut tree = LinkedMerkleTree::new(IndexedMixHasher::new());
+        for leaf in leaves {
+            tree.add(leaf);
+        }
+        let mut bytes = vec![];
+        tree.write(&mut bytes).unwrap();
+        IndexedMerkleTree::read(IndexedMixHasher::new(), &mut &bytes[..]).map(|tree| tree.len())
+    };
+    let invalid = |leaves| read(leaves).unwrap_err().kind() == io::ErrorKind::InvalidData;
+
+    assert_eq!(read(vec![leaf(0, 0, 0)]).unwrap(), 1);
+    assert_eq!(
+        read(vec![leaf(0, 2, 3), leaf(7, 0, 0), leaf(3, 1, 7)]).unwrap(),
+        3
+    );
+    // the same value twice
+    assert!(invalid(vec![leaf(0, 1, 3), leaf(3, 2, 3), leaf(3, 0, 0)]));
+    // next_value doesn't match the leaf at next_index
+    assert!(invalid(vec![leaf(0, 1, 4), leaf(3, 0, 0)]));
+    // a link to a smaller value
+    assert!(invalid(vec![leaf(0, 2, 7), leaf(3, 0, 0), leaf(7, 1, 3)]));
+    // position 0 doesn't hold the lowest value
+    assert!(invalid(vec![leaf(5, 1, 7), leaf(7, 0, 0), leaf(3, 0, 0)]));
+    // a link past the last leaf
+    assert!(invalid(vec![leaf(0, 5, 3), leaf(3, 0, 0)]));
+    // a leaf that nothing links to
+    assert!(invalid(vec![leaf(0, 1, 3), leaf(3, 0, 0), leaf(5, 0, 0)]));
+    // the largest value links onwards
+    assert!(invalid(vec![leaf(0, 1, 3), leaf(3, 1, 3)]));
+}
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn leaf_serialization() {
+    let leaf = leaf(5, u32::MAX as usize + 1, 6);
+    let mut bytes = vec![];
+    leaf.write(&mut bytes).unwrap();
+    assert_eq!(IndexedLeaf::read(&mut &bytes[..]).unwrap(), leaf);
+}
@@ -0,0 +1,558 @@
+//! Consistency checks for the rows a persistent tree keeps on disk.
+//!
+//! The sled and RocksDB trees trust every row they read, so a corrupt row
+//! only shows up as a panic deep inside `witness` or `add`. Their
+//! `verify_integrity` methods read every leaf and node row instead, and
+//! report what doesn't fit together as an `IntegrityReport` rather than
+//! panicking.
+//!
+//! Rows that a tree legitimately lacks aren't problems: leaves and nodes
+//! that were pruned, the leaves before the point a tree was synced or
+//! imported from, and leftovers past the leaf and node counters that
+//! truncation leaves behind. Hashes are only recomputed where the rows
+//! they are computed from are all there.
+
+#[cfg(any(test, feature = "sledder", feature = "rocker"))]
+use super::MerkleHasher;
+#[cfg(any(test, feature = "sledder", feature = "rocker"))]
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// A row of a persistent tree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Row {
+    /// The rows of the leaf at a position.
+    Leaf(u64),
+    /// The internal node at an index.
+    Node(u64),
+    /// Where a tree that was synced or imported starts.
+    SyncPoint,
+    /// A leaf or node counter.
+    Counter(&'static str),
+}
+
+/// Something wrong with the rows of a persistent tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntegrityProblem {
+    /// The row couldn't be decoded.
+    Unreadable(Row),
+    /// The leaf at this position has rows, but no hash.
+    MissingLeafHash(u64),
+    /// The leaf at this position has rows, but no parent.
+    MissingParent(u64),
+    /// The stored element at this position doesn't hash to the stored hash.
+    ElementHashMismatch(u64),
+    /// The leaf at this odd position has a different parent than the leaf
+    /// to its left.
+    MismatchedSiblings(u64),
+    /// The last leaf, at this position, is missing, though nothing can
+    /// prune it.
+    MissingLastLeaf(u64),
+    /// A row refers to a node that isn't stored or is past the node count.
+    DanglingLink { from: Row, to: u64 },
+    /// The node at this index is a left node where a right node belongs,
+    /// or the other way around.
+    WrongSide(u64),
+    /// The node at this index is reached from two different places in the
+    /// tree.
+    ConflictingPosition(u64),
+    /// The node at this index has no parent, but isn't alone at the top of
+    /// the tree.
+    MisplacedRoot(u64),
+    /// The path up from the node at this index is longer than the tree is
+    /// deep.
+    TooDeep(u64),
+    /// The hash of its sibling that the node at this index holds doesn't
+    /// match the one recomputed from the sibling's children.
+    HashMismatch(u64),
+    /// A counter doesn't match the stored rows.
+    CounterMismatch {
+        counter: &'static str,
+        stored: u64,
+        expected: u64,
+    },
+}
+
+impl fmt::Display for IntegrityProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IntegrityProblem::Unreadable(row) => write!(f, "{:?} can't be decoded", row),
+            IntegrityProblem::MissingLeafHash(position) => {
+                write!(f, "Leaf {} has no hash", position)
+            }
+            IntegrityProblem::MissingParent(position) => {
+                write!(f, "Leaf {} has no parent", position)
+            }
+            IntegrityProblem::ElementHashMismatch(position) => write!(
+                f,
+                "Leaf {} has an element that doesn't match its hash",
+                position
+            ),
+            IntegrityProblem::MismatchedSiblings(position) => write!(
+                f,
+                "Leaf {} has a different parent than leaf {}",
+                position,
+                position - 1
+            ),
+            IntegrityProblem::MissingLastLeaf(position) => {
+                write!(f, "The last leaf, {}, is missing", position)
+            }
+            IntegrityProblem::DanglingLink { from, to } => {
+                write!(f, "{:?} links to missing node {}", from, to)
+            }
+            IntegrityProblem::WrongSide(index) => {
+                write!(f, "Node {} is on the wrong side of its parent", index)
+            }
+            IntegrityProblem::ConflictingPosition(index) => {
+                write!(f, "Node {} is in two places in the tree", index)
+            }
+            IntegrityProblem::MisplacedRoot(index) => {
+                write!(f, "Node {} has no parent but isn't the root", index)
+            }
+            IntegrityProblem::TooDeep(index) => {
+                write!(f, "The path up from node {} is too long", index)
+            }
+            IntegrityProblem::HashMismatch(index) => {
+                write!(f, "Node {} holds the wrong hash for its sibling", index)
+            }
+            IntegrityProblem::CounterMismatch {
+                counter,
+                stored,
+                expected,
+            } => write!(f, "{} is {}, but should be {}", counter, stored, expected),
+        }
+    }
+}
+
+/// What `verify_integrity` found.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct IntegrityReport {
+    /// The number of leaves with rows below the leaf count.
+    pub leaves_checked: usize,
+    /// The number of internal nodes stored below the node count.
+    pub nodes_checked: usize,
+    pub problems: Vec<IntegrityProblem>,
+}
+
+impl IntegrityReport {
+    /// Determine whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// The rows of a leaf, as far as they could be decoded.
+#[cfg(any(test, feature = "sledder", feature = "rocker"))]
+pub(crate) struct StoredLeaf<H> {
+    pub(crate) hash: Option<H>,
+    pub(crate) parent: Option<u64>,
+    /// The hash of the stored element, if there is one.
+    pub(crate) element_hash: Option<H>,
+}
+
+#[cfg(any(test, feature = "sledder", feature = "rocker"))]
+impl<H> Default for StoredLeaf<H> {
+    fn default() -> Self {
+        StoredLeaf {
+            hash: None,
+            parent: None,
+            element_hash: None,
+        }
+    }
+}
+
+/// A decoded internal node row.
+#[cfg(any(test, feature = "sledder", feature = "rocker"))]
+pub(crate) enum StoredNode<H> {
+    Left { hash_of_sibling: H, parent: u64 },
+    Right { hash_of_sibling: H, left: u64 },
+}
+
+#[cfg(any(test, feature = "sledder", feature = "rocker"))]
+impl<H> StoredNode<H> {
+    fn hash_of_sibling(&self) -> &H {
+        match self {
+            StoredNode::Left {
+                hash_of_sibling, ..
+            }
+            | StoredNode::Right {
+                hash_of_sibling, ..
+            } => hash_of_sibling,
+        }
+    }
+}
+
+/// Everything a backend read from its database, for `check_rows`. Rows
+/// that couldn't be decoded are left out and recorded in problems.
+#[cfg(any(test, feature = "sledder", feature = "rocker"))]
+pub(crate) struct StoredRows<H> {
+    pub(crate) num_leaves: u64,
+    pub(crate) num_nodes: u64,
+    /// The size, level and node of the sync point, if there is one.
+    pub(crate) sync_point: Option<(u64, usize, u64)>,
+    pub(crate) leaves: BTreeMap<u64, StoredLeaf<H>>,
+    pub(crate) nodes: HashMap<u64, StoredNode<H>>,
+    pub(crate) problems: Vec<IntegrityProblem>,
+}
+
+#[cfg(any(test, feature = "sledder", feature = "rocker"))]
+impl<H> StoredRows<H> {
+    pub(crate) fn new(num_leaves: u64, num_nodes: u64) -> Self {
+        StoredRows {
+            num_leaves,
+            num_nodes,
+            sync_point: None,
+            leaves: BTreeMap::new(),
+            nodes: HashMap::new(),
+            problems: vec![],
+        }
+    }
+}
+
+/// Check the rows of a tree with tree_depth levels of hashing.
+#[cfg(any(test, feature = "sledder", feature = "rocker"))]
+pub(crate) fn check_rows<H: MerkleHasher>(
+    hasher: &H,
+    tree_depth: usize,
+    rows: StoredRows<<H::Element as super::HashableElement>::Hash>,
+) -> IntegrityReport {
+    let mut checker = Checker {
+        hasher,
+        tree_depth,
+        rows,
+        problems: vec![],
+        left_child: HashMap::new(),
+        right_sibling: HashMap::new(),
+        placed: HashMap::new(),
+    };
+    let mut report = IntegrityReport {
+        leaves_checked: checker.check_leaves(),
+        nodes_checked: checker.check_links(),
+        problems: vec![],
+    };
+    checker.place_nodes();
+    checker.check_hashes();
+    checker.check_counters();
+    report.problems = checker.rows.problems;
+    report.problems.extend(checker.problems);
+    report
+}
+
+#[cfg(any(test, feature = "sledder", feature = "rocker"))]
+struct Checker<'a, H: MerkleHasher> {
+    hasher: &'a H,
+    tree_depth: usize,
+    rows: StoredRows<<H::Element as super::HashableElement>::Hash>,
+    problems: Vec<IntegrityProblem>,
+    /// The left node below each node that has one.
+    left_child: HashMap<u64, u64>,
+    /// The right node next to each left node that has one.
+    right_sibling: HashMap<u64, u64>,
+    /// The height and position of each node reached from a leaf or the
+    /// sync point.
+    placed: HashMap<u64, (usize, u64)>,
+}
+
+#[cfg(any(test, feature = "sledder", feature = "rocker"))]
+impl<'a, H: MerkleHasher> Checker<'a, H> {
+    fn node(
+        &self,
+        index: u64,
+    ) -> Option<&StoredNode<<H::Element as super::HashableElement>::Hash>> {
+        if index < self.rows.num_nodes {
+            self.rows.nodes.get(&index)
+        } else {
+            None
+        }
+    }
+
+    /// Check each leaf's own rows and its link to its parent.
+    fn check_leaves(&mut self) -> usize {
+        let num_leaves = self.rows.num_leaves;
+        let mut checked = 0;
+        for (position, leaf) in self.rows.leaves.range(..num_leaves) {
+            checked += 1;
+            if leaf.hash.is_none() {
+                self.problems
+                    .push(IntegrityProblem::MissingLeafHash(*position));
+            }
+            if let (Some(hash), Some(element_hash)) = (&leaf.hash, &leaf.element_hash) {
+                if hash != element_hash {
+                    self.problems
+                        .push(IntegrityProblem::ElementHashMismatch(*position));
+                }
+            }
+            match leaf.parent {
+                None => self
+                    .problems
+                    .push(IntegrityProblem::MissingParent(*position)),
+                // only a lone leaf has no parent node
+                Some(0) if num_leaves == 1 => {}
+                Some(parent) if self.node(parent).is_none() => {
+                    self.problems.push(IntegrityProblem::DanglingLink {
+                        from: Row::Leaf(*position),
+                        to: parent,
+                    })
+                }
+                Some(_) => {}
+            }
+            if position % 2 == 1 {
+                if let Some(left) = self.rows.leaves.get(&(position - 1)) {
+                    if left.parent.is_some() && left.parent != leaf.parent {
+                        self.problems
+                            .push(IntegrityProblem::MismatchedSiblings(*position));
+                    }
+                }
+            }
+        }
+        checked
+    }
+
+    /// Check that every node links to stored nodes of the right kind, and
+    /// that no two nodes claim the same place under a parent.
+    fn check_links(&mut self) -> usize {
+        let mut indices: Vec<u64> = self
+            .rows
+            .nodes
+            .keys()
+            .cloned()
+            .filter(|index| *index > 0 && *index < self.rows.num_nodes)
+            .collect();
+        indices.sort_unstable();
+        for index in indices.iter().cloned() {
+            let problem = match self.rows.nodes[&index] {
+                StoredNode::Left { parent: 0, .. } => None,
+                StoredNode::Left { parent, .. } => match self.node(parent) {
+                    None => Some(IntegrityProblem::DanglingLink {
+                        from: Row::Node(index),
+                        to: parent,
+                    }),
+                    Some(_) => self
+                        .left_child
+                        .insert(parent, index)
+                        .map(|_| IntegrityProblem::ConflictingPosition(index)),
+                },
+                StoredNode::Right { left, .. } => match self.node(left) {
+                    None => Some(IntegrityProblem::DanglingLink {
+                        from: Row::Node(index),
+                        to: left,
+                    }),
+                    Some(StoredNode::Right { .. }) => Some(IntegrityProblem::WrongSide(left)),
+                    Some(StoredNode::Left { .. }) => self
+                        .right_sibling
+                        .insert(left, index)
+                        .map(|_| IntegrityProblem::ConflictingPosition(index)),
+                },
+            };
+            self.problems.extend(problem);
+        }
+        indices.len()
+    }
+
+    /// Work out the height and position of the nodes on the path up from
+    /// each leaf and from the sync point, checking that each is on the side
+    /// its position puts it on.
+    fn place_nodes(&mut self) {
+        let num_leaves = self.rows.num_leaves;
+        let starts: Vec<(u64, usize, u64)> = self
+            .rows
+            .leaves
+            .range(..num_leaves)
+            .filter_map(|(position, leaf)| match leaf.parent {
+                Some(parent) if parent > 0 => Some((parent, 1, position >> 1)),
+                _ => None,
+            })
+            .collect();
+        for (index, height, position) in starts {
+            self.place_path(index, height, position);
+        }
+        if let Some((size, level, node)) = self.rows.sync_point {
+            if size == num_leaves && level > 0 {
+                if self.node(node).is_none() {
+                    self.problems.push(IntegrityProblem::DanglingLink {
+                        from: Row::SyncPoint,
+                        to: node,
+                    });
+                } else {
+                    self.place_path(node, level, (size - 1) >> level);
+                }
+            }
+        }
+    }
+
+    fn place_path(&mut self, mut index: u64, mut height: usize, mut position: u64) {
+        loop {
+            if height > self.tree_depth {
+                self.problems.push(IntegrityProblem::TooDeep(index));
+                return;
+            }
+            // the other end of the node's link
+            let link = match self.node(index) {
+                Some(StoredNode::Left { parent, .. }) => Ok(*parent),
+                Some(StoredNode::Right { left, .. }) => Err(*left),
+                None => return, // reported by the links check
+            };
+            match self.placed.get(&index) {
+                Some(placed) if *placed == (height, position) => return,
+                Some(_) => {
+                    self.problems
+                        .push(IntegrityProblem::ConflictingPosition(index));
+                    return;
+                }
+                None => {
+                    self.placed.insert(index, (height, position));
+                }
+            }
+            match (link, position % 2) {
+                (Ok(parent), 0) => {
+                    if parent == 0 {
+                        if position != 0 || self.right_sibling.contains_key(&index) {
+                            self.problems.push(IntegrityProblem::MisplacedRoot(index));
+                        }
+                        return;
+                    }
+                    index = parent;
+                    height += 1;
+                    position >>= 1;
+                }
+                (Err(left), 1) => {
+                    // carry on up through the left sibling
+                    index = left;
+                    position -= 1;
+                }
+                _ => {
+                    self.problems.push(IntegrityProblem::WrongSide(index));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Determine whether a node has nothing to its right, so that it is
+    /// hashed with itself.
+    fn on_right_edge(&self, height: usize, position: u64) -> bool {
+        (u128::from(position) + 1) << height >= u128::from(self.rows.num_leaves)
+    }
+
+    /// The hash of the leaf at position, if it is stored.
+    fn leaf_hash(&self, position: u64) -> Option<&<H::Element as super::HashableElement>::Hash> {
+        self.rows
+            .leaves
+            .get(&position)
+            .and_then(|leaf| leaf.hash.as_ref())
+    }
+
+    /// Recompute the hash of each placed node from its children, and
+    /// compare it to the one stored in its sibling, or in itself if it has
+    /// no sibling.
+    fn check_hashes(&mut self) {
+        let mut placed: Vec<(u64, (usize, u64))> = self
+            .placed
+            .iter()
+            .map(|(index, at)| (*index, *at))
+            .collect();
+        placed.sort_unstable();
+        for (index, (height, position)) in placed {
+            let computed = if height == 1 {
+                let left = self.leaf_hash(position << 1);
+                let right = if ((position << 1) + 1) < self.rows.num_leaves {
+                    self.leaf_hash((position << 1) + 1)
+                } else {
+                    left
+                };
+                match (left, right) {
+                    (Some(left), Some(right)) => Some(self.hasher.combine_hash(0, left, right)),
+                    _ => None,
+                }
+            } else {
+                self.left_child
+                    .get(&index)
+                    .filter(|child| self.placed.get(child) == Some(&(height - 1, position << 1)))
+                    .and_then(|child| {
+                        let left = &self.rows.nodes[child];
+                        match self.right_sibling.get(child) {
+                            Some(right) => Some(self.hasher.combine_hash(
+                                height - 1,
+                                self.rows.nodes[right].hash_of_sibling(),
+                                left.hash_of_sibling(),
+                            )),
+                            None if self.on_right_edge(height - 1, position << 1) => {
+                                Some(self.hasher.combine_hash(
+                                    height - 1,
+                                    left.hash_of_sibling(),
+                                    left.hash_of_sibling(),
+                                ))
+                            }
+                            // the right child was pruned
+                            None => None,
+                        }
+                    })
+            };
+            let holder = match &self.rows.nodes[&index] {
+                StoredNode::Right { left, .. } => Some(*left),
+                StoredNode::Left { .. } => match self.right_sibling.get(&index) {
+                    Some(right) => Some(*right),
+                    None if self.on_right_edge(height, position) => Some(index),
+                    None => None,
+                },
+            };
+            if let (Some(computed), Some(holder)) = (computed, holder) {
+                if self.rows.nodes[&holder].hash_of_sibling() != &computed {
+                    self.problems.push(IntegrityProblem::HashMismatch(holder));
+                }
+            }
+        }
+    }
+
+    /// Check that the last leaf is there, and that the node count is one
+    /// past the highest node on its path, which is the last one created.
+    fn check_counters(&mut self) {
+        let num_leaves = self.rows.num_leaves;
+        if num_leaves == 0 {
+            self.check_node_count(0);
+            return;
+        }
+        let last = num_leaves - 1;
+        let start = match self.rows.leaves.get(&last) {
+            Some(leaf) => leaf.parent,
+            None => match self.rows.sync_point {
+                Some((size, level, node)) if size == num_leaves && level > 0 => Some(node),
+                _ => {
+                    self.problems.push(IntegrityProblem::MissingLastLeaf(last));
+                    None
+                }
+            },
+        };
+        let mut index = match start {
+            Some(index) => index,
+            None => return,
+        };
+        let mut highest = index;
+        for _ in 0..=self.tree_depth {
+            index = match self.rows.nodes.get(&index) {
+                _ if index == 0 => break,
+                Some(StoredNode::Left { parent, .. }) => *parent,
+                Some(StoredNode::Right { left, .. }) => match self.rows.nodes.get(left) {
+                    Some(StoredNode::Left { parent, .. }) => *parent,
+                    _ => return,
+                },
+                None => return, // already reported
+            };
+            highest = highest.max(index);
+        }
+        self.check_node_count(highest);
+    }
+
+    fn check_node_count(&mut self, highest: u64) {
+        if self.rows.num_nodes != highest + 1 {
+            self.problems.push(IntegrityProblem::CounterMismatch {
+                counter: "NodeCount",
+                stored: self.rows.num_nodes,
+                expected: highest + 1,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
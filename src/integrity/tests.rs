@@ -0,0 +1,134 @@
+use super::{check_rows, IntegrityProblem, Row, StoredNode, StoredRows};
+use crate::linked::LinkedMerkleTree;
+use crate::test_helper::StringHasher;
+use crate::MerkleTree;
+
+const CHARACTERS: &str = "abcdefghijklmnopqrstu";
+
+fn make_tree(num_leaves: usize) -> Box<LinkedMerkleTree<StringHasher>> {
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    for character in CHARACTERS.chars().take(num_leaves) {
+        tree.add(character.to_string());
+    }
+    tree
+}
+
+fn check(rows: StoredRows<String>) -> Vec<IntegrityProblem> {
+    check_rows(&*StringHasher::new(), 5, rows).problems
+}
+
+#[test]
+fn healthy_trees() {
+    for num_leaves in 0..=CHARACTERS.len() {
+        let tree = make_tree(num_leaves);
+        let report = check_rows(&*StringHasher::new(), 5, tree.stored_rows());
+        assert!(report.is_ok(), "{}: {:?}", num_leaves, report.problems);
+        assert_eq!(report.leaves_checked, num_leaves);
+
+        let mut truncated = make_tree(CHARACTERS.len());
+        truncated.truncate(num_leaves).unwrap();
+        assert_eq!(check(truncated.stored_rows()), vec![], "{}", num_leaves);
+
+        let mut pruned = make_tree(num_leaves);
+        pruned.mark(num_leaves / 3);
+        pruned.prune();
+        assert_eq!(check(pruned.stored_rows()), vec![], "{}", num_leaves);
+
+        let mut state = vec![];
+        tree.export_state(&mut state).unwrap();
+        let mut imported = make_tree(0);
+        imported.import_state(&mut &state[..]).unwrap();
+        assert_eq!(check(imported.stored_rows()), vec![], "{}", num_leaves);
+        for character in CHARACTERS.chars().skip(num_leaves) {
+            imported.add(character.to_string());
+        }
+        assert_eq!(check(imported.stored_rows()), vec![], "{}", num_leaves);
+    }
+}
+
+#[test]
+fn corrupt_hashes() {
+    let mut rows = make_tree(13).stored_rows();
+    rows.leaves.get_mut(&5).unwrap().hash = Some("x".to_string());
+    let problems = check(rows);
+    assert!(problems.contains(&IntegrityProblem::ElementHashMismatch(5)));
+    assert!(problems
+        .iter()
+        .any(|problem| matches!(problem, IntegrityProblem::HashMismatch(_))));
+
+    let mut rows = make_tree(13).stored_rows();
+    let index = rows.leaves[&8].parent.unwrap();
+    match rows.nodes.get_mut(&index).unwrap() {
+        StoredNode::Left {
+            hash_of_sibling, ..
+        }
+        | StoredNode::Right {
+            hash_of_sibling, ..
+        } => *hash_of_sibling = "x".to_string(),
+    }
+    // the parent's hash is computed from the corrupt one, so it's wrong too
+    assert!(check(rows).contains(&IntegrityProblem::HashMismatch(index)));
+}
+
+#[test]
+fn corrupt_links() {
+    let mut rows = make_tree(13).stored_rows();
+    let index = rows.leaves[&12].parent.unwrap();
+    rows.nodes.remove(&index);
+    let problems = check(rows);
+    assert!(problems.contains(&IntegrityProblem::DanglingLink {
+        from: Row::Leaf(12),
+        to: index
+    }));
+
+    let mut rows = make_tree(13).stored_rows();
+    let index = rows.leaves[&2].parent.unwrap();
+    let node = rows.nodes.remove(&index).unwrap();
+    rows.nodes.insert(
+        index,
+        StoredNode::Left {
+            hash_of_sibling: node.hash_of_sibling().clone(),
+            parent: 0,
+        },
+    );
+    assert!(check(rows).contains(&IntegrityProblem::WrongSide(index)));
+
+    let mut rows = make_tree(13).stored_rows();
+    rows.leaves.get_mut(&7).unwrap().parent = rows.leaves[&4].parent;
+    let problems = check(rows);
+    assert!(problems.contains(&IntegrityProblem::MismatchedSiblings(7)));
+
+    // a node that is its own parent
+    let mut rows = make_tree(13).stored_rows();
+    let index = rows.leaves[&12].parent.unwrap();
+    if let Some(StoredNode::Left { parent, .. }) = rows.nodes.get_mut(&index) {
+        *parent = index;
+    }
+    let problems = check(rows);
+    assert!(problems.contains(&IntegrityProblem::ConflictingPosition(index)));
+}
+
+#[test]
+fn corrupt_counters() {
+    let mut rows = make_tree(13).stored_rows();
+    rows.num_nodes += 1;
+    let expected = rows.num_nodes - 1;
+    assert_eq!(
+        check(rows),
+        vec![IntegrityProblem::CounterMismatch {
+            counter: "NodeCount",
+            stored: expected + 1,
+            expected,
+        }]
+    );
+
+    let mut rows = make_tree(13).stored_rows();
+    rows.leaves.remove(&12);
+    assert!(check(rows).contains(&IntegrityProblem::MissingLastLeaf(12)));
+
+    // rows past the counters are leftovers, not problems
+    let mut rows = make_tree(13).stored_rows();
+    rows.num_leaves = 12;
+    rows.leaves.get_mut(&12).unwrap().hash = None;
+    assert!(!check(rows).contains(&IntegrityProblem::MissingLeafHash(12)));
+}
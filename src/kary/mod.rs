@@ -0,0 +1,490 @@
+//! The k-ary counterpart of `LinkedMerkleTree`: a Merkle tree in which each
+//! internal node has up to `arity` children instead of two, for hashes (such
+//! as Poseidon) that are cheaper in a circuit when they absorb several inputs
+//! at once.
+//!
+//! Like the binary trees, the tree is filled from left to right and padded
+//! on the fly: a node whose last children don't exist yet is hashed as if
+//! each missing child were a copy of the last child that does exist. With
+//! an arity of 2 that is the "hash with itself" rule, and the tree has the
+//! same root hash as a `LinkedMerkleTree` of the same depth.
+//!
+//! `KaryMerkleTree` doesn't implement `MerkleTree`, whose witnesses are
+//! binary. It has the same methods, except that its witnesses are
+//! `KaryWitness`es, which are checked with `KaryWitness::verify`. A binary
+//! `Witness` converts into a `KaryWitness` of arity 2, and one of arity 2
+//! converts back.
+
+use super::{HashableElement, MerkleHasher, Witness, WitnessNode};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::convert::TryFrom;
+use std::io;
+use std::sync::Arc;
+
+type Hash<H> = <<H as MerkleHasher>::Element as HashableElement>::Hash;
+
+/// A MerkleHasher that can hash any number of children into their parent.
+pub trait MerkleHasherN: MerkleHasher {
+    /// Hash the children of a node, from left to right, to calculate the
+    /// hash of the node.
+    ///
+    /// Depth is the "level" of the children, as in `combine_hash`: zero when
+    /// hashing leaves together, 1 when hashing their parents, and so on.
+    fn combine_many(&self, depth: usize, hashes: &[Hash<Self>]) -> Hash<Self>;
+}
+
+/// The siblings of one node on an authentication path, and where the node
+/// sits among them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KaryWitnessNode<T> {
+    /// The position of the node among its parent's children.
+    pub index: usize,
+    /// The hashes of the other children of its parent, from left to right.
+    pub siblings: Vec<T>,
+}
+
+/// Commitment that a leaf exists in a k-ary tree, with an authentication
+/// path and the root hash the path leads to.
+pub struct KaryWitness<H: MerkleHasher> {
+    pub tree_size: usize,
+    pub root_hash: Hash<H>,
+    /// The number of children of each node on the path.
+    pub arity: usize,
+    pub auth_path: Vec<KaryWitnessNode<Hash<H>>>,
+}
+
+impl<H: MerkleHasherN> KaryWitness<H> {
+    /// Verify that the authentication path leads from my_hash to the root
+    /// hash.
+    pub fn verify(&self, hasher: &H, my_hash: &Hash<H>) -> bool {
+        self.verify_subtree(hasher, 0, my_hash)
+    }
+
+    /// Verify that the authentication path, as returned by
+    /// `KaryMerkleTree::subtree_witness`, leads from subtree_root, the hash of a
+    /// node at the given level of the tree, to the root hash.
+    ///
+    /// Every node on the path must have exactly arity - 1 siblings and sit
+    /// at an index below arity; a path that doesn't is rejected rather than
+    /// hashed with the wrong number of children.
+    pub fn verify_subtree(&self, hasher: &H, level: usize, subtree_root: &Hash<H>) -> bool {
+        if self.arity < 2 {
+            return false;
+        }
+        let mut current = subtree_root.clone();
+        for (depth, node) in self.auth_path.iter().enumerate() {
+            if node.siblings.len() != self.arity - 1 || node.index >= self.arity {
+                return false;
+            }
+            let mut children = node.siblings.clone();
+            children.insert(node.index, current);
+            current = hasher.combine_many(level + depth, &children);
+        }
+        current == self.root_hash
+    }
+}
+
+/// A binary witness is a k-ary witness of arity 2, whose single sibling is
+/// on the other side of the node.
+impl<H: MerkleHasher> From<Witness<H>> for KaryWitness<H> {
+    fn from(witness: Witness<H>) -> Self {
+        let auth_path = witness
+            .auth_path
+            .into_iter()
+            .map(|node| match node {
+                WitnessNode::Left(hash) => KaryWitnessNode {
+                    index: 0,
+                    siblings: vec![hash],
+                },
+                WitnessNode::Right(hash) => KaryWitnessNode {
+                    index: 1,
+                    siblings: vec![hash],
+                },
+            })
+            .collect();
+        KaryWitness {
+            tree_size: witness.tree_size,
+            root_hash: witness.root_hash,
+            arity: 2,
+            auth_path,
+        }
+    }
+}
+
+/// Fails, handing the witness back, unless it is a well formed witness of
+/// arity 2.
+impl<H: MerkleHasher> TryFrom<KaryWitness<H>> for Witness<H> {
+    type Error = KaryWitness<H>;
+
+    fn try_from(witness: KaryWitness<H>) -> Result<Self, KaryWitness<H>> {
+        let binary = witness.arity == 2
+            && witness
+                .auth_path
+                .iter()
+                .all(|node| node.siblings.len() == 1 && node.index < 2);
+        if !binary {
+            return Err(witness);
+        }
+        let auth_path = witness
+            .auth_path
+            .into_iter()
+            .map(|mut node| {
+                let sibling = node.siblings.remove(0);
+                if node.index == 0 {
+                    WitnessNode::Left(sibling)
+                } else {
+                    WitnessNode::Right(sibling)
+                }
+            })
+            .collect();
+        Ok(Witness {
+            tree_size: witness.tree_size,
+            root_hash: witness.root_hash,
+            auth_path,
+        })
+    }
+}
+
+impl<H: MerkleHasher> PartialEq for KaryWitness<H> {
+    fn eq(&self, other: &Self) -> bool {
+        self.tree_size == other.tree_size
+            && self.root_hash == other.root_hash
+            && self.arity == other.arity
+            && self.auth_path == other.auth_path
+    }
+}
+
+impl<H: MerkleHasher> std::fmt::Debug for KaryWitness<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("KaryWitness")
+            .field("tree_size", &self.tree_size)
+            .field("root_hash", &self.root_hash)
+            .field("arity", &self.arity)
+            .field("auth_path", &self.auth_path)
+            .finish()
+    }
+}
+
+/// Append-only k-ary Merkle tree held in memory.
+///
+/// Each level of the tree is kept as a list of node hashes, with the leaves
+/// at level 0 and the root alone at level `depth`. Adding a leaf rehashes
+/// the rightmost node at each level, which takes O(depth * arity) hashes.
+///
+/// The lists are shared copy-on-write between clones of the tree, so cloning
+/// is cheap, and a clone is a snapshot that later changes to the tree don't
+/// affect. The first modification after a clone copies the lists.
+pub struct KaryMerkleTree<H: MerkleHasherN> {
+    hasher: Arc<H>,
+    arity: usize,
+    depth: usize,
+    leaves: Arc<Vec<H::Element>>,
+    /// levels[0] holds the leaf hashes, and levels[depth] the root.
+    levels: Arc<Vec<Vec<Hash<H>>>>,
+}
+
+impl<H: MerkleHasherN> Clone for KaryMerkleTree<H> {
+    fn clone(&self) -> Self {
+        KaryMerkleTree {
+            hasher: self.hasher.clone(),
+            arity: self.arity,
+            depth: self.depth,
+            leaves: self.leaves.clone(),
+            levels: self.levels.clone(),
+        }
+    }
+}
+
+impl<H: MerkleHasherN> KaryMerkleTree<H> {
+    /// Construct a new, empty tree with the given number of children per
+    /// node and levels below the root, on the heap, and return a Box
+    /// pointing to it. It can hold arity^depth leaves.
+    ///
+    /// Panics if the arity is less than 2 or more than 255, if the depth is
+    /// 0, or if the tree could hold more leaves than a u64 can count.
+    pub fn new(hasher: Arc<H>, arity: usize, depth: usize) -> Box<Self> {
+        if let Err(message) = check_shape(arity, depth) {
+            panic!("{}", message);
+        }
+        Box::new(KaryMerkleTree {
+            hasher,
+            arity,
+            depth,
+            leaves: Arc::new(vec![]),
+            levels: Arc::new(vec![vec![]; depth + 1]),
+        })
+    }
+
+    /// The number of children of each internal node.
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    /// The number of levels below the root.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The hashes of the children, at depth, of the node at depth + 1 and
+    /// index parent, padded out to arity with copies of the last one.
+    fn children(&self, depth: usize, parent: usize) -> Vec<Hash<H>> {
+        let level = &self.levels[depth];
+        let start = parent * self.arity;
+        let end = std::cmp::min(start + self.arity, level.len());
+        let mut children = level[start..end].to_vec();
+        let last = children.last().expect("parent has a child").clone();
+        children.resize(self.arity, last);
+        children
+    }
+
+    /// The hashes of the children, at depth, of the parent of the node at
+    /// last_index, when that was the last node at depth and had last_hash.
+    /// The nodes to its left were complete by then, so they have the hashes
+    /// they have now.
+    fn past_children(
+        &self,
+        depth: usize,
+        parent: usize,
+        last_index: usize,
+        last_hash: &Hash<H>,
+    ) -> Vec<Hash<H>> {
+        let start = parent * self.arity;
+        let mut children = self.levels[depth][start..last_index].to_vec();
+        children.resize(self.arity, last_hash.clone());
+        children
+    }
+
+    /// The hash of the last node at each level, from the last leaf up to the
+    /// root, when the tree had past_size leaves. past_size must be between 1
+    /// and the current length.
+    fn past_path(&self, past_size: usize) -> Vec<Hash<H>> {
+        let mut index = past_size - 1;
+        let mut path = vec![self.levels[0][index].clone()];
+        for depth in 0..self.depth {
+            let parent = index / self.arity;
+            let children = self.past_children(depth, parent, index, &path[depth]);
+            path.push(self.hasher.combine_many(depth, &children));
+            index = parent;
+        }
+        path
+    }
+
+    /// Recalculate the rightmost node at each level above the leaves.
+    fn rehash_right_path(&mut self) {
+        let mut index = self.len() - 1;
+        for depth in 0..self.depth {
+            let parent = index / self.arity;
+            let hash = self
+                .hasher
+                .combine_many(depth, &self.children(depth, parent));
+            let level = &mut Arc::make_mut(&mut self.levels)[depth + 1];
+            if parent < level.len() {
+                level[parent] = hash;
+            } else {
+                level.push(hash);
+            }
+            index = parent;
+        }
+    }
+
+    /// Load a tree that was written with `KaryMerkleTree::write`.
+    pub fn read<R: io::Read>(hasher: Arc<H>, reader: &mut R) -> io::Result<Box<Self>> {
+        let arity = reader.read_u8()? as usize;
+        let depth = reader.read_u8()? as usize;
+        check_shape(arity, depth).map_err(super::invalid_data)?;
+        let num_leaves = reader.read_u64::<LittleEndian>()?;
+        if num_leaves > capacity(arity, depth) as u64 {
+            return Err(super::invalid_data(format!(
+                "{} leaves do not fit in a tree of arity {} and depth {}",
+                num_leaves, arity, depth
+            )));
+        }
+        let mut tree = KaryMerkleTree::new(hasher, arity, depth);
+        for _ in 0..num_leaves {
+            tree.add(tree.hasher.read_element(reader)?);
+        }
+        Ok(tree)
+    }
+
+    /// Get a reference-counted pointer to the hasher.
+    pub fn hasher(&self) -> Arc<H> {
+        self.hasher.clone()
+    }
+
+    /// Append a leaf and rehash its path up to the root.
+    ///
+    /// Panics if the tree is full.
+    pub fn add(&mut self, element: H::Element) {
+        if self.len() >= capacity(self.arity, self.depth) {
+            panic!("Tree is full");
+        }
+        Arc::make_mut(&mut self.levels)[0].push(element.merkle_hash());
+        Arc::make_mut(&mut self.leaves).push(element);
+        self.rehash_right_path();
+    }
+
+    /// Get a clone of the element at position. The tree keeps every leaf.
+    pub fn get(&self, position: usize) -> Option<H::Element> {
+        self.leaves.get(position).cloned()
+    }
+
+    /// Get the number of leaf nodes in the tree.
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// Determine whether the tree has no leaf nodes.
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// Truncate the tree to the leaves it had when it contained past_size
+    /// leaves. Truncating to a size that is higher than self.len() is a
+    /// no-op.
+    pub fn truncate(&mut self, past_size: usize) {
+        if past_size >= self.len() {
+            return;
+        }
+        Arc::make_mut(&mut self.leaves).truncate(past_size);
+        let mut size = past_size;
+        for level in Arc::make_mut(&mut self.levels).iter_mut() {
+            level.truncate(size);
+            size = size.div_ceil(self.arity);
+        }
+        if past_size > 0 {
+            self.rehash_right_path();
+        }
+    }
+
+    /// Iterate over clones of the leaf elements in order.
+    pub fn iter_notes(&self) -> impl Iterator<Item = H::Element> + '_ {
+        self.leaves.iter().cloned()
+    }
+
+    /// Get the hash of the root node, or None if the tree is empty.
+    pub fn root_hash(&self) -> Option<Hash<H>> {
+        self.levels[self.depth].first().cloned()
+    }
+
+    /// Rehash the path of the last leaf the tree had at past_size.
+    pub fn past_root(&self, past_size: usize) -> Option<Hash<H>> {
+        if past_size == 0 || past_size > self.len() {
+            return None;
+        }
+        self.past_path(past_size).pop()
+    }
+
+    /// Determine whether the tree contains a value at its current size.
+    pub fn contains(&self, value: &H::Element) -> bool {
+        self.contained(value, self.len())
+    }
+
+    /// Determine whether the tree contained a value when it had past_size
+    /// leaves. This is a linear scan.
+    pub fn contained(&self, value: &H::Element, past_size: usize) -> bool {
+        self.leaves.iter().take(past_size).any(|leaf| leaf == value)
+    }
+
+    /// Construct the proof that the leaf at position exists. The path has
+    /// one node for each level below the root, each with arity - 1
+    /// siblings.
+    pub fn witness(&self, position: usize) -> Option<KaryWitness<H>> {
+        self.past_witness(position, self.len())
+    }
+
+    /// Construct the proof that the leaf at position existed when the tree
+    /// had past_size leaves. It leads to `past_root(past_size)`.
+    pub fn past_witness(&self, position: usize, past_size: usize) -> Option<KaryWitness<H>> {
+        if position >= past_size || past_size > self.len() {
+            return None;
+        }
+        let path = self.past_path(past_size);
+        let mut index = position;
+        let mut last_index = past_size - 1;
+        let mut auth_path = vec![];
+        for (depth, last_hash) in path.iter().take(self.depth).enumerate() {
+            let parent = index / self.arity;
+            let mut siblings = if parent == last_index / self.arity {
+                self.past_children(depth, parent, last_index, last_hash)
+            } else {
+                self.children(depth, parent)
+            };
+            siblings.remove(index % self.arity);
+            auth_path.push(KaryWitnessNode {
+                index: index % self.arity,
+                siblings,
+            });
+            index = parent;
+            last_index /= self.arity;
+        }
+        Some(KaryWitness {
+            tree_size: past_size,
+            root_hash: path[self.depth].clone(),
+            arity: self.arity,
+            auth_path,
+        })
+    }
+
+    /// Get the hash of the node at `level` (0 being the leaves) and `index`,
+    /// which covers the leaves from `index * arity^level`.
+    pub fn subtree_root(&self, level: usize, index: usize) -> Option<Hash<H>> {
+        self.levels.get(level)?.get(index).cloned()
+    }
+
+    /// Construct the path from the node that `subtree_root` returns up to
+    /// the root. Check it with `KaryWitness::verify_subtree`.
+    pub fn subtree_witness(&self, level: usize, index: usize) -> Option<KaryWitness<H>> {
+        let span = self.arity.checked_pow(u32::try_from(level).ok()?)?;
+        let mut witness = self.witness(index.checked_mul(span)?)?;
+        if level > witness.auth_path.len() {
+            return None;
+        }
+        witness.auth_path.drain(..level);
+        Some(witness)
+    }
+
+    /// Write the arity and depth as bytes, the number of leaves as a little
+    /// endian u64, and then each element.
+    pub fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u8(self.arity as u8)?;
+        writer.write_u8(self.depth as u8)?;
+        writer.write_u64::<LittleEndian>(self.len() as u64)?;
+        for element in self.leaves.iter() {
+            element.write(writer)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that a tree of the given arity and depth can be built, and written
+/// with the arity and depth as single bytes.
+fn check_shape(arity: usize, depth: usize) -> Result<(), String> {
+    if !(2..=u8::MAX as usize).contains(&arity) {
+        return Err(format!(
+            "A tree needs between 2 and 255 children per node, not {}",
+            arity
+        ));
+    }
+    if depth == 0 {
+        return Err("A tree needs at least one level below the root".to_string());
+    }
+    let addressable = u32::try_from(depth)
+        .ok()
+        .and_then(|depth| (arity as u64).checked_pow(depth))
+        .is_some();
+    if !addressable {
+        return Err(format!(
+            "A tree of arity {} and depth {} has more leaves than can be addressed",
+            arity, depth
+        ));
+    }
+    Ok(())
+}
+
+/// The number of leaves a tree of the given arity and depth can hold.
+fn capacity(arity: usize, depth: usize) -> usize {
+    arity.checked_pow(depth as u32).unwrap_or(usize::MAX)
+}
+
+#[cfg(test)]
+mod tests;
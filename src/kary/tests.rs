@@ -0,0 +1,237 @@
+use super::{KaryMerkleTree, KaryWitness, KaryWitnessNode};
+use crate::linked::LinkedMerkleTree;
+use crate::test_helper::{MixHasher, StringHasher};
+use crate::{MerkleTree, Witness, WitnessNode};
+use std::convert::TryFrom;
+
+fn make_tree(characters: &str, arity: usize, depth: usize) -> Box<KaryMerkleTree<StringHasher>> {
+    let mut tree = KaryMerkleTree::new(StringHasher::new(), arity, depth);
+    for character in characters.chars() {
+        tree.add(character.to_string());
+    }
+    tree
+}
+
+#[test]
+fn root_hash() {
+    assert_eq!(make_tree("", 4, 2).root_hash(), None);
+    assert_eq!(
+        make_tree("a", 4, 2).root_hash(),
+        Some("<<a|a|a|a-0>|<a|a|a|a-0>|<a|a|a|a-0>|<a|a|a|a-0>-1>".to_string())
+    );
+    assert_eq!(
+        make_tree("abcdef", 4, 2).root_hash(),
+        Some("<<a|b|c|d-0>|<e|f|f|f-0>|<e|f|f|f-0>|<e|f|f|f-0>-1>".to_string())
+    );
+    assert_eq!(
+        make_tree("abcdefg", 3, 2).root_hash(),
+        Some("<<a|b|c-0>|<d|e|f-0>|<g|g|g-0>-1>".to_string())
+    );
+}
+
+#[test]
+fn witness() {
+    let hasher = StringHasher {};
+    let tree = make_tree("abcdef", 4, 2);
+    let witness = tree.witness(4).unwrap();
+    assert_eq!(witness.tree_size, 6);
+    assert_eq!(
+        witness.auth_path,
+        vec![
+            KaryWitnessNode {
+                index: 0,
+                siblings: vec!["f".to_string(), "f".to_string(), "f".to_string()]
+            },
+            KaryWitnessNode {
+                index: 1,
+                siblings: vec![
+                    "<a|b|c|d-0>".to_string(),
+                    "<e|f|f|f-0>".to_string(),
+                    "<e|f|f|f-0>".to_string()
+                ]
+            },
+        ]
+    );
+    for position in 0..6 {
+        let element = tree.get(position).unwrap();
+        assert!(tree.witness(position).unwrap().verify(&hasher, &element));
+        assert!(!tree
+            .witness(position)
+            .unwrap()
+            .verify(&hasher, &"x".to_string()));
+    }
+    assert_eq!(tree.witness(6), None);
+
+    let mut moved = tree.witness(1).unwrap();
+    moved.auth_path[0].index = 2;
+    assert!(!moved.verify(&hasher, &"b".to_string()));
+    moved.auth_path[0].index = 7;
+    assert!(!moved.verify(&hasher, &"b".to_string()));
+}
+
+#[test]
+fn verify_checks_sibling_counts() {
+    let hasher = StringHasher {};
+    let tree = make_tree("abcdef", 4, 2);
+    let witness = tree.witness(1).unwrap();
+    assert!(witness.verify(&hasher, &"b".to_string()));
+
+    let mut short = tree.witness(1).unwrap();
+    short.auth_path[1].siblings.pop();
+    assert!(!short.verify(&hasher, &"b".to_string()));
+
+    // A path of arity 2 passed off as one of arity 4.
+    let mut wrong_arity = make_tree("ab", 2, 1).witness(0).unwrap();
+    wrong_arity.arity = 4;
+    assert!(!wrong_arity.verify(&hasher, &"a".to_string()));
+    wrong_arity.arity = 1;
+    wrong_arity.auth_path.clear();
+    wrong_arity.root_hash = "a".to_string();
+    assert!(!wrong_arity.verify(&hasher, &"a".to_string()));
+}
+
+#[test]
+fn past_witness() {
+    let hasher = StringHasher {};
+    let characters = "abcdefghijklmnopq";
+    let tree = make_tree(characters, 3, 3);
+    for past_size in 0..=characters.len() {
+        let past = make_tree(&characters[..past_size], 3, 3);
+        assert_eq!(tree.past_root(past_size), past.root_hash());
+        for position in 0..past_size {
+            let witness = tree.past_witness(position, past_size).unwrap();
+            assert_eq!(Some(&witness), past.witness(position).as_ref());
+            let element = tree.get(position).unwrap();
+            assert!(witness.verify(&hasher, &element));
+        }
+        assert_eq!(tree.past_witness(past_size, past_size), None);
+    }
+    assert_eq!(tree.past_root(characters.len() + 1), None);
+}
+
+#[test]
+fn subtree_witness() {
+    let hasher = StringHasher {};
+    let tree = make_tree("abcdefghijklmn", 3, 3);
+    for (level, index) in [(0, 13), (1, 2), (1, 4), (2, 1), (3, 0)] {
+        let root = tree.subtree_root(level, index).unwrap();
+        let witness = tree.subtree_witness(level, index).unwrap();
+        assert_eq!(witness.auth_path.len(), 3 - level);
+        assert!(witness.verify_subtree(&hasher, level, &root));
+        assert!(!witness.verify_subtree(&hasher, level, &"x".to_string()));
+    }
+    assert_eq!(tree.subtree_root(1, 1), Some("<d|e|f-0>".to_string()));
+    assert_eq!(tree.subtree_root(1, 5), None);
+    assert_eq!(tree.subtree_witness(1, 5), None);
+    assert_eq!(tree.subtree_root(4, 0), None);
+}
+
+#[test]
+fn binary_matches_linked_tree() {
+    let hasher = MixHasher::new();
+    let mut tree = KaryMerkleTree::new(hasher.clone(), 2, 5);
+    let mut linked = LinkedMerkleTree::new_with_size(hasher.clone(), 6);
+    for value in 0..32u64 {
+        tree.add(value * 13 + 1);
+        linked.add(value * 13 + 1);
+        assert_eq!(tree.root_hash(), linked.root_hash());
+    }
+    for position in 0..32 {
+        let witness = tree.witness(position).unwrap();
+        let binary = linked.witness(position).unwrap();
+        let converted = Witness::try_from(tree.witness(position).unwrap()).unwrap();
+        assert_eq!(converted, linked.witness(position).unwrap());
+        assert_eq!(
+            KaryWitness::from(converted),
+            tree.witness(position).unwrap()
+        );
+        assert_eq!(witness.root_hash, binary.root_hash);
+        for (node, binary_node) in witness.auth_path.iter().zip(binary.auth_path.iter()) {
+            match binary_node {
+                WitnessNode::Left(hash) => {
+                    assert_eq!(node.index, 0);
+                    assert_eq!(node.siblings, vec![*hash]);
+                }
+                WitnessNode::Right(hash) => {
+                    assert_eq!(node.index, 1);
+                    assert_eq!(node.siblings, vec![*hash]);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn wider_trees_have_no_binary_witness() {
+    let tree = make_tree("abcd", 3, 2);
+    let witness = tree.witness(0).unwrap();
+    let witness = Witness::try_from(witness).unwrap_err();
+    assert_eq!(Some(witness), tree.witness(0));
+}
+
+#[test]
+#[should_panic(expected = "Tree is full")]
+fn full() {
+    make_tree("abcdefghij", 3, 2);
+}
+
+#[test]
+#[should_panic(expected = "between 2 and 255 children")]
+fn arity_fits_in_a_byte() {
+    make_tree("", 256, 2);
+}
+
+#[test]
+#[should_panic(expected = "more leaves than can be addressed")]
+fn capacity_fits_in_a_u64() {
+    make_tree("", 255, 9);
+}
+
+#[test]
+fn truncate() {
+    let mut tree = make_tree("abcdefghijklm", 3, 3);
+    tree.truncate(5);
+    let expected = make_tree("abcde", 3, 3);
+    assert_eq!(tree.len(), 5);
+    assert_eq!(tree.root_hash(), expected.root_hash());
+    assert_eq!(tree.witness(4), expected.witness(4));
+    tree.add("x".to_string());
+    assert_eq!(tree.root_hash(), make_tree("abcdex", 3, 3).root_hash());
+    tree.truncate(0);
+    assert!(tree.is_empty());
+    assert_eq!(tree.root_hash(), None);
+}
+
+#[test]
+fn serialization() {
+    let tree = make_tree("abcdefg", 4, 3);
+    let mut bytes = vec![];
+    tree.write(&mut bytes).unwrap();
+    let read_back = KaryMerkleTree::read(StringHasher::new(), &mut &bytes[..]).unwrap();
+    assert_eq!(read_back.arity(), 4);
+    assert_eq!(read_back.depth(), 3);
+    assert_eq!(read_back.root_hash(), tree.root_hash());
+    assert_eq!(read_back.iter_notes().count(), 7);
+
+    bytes[0] = 1;
+    assert!(KaryMerkleTree::read(StringHasher::new(), &mut &bytes[..]).is_err());
+    bytes[0] = 4;
+    bytes[2] = 65;
+    assert!(KaryMerkleTree::read(StringHasher::new(), &mut &bytes[..]).is_err());
+}
+
+#[test]
+fn clones_ignore_later_changes() {
+    let mut tree = make_tree("abcde", 3, 3);
+    let snapshot = tree.clone();
+    tree.add("f".to_string());
+    tree.add("g".to_string());
+    assert_eq!(snapshot.root_hash(), make_tree("abcde", 3, 3).root_hash());
+    assert_eq!(snapshot.witness(4), tree.past_witness(4, 5));
+
+    tree.truncate(1);
+    assert_eq!(snapshot.len(), 5);
+    assert!(snapshot.contains(&"e".to_string()));
+    assert_eq!(snapshot.root_hash(), make_tree("abcde", 3, 3).root_hash());
+    assert_eq!(snapshot.get(4), Some("e".to_string()));
+}
@@ -1,15 +1,35 @@
 #[macro_use]
 extern crate shrinkwraprs;
 
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::convert::TryFrom;
 use std::fmt::{self, Debug};
 use std::io;
+#[cfg(any(feature = "sledder", feature = "rocker"))]
+use std::iter::Peekable;
+use std::ops::Range;
+#[cfg(any(feature = "sledder", feature = "rocker"))]
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
+pub mod checkpoint;
+pub mod config;
+pub mod display;
+pub mod fixed;
+pub mod indexed;
+pub mod integrity;
+pub mod kary;
 pub mod linked;
+pub mod mmr;
+#[cfg(feature = "notes")]
+pub mod notes;
 #[cfg(feature = "rocker")]
 pub mod rocks;
+pub mod shared;
 #[cfg(feature = "sledder")]
 pub mod sled;
+pub mod snapshot;
+pub mod sparse;
 pub mod vector;
 
 #[cfg(test)]
@@ -19,6 +39,79 @@ extern crate assert_matches;
 #[cfg(test)]
 pub(crate) mod test_helper;
 
+/// The deepest tree (including the root level) that can be deserialized.
+/// Leaf positions are u64s, so a deeper tree could hold more leaves than we
+/// are able to address.
+pub(crate) const MAX_TREE_DEPTH: usize = 64;
+
+/// The deepest tree whose leaf count is serialized as a u32. Serialized trees
+/// and witnesses predate 64-bit leaf counts, so anything that fits in a u32
+/// keeps the original format and only larger trees pay for the wider one.
+pub(crate) const U32_TREE_DEPTH: usize = 33;
+
+/// What a persistent tree keeps for each leaf. In-memory trees always keep
+/// their elements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LeafStorage {
+    /// Store each element alongside its hash.
+    #[default]
+    Elements,
+    /// Store only the leaf hashes. Roots, witnesses and `contains` work as
+    /// usual, but the elements can't be read back out of the tree.
+    HashesOnly,
+}
+
+/// Returned when asking a tree for the element of a leaf it has, but doesn't
+/// keep the element of: one that only stores leaf hashes, or one that has
+/// pruned the leaf or skipped over it with a sync or import.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotRetained {
+    pub position: usize,
+}
+
+impl fmt::Display for NotRetained {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The element at position {} was not retained",
+            self.position
+        )
+    }
+}
+
+impl std::error::Error for NotRetained {}
+
+/// Returned when a leaf can't be updated in place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateError {
+    /// There is no leaf at the position: the tree has only len leaves.
+    OutOfRange { position: usize, len: usize },
+    /// Rehashing the path needs a leaf that the tree has pruned or skipped
+    /// over with a sync or import.
+    NotRetained(NotRetained),
+}
+
+impl From<NotRetained> for UpdateError {
+    fn from(error: NotRetained) -> Self {
+        UpdateError::NotRetained(error)
+    }
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateError::OutOfRange { position, len } => write!(
+                f,
+                "No leaf at position {} in a tree of {} leaves",
+                position, len
+            ),
+            UpdateError::NotRetained(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
 /// An object that can be used as a hash in a Merkle tree. Basic usage might
 /// use bytes or a string here, but in a production system it might be a
 /// point on an elliptic curve.
@@ -76,8 +169,6 @@ pub trait MerkleHasher {
         writer: &mut W,
     ) -> io::Result<()>;
 
-    /// Write a hash to a writer
-
     /// Hash two child hashes together to calculate the hash of the
     /// new parent.
     ///
@@ -108,24 +199,79 @@ pub trait MerkleTree {
     /// Insert the new leaf element into the tree, and update all hashes.
     fn add(&mut self, element: <Self::Hasher as MerkleHasher>::Element);
 
-    /// Get a clone of the element at position.
-    fn get(&self, position: usize) -> Option<<Self::Hasher as MerkleHasher>::Element>;
+    /// Get a clone of the element at position, or None if the tree has no
+    /// leaf there. A leaf whose element the tree doesn't keep is a
+    /// NotRetained error.
+    fn get(
+        &self,
+        position: usize,
+    ) -> Result<Option<<Self::Hasher as MerkleHasher>::Element>, NotRetained>;
 
     /// Get the number of leaf nodes in the tree.
     fn len(&self) -> usize;
 
+    /// Determine whether the tree has no leaf nodes.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Truncate the tree to the values it contained when it contained past_size
     /// elements.
     ///
     /// After calling, it will contain at most past_size elements, but truncating
-    /// to a size that is higher than self.len() is a no-op.
-    fn truncate(&mut self, past_size: usize);
+    /// to a size that is higher than self.len() is a no-op. Checkpoints recorded
+    /// when the tree was larger than past_size are forgotten.
+    ///
+    /// Fails without changing anything if the tree doesn't keep the leaf that
+    /// would become the last one, because it was pruned or skipped by a sync.
+    fn truncate(&mut self, past_size: usize) -> Result<(), NotRetained>;
 
     /// Iterate over clones of all leaf notes in the tree, without consuming
-    /// the tree.
+    /// the tree. A leaf whose element the tree doesn't keep yields a
+    /// NotRetained error, and the iteration carries on past it.
     fn iter_notes<'a>(
         &'a self,
-    ) -> Box<dyn Iterator<Item = <Self::Hasher as MerkleHasher>::Element> + 'a>;
+    ) -> Box<dyn Iterator<Item = Result<<Self::Hasher as MerkleHasher>::Element, NotRetained>> + 'a>;
+
+    /// Iterate over clones of the leaf notes at the positions in range, from
+    /// either end. The range is clamped to the size of the tree, so
+    /// `iter_range(start..usize::MAX)` resumes a scan at start.
+    ///
+    /// A leaf whose element isn't retained yields a NotRetained error, and
+    /// the iteration carries on past it.
+    fn iter_range<'a>(
+        &'a self,
+        range: Range<usize>,
+    ) -> Box<
+        dyn DoubleEndedIterator<Item = Result<<Self::Hasher as MerkleHasher>::Element, NotRetained>>
+            + 'a,
+    > {
+        Box::new(
+            clamp_range(range, self.len()).map(move |position| match self.get(position) {
+                Ok(Some(element)) => Ok(element),
+                _ => Err(NotRetained { position }),
+            }),
+        )
+    }
+
+    /// Iterate over the position and hash of each leaf in the tree, along
+    /// with its element if the tree stores it. Trees that store leaf hashes
+    /// read them instead of hashing the element again. Leaves that have been
+    /// pruned are skipped. If a persistent tree can't read a leaf, the error
+    /// is yielded and the iteration ends.
+    fn iter_leaves<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = io::Result<LeafEntry<Self::Hasher>>> + 'a> {
+        Box::new((0..self.len()).filter_map(move |position| {
+            self.get(position).ok().flatten().map(|element| {
+                Ok(LeafEntry {
+                    position,
+                    hash: element.merkle_hash(),
+                    element: Some(element),
+                })
+            })
+        }))
+    }
 
     /// Get the hash of the current root element in the tree.
     fn root_hash(
@@ -159,6 +305,101 @@ pub trait MerkleTree {
     /// The root hash is not included in the authentication path.
     fn witness(&self, position: usize) -> Option<Witness<Self::Hasher>>;
 
+    /// Construct the proof that the leaf node at `position` existed at the
+    /// time the tree contained `past_size` elements. The witness verifies
+    /// against `past_root(past_size)`.
+    ///
+    /// Returns None if position is not less than past_size, or past_size is
+    /// greater than the current length of the tree.
+    ///
+    /// The default replays the path of the last leaf at past_size from the
+    /// current witnesses of that leaf and of position, so it also returns
+    /// None if either of them can't be witnessed.
+    fn past_witness(&self, position: usize, past_size: usize) -> Option<Witness<Self::Hasher>> {
+        if position >= past_size || past_size > self.len() {
+            return None;
+        }
+        if past_size == self.len() {
+            return self.witness(position);
+        }
+        let last = past_size - 1;
+        let last_leaf = self.get(last).ok()??.merkle_hash();
+        let last_path = sibling_hashes(self.witness(last)?);
+        let path = sibling_hashes(self.witness(position)?);
+        Some(past_witness(
+            &*self.hasher(),
+            position,
+            past_size,
+            |index| {
+                if index == last {
+                    last_leaf.clone()
+                } else if index == position ^ 1 {
+                    path[0].clone()
+                } else {
+                    last_path[0].clone()
+                }
+            },
+            |index| {
+                let siblings = if index == last { &last_path } else { &path };
+                siblings.iter().skip(1).cloned().map(Some).collect()
+            },
+        ))
+    }
+
+    /// Get the hash of the node at `level` (0 being the leaves) that covers
+    /// the leaves from `index * 2^level`, such as the root of an aligned
+    /// block of notes. A node that is only partially filled is hashed the
+    /// way the tree hashes it on the way to the root.
+    ///
+    /// Returns None if the node doesn't contain any leaves yet, the level is
+    /// above the root, or the leaf the node starts at has been pruned.
+    fn subtree_root(
+        &self,
+        level: usize,
+        index: usize,
+    ) -> Option<<<Self::Hasher as MerkleHasher>::Element as HashableElement>::Hash> {
+        let position = subtree_start(level, index)?;
+        let witness = self.witness(position)?;
+        let leaf_hash = self.get(position).ok()??.merkle_hash();
+        subtree_hash(&*self.hasher(), &witness, level, leaf_hash)
+    }
+
+    /// Construct the proof that `subtree_root(level, index)` is part of the
+    /// current tree. Its authentication path starts at `level` rather than
+    /// at the leaves; check it with `Witness::verify_subtree`.
+    ///
+    /// Returns None when subtree_root would, except that the leaf the node
+    /// starts at is only needed for its witness.
+    fn subtree_witness(&self, level: usize, index: usize) -> Option<Witness<Self::Hasher>> {
+        let witness = self.witness(subtree_start(level, index)?)?;
+        if level > witness.auth_path.len() {
+            return None;
+        }
+        Some(Witness {
+            tree_size: witness.tree_size,
+            root_hash: witness.root_hash,
+            auth_path: witness.auth_path.into_iter().skip(level).collect(),
+        })
+    }
+
+    /// Write the state another tree needs to carry on from this one: the
+    /// number of leaves, and the hash of each complete subtree on the
+    /// rightmost path, to the left of where the next leaf goes. The linked,
+    /// sled and rocks trees can `import_state` it, and then produce the same
+    /// roots as this tree as both keep appending.
+    ///
+    /// The format is the leaf count as a little endian u64, followed by a
+    /// hash, written with `MerkleHasher::write_hash`, for each bit set in the
+    /// count, starting from the lowest. The hash for bit h is the root of the
+    /// subtree of 2^h leaves that the count's higher bits start at, so the
+    /// first one is for the subtree that ends with the last leaf. The depth
+    /// of the tree isn't included; the importing tree must have the same one.
+    ///
+    /// Fails if the last leaf can't be witnessed.
+    fn export_state<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_witnessed_state(self, writer)
+    }
+
     /// Serialize the Merkle tree to a writer.
     fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()>;
 }
@@ -168,15 +409,50 @@ pub trait MerkleTree {
 /// The Left/Right is the Hash of THIS node, but the MerkleHash at node.0 is
 /// the hash of the SIBLING node.
 #[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum WitnessNode<H: MerkleHash> {
     Left(H),
     Right(H),
 }
 
+/// A leaf of a tree, as yielded by `MerkleTree::iter_leaves`.
+pub struct LeafEntry<H: MerkleHasher> {
+    pub position: usize,
+    pub hash: <H::Element as HashableElement>::Hash,
+    /// None if the tree only stores the hash of this leaf.
+    pub element: Option<H::Element>,
+}
+
+impl<H: MerkleHasher> PartialEq for LeafEntry<H> {
+    fn eq(&self, other: &Self) -> bool {
+        self.position == other.position && self.hash == other.hash && self.element == other.element
+    }
+}
+
+impl<H: MerkleHasher> fmt::Debug for LeafEntry<H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LeafEntry")
+            .field("position", &self.position)
+            .field("hash", &self.hash)
+            .field("element", &self.element)
+            .finish()
+    }
+}
+
 /// Commitment that a leaf node exists in the tree, with an authentication path
 /// and the root_hash of the tree at the time the authentication_path was
 /// calculated.
+///
+/// With the `serde` feature, it is serializable if the hash type is.
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "<H::Element as HashableElement>::Hash: serde::Serialize",
+        deserialize = "<H::Element as HashableElement>::Hash: serde::Deserialize<'de>"
+    ))
+)]
 pub struct Witness<H: MerkleHasher> {
     pub tree_size: usize,
     pub root_hash: <H::Element as HashableElement>::Hash,
@@ -188,15 +464,111 @@ impl<H: MerkleHasher> Witness<H> {
     /// valid confirmation that the given element exists at this point in the
     /// tree.
     pub fn verify(&self, hasher: &H, my_hash: &<H::Element as HashableElement>::Hash) -> bool {
-        let mut cur_hash = (*my_hash).clone();
-        for (i, node) in self.auth_path.iter().enumerate() {
-            cur_hash = match node {
-                WitnessNode::Left(ref right_hash) => hasher.combine_hash(i, &cur_hash, right_hash),
-                WitnessNode::Right(ref left_hash) => hasher.combine_hash(i, left_hash, &cur_hash),
+        hash_path(hasher, 0, my_hash, &self.auth_path) == self.root_hash
+    }
+
+    /// Verify that the root hash and authentication path on this witness,
+    /// as returned by `MerkleTree::subtree_witness`, confirm that
+    /// subtree_root is the hash of a node at the given level of the tree.
+    pub fn verify_subtree(
+        &self,
+        hasher: &H,
+        level: usize,
+        subtree_root: &<H::Element as HashableElement>::Hash,
+    ) -> bool {
+        hash_path(hasher, level, subtree_root, &self.auth_path) == self.root_hash
+    }
+
+    /// Serialize the witness to a writer. The format is the tree size as a
+    /// little endian u32, the root hash, a one byte count of authentication
+    /// path nodes, and then each node as a side byte (0 for Left, 1 for Right)
+    /// followed by its hash. Hashes are written with `MerkleHasher::write_hash`.
+    ///
+    /// A tree size that doesn't fit in a u32 is written as a zero u32 followed
+    /// by the size as a little endian u64. No witness has a tree size of zero,
+    /// so witnesses of smaller trees are unchanged.
+    ///
+    /// Fails without writing anything if the authentication path is too long
+    /// for `Witness::read` to accept.
+    pub fn write<W: io::Write>(&self, hasher: &H, writer: &mut W) -> io::Result<()> {
+        if self.auth_path.len() >= MAX_TREE_DEPTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Authentication path of {} nodes is too long",
+                    self.auth_path.len()
+                ),
+            ));
+        }
+        match u32::try_from(self.tree_size) {
+            Ok(tree_size) => writer.write_u32::<LittleEndian>(tree_size)?,
+            Err(_) => {
+                writer.write_u32::<LittleEndian>(0)?;
+                writer.write_u64::<LittleEndian>(self.tree_size as u64)?;
+            }
+        }
+        hasher.write_hash(&self.root_hash, writer)?;
+        writer.write_u8(self.auth_path.len() as u8)?;
+        for node in self.auth_path.iter() {
+            match node {
+                WitnessNode::Left(ref hash) => {
+                    writer.write_u8(0)?;
+                    hasher.write_hash(hash, writer)?;
+                }
+                WitnessNode::Right(ref hash) => {
+                    writer.write_u8(1)?;
+                    hasher.write_hash(hash, writer)?;
+                }
             }
         }
+        Ok(())
+    }
 
-        cur_hash == self.root_hash
+    /// Deserialize a witness that was written with `Witness::write`.
+    ///
+    /// The input is untrusted, so anything a tree could not have produced
+    /// is rejected with an `InvalidData` error instead of being allocated.
+    pub fn read<R: io::Read>(hasher: &H, reader: &mut R) -> io::Result<Self> {
+        let tree_size = match reader.read_u32::<LittleEndian>()? {
+            0 => {
+                // write only escapes sizes that don't fit in the u32
+                let tree_size = reader.read_u64::<LittleEndian>()?;
+                if tree_size <= u64::from(u32::MAX) {
+                    return Err(invalid_data(format!(
+                        "Witness tree size {} should not be escaped",
+                        tree_size
+                    )));
+                }
+                usize::try_from(tree_size)
+                    .map_err(|_| invalid_data("Witness tree size is too large".to_string()))?
+            }
+            tree_size => tree_size as usize,
+        };
+        if tree_size == 0 {
+            return Err(invalid_data("Witness for an empty tree".to_string()));
+        }
+        let root_hash = hasher.read_hash(reader)?;
+        let path_length = reader.read_u8()? as usize;
+        if path_length >= MAX_TREE_DEPTH {
+            return Err(invalid_data(format!(
+                "Authentication path of {} nodes is too long",
+                path_length
+            )));
+        }
+        let mut auth_path = Vec::with_capacity(path_length);
+        for _ in 0..path_length {
+            let node = match reader.read_u8()? {
+                0 => WitnessNode::Left(hasher.read_hash(reader)?),
+                1 => WitnessNode::Right(hasher.read_hash(reader)?),
+                side => return Err(invalid_data(format!("Unknown witness node side {}", side))),
+            };
+            auth_path.push(node);
+        }
+        Ok(Witness {
+            tree_size,
+            root_hash,
+            auth_path,
+        })
     }
 }
 
@@ -215,3 +587,573 @@ impl<H: MerkleHasher> fmt::Debug for Witness<H> {
         Ok(())
     }
 }
+
+/// Serialize an in-memory tree as the bytes `MerkleTree::write` writes, so
+/// that elements and hashes don't need serde implementations of their own.
+#[cfg(feature = "serde")]
+pub(crate) fn serialize_tree<M: MerkleTree, S: serde::Serializer>(
+    tree: &M,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut bytes = vec![];
+    tree.write(&mut bytes).map_err(serde::ser::Error::custom)?;
+    serializer.serialize_bytes(&bytes)
+}
+
+/// Deserializes an in-memory tree that was serialized with serde, using
+/// the hasher it was built with to read the elements back. Pass it to
+/// `serde::de::DeserializeSeed::deserialize`.
+#[cfg(feature = "serde")]
+pub struct TreeSeed<M: MerkleTree> {
+    hasher: Arc<M::Hasher>,
+}
+
+#[cfg(feature = "serde")]
+impl<M: MerkleTree> TreeSeed<M> {
+    pub fn new(hasher: Arc<M::Hasher>) -> Self {
+        TreeSeed { hasher }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, M: MerkleTree> serde::de::DeserializeSeed<'de> for TreeSeed<M> {
+    type Value = Box<M>;
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<Box<M>, D::Error> {
+        deserializer.deserialize_bytes(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, M: MerkleTree> serde::de::Visitor<'de> for TreeSeed<M> {
+    type Value = Box<M>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the bytes of a serialized merkle tree")
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Box<M>, E> {
+        M::read(self.hasher, &mut &bytes[..]).map_err(E::custom)
+    }
+
+    /// Formats without a byte type, such as JSON, write the bytes as a
+    /// sequence.
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Box<M>, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element::<u8>()? {
+            bytes.push(byte);
+        }
+        self.visit_bytes(&bytes)
+    }
+}
+
+/// Write the depth and leaf count that prefix a serialized in-memory tree.
+/// The count is a little endian u32 for trees of up to `U32_TREE_DEPTH`
+/// levels and a little endian u64 for deeper ones.
+pub(crate) fn write_tree_header<W: io::Write>(
+    writer: &mut W,
+    tree_depth: usize,
+    num_leaves: usize,
+) -> io::Result<()> {
+    writer.write_u8(tree_depth as u8)?;
+    if tree_depth <= U32_TREE_DEPTH {
+        writer.write_u32::<LittleEndian>(num_leaves as u32)
+    } else {
+        writer.write_u64::<LittleEndian>(num_leaves as u64)
+    }
+}
+
+/// Read the depth and leaf count that prefix a serialized in-memory tree.
+///
+/// Neither value is trusted: a depth the trees can't be built with, or more
+/// leaves than a tree of that depth can hold, is an `InvalidData` error rather
+/// than a panic further down the line.
+pub(crate) fn read_tree_header<R: io::Read>(reader: &mut R) -> io::Result<(usize, usize)> {
+    let tree_depth = reader.read_u8()? as usize;
+    if !(2..=MAX_TREE_DEPTH).contains(&tree_depth) {
+        return Err(invalid_data(format!(
+            "Unsupported tree depth {}",
+            tree_depth
+        )));
+    }
+    let num_leaves = if tree_depth <= U32_TREE_DEPTH {
+        u64::from(reader.read_u32::<LittleEndian>()?)
+    } else {
+        reader.read_u64::<LittleEndian>()?
+    };
+    if num_leaves > 1 << (tree_depth - 1) || usize::try_from(num_leaves).is_err() {
+        return Err(invalid_data(format!(
+            "{} leaves do not fit in a tree of depth {}",
+            num_leaves, tree_depth
+        )));
+    }
+    Ok((tree_depth, num_leaves as usize))
+}
+
+/// Combine hash, the hash of a node at first_level, with each node of an
+/// authentication path in turn, and return the hash at the top of the path.
+fn hash_path<H: MerkleHasher>(
+    hasher: &H,
+    first_level: usize,
+    hash: &<H::Element as HashableElement>::Hash,
+    path: &[WitnessNode<<H::Element as HashableElement>::Hash>],
+) -> <H::Element as HashableElement>::Hash {
+    let mut cur_hash = hash.clone();
+    for (i, node) in path.iter().enumerate() {
+        let depth = first_level + i;
+        cur_hash = match node {
+            WitnessNode::Left(ref right_hash) => hasher.combine_hash(depth, &cur_hash, right_hash),
+            WitnessNode::Right(ref left_hash) => hasher.combine_hash(depth, left_hash, &cur_hash),
+        }
+    }
+    cur_hash
+}
+
+/// The position of the first leaf under the node at level and index, if it
+/// can be addressed.
+pub(crate) fn subtree_start(level: usize, index: usize) -> Option<usize> {
+    index.checked_mul(1usize.checked_shl(u32::try_from(level).ok()?)?)
+}
+
+/// Hash the leaf at the start of a subtree up to the subtree's root at
+/// level, with the bottom of that leaf's witness.
+pub(crate) fn subtree_hash<H: MerkleHasher>(
+    hasher: &H,
+    witness: &Witness<H>,
+    level: usize,
+    leaf_hash: <H::Element as HashableElement>::Hash,
+) -> Option<<H::Element as HashableElement>::Hash> {
+    if level > witness.auth_path.len() {
+        return None;
+    }
+    Some(hash_path(
+        hasher,
+        0,
+        &leaf_hash,
+        &witness.auth_path[..level],
+    ))
+}
+
+/// The leaves whose hashes or parents updating the leaf at position reads:
+/// the leaf and the other leaf of its pair, and the first leaf of each
+/// complete or partial subtree to the right of its path, whose path leads to
+/// the right sibling that the update rehashes. Fails with the first of them
+/// that isn't retained, according to `retained`.
+pub(crate) fn check_update_leaves(
+    position: usize,
+    num_leaves: usize,
+    tree_depth: usize,
+    retained: impl Fn(usize) -> bool,
+) -> Result<(), UpdateError> {
+    if position >= num_leaves {
+        return Err(UpdateError::OutOfRange {
+            position,
+            len: num_leaves,
+        });
+    }
+    let pair = (position & !1)..((position | 1) + 1).min(num_leaves);
+    let right_starts = (1..tree_depth)
+        .filter(|depth| (position >> depth) & 1 == 0)
+        .map(|depth| ((position >> depth) + 1) << depth)
+        .filter(|&start| start < num_leaves);
+    match pair.chain(right_starts).find(|&leaf| !retained(leaf)) {
+        Some(position) => Err(NotRetained { position }.into()),
+        None => Ok(()),
+    }
+}
+
+/// The frontier of a tree whose first complete subtrees at level have the
+/// given roots: the number of leaves they cover, and the hash of each
+/// complete subtree to the left of the next leaf, lowest first.
+///
+/// Fails with InvalidInput if level is 0 or more than tree_depth, or if the
+/// roots and the `following` leaves to be added after them don't fit in a
+/// tree with tree_depth levels of hashing.
+pub(crate) fn frontier_from_roots<H: MerkleHasher>(
+    hasher: &H,
+    tree_depth: usize,
+    level: usize,
+    roots: &[<H::Element as HashableElement>::Hash],
+    following: usize,
+) -> io::Result<(usize, Vec<<H::Element as HashableElement>::Hash>)> {
+    if level == 0 || level > tree_depth {
+        return Err(invalid_input(format!(
+            "Subtree level must be between 1 and {}, not {}",
+            tree_depth, level
+        )));
+    }
+    let size = subtree_start(level, roots.len())
+        .filter(|size| {
+            size.checked_add(following)
+                .is_some_and(|end| end <= 1 << tree_depth)
+        })
+        .ok_or_else(|| {
+            invalid_input(format!(
+                "{} subtrees at level {} and {} leaves do not fit in the tree",
+                roots.len(),
+                level,
+                following
+            ))
+        })?;
+    let mut frontier = vec![];
+    let mut hashes = roots.to_vec();
+    let mut depth = level;
+    while !hashes.is_empty() {
+        if hashes.len() % 2 == 1 {
+            frontier.push(hashes[hashes.len() - 1].clone());
+        }
+        hashes = hashes
+            .chunks_exact(2)
+            .map(|pair| hasher.combine_hash(depth, &pair[0], &pair[1]))
+            .collect();
+        depth += 1;
+    }
+    Ok((size, frontier))
+}
+
+/// A node on the right edge of a tree built from its frontier. A node that
+/// is the right child of its parent also has the hash of its left sibling.
+pub(crate) struct EdgeNode<H> {
+    pub(crate) hash: H,
+    pub(crate) left_sibling: Option<H>,
+}
+
+/// The right edge of a tree with num_leaves leaves, from the lowest complete
+/// subtree of its frontier (at level, which is 0 if that is the last leaf)
+/// up to the node above all the leaves, and the root hash of the tree.
+pub(crate) struct FrontierEdge<H> {
+    pub(crate) level: usize,
+    pub(crate) nodes: Vec<EdgeNode<H>>,
+    pub(crate) root_hash: H,
+}
+
+/// Rebuild the right edge of a tree with tree_depth levels of hashing from
+/// its frontier, which must have a hash for each bit set in num_leaves.
+pub(crate) fn frontier_edge<H: MerkleHasher>(
+    hasher: &H,
+    tree_depth: usize,
+    num_leaves: usize,
+    frontier: &[<H::Element as HashableElement>::Hash],
+) -> FrontierEdge<<H::Element as HashableElement>::Hash> {
+    assert_eq!(frontier.len(), num_leaves.count_ones() as usize);
+    let level = num_leaves.trailing_zeros() as usize;
+    let mut left_siblings = frontier[1..].iter();
+    let mut hash = frontier[0].clone();
+    let mut position = (num_leaves - 1) >> level;
+    let mut depth = level;
+    let mut nodes = vec![];
+    loop {
+        let left_sibling = if position % 2 == 1 {
+            left_siblings.next().cloned()
+        } else {
+            None
+        };
+        let parent_hash = match &left_sibling {
+            Some(left_hash) => hasher.combine_hash(depth, left_hash, &hash),
+            None => hasher.combine_hash(depth, &hash, &hash),
+        };
+        nodes.push(EdgeNode { hash, left_sibling });
+        if position == 0 {
+            break;
+        }
+        hash = parent_hash;
+        position /= 2;
+        depth += 1;
+    }
+    let mut root_hash = nodes[nodes.len() - 1].hash.clone();
+    for depth in depth..tree_depth {
+        root_hash = hasher.combine_hash(depth, &root_hash, &root_hash);
+    }
+    FrontierEdge {
+        level,
+        nodes,
+        root_hash,
+    }
+}
+
+/// The frontier of a nonempty tree, read from the witness of its last leaf:
+/// the hash of the complete subtree that ends with the last leaf, and the
+/// left siblings on its path above that. None if the last leaf can't be
+/// witnessed.
+fn witness_frontier<M: MerkleTree + ?Sized>(
+    tree: &M,
+) -> Option<Vec<<<M::Hasher as MerkleHasher>::Element as HashableElement>::Hash>> {
+    let num_leaves = tree.len();
+    let last = num_leaves - 1;
+    let witness = tree.witness(last)?;
+    let level = num_leaves.trailing_zeros() as usize;
+    let mut frontier = vec![subtree_hash(
+        &*tree.hasher(),
+        &witness,
+        level,
+        tree.subtree_root(0, last)?,
+    )?];
+    for (depth, node) in witness.auth_path.iter().enumerate().skip(level + 1) {
+        if (num_leaves >> depth) & 1 == 1 {
+            match node {
+                WitnessNode::Right(left_hash) => frontier.push(left_hash.clone()),
+                WitnessNode::Left(_) => return None,
+            }
+        }
+    }
+    Some(frontier)
+}
+
+/// Write the state of a tree with the frontier read from the witness of its
+/// last leaf, as `MerkleTree::export_state` does by default.
+pub(crate) fn write_witnessed_state<M: MerkleTree + ?Sized, W: io::Write>(
+    tree: &M,
+    writer: &mut W,
+) -> io::Result<()> {
+    let frontier = if tree.is_empty() {
+        vec![]
+    } else {
+        witness_frontier(tree)
+            .ok_or_else(|| io::Error::other("The last leaf can't be witnessed"))?
+    };
+    write_state(&*tree.hasher(), tree.len(), &frontier, writer)
+}
+
+/// Write a tree state in the format documented on `MerkleTree::export_state`.
+pub(crate) fn write_state<H: MerkleHasher, W: io::Write>(
+    hasher: &H,
+    num_leaves: usize,
+    frontier: &[<H::Element as HashableElement>::Hash],
+    writer: &mut W,
+) -> io::Result<()> {
+    writer.write_u64::<LittleEndian>(num_leaves as u64)?;
+    for hash in frontier {
+        hasher.write_hash(hash, writer)?;
+    }
+    Ok(())
+}
+
+/// Read a tree state written by `MerkleTree::export_state`, checking that
+/// the leaves fit in a tree with tree_depth levels of hashing.
+pub(crate) fn read_state<H: MerkleHasher, R: io::Read>(
+    hasher: &H,
+    tree_depth: usize,
+    reader: &mut R,
+) -> io::Result<(usize, Vec<<H::Element as HashableElement>::Hash>)> {
+    let num_leaves = reader.read_u64::<LittleEndian>()?;
+    if num_leaves > 1 << tree_depth {
+        return Err(invalid_data(format!(
+            "{} leaves do not fit in a tree of depth {}",
+            num_leaves,
+            tree_depth + 1
+        )));
+    }
+    let frontier = (0..num_leaves.count_ones())
+        .map(|_| hasher.read_hash(reader))
+        .collect::<io::Result<_>>()?;
+    Ok((num_leaves as usize, frontier))
+}
+
+/// Clamp a range of leaf positions to a tree containing num_leaves leaves.
+pub(crate) fn clamp_range(range: Range<usize>, num_leaves: usize) -> Range<usize> {
+    let end = range.end.min(num_leaves);
+    range.start.min(end)..end
+}
+
+/// Double ended iterator over the leaf elements in a range, for backends that
+/// read them with a scan from each end of the range. The scans yield the
+/// position and element of each stored element in order, so a position that
+/// doesn't come up next is one whose element wasn't retained, and yields a
+/// NotRetained error.
+///
+/// A backend without database snapshots can have it watch the mark that the
+/// tree lowers before it is truncated. Once the tree is truncated below a
+/// position that has just been read, that read can't be trusted, and the
+/// iterator ends instead.
+#[cfg(any(feature = "sledder", feature = "rocker"))]
+pub(crate) struct LeafRange<F: Iterator, B: Iterator> {
+    front: Peekable<F>,
+    back: Peekable<B>,
+    range: Range<usize>,
+    truncated_to: Option<Arc<AtomicUsize>>,
+}
+
+#[cfg(any(feature = "sledder", feature = "rocker"))]
+impl<E, F, B> LeafRange<F, B>
+where
+    F: Iterator<Item = (usize, E)>,
+    B: Iterator<Item = (usize, E)>,
+{
+    pub(crate) fn new(front: F, back: B, range: Range<usize>) -> Self {
+        LeafRange {
+            front: front.peekable(),
+            back: back.peekable(),
+            range,
+            truncated_to: None,
+        }
+    }
+
+    /// End the iteration once the tree has been truncated below the
+    /// position being read.
+    #[cfg(feature = "sledder")]
+    pub(crate) fn watch(mut self, truncated_to: Arc<AtomicUsize>) -> Self {
+        self.truncated_to = Some(truncated_to);
+        self
+    }
+
+    /// What to yield for the leaf at position, read from one of the scans.
+    fn checked(
+        &mut self,
+        leaf: Result<E, NotRetained>,
+        position: usize,
+    ) -> Option<Result<E, NotRetained>> {
+        if let Some(truncated_to) = &self.truncated_to {
+            if truncated_to.load(Ordering::SeqCst) <= position {
+                self.range = 0..0;
+                return None;
+            }
+        }
+        Some(leaf)
+    }
+}
+
+/// Take the element at position from a scan, leaving the scan where it is if
+/// the next element it has is for a later position.
+#[cfg(any(feature = "sledder", feature = "rocker"))]
+fn take_leaf<E, I: Iterator<Item = (usize, E)>>(
+    scan: &mut Peekable<I>,
+    position: usize,
+) -> Result<E, NotRetained> {
+    scan.next_if(|(found, _)| *found == position)
+        .map(|(_, element)| element)
+        .ok_or(NotRetained { position })
+}
+
+#[cfg(any(feature = "sledder", feature = "rocker"))]
+impl<E, F, B> Iterator for LeafRange<F, B>
+where
+    F: Iterator<Item = (usize, E)>,
+    B: Iterator<Item = (usize, E)>,
+{
+    type Item = Result<E, NotRetained>;
+
+    fn next(&mut self) -> Option<Result<E, NotRetained>> {
+        let position = self.range.next()?;
+        let leaf = take_leaf(&mut self.front, position);
+        self.checked(leaf, position)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.range.size_hint().1)
+    }
+}
+
+#[cfg(any(feature = "sledder", feature = "rocker"))]
+impl<E, F, B> DoubleEndedIterator for LeafRange<F, B>
+where
+    F: Iterator<Item = (usize, E)>,
+    B: Iterator<Item = (usize, E)>,
+{
+    fn next_back(&mut self) -> Option<Result<E, NotRetained>> {
+        let position = self.range.next_back()?;
+        let leaf = take_leaf(&mut self.back, position);
+        self.checked(leaf, position)
+    }
+}
+
+/// The hash in each node of a witness's authentication path.
+fn sibling_hashes<H: MerkleHasher>(
+    witness: Witness<H>,
+) -> Vec<<H::Element as HashableElement>::Hash> {
+    witness
+        .auth_path
+        .into_iter()
+        .map(|node| match node {
+            WitnessNode::Left(hash) | WitnessNode::Right(hash) => hash,
+        })
+        .collect()
+}
+
+/// Construct a witness for the leaf at `position` as of a tree containing
+/// `past_size` leaves, for backends that store each node alongside the hash of
+/// its sibling.
+///
+/// `leaf_hash` returns the hash of the leaf at an index. `stored_siblings`
+/// walks up from the leaf at an index and returns the hash_of_sibling stored
+/// in each internal node on its path (None once the path passes the root),
+/// starting with the leaf's parent and ending just below the root. Those hashes
+/// describe the tree at its current size; any sibling that was still being
+/// filled (or was empty) when the tree had `past_size` leaves lies on the path
+/// of the last leaf at that size, so its old hash is recalculated from that
+/// path instead.
+pub(crate) fn past_witness<H: MerkleHasher>(
+    hasher: &H,
+    position: usize,
+    past_size: usize,
+    leaf_hash: impl Fn(usize) -> <H::Element as HashableElement>::Hash,
+    stored_siblings: impl Fn(usize) -> Vec<Option<<H::Element as HashableElement>::Hash>>,
+) -> Witness<H> {
+    let last = past_size - 1;
+    let last_siblings = stored_siblings(last);
+    let levels = last_siblings.len() + 1;
+
+    // frontier[depth] is the hash of the node at that depth on the path of
+    // the last leaf, as it was when the last leaf had just been added.
+    let mut frontier = vec![leaf_hash(last)];
+    for depth in 0..levels {
+        let current = frontier.last().expect("frontier starts with the leaf");
+        let next = if (last >> depth) % 2 == 1 {
+            let sibling = if depth == 0 {
+                leaf_hash(last - 1)
+            } else {
+                last_siblings[depth - 1]
+                    .clone()
+                    .expect("Right node must have a left sibling")
+            };
+            hasher.combine_hash(depth, &sibling, current)
+        } else {
+            hasher.combine_hash(depth, current, current)
+        };
+        frontier.push(next);
+    }
+
+    let siblings = if position == last {
+        last_siblings
+    } else {
+        stored_siblings(position)
+    };
+    let stored = |depth: usize| {
+        if depth == 0 {
+            leaf_hash(position ^ 1)
+        } else {
+            siblings[depth - 1]
+                .clone()
+                .expect("Complete sibling must be stored")
+        }
+    };
+    let auth_path = frontier[..levels]
+        .iter()
+        .enumerate()
+        .map(|(depth, frontier_hash)| {
+            let mine = position >> depth;
+            if mine % 2 == 1 {
+                WitnessNode::Right(stored(depth))
+            } else if last >> depth <= mine + 1 {
+                // Either the right sibling was being filled, or it was empty and
+                // this node was hashed with itself. Both are on the last leaf's path.
+                WitnessNode::Left(frontier_hash.clone())
+            } else {
+                WitnessNode::Left(stored(depth))
+            }
+        })
+        .collect();
+
+    Witness {
+        tree_size: past_size,
+        root_hash: frontier.pop().expect("frontier includes the root"),
+        auth_path,
+    }
+}
+
+pub(crate) fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub(crate) fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
@@ -0,0 +1,239 @@
+use std::fmt;
+use std::iter::FromIterator;
+use std::sync::Arc;
+
+/// Each chunk holds 2^BITS items, and each level above them 2^BITS chunks.
+const BITS: u32 = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+/// A vector whose clones share everything they haven't modified since.
+///
+/// The items are kept in fixed size chunks, at the bottom of a shallow tree
+/// of shared pointers. Modifying an item copies the chunk it is in and the
+/// handful of pointer lists above it, instead of the whole vector, so a tree
+/// that is cloned before every modification (as SharedMerkleTree does)
+/// doesn't copy all of its leaves and nodes each time.
+pub(super) struct ChunkedVec<V> {
+    root: Arc<Chunk<V>>,
+    len: usize,
+    /// How far to shift an index right to find its child of the root.
+    /// Zero when the root is itself a chunk of items.
+    shift: u32,
+}
+
+enum Chunk<V> {
+    Items(Vec<V>),
+    Children(Vec<Arc<Chunk<V>>>),
+}
+
+impl<V: Clone> Clone for Chunk<V> {
+    fn clone(&self) -> Self {
+        match self {
+            Chunk::Items(items) => Chunk::Items(items.clone()),
+            Chunk::Children(children) => Chunk::Children(children.clone()),
+        }
+    }
+}
+
+impl<V> Chunk<V> {
+    /// An empty chunk for the level with the given shift.
+    fn empty(shift: u32) -> Self {
+        if shift == 0 {
+            Chunk::Items(Vec::with_capacity(WIDTH))
+        } else {
+            Chunk::Children(Vec::with_capacity(WIDTH))
+        }
+    }
+}
+
+impl<V: Clone> Chunk<V> {
+    fn push(&mut self, shift: u32, index: usize, value: V) {
+        match self {
+            Chunk::Items(items) => items.push(value),
+            Chunk::Children(children) => {
+                let child = (index >> shift) & MASK;
+                if child == children.len() {
+                    children.push(Arc::new(Chunk::empty(shift - BITS)));
+                }
+                Arc::make_mut(&mut children[child]).push(shift - BITS, index, value);
+            }
+        }
+    }
+
+    fn get_mut(&mut self, shift: u32, index: usize) -> &mut V {
+        match self {
+            Chunk::Items(items) => &mut items[index & MASK],
+            Chunk::Children(children) => {
+                Arc::make_mut(&mut children[(index >> shift) & MASK]).get_mut(shift - BITS, index)
+            }
+        }
+    }
+
+    /// Keep the first len items, of which there is at least one.
+    fn truncate(&mut self, shift: u32, len: usize) {
+        match self {
+            Chunk::Items(items) => items.truncate(((len - 1) & MASK) + 1),
+            Chunk::Children(children) => {
+                let last = ((len - 1) >> shift) & MASK;
+                children.truncate(last + 1);
+                Arc::make_mut(&mut children[last]).truncate(shift - BITS, len);
+            }
+        }
+    }
+}
+
+impl<V> Chunk<V> {
+    /// The chunks of items under chunk, in order.
+    #[cfg(test)]
+    fn item_chunks(chunk: &Arc<Chunk<V>>) -> Vec<&Arc<Chunk<V>>> {
+        match &**chunk {
+            Chunk::Items(_) => vec![chunk],
+            Chunk::Children(children) => children.iter().flat_map(Chunk::item_chunks).collect(),
+        }
+    }
+}
+
+impl<V> ChunkedVec<V> {
+    pub(super) fn new() -> Self {
+        ChunkedVec {
+            root: Arc::new(Chunk::empty(0)),
+            len: 0,
+            shift: 0,
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(super) fn get(&self, index: usize) -> Option<&V> {
+        if index >= self.len {
+            return None;
+        }
+        let mut chunk = &*self.root;
+        let mut shift = self.shift;
+        loop {
+            match chunk {
+                Chunk::Items(items) => return items.get(index & MASK),
+                Chunk::Children(children) => {
+                    chunk = &children[(index >> shift) & MASK];
+                    shift -= BITS;
+                }
+            }
+        }
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = &V> {
+        (0..self.len).map(move |index| &self[index])
+    }
+
+    /// Count the chunks of items, and how many of them are the very same
+    /// chunks as at the same place in other.
+    #[cfg(test)]
+    pub(super) fn shared_chunks(&self, other: &Self) -> (usize, usize) {
+        let chunks = Chunk::item_chunks(&self.root);
+        let shared = chunks
+            .iter()
+            .zip(Chunk::item_chunks(&other.root))
+            .filter(|(chunk, other)| Arc::ptr_eq(chunk, other))
+            .count();
+        (shared, chunks.len())
+    }
+}
+
+impl<V: Clone> ChunkedVec<V> {
+    /// Get the item at index, copying whatever is shared on the way to it.
+    pub(super) fn get_mut(&mut self, index: usize) -> Option<&mut V> {
+        if index >= self.len {
+            return None;
+        }
+        Some(Arc::make_mut(&mut self.root).get_mut(self.shift, index))
+    }
+
+    pub(super) fn push(&mut self, value: V) {
+        if self.len == WIDTH << self.shift {
+            // the tree is full, so add a level above it
+            let old_root = std::mem::replace(&mut self.root, Arc::new(Chunk::Children(vec![])));
+            if let Chunk::Children(children) = Arc::make_mut(&mut self.root) {
+                children.push(old_root);
+            }
+            self.shift += BITS;
+        }
+        Arc::make_mut(&mut self.root).push(self.shift, self.len, value);
+        self.len += 1;
+    }
+
+    pub(super) fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+        if len == 0 {
+            *self = ChunkedVec::new();
+            return;
+        }
+        Arc::make_mut(&mut self.root).truncate(self.shift, len);
+        self.len = len;
+        // drop the levels that only have one child
+        while self.shift > 0 && len <= WIDTH << (self.shift - BITS) {
+            let child = match &*self.root {
+                Chunk::Children(children) => children[0].clone(),
+                Chunk::Items(_) => unreachable!("only the bottom level has items"),
+            };
+            self.root = child;
+            self.shift -= BITS;
+        }
+    }
+
+    /// Take the items out, cloning the ones that are shared with other
+    /// vectors.
+    pub(super) fn into_vec(self) -> Vec<V> {
+        self.iter().cloned().collect()
+    }
+}
+
+impl<V> Default for ChunkedVec<V> {
+    fn default() -> Self {
+        ChunkedVec::new()
+    }
+}
+
+impl<V> Clone for ChunkedVec<V> {
+    fn clone(&self) -> Self {
+        ChunkedVec {
+            root: self.root.clone(),
+            len: self.len,
+            shift: self.shift,
+        }
+    }
+}
+
+impl<V: Clone> FromIterator<V> for ChunkedVec<V> {
+    fn from_iter<I: IntoIterator<Item = V>>(iter: I) -> Self {
+        let mut items = ChunkedVec::new();
+        for item in iter {
+            items.push(item);
+        }
+        items
+    }
+}
+
+impl<V> std::ops::Index<usize> for ChunkedVec<V> {
+    type Output = V;
+
+    fn index(&self, index: usize) -> &V {
+        self.get(index).expect("index out of bounds")
+    }
+}
+
+impl<V: fmt::Debug> fmt::Debug for ChunkedVec<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<V: PartialEq> PartialEq for ChunkedVec<V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
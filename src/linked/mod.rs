@@ -1,11 +1,24 @@
-use crate::{HashableElement, MerkleHasher, MerkleTree, Witness, WitnessNode};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::checkpoint::{Checkpointed, Checkpoints};
+use crate::config::{ConfigError, TreeConfig};
+#[cfg(feature = "serde")]
+use crate::serialize_tree;
+use crate::snapshot::Snapshot;
+use crate::{
+    check_update_leaves, frontier_edge, frontier_from_roots, past_witness, read_state,
+    read_tree_header, write_state, write_tree_header, write_witnessed_state, HashableElement,
+    MerkleHasher, MerkleTree, NotRetained, UpdateError, Witness, WitnessNode,
+};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io;
+use std::ops::{Index, IndexMut};
 use std::sync::Arc;
 
-/// Newtype wrapper of u32. It just represents an index into a vector,
+mod chunked;
+use chunked::ChunkedVec;
+
+/// Newtype wrapper of u64. It just represents an index into a vector,
 #[derive(Shrinkwrap, Debug, Clone, Copy, PartialEq, PartialOrd)]
-struct NodeIndex(u32);
+struct NodeIndex(u64);
 
 impl NodeIndex {
     fn empty() -> Self {
@@ -15,28 +28,182 @@ impl NodeIndex {
 
 impl From<usize> for NodeIndex {
     fn from(value: usize) -> NodeIndex {
-        NodeIndex(value as u32)
+        NodeIndex(value as u64)
     }
 }
 
-impl std::ops::Add<u32> for NodeIndex {
+impl std::ops::Add<u64> for NodeIndex {
     type Output = NodeIndex;
 
-    fn add(self, other: u32) -> NodeIndex {
+    fn add(self, other: u64) -> NodeIndex {
         NodeIndex(self.0 + other)
     }
 }
 
+/// A vector that may have holes in it. Until the tree is pruned or synced,
+/// every index below len is present and the items are kept in a ChunkedVec,
+/// which clones of the tree share until they modify it.
+/// Pruning removes leaves and nodes that are no longer needed without moving
+/// the ones that remain, since nodes refer to each other by index, so from
+/// then on the items are kept in a map.
+#[derive(Debug, Clone, PartialEq)]
+enum SparseVec<V> {
+    Dense(ChunkedVec<V>),
+    Sparse {
+        len: usize,
+        items: HashMap<usize, V>,
+    },
+}
+
+impl<V: Clone> SparseVec<V> {
+    fn new() -> Self {
+        SparseVec::Dense(ChunkedVec::new())
+    }
+
+    /// One past the highest index, whether or not it has been pruned.
+    fn len(&self) -> usize {
+        match self {
+            SparseVec::Dense(items) => items.len(),
+            SparseVec::Sparse { len, .. } => *len,
+        }
+    }
+
+    /// Determine whether nothing has been pruned.
+    fn is_complete(&self) -> bool {
+        match self {
+            SparseVec::Dense(_) => true,
+            SparseVec::Sparse { len, items } => items.len() == *len,
+        }
+    }
+
+    fn get(&self, index: usize) -> Option<&V> {
+        match self {
+            SparseVec::Dense(items) => items.get(index),
+            SparseVec::Sparse { items, .. } => items.get(&index),
+        }
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut V> {
+        match self {
+            SparseVec::Dense(items) => items.get_mut(index),
+            SparseVec::Sparse { items, .. } => items.get_mut(&index),
+        }
+    }
+
+    fn contains(&self, index: usize) -> bool {
+        self.get(index).is_some()
+    }
+
+    fn push(&mut self, value: V) {
+        match self {
+            SparseVec::Dense(items) => items.push(value),
+            SparseVec::Sparse { len, items } => {
+                items.insert(*len, value);
+                *len += 1;
+            }
+        }
+    }
+
+    /// Extend the length to len without adding any items, as if the items
+    /// up to it had been pruned.
+    fn skip_to(&mut self, len: usize) {
+        if len > self.len() {
+            if let SparseVec::Sparse { len: old_len, .. } = self.make_sparse() {
+                *old_len = len;
+            }
+        }
+    }
+
+    fn truncate(&mut self, len: usize) {
+        match self {
+            SparseVec::Dense(items) => items.truncate(len),
+            SparseVec::Sparse {
+                len: old_len,
+                items,
+            } => {
+                if len < *old_len {
+                    items.retain(|index, _| *index < len);
+                    *old_len = len;
+                }
+                self.make_dense_if_complete();
+            }
+        }
+    }
+
+    /// Remove every item whose index isn't in keep. The length is unchanged.
+    fn retain(&mut self, keep: &HashSet<usize>) {
+        if let SparseVec::Sparse { items, .. } = self.make_sparse() {
+            items.retain(|index, _| keep.contains(index));
+        }
+        self.make_dense_if_complete();
+    }
+
+    /// Iterate over the items in index order, as Some, or None where they
+    /// have been pruned.
+    fn iter(&self) -> impl Iterator<Item = Option<&V>> {
+        (0..self.len()).map(move |index| self.get(index))
+    }
+
+    /// The chunks of a list that hasn't been pruned.
+    #[cfg(test)]
+    fn chunks(&self) -> Option<&ChunkedVec<V>> {
+        match self {
+            SparseVec::Dense(items) => Some(items),
+            SparseVec::Sparse { .. } => None,
+        }
+    }
+
+    /// Move the items into a map, if they aren't already.
+    fn make_sparse(&mut self) -> &mut Self {
+        if let SparseVec::Dense(items) = self {
+            let items = std::mem::take(items).into_vec();
+            *self = SparseVec::Sparse {
+                len: items.len(),
+                items: items.into_iter().enumerate().collect(),
+            };
+        }
+        self
+    }
+
+    /// Move the items back into a Vec once none are missing.
+    fn make_dense_if_complete(&mut self) {
+        if let SparseVec::Sparse { len, items } = self {
+            if items.len() == *len {
+                let mut items = std::mem::take(items).into_iter().collect::<Vec<_>>();
+                items.sort_unstable_by_key(|(index, _)| *index);
+                *self = SparseVec::Dense(items.into_iter().map(|(_, item)| item).collect());
+            }
+        }
+    }
+}
+
+impl<V: Clone> Index<usize> for SparseVec<V> {
+    type Output = V;
+
+    fn index(&self, index: usize) -> &V {
+        self.get(index)
+            .unwrap_or_else(|| panic!("Index {} was pruned or never existed", index))
+    }
+}
+
+impl<V: Clone> IndexMut<usize> for SparseVec<V> {
+    fn index_mut(&mut self, index: usize) -> &mut V {
+        self.get_mut(index)
+            .unwrap_or_else(|| panic!("Index {} was pruned or never existed", index))
+    }
+}
+
 /// Represent an internal node in the tree of nodes. To make it easy
 /// to create an authentication path, internal nodes store the hash of their
 /// *sibling* rather than their own hash.
 ///
-/// The nodes are all stored in a vector in LinkedMerkleTree. I use u32 indices
+/// The nodes are all stored in a vector in LinkedMerkleTree. I use u64 indices
 /// into that vector instead of pointers to other nodes for a few reasons:
-/// 1) On a 64-bit system, u32 uses half as much memory as a pointer,
-/// and with the number of nodes we expect, that's an appreciable amount of memory.
-/// 2) Maintaining a tree of linked nodes using safe Rust is unpleasant.
-/// 3) Something something storing the tree in contiguous memory.
+/// 1) Maintaining a tree of linked nodes using safe Rust is unpleasant.
+/// 2) Something something storing the tree in contiguous memory.
+///
+/// The indices used to be u32 to save memory, but that capped the tree at
+/// about 4 billion nodes whatever its depth.
 #[derive(Debug, PartialEq)]
 enum InternalNode<T: MerkleHasher> {
     /// In the case of a left node, there may not be a right sibling.
@@ -68,14 +235,14 @@ impl<T: MerkleHasher> Clone for InternalNode<T> {
                 parent,
             } => InternalNode::Left {
                 hash_of_sibling: hash_of_sibling.clone(),
-                parent: parent.clone(),
+                parent: *parent,
             },
             InternalNode::Right {
                 hash_of_sibling,
                 left,
             } => InternalNode::Right {
                 hash_of_sibling: hash_of_sibling.clone(),
-                left: left.clone(),
+                left: *left,
             },
             InternalNode::Empty => InternalNode::Empty,
         }
@@ -88,19 +255,79 @@ struct LeafNode<T: MerkleHasher> {
     parent: NodeIndex,
 }
 
+impl<T: MerkleHasher> Clone for LeafNode<T> {
+    fn clone(&self) -> Self {
+        LeafNode {
+            element: self.element.clone(),
+            parent: self.parent,
+        }
+    }
+}
+
 impl<T: MerkleHasher> LeafNode<T> {
     fn merkle_hash(&self) -> <T::Element as HashableElement>::Hash {
         self.element.merkle_hash()
     }
 }
 
+/// Where a tree synced from subtree roots or an imported state starts. The
+/// leaves before size were never added, so they look pruned. The frontier
+/// has the hashes of the complete subtrees they make up, the lowest of which
+/// is at level. node is the internal node at that level above the last of
+/// the leaves (or its parent, at level 0), which the first leaf added after
+/// them is attached to.
+struct SyncPoint<T: MerkleHasher> {
+    size: usize,
+    level: usize,
+    node: NodeIndex,
+    root_hash: <T::Element as HashableElement>::Hash,
+    frontier: Vec<<T::Element as HashableElement>::Hash>,
+}
+
+impl<T: MerkleHasher> Clone for SyncPoint<T> {
+    fn clone(&self) -> Self {
+        SyncPoint {
+            size: self.size,
+            level: self.level,
+            node: self.node,
+            root_hash: self.root_hash.clone(),
+            frontier: self.frontier.clone(),
+        }
+    }
+}
+
 /// Linked nodes implementation of MerkleTree trait. Keeps a list of leaves
 /// and nodes, where nodes are defined as in InternalNode above.
+///
+/// The lists are shared copy-on-write between clones of the tree, so cloning
+/// is cheap. A modification after a clone copies only the chunks of the lists
+/// that it changes.
+///
+/// Wallets that only care about a few of the leaves can `mark` them and
+/// `prune` the tree, which throws away everything that isn't needed to
+/// witness the marked leaves or to keep adding to the tree.
 pub struct LinkedMerkleTree<T: MerkleHasher> {
     hasher: Arc<T>,
-    leaves: Vec<LeafNode<T>>,
-    nodes: Vec<InternalNode<T>>,
+    leaves: Arc<SparseVec<LeafNode<T>>>,
+    nodes: Arc<SparseVec<InternalNode<T>>>,
     tree_depth: usize,
+    checkpoints: Checkpoints,
+    marked: BTreeSet<usize>,
+    synced: Option<SyncPoint<T>>,
+}
+
+impl<T: MerkleHasher> Clone for LinkedMerkleTree<T> {
+    fn clone(&self) -> Self {
+        LinkedMerkleTree {
+            hasher: self.hasher.clone(),
+            leaves: self.leaves.clone(),
+            nodes: self.nodes.clone(),
+            tree_depth: self.tree_depth,
+            checkpoints: self.checkpoints.clone(),
+            marked: self.marked.clone(),
+            synced: self.synced.clone(),
+        }
+    }
 }
 
 impl<T: MerkleHasher> LinkedMerkleTree<T> {
@@ -113,15 +340,311 @@ impl<T: MerkleHasher> LinkedMerkleTree<T> {
     /// The MerkleTree trait has a new associated function that does not
     /// specify the depth. This function is used to make shallower unit tests
     /// that are easier to reason about and faster to execute.
-    fn new_with_size(hasher: Arc<T>, tree_depth: usize) -> Box<Self> {
+    pub(crate) fn new_with_size(hasher: Arc<T>, tree_depth: usize) -> Box<Self> {
         Box::new(LinkedMerkleTree {
-            leaves: vec![],
-            nodes: vec![InternalNode::Empty],
+            leaves: Arc::new(SparseVec::new()),
+            nodes: Arc::new(empty_nodes()),
             tree_depth: tree_depth - 1,
             hasher,
+            checkpoints: Checkpoints::default(),
+            marked: BTreeSet::new(),
+            synced: None,
         })
     }
 
+    /// Construct a new, empty tree as described by config.
+    pub fn with_config(hasher: Arc<T>, config: TreeConfig) -> Result<Box<Self>, ConfigError> {
+        config.validate_in_memory()?;
+        let mut tree = LinkedMerkleTree::new_with_size(hasher, config.depth);
+        if let Some(reorg_depth) = config.reorg_depth {
+            tree.checkpoints.set_reorg_depth(reorg_depth);
+        }
+        Ok(tree)
+    }
+
+    /// Fill an empty tree from the roots of its first complete subtrees at
+    /// level, such as another copy's `subtree_root(level, index)`, and the
+    /// leaves after them, instead of adding every leaf.
+    ///
+    /// The tree then has `roots.len() << level` leaves before the given ones.
+    /// Those look pruned: `get` and `witness` return None for them, and the
+    /// tree can't be written, updated, or truncated to the sync point or
+    /// below it, except to empty it. The root hash, past roots from the sync
+    /// point on, and witnesses of the given leaves match the fully built
+    /// tree's.
+    ///
+    /// Fails without changing anything if the tree isn't empty, if level is
+    /// 0 or deeper than the tree, or if the roots and leaves don't fit in the
+    /// tree.
+    pub fn sync_from_subtree_roots(
+        &mut self,
+        level: usize,
+        roots: &[<T::Element as HashableElement>::Hash],
+        leaves: Vec<T::Element>,
+    ) -> io::Result<()> {
+        if !self.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Only an empty tree can be synced",
+            ));
+        }
+        let (size, frontier) =
+            frontier_from_roots(&*self.hasher, self.tree_depth, level, roots, leaves.len())?;
+        if size > 0 {
+            self.start_from_frontier(size, frontier);
+        }
+        for element in leaves {
+            self.add(element);
+        }
+        Ok(())
+    }
+
+    /// Fill an empty tree from a state written by `export_state`, so that it
+    /// can carry on from there. The tree must have the same depth as the one
+    /// that wrote the state. Like a tree synced from subtree roots, it has
+    /// none of the leaves before that point, and can't be written, updated,
+    /// or truncated to that point or below it, except to empty it.
+    ///
+    /// Fails without changing anything if the tree isn't empty.
+    pub fn import_state<R: io::Read>(&mut self, reader: &mut R) -> io::Result<()> {
+        if !self.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Only an empty tree can import a state",
+            ));
+        }
+        let (num_leaves, frontier) = read_state(&*self.hasher, self.tree_depth, reader)?;
+        if num_leaves > 0 {
+            self.start_from_frontier(num_leaves, frontier);
+        }
+        Ok(())
+    }
+
+    /// Build the right edge of an empty tree with size leaves from its
+    /// frontier, leaving the leaves themselves out.
+    fn start_from_frontier(
+        &mut self,
+        size: usize,
+        frontier: Vec<<T::Element as HashableElement>::Hash>,
+    ) {
+        let edge = frontier_edge(&*self.hasher, self.tree_depth, size, &frontier);
+        // at level 0 the edge starts with the last leaf, which only has a hash
+        let edge_nodes = &edge.nodes[usize::from(edge.level == 0)..];
+        let nodes = Arc::make_mut(&mut self.nodes);
+        let mut first = None;
+        for (height, edge_node) in edge_nodes.iter().enumerate() {
+            // a right node is pushed after its left sibling
+            let pushed = if edge_node.left_sibling.is_some() {
+                2
+            } else {
+                1
+            };
+            let parent = match edge_nodes.get(height + 1) {
+                Some(above) => NodeIndex::from(
+                    nodes.len() + pushed + usize::from(above.left_sibling.is_some()),
+                ),
+                None => NodeIndex::empty(),
+            };
+            nodes.push(InternalNode::Left {
+                hash_of_sibling: edge_node.hash.clone(),
+                parent,
+            });
+            if let Some(left_hash) = &edge_node.left_sibling {
+                nodes.push(InternalNode::Right {
+                    hash_of_sibling: left_hash.clone(),
+                    left: NodeIndex::from(nodes.len() - 1),
+                });
+            }
+            first.get_or_insert(NodeIndex::from(nodes.len() - 1));
+        }
+        Arc::make_mut(&mut self.leaves).skip_to(size);
+        self.synced = Some(SyncPoint {
+            size,
+            level: edge.level,
+            node: first.unwrap_or_else(NodeIndex::empty),
+            root_hash: edge.root_hash,
+            frontier,
+        });
+    }
+
+    /// The hash of the leaf at position. A tree synced to an odd number of
+    /// leaves has the hash of the last of them, which is all it has of it.
+    fn leaf_hash(&self, position: usize) -> <T::Element as HashableElement>::Hash {
+        match (self.leaves.get(position), &self.synced) {
+            (Some(leaf), _) => leaf.merkle_hash(),
+            (None, Some(synced)) if synced.level == 0 && position + 1 == synced.size => {
+                synced.frontier[0].clone()
+            }
+            _ => panic!("Index {} was pruned or never existed", position),
+        }
+    }
+
+    /// Mark the leaf at position as one whose witness will be needed later,
+    /// so that `prune` keeps it. Returns false if there is no such leaf, or
+    /// it has already been pruned.
+    pub fn mark(&mut self, position: usize) -> bool {
+        if !self.leaves.contains(position) {
+            return false;
+        }
+        self.marked.insert(position);
+        true
+    }
+
+    /// Throw away the leaves and nodes that aren't needed to witness a
+    /// marked leaf or to keep adding to the tree. What survives is the path
+    /// of each marked leaf and its sibling, plus the paths of the last leaf
+    /// and of the last leaf at each checkpoint, so that the tree can still be
+    /// rewound to any of its checkpoints.
+    ///
+    /// Afterwards, `get` returns a NotRetained error for the leaves that were
+    /// thrown away, and witnesses and past roots that would need them are
+    /// None.
+    /// Marked leaves can be witnessed as usual, however large the tree grows.
+    /// A pruned tree can't be written.
+    pub fn prune(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        let mut keep_leaves: HashSet<usize> = self.marked.iter().cloned().collect();
+        keep_leaves.insert(self.len() - 1);
+        for (_, size) in self.checkpoints.list() {
+            if size > 0 {
+                keep_leaves.insert(size - 1);
+            }
+        }
+        let siblings: Vec<usize> = keep_leaves
+            .iter()
+            .map(|position| position ^ 1)
+            .filter(|sibling| *sibling < self.len())
+            .collect();
+        keep_leaves.extend(siblings);
+
+        // a synced tree doesn't have the leaves before the sync point
+        let mut starts: Vec<NodeIndex> = keep_leaves
+            .iter()
+            .filter_map(|position| self.leaves.get(*position))
+            .map(|leaf| leaf.parent)
+            .collect();
+        if let Some(synced) = &self.synced {
+            if synced.size == self.len() {
+                starts.push(synced.node);
+            }
+        }
+
+        let mut keep_nodes = HashSet::new();
+        keep_nodes.insert(0);
+        for start in starts {
+            let mut current = start;
+            while keep_nodes.insert(current.0 as usize) {
+                current = match self.node_at(current) {
+                    InternalNode::Empty => break,
+                    InternalNode::Left { parent, .. } => parent,
+                    InternalNode::Right { left, .. } => {
+                        keep_nodes.insert(left.0 as usize);
+                        self.parent_index(left)
+                    }
+                }
+            }
+        }
+
+        Arc::make_mut(&mut self.leaves).retain(&keep_leaves);
+        Arc::make_mut(&mut self.nodes).retain(&keep_nodes);
+    }
+
+    /// Replace the element at position and rehash the path from it up to the
+    /// root, so that the root hash and witnesses reflect the new element.
+    /// This takes O(depth) time, however far position is from the end.
+    ///
+    /// The tree only keeps its current nodes, so `past_root` and
+    /// `past_witness` are undefined across an update: they are calculated
+    /// with the new element, and don't match the roots the tree actually had
+    /// before it. Snapshots taken before the update are unaffected.
+    ///
+    /// Fails without changing anything if there is no leaf at position, or
+    /// if a leaf needed to rehash the path has been pruned.
+    pub fn update(&mut self, position: usize, element: T::Element) -> Result<(), UpdateError> {
+        check_update_leaves(position, self.len(), self.tree_depth, |leaf| {
+            self.leaves.get(leaf).is_some()
+        })?;
+        Arc::make_mut(&mut self.leaves)[position].element = element;
+
+        let pair_start = position & !1;
+        let left_hash = self.leaves[pair_start].merkle_hash();
+        let right_hash = if pair_start + 1 < self.len() {
+            self.leaves[pair_start + 1].merkle_hash()
+        } else {
+            left_hash.clone()
+        };
+        let mut current_hash = self.hasher.combine_hash(0, &left_hash, &right_hash);
+        let mut current_index = self.leaves[position].parent;
+        for depth in 1..self.tree_depth {
+            match self.node_at(current_index) {
+                InternalNode::Empty => break,
+                InternalNode::Left {
+                    parent,
+                    hash_of_sibling,
+                } => {
+                    let right_start = ((position >> depth) + 1) << depth;
+                    if right_start < self.len() {
+                        let right_index = self.node_above(right_start, depth);
+                        if let InternalNode::Right { left, .. } = self.node_at(right_index) {
+                            self.set_node(
+                                right_index,
+                                InternalNode::Right {
+                                    left,
+                                    hash_of_sibling: current_hash.clone(),
+                                },
+                            );
+                        }
+                        current_hash =
+                            self.hasher
+                                .combine_hash(depth, &current_hash, &hash_of_sibling);
+                    } else {
+                        self.set_node(
+                            current_index,
+                            InternalNode::Left {
+                                parent,
+                                hash_of_sibling: current_hash.clone(),
+                            },
+                        );
+                        current_hash =
+                            self.hasher
+                                .combine_hash(depth, &current_hash, &current_hash);
+                    }
+                    current_index = parent;
+                }
+                InternalNode::Right {
+                    left,
+                    hash_of_sibling,
+                } => {
+                    let parent = self.parent_index(left);
+                    self.set_node(
+                        left,
+                        InternalNode::Left {
+                            parent,
+                            hash_of_sibling: current_hash.clone(),
+                        },
+                    );
+                    current_hash = self
+                        .hasher
+                        .combine_hash(depth, &hash_of_sibling, &current_hash);
+                    current_index = parent;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The index of the internal node at depth above the leaf at position,
+    /// where the parent of the leaf is at depth 1.
+    fn node_above(&self, position: usize, depth: usize) -> NodeIndex {
+        let mut index = self.leaves[position].parent;
+        for _ in 1..depth {
+            index = self.parent_index(index);
+        }
+        index
+    }
+
     /// Get a COPY of the node at a given index. This may panic if the index
     /// is out of bounds. So don't do that (it's a private method,
     /// so an index out of bounds is a coding error).
@@ -138,7 +661,41 @@ impl<T: MerkleHasher> LinkedMerkleTree<T> {
     }
 
     fn set_node(&mut self, index: NodeIndex, node: InternalNode<T>) {
-        self.nodes[index.0 as usize] = node;
+        Arc::make_mut(&mut self.nodes)[index.0 as usize] = node;
+    }
+
+    /// Take a read-only view of the tree at its current size. Later changes
+    /// to this tree are not visible through the snapshot.
+    pub fn snapshot(&self) -> Snapshot<Self> {
+        Snapshot::new(self.clone())
+    }
+
+    /// The hash_of_sibling stored in each internal node on the path from the
+    /// leaf at position up to the root.
+    fn stored_siblings(
+        &self,
+        position: usize,
+    ) -> Vec<Option<<T::Element as HashableElement>::Hash>> {
+        let mut current_position = self.leaves[position].parent;
+        (1..self.tree_depth)
+            .map(|_| match self.node_at(current_position) {
+                InternalNode::Empty => None,
+                InternalNode::Left {
+                    parent,
+                    hash_of_sibling,
+                } => {
+                    current_position = parent;
+                    Some(hash_of_sibling)
+                }
+                InternalNode::Right {
+                    left,
+                    hash_of_sibling,
+                } => {
+                    current_position = self.parent_index(left);
+                    Some(hash_of_sibling)
+                }
+            })
+            .collect()
     }
 
     /// Get the index of the parent of this node. If this is a left node,
@@ -161,7 +718,7 @@ impl<T: MerkleHasher> LinkedMerkleTree<T> {
         let mut parent_hash = if is_right_leaf(leaf_index) {
             self.hasher.combine_hash(
                 depth,
-                &self.leaves[leaf_index - 1].merkle_hash(),
+                &self.leaf_hash(leaf_index - 1),
                 &self.leaves[leaf_index].merkle_hash(),
             )
         } else {
@@ -216,31 +773,49 @@ impl<T: MerkleHasher> LinkedMerkleTree<T> {
     }
 
     fn most_recent_node_index(&self) -> NodeIndex {
-        NodeIndex(self.nodes.len() as u32 - 1)
+        NodeIndex::from(self.nodes.len() - 1)
     }
-    fn is_empty(&self) -> bool {
-        self.len() == 0
+}
+
+/// Serialized as the bytes `write` writes, so a pruned tree can't be. Read
+/// it back with a `TreeSeed`.
+#[cfg(feature = "serde")]
+impl<T: MerkleHasher> serde::Serialize for LinkedMerkleTree<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_tree(self, serializer)
     }
 }
 
 impl<T: MerkleHasher> MerkleTree for LinkedMerkleTree<T> {
     type Hasher = T;
 
-    /// Write the vector to an array
+    /// Write the vector to an array. Fails if the tree has been pruned.
     fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
-        writer.write_u8((self.tree_depth + 1) as u8)?;
-        writer.write_u32::<LittleEndian>(self.len() as u32)?;
-        for element in self.iter_notes() {
-            element.write(writer)?;
+        if !self.leaves.is_complete() {
+            return Err(io::Error::other("A pruned tree can't be written"));
+        }
+        write_tree_header(writer, self.tree_depth + 1, self.len())?;
+        for leaf in self.leaves.iter().flatten() {
+            leaf.element.write(writer)?;
         }
         Ok(())
     }
 
+    /// A tree that was synced or imported and has had nothing added since
+    /// writes the frontier it started from, since it has no last leaf.
+    fn export_state<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        match &self.synced {
+            Some(synced) if synced.size == self.len() => {
+                write_state(&*self.hasher, synced.size, &synced.frontier, writer)
+            }
+            _ => write_witnessed_state(self, writer),
+        }
+    }
+
     /// Load a merkle tree from a reader and return a box pointer to it
     fn read<R: io::Read>(hasher: Arc<T>, reader: &mut R) -> io::Result<Box<Self>> {
-        let tree_depth = reader.read_u8()?;
-        let num_nodes = reader.read_u32::<LittleEndian>()?;
-        let mut tree = LinkedMerkleTree::new_with_size(hasher, tree_depth as usize);
+        let (tree_depth, num_nodes) = read_tree_header(reader)?;
+        let mut tree = LinkedMerkleTree::new_with_size(hasher, tree_depth);
         for _ in 0..num_nodes {
             tree.add(tree.hasher.read_element(reader)?);
         }
@@ -257,30 +832,40 @@ impl<T: MerkleHasher> MerkleTree for LinkedMerkleTree<T> {
         self.leaves.len()
     }
 
-    /// Get the leaf note at a specific position
-    fn get(&self, position: usize) -> Option<<T as MerkleHasher>::Element> {
-        self.leaves.get(position).map(|leaf| leaf.element.clone())
+    /// Get the leaf note at a specific position. A leaf that has been pruned
+    /// is a NotRetained error.
+    fn get(&self, position: usize) -> Result<Option<<T as MerkleHasher>::Element>, NotRetained> {
+        if position >= self.len() {
+            return Ok(None);
+        }
+        match self.leaves.get(position) {
+            Some(leaf) => Ok(Some(leaf.element.clone())),
+            None => Err(NotRetained { position }),
+        }
     }
 
     /// Iterate over clones of all leaf notes in the tree, without consuming
-    /// the tree.
-    fn iter_notes<'a>(&'a self) -> Box<dyn Iterator<Item = <T as MerkleHasher>::Element> + 'a> {
-        Box::new(self.leaves.iter().map(|leaf| leaf.element.clone()))
+    /// the tree. Leaves that have been pruned yield NotRetained errors.
+    fn iter_notes<'a>(&'a self) -> Box<dyn Iterator<Item = Result<T::Element, NotRetained>> + 'a> {
+        Box::new(
+            self.leaves
+                .iter()
+                .enumerate()
+                .map(|(position, leaf)| match leaf {
+                    Some(leaf) => Ok(leaf.element.clone()),
+                    None => Err(NotRetained { position }),
+                }),
+        )
     }
 
     /// did the tree contain the given element when it was the given size?
     ///
-    /// This is a linear scan.
+    /// This is a linear scan. Leaves that have been pruned are not found.
     fn contained(&self, value: &T::Element, past_size: usize) -> bool {
-        for (index, candidate) in self.iter_notes().enumerate() {
-            if index == past_size {
-                break;
-            }
-            if candidate == *value {
-                return true;
-            }
-        }
-        false
+        self.leaves
+            .iter()
+            .take(past_size)
+            .any(|leaf| leaf.is_some_and(|leaf| leaf.element == *value))
     }
 
     /// The current root hash of the tree. Start with the left-most node
@@ -294,13 +879,22 @@ impl<T: MerkleHasher> MerkleTree for LinkedMerkleTree<T> {
         if self.is_empty() || past_size > self.len() || past_size == 0 {
             return None;
         }
+        if let Some(synced) = &self.synced {
+            if past_size == synced.size {
+                return Some(synced.root_hash.clone());
+            }
+        }
+        if !self.leaves.contains(past_size - 1) {
+            // pruned
+            return None;
+        }
         let leaf_index = past_size - 1;
         let mut current_hash;
         let mut current_node_index = self.leaves[leaf_index].parent;
         if is_right_leaf(leaf_index) {
             current_hash = self.hasher.combine_hash(
                 0,
-                &self.leaves[leaf_index - 1].element.merkle_hash(),
+                &self.leaf_hash(leaf_index - 1),
                 &self.leaves[leaf_index].element.merkle_hash(),
             );
         } else {
@@ -359,38 +953,61 @@ impl<T: MerkleHasher> MerkleTree for LinkedMerkleTree<T> {
             element,
             parent: new_parent_index,
         };
-        self.leaves.push(leaf);
+        Arc::make_mut(&mut self.leaves).push(leaf);
 
         if self.leaves.len() == 1 {
             return;
         } else if is_right_leaf(index_of_new_leaf) {
-            let left_leaf = &self.leaves[index_of_new_leaf - 1];
-            let parent = self.node_at(left_leaf.parent);
+            // the left leaf is missing from a tree synced to an odd size
+            let left_parent = match (self.leaves.get(index_of_new_leaf - 1), &self.synced) {
+                (Some(left_leaf), _) => left_leaf.parent,
+                (None, Some(synced)) => synced.node,
+                (None, None) => panic!("must have leaf left of the new right leaf"),
+            };
 
-            match parent {
+            match self.node_at(left_parent) {
                 InternalNode::Empty => {
                     let new_parent_of_both = InternalNode::Left {
                         parent: NodeIndex::empty(),
                         hash_of_sibling: self.hasher.combine_hash(
                             0,
-                            &left_leaf.element.merkle_hash(),
+                            &self.leaf_hash(index_of_new_leaf - 1),
                             &leaf_hash,
                         ),
                     };
-                    self.nodes.push(new_parent_of_both);
-                    self.leaves[index_of_new_leaf - 1].parent = new_parent_index;
-                }
-                _ => {
-                    self.leaves[index_of_new_leaf].parent =
-                        self.leaves[index_of_new_leaf - 1].parent
+                    Arc::make_mut(&mut self.nodes).push(new_parent_of_both);
+                    if self.leaves.contains(index_of_new_leaf - 1) {
+                        Arc::make_mut(&mut self.leaves)[index_of_new_leaf - 1].parent =
+                            new_parent_index;
+                    }
                 }
+                _ => Arc::make_mut(&mut self.leaves)[index_of_new_leaf].parent = left_parent,
             }
         } else {
             // Walk up the path from the previous leaf until find empty or right-hand leaf.
             // Create a bunch of left-hand leaves for each step up that path.
-            let mut previous_parent_index = self.leaves[index_of_new_leaf - 1].parent;
             let mut my_hash = hasher.combine_hash(0, &leaf_hash, &leaf_hash);
             let mut depth = 1;
+            let mut previous_parent_index = match self.leaves.get(index_of_new_leaf - 1) {
+                Some(previous_leaf) => previous_leaf.parent,
+                None => {
+                    // The first leaf after the sync point. The missing leaf
+                    // before it would be a right child all the way up to the
+                    // synced level, so this leaf gets a new left node at each
+                    // depth below that.
+                    let synced = self.synced.clone().expect("previous leaf must exist");
+                    while depth < synced.level {
+                        my_hash = hasher.combine_hash(depth, &my_hash, &my_hash);
+                        let new_node = InternalNode::Left {
+                            parent: NodeIndex::from(self.nodes.len() + 1),
+                            hash_of_sibling: my_hash.clone(),
+                        };
+                        Arc::make_mut(&mut self.nodes).push(new_node);
+                        depth += 1;
+                    }
+                    synced.node
+                }
+            };
             loop {
                 let previous_parent = self.node_at(previous_parent_index);
                 match previous_parent {
@@ -402,7 +1019,7 @@ impl<T: MerkleHasher> MerkleTree for LinkedMerkleTree<T> {
                             left: previous_parent_index,
                             hash_of_sibling: hash_of_sibling.clone(),
                         };
-                        self.nodes.push(new_node);
+                        Arc::make_mut(&mut self.nodes).push(new_node);
                         if parent == NodeIndex::empty() {
                             let new_parent = InternalNode::Left {
                                 parent: NodeIndex::empty(),
@@ -412,7 +1029,7 @@ impl<T: MerkleHasher> MerkleTree for LinkedMerkleTree<T> {
                                     &my_hash,
                                 ),
                             };
-                            self.nodes.push(new_parent);
+                            Arc::make_mut(&mut self.nodes).push(new_parent);
                             self.set_node(
                                 previous_parent_index,
                                 InternalNode::Left {
@@ -429,7 +1046,7 @@ impl<T: MerkleHasher> MerkleTree for LinkedMerkleTree<T> {
                             parent: NodeIndex::from(self.nodes.len() + 1), // This is where the next node *WILL* go
                             hash_of_sibling: my_hash.clone(),
                         };
-                        self.nodes.push(new_node);
+                        Arc::make_mut(&mut self.nodes).push(new_node);
                         previous_parent_index = self.parent_index(left);
                         depth += 1;
                     }
@@ -448,20 +1065,30 @@ impl<T: MerkleHasher> MerkleTree for LinkedMerkleTree<T> {
     /// path from the new leaf. All node index higher than the
     /// the maximum index in that path can be cleared and the leftmost node
     /// has its parent updated to empty.
-    fn truncate(&mut self, past_size: usize) {
+    ///
+    /// Fails if the tree has been pruned and the leaf that would become the
+    /// last one was thrown away. Truncating to a checkpoint always works.
+    fn truncate(&mut self, past_size: usize) -> Result<(), NotRetained> {
         if past_size >= self.len() {
-            return;
+            return Ok(());
         }
-        for _ in 0..self.len() - past_size {
-            self.leaves.pop();
+        if past_size > 0 && !self.leaves.contains(past_size - 1) {
+            return Err(NotRetained {
+                position: past_size - 1,
+            });
         }
+        self.checkpoints.truncated(past_size);
+        self.marked.split_off(&past_size);
+        Arc::make_mut(&mut self.leaves).truncate(past_size);
         if past_size == 1 {
-            self.leaves[0].parent = NodeIndex::empty();
+            Arc::make_mut(&mut self.leaves)[0].parent = NodeIndex::empty();
+        }
+        if past_size == 0 {
+            self.synced = None;
         }
         if past_size == 0 || past_size == 1 {
-            self.nodes.clear();
-            self.nodes.push(InternalNode::Empty);
-            return;
+            self.nodes = Arc::new(empty_nodes());
+            return Ok(());
         }
 
         let depth = depth_at_leaf_count(self.len()) - 2;
@@ -476,19 +1103,18 @@ impl<T: MerkleHasher> MerkleTree for LinkedMerkleTree<T> {
         match self.node_at(parent) {
             InternalNode::Left {
                 hash_of_sibling, ..
-            } => {
-                self.nodes[parent.0 as usize] = InternalNode::Left {
+            } => self.set_node(
+                parent,
+                InternalNode::Left {
                     hash_of_sibling,
                     parent: NodeIndex::empty(),
-                }
-            }
+                },
+            ),
             _ => panic!("new root should be left node"),
         }
-        let num_to_remove = self.nodes.len() - max_parent.0 as usize - 1;
-        for _ in 0..num_to_remove {
-            self.nodes.pop();
-        }
+        Arc::make_mut(&mut self.nodes).truncate(max_parent.0 as usize + 1);
         self.rehash_right_path();
+        Ok(())
     }
 
     /// Constructed proof that the leaf node at `position` exists.WitnessNode
@@ -496,13 +1122,13 @@ impl<T: MerkleHasher> MerkleTree for LinkedMerkleTree<T> {
     /// Guarantees that the witness_path is tree_depth levels deep by repeatedly hashing
     /// the last root_hash with itself.
     fn witness(&self, position: usize) -> Option<Witness<T>> {
-        if self.is_empty() || position >= self.len() {
+        if self.is_empty() || !self.leaves.contains(position) {
             return None;
         }
         let mut authentication_path = vec![];
         let mut current_hash = self.leaves[position].element.merkle_hash();
         if is_right_leaf(position) {
-            let sibling_hash = self.leaves[position - 1].element.merkle_hash();
+            let sibling_hash = self.leaf_hash(position - 1);
             current_hash = self.hasher.combine_hash(0, &sibling_hash, &current_hash);
             authentication_path.push(WitnessNode::Right(sibling_hash));
         } else if position < self.len() - 1 {
@@ -552,6 +1178,140 @@ impl<T: MerkleHasher> MerkleTree for LinkedMerkleTree<T> {
             tree_size: self.len(),
         })
     }
+
+    fn past_witness(&self, position: usize, past_size: usize) -> Option<Witness<T>> {
+        if position >= past_size || past_size > self.len() {
+            return None;
+        }
+        if !self.leaves.contains(position) || !self.leaves.contains(past_size - 1) {
+            // pruned
+            return None;
+        }
+        Some(past_witness(
+            &*self.hasher,
+            position,
+            past_size,
+            |index| self.leaf_hash(index),
+            |index| self.stored_siblings(index),
+        ))
+    }
+}
+
+impl<T: MerkleHasher> Checkpointed for LinkedMerkleTree<T> {
+    /// Record the current size under id. Checkpoints of in-memory trees are
+    /// not included when the tree is written.
+    fn checkpoint(&mut self, id: &[u8]) -> io::Result<()> {
+        let size = self.len();
+        self.checkpoints.record(id, size);
+        Ok(())
+    }
+
+    /// Pruning keeps the last leaf of each checkpoint, so this only fails
+    /// for a checkpoint recorded right after a sync or an import.
+    fn rewind_to(&mut self, id: &[u8]) -> io::Result<bool> {
+        let size = match self.checkpoints.find(id) {
+            Some(size) => size,
+            None => return Ok(false),
+        };
+        self.truncate(size).map_err(io::Error::other)?;
+        self.checkpoints.rewind(id);
+        Ok(true)
+    }
+
+    fn checkpoints(&self) -> io::Result<Vec<(Vec<u8>, usize)>> {
+        Ok(self.checkpoints.list())
+    }
+
+    fn set_reorg_depth(&mut self, reorg_depth: usize) -> io::Result<()> {
+        self.checkpoints.set_reorg_depth(reorg_depth);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl<T: MerkleHasher> LinkedMerkleTree<T> {
+    /// Count the chunks the leaves and nodes are kept in, and how many of
+    /// them this tree shares with other. Pruned lists aren't kept in chunks.
+    pub(crate) fn shared_chunks(&self, other: &Self) -> (usize, usize) {
+        let leaves = match (self.leaves.chunks(), other.leaves.chunks()) {
+            (Some(mine), Some(theirs)) => mine.shared_chunks(theirs),
+            _ => (0, 0),
+        };
+        let nodes = match (self.nodes.chunks(), other.nodes.chunks()) {
+            (Some(mine), Some(theirs)) => mine.shared_chunks(theirs),
+            _ => (0, 0),
+        };
+        (leaves.0 + nodes.0, leaves.1 + nodes.1)
+    }
+
+    /// The leaves and nodes as the rows a persistent tree would store for
+    /// them, since those are laid out the same way, for the integrity tests.
+    pub(crate) fn stored_rows(
+        &self,
+    ) -> crate::integrity::StoredRows<<T::Element as HashableElement>::Hash> {
+        use crate::integrity::{StoredLeaf, StoredNode, StoredRows};
+
+        let mut rows = StoredRows::new(self.len() as u64, self.nodes.len() as u64);
+        for (position, leaf) in self.leaves.iter().enumerate() {
+            if let Some(leaf) = leaf {
+                rows.leaves.insert(
+                    position as u64,
+                    StoredLeaf {
+                        hash: Some(leaf.merkle_hash()),
+                        parent: Some(leaf.parent.0),
+                        element_hash: Some(leaf.element.merkle_hash()),
+                    },
+                );
+            }
+        }
+        if let Some(synced) = &self.synced {
+            rows.sync_point = Some((synced.size as u64, synced.level, synced.node.0));
+            // the persistent trees store the hash of a lone last leaf, and
+            // give it the same parent as a right sibling added after it
+            if synced.level == 0 {
+                let parent = match self.leaves.get(synced.size) {
+                    Some(sibling) => sibling.parent,
+                    None => synced.node,
+                };
+                rows.leaves.insert(
+                    synced.size as u64 - 1,
+                    StoredLeaf {
+                        hash: Some(synced.frontier[0].clone()),
+                        parent: Some(parent.0),
+                        element_hash: None,
+                    },
+                );
+            }
+        }
+        for (index, node) in self.nodes.iter().enumerate() {
+            let node = match node {
+                Some(InternalNode::Left {
+                    hash_of_sibling,
+                    parent,
+                }) => StoredNode::Left {
+                    hash_of_sibling: hash_of_sibling.clone(),
+                    parent: parent.0,
+                },
+                Some(InternalNode::Right {
+                    hash_of_sibling,
+                    left,
+                }) => StoredNode::Right {
+                    hash_of_sibling: hash_of_sibling.clone(),
+                    left: left.0,
+                },
+                _ => continue,
+            };
+            rows.nodes.insert(index as u64, node);
+        }
+        rows
+    }
+}
+
+/// The node list of an empty tree, which only has the empty node.
+fn empty_nodes<T: MerkleHasher>() -> SparseVec<InternalNode<T>> {
+    let mut nodes = SparseVec::new();
+    nodes.push(InternalNode::Empty);
+    nodes
 }
 
 fn is_right_leaf(value: usize) -> bool {
@@ -566,9 +1326,11 @@ fn depth_at_leaf_count(index: usize) -> usize {
     match index {
         0 => 0,
         1 => 1,
-        n => ((n - 1) as f32).log2() as usize + 2,
+        n => (usize::BITS - (n - 1).leading_zeros()) as usize + 1,
     }
 }
 
 #[cfg(test)]
+// The tests compare against owned strings throughout
+#[allow(clippy::cmp_owned)]
 mod tests;
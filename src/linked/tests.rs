@@ -1,7 +1,13 @@
-use super::{InternalNode, LeafNode, LinkedMerkleTree, MerkleTree, NodeIndex, WitnessNode};
-use crate::test_helper::StringHasher;
+use super::chunked::ChunkedVec;
+use super::{
+    InternalNode, LeafNode, LinkedMerkleTree, MerkleTree, NodeIndex, SparseVec, WitnessNode,
+};
+use crate::checkpoint::Checkpointed;
+use crate::test_helper::{MixHasher, StringHasher};
+use crate::{NotRetained, UpdateError, Witness};
+use std::io;
 
-fn leaf(value: char, parent: u32) -> LeafNode<StringHasher> {
+fn leaf(value: char, parent: u64) -> LeafNode<StringHasher> {
     LeafNode {
         element: value.to_string(),
         parent: NodeIndex(parent),
@@ -22,9 +28,9 @@ fn make_tree(characters: &str) -> Box<LinkedMerkleTree<StringHasher>> {
 
 fn node_matches(
     tree: &LinkedMerkleTree<StringHasher>,
-    my_index: u32,
+    my_index: u64,
     is_left: bool,
-    other_index: u32, // parent or left, depending
+    other_index: u64, // parent or left, depending
     expected_hash_of_sibling: &str,
 ) -> bool {
     let node = tree.node_at(NodeIndex(my_index));
@@ -53,7 +59,7 @@ fn node_matches(
     is_match
 }
 
-fn assert_leaves(tree: &LinkedMerkleTree<StringHasher>, characters: &str, parents: &[u32]) {
+fn assert_leaves(tree: &LinkedMerkleTree<StringHasher>, characters: &str, parents: &[u64]) {
     assert_eq!(tree.leaves.len(), characters.len());
     assert_eq!(tree.leaves.len(), parents.len());
     for (index, (character, parent)) in characters.chars().zip(parents).enumerate() {
@@ -218,37 +224,37 @@ fn iteration_and_get() {
     color_backtrace::install();
     let mut tree = LinkedMerkleTree::new(StringHasher::new());
     {
-        assert_eq!(tree.get(0), None);
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(0), Ok(None));
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), None);
     }
 
     tree.add("a".to_string());
     {
-        assert_eq!(tree.get(1), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(1), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), None);
     }
 
     tree.add("b".to_string());
     {
-        assert_eq!(tree.get(2), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(2), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), None);
     }
     tree.add("c".to_string());
     {
-        assert_eq!(tree.get(3), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(3), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -256,12 +262,12 @@ fn iteration_and_get() {
     }
     tree.add("d".to_string());
     {
-        assert_eq!(tree.get(4), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        assert_eq!(*tree.get(3).unwrap(), "d".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(4), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        assert_eq!(*tree.get(3).unwrap().unwrap(), "d".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -271,13 +277,13 @@ fn iteration_and_get() {
 
     tree.add("e".to_string());
     {
-        assert_eq!(tree.get(5), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        assert_eq!(*tree.get(3).unwrap(), "d".to_string());
-        assert_eq!(*tree.get(4).unwrap(), "e".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(5), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        assert_eq!(*tree.get(3).unwrap().unwrap(), "d".to_string());
+        assert_eq!(*tree.get(4).unwrap().unwrap(), "e".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -288,14 +294,14 @@ fn iteration_and_get() {
 
     tree.add("f".to_string());
     {
-        assert_eq!(tree.get(6), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        assert_eq!(*tree.get(3).unwrap(), "d".to_string());
-        assert_eq!(*tree.get(4).unwrap(), "e".to_string());
-        assert_eq!(*tree.get(5).unwrap(), "f".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(6), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        assert_eq!(*tree.get(3).unwrap().unwrap(), "d".to_string());
+        assert_eq!(*tree.get(4).unwrap().unwrap(), "e".to_string());
+        assert_eq!(*tree.get(5).unwrap().unwrap(), "f".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -308,7 +314,7 @@ fn iteration_and_get() {
     for i in 0..100 {
         tree.add(i.to_string());
     }
-    let mut iter = tree.iter_notes();
+    let mut iter = tree.iter_notes().map(Result::unwrap);
     for char in ["a", "b", "c", "d", "e", "f"].iter() {
         assert_eq!(iter.next(), Some(char.to_string()));
     }
@@ -319,65 +325,330 @@ fn iteration_and_get() {
     assert_eq!(iter.next(), None);
 }
 
+#[test]
+fn iter_range() {
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    assert_eq!(tree.iter_range(0..3).next(), None);
+    for character in "abcdefg".chars() {
+        tree.add(character.to_string());
+    }
+    let collect = |iter: Box<dyn DoubleEndedIterator<Item = Result<String, NotRetained>>>| {
+        iter.collect::<Result<String, _>>().unwrap()
+    };
+    assert_eq!(collect(tree.iter_range(2..5)), "cde");
+    assert_eq!(collect(Box::new(tree.iter_range(2..5).rev())), "edc");
+    assert_eq!(collect(tree.iter_range(4..usize::MAX)), "efg");
+    assert_eq!(collect(tree.iter_range(9..12)), "");
+
+    let mut iter = tree.iter_range(1..5);
+    assert_eq!(iter.next(), Some(Ok("b".to_string())));
+    assert_eq!(iter.next_back(), Some(Ok("e".to_string())));
+    assert_eq!(iter.next_back(), Some(Ok("d".to_string())));
+    assert_eq!(iter.next(), Some(Ok("c".to_string())));
+    assert_eq!(iter.next(), None);
+    assert_eq!(iter.next_back(), None);
+}
+
+#[test]
+fn iter_leaves() {
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in "abcdefg".chars() {
+        tree.add(character.to_string());
+    }
+    let leaves: Vec<_> = tree.iter_leaves().map(Result::unwrap).collect();
+    assert_eq!(leaves.len(), 7);
+    assert_eq!(leaves[3].position, 3);
+    assert_eq!(leaves[3].hash, "d".to_string());
+    assert_eq!(leaves[3].element, Some("d".to_string()));
+
+    // pruned leaves are skipped
+    tree.mark(2);
+    tree.prune();
+    let retained: Vec<_> = (0..7)
+        .filter(|position| tree.get(*position).is_ok())
+        .collect();
+    assert!(retained.len() < 7);
+    assert_eq!(
+        tree.iter_leaves()
+            .map(|leaf| leaf.unwrap().position)
+            .collect::<Vec<_>>(),
+        retained
+    );
+}
+
+#[test]
+fn subtree_root() {
+    let characters = "abcdefghijk";
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in characters.chars() {
+        tree.add(character.to_string());
+    }
+    let hasher = tree.hasher();
+    for level in 1..5 {
+        let width = 1 << level;
+        for index in 0..3 {
+            let start = index * width;
+            if start >= characters.len() {
+                assert_eq!(tree.subtree_root(level, index), None);
+                assert!(tree.subtree_witness(level, index).is_none());
+                continue;
+            }
+            // a tree holding just the leaves under the node has it as its root
+            let mut subtree = LinkedMerkleTree::new_with_size(StringHasher::new(), level + 1);
+            for character in characters.chars().skip(start).take(width) {
+                subtree.add(character.to_string());
+            }
+            let root = tree.subtree_root(level, index).expect("node has leaves");
+            assert_eq!(Some(root.clone()), subtree.root_hash());
+
+            let witness = tree.subtree_witness(level, index).expect("node has leaves");
+            assert_eq!(witness.auth_path.len(), 4 - level);
+            assert!(witness.verify_subtree(&hasher, level, &root));
+            assert!(!witness.verify_subtree(&hasher, level, &"x".to_string()));
+        }
+    }
+    assert_eq!(tree.subtree_root(0, 3), Some("d".to_string()));
+    assert_eq!(tree.subtree_root(4, 0), tree.root_hash());
+    assert_eq!(tree.subtree_root(5, 0), None);
+    assert_eq!(tree.subtree_root(70, 0), None);
+}
+
+#[test]
+fn sync_from_subtree_roots() {
+    let characters = "abcdefghijklmnopqrstu";
+    let mut full = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    for character in characters.chars() {
+        full.add(character.to_string());
+    }
+    for level in 1..5 {
+        let width = 1 << level;
+        for num_roots in 0..=characters.len() / width {
+            let roots: Vec<String> = (0..num_roots)
+                .map(|index| full.subtree_root(level, index).unwrap())
+                .collect();
+            let sync_size = num_roots * width;
+            for num_leaves in sync_size.max(1)..=characters.len() {
+                let mut expected = full.clone();
+                expected.truncate(num_leaves).unwrap();
+                let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+                tree.sync_from_subtree_roots(
+                    level,
+                    &roots,
+                    characters[sync_size..num_leaves]
+                        .chars()
+                        .map(|character| character.to_string())
+                        .collect(),
+                )
+                .unwrap();
+                assert_eq!(tree.len(), num_leaves);
+                assert_eq!(tree.root_hash(), expected.root_hash());
+                for past_size in sync_size.max(1)..=num_leaves {
+                    assert_eq!(tree.past_root(past_size), expected.past_root(past_size));
+                }
+                for position in 0..num_leaves {
+                    if position < sync_size {
+                        assert_eq!(tree.get(position), Err(NotRetained { position }));
+                        assert_eq!(tree.witness(position), None);
+                    } else {
+                        assert_eq!(tree.witness(position), expected.witness(position));
+                    }
+                }
+
+                for character in characters.chars().skip(num_leaves) {
+                    tree.add(character.to_string());
+                }
+                assert_eq!(tree.root_hash(), full.root_hash());
+                assert_eq!(tree.past_root(num_leaves), full.past_root(num_leaves));
+                for position in sync_size..characters.len() {
+                    assert_eq!(tree.witness(position), full.witness(position));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn prune_synced() {
+    let mut full = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    for character in "abcdefghij".chars() {
+        full.add(character.to_string());
+    }
+    let roots = vec![full.subtree_root(3, 0).unwrap()];
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    tree.sync_from_subtree_roots(3, &roots, vec![]).unwrap();
+    tree.checkpoint(b"synced").unwrap();
+    tree.prune();
+    tree.add("i".to_string());
+    tree.add("j".to_string());
+    tree.mark(8);
+    tree.prune();
+    assert_eq!(tree.root_hash(), full.root_hash());
+    assert_eq!(tree.past_root(8), full.past_root(8));
+    assert_eq!(tree.witness(8), full.witness(8));
+
+    tree.add("k".to_string());
+    full.add("k".to_string());
+    assert_eq!(tree.witness(8), full.witness(8));
+    // the leaf before the sync point was never added
+    assert!(tree.rewind_to(b"synced").is_err());
+    assert_eq!(tree.len(), 11);
+    assert_eq!(tree.checkpoints().unwrap(), vec![(b"synced".to_vec(), 8)]);
+    tree.truncate(0).unwrap();
+    assert!(tree.is_empty());
+    assert_eq!(tree.root_hash(), None);
+}
+
+#[test]
+fn sync_bad_input() {
+    let mut tree = make_tree("ab");
+    let error = tree
+        .sync_from_subtree_roots(1, &["<a|b-0>".to_string()], vec![])
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+
+    // nothing is changed when the level or the number of roots is wrong
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 4);
+    let roots = vec!["<a|b-0>".to_string(); 4];
+    for &level in [0, 4].iter() {
+        let error = tree
+            .sync_from_subtree_roots(level, &roots[..1], vec![])
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+    let error = tree
+        .sync_from_subtree_roots(1, &roots, vec!["i".to_string()])
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    let error = tree
+        .sync_from_subtree_roots(1, &roots[..1], vec!["c".to_string(); 7])
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    assert!(tree.is_empty());
+
+    tree.sync_from_subtree_roots(1, &roots, vec![]).unwrap();
+    assert_eq!(tree.len(), 8);
+}
+
+#[test]
+fn export_and_import_state() {
+    let characters = "abcdefghijklmnopqrstu";
+    let mut full = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    for character in characters.chars() {
+        full.add(character.to_string());
+    }
+    for num_leaves in 0..=characters.len() {
+        let mut expected = full.clone();
+        expected.truncate(num_leaves).unwrap();
+        let mut state = vec![];
+        expected.export_state(&mut state).unwrap();
+
+        let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+        tree.import_state(&mut &state[..]).unwrap();
+        assert_eq!(tree.len(), num_leaves);
+        assert_eq!(tree.root_hash(), expected.root_hash());
+        assert_eq!(tree.get(0).is_err(), num_leaves > 0);
+        let mut exported = vec![];
+        tree.export_state(&mut exported).unwrap();
+        assert_eq!(exported, state);
+
+        for character in characters.chars().skip(num_leaves) {
+            tree.add(character.to_string());
+        }
+        assert_eq!(tree.root_hash(), full.root_hash());
+        for past_size in num_leaves.max(1)..=characters.len() {
+            assert_eq!(tree.past_root(past_size), full.past_root(past_size));
+        }
+        for position in num_leaves..characters.len() {
+            assert_eq!(tree.witness(position), full.witness(position));
+        }
+        let mut exported = vec![];
+        tree.export_state(&mut exported).unwrap();
+        let mut expected_state = vec![];
+        full.export_state(&mut expected_state).unwrap();
+        assert_eq!(exported, expected_state);
+    }
+}
+
+#[test]
+fn import_state_errors() {
+    let mut source = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    for character in "abc".chars() {
+        source.add(character.to_string());
+    }
+    let mut state = vec![];
+    source.export_state(&mut state).unwrap();
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    assert!(tree.import_state(&mut &state[..state.len() - 1]).is_err());
+
+    let mut small = LinkedMerkleTree::new_with_size(StringHasher::new(), 2);
+    assert!(small.import_state(&mut &state[..]).is_err());
+    assert!(small.is_empty());
+
+    let mut full = make_tree("ab");
+    let error = full.import_state(&mut &state[..]).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(full.len(), 2);
+}
+
 #[test]
 fn truncate() {
     color_backtrace::install();
     let mut tree = LinkedMerkleTree::new(StringHasher::new());
-    tree.truncate(0);
+    tree.truncate(0).unwrap();
     assert_tree(&tree, "");
     tree = make_full_tree();
-    tree.truncate(0);
+    tree.truncate(0).unwrap();
     assert_tree(&tree, "");
     tree = make_full_tree();
-    tree.truncate(1);
+    tree.truncate(1).unwrap();
     assert_tree(&tree, "a");
     tree = make_full_tree();
-    tree.truncate(2);
+    tree.truncate(2).unwrap();
     assert_tree(&tree, "ab");
     tree = make_full_tree();
-    tree.truncate(3);
+    tree.truncate(3).unwrap();
     assert_tree(&tree, "abc");
     tree = make_full_tree();
-    tree.truncate(4);
+    tree.truncate(4).unwrap();
     assert_tree(&tree, "abcd");
     tree = make_full_tree();
-    tree.truncate(5);
+    tree.truncate(5).unwrap();
     assert_tree(&tree, "abcde");
     tree = make_full_tree();
-    tree.truncate(6);
+    tree.truncate(6).unwrap();
     assert_tree(&tree, "abcdef");
     tree = make_full_tree();
-    tree.truncate(7);
+    tree.truncate(7).unwrap();
     assert_tree(&tree, "abcdefg");
     tree = make_full_tree();
-    tree.truncate(8);
+    tree.truncate(8).unwrap();
     assert_tree(&tree, "abcdefgh");
     tree = make_full_tree();
-    tree.truncate(9);
+    tree.truncate(9).unwrap();
     assert_tree(&tree, "abcdefghi");
     tree = make_full_tree();
-    tree.truncate(10);
+    tree.truncate(10).unwrap();
     assert_tree(&tree, "abcdefghij");
     tree = make_full_tree();
-    tree.truncate(11);
+    tree.truncate(11).unwrap();
     assert_tree(&tree, "abcdefghijk");
     tree = make_full_tree();
-    tree.truncate(12);
+    tree.truncate(12).unwrap();
     assert_tree(&tree, "abcdefghijkl");
     tree = make_full_tree();
-    tree.truncate(13);
+    tree.truncate(13).unwrap();
     assert_tree(&tree, "abcdefghijklm");
     tree = make_full_tree();
-    tree.truncate(14);
+    tree.truncate(14).unwrap();
     assert_tree(&tree, "abcdefghijklmn");
     tree = make_full_tree();
-    tree.truncate(15);
+    tree.truncate(15).unwrap();
     assert_tree(&tree, "abcdefghijklmno");
     tree = make_full_tree();
-    tree.truncate(16);
+    tree.truncate(16).unwrap();
     assert_tree(&tree, "abcdefghijklmnop");
     tree = make_full_tree();
-    tree.truncate(17);
+    tree.truncate(17).unwrap();
     assert_tree(&tree, "abcdefghijklmnop");
 }
 
@@ -631,3 +902,446 @@ fn serialization() {
         .expect("should still be able to write bytes.");
     assert_eq!(bytes, bytes_again);
 }
+
+#[test]
+fn read_rejects_malformed_input() {
+    let read = |bytes: &[u8]| {
+        LinkedMerkleTree::read(StringHasher::new(), &mut { bytes }).map(|tree| tree.len())
+    };
+    assert!(read(&[]).is_err());
+    assert!(read(&[0, 0, 0, 0, 0]).is_err());
+    assert!(read(&[1, 0, 0, 0, 0]).is_err());
+    assert!(read(&[65, 0, 0, 0, 0]).is_err());
+    assert!(read(&[3, 5, 0, 0, 0]).is_err());
+    assert!(read(&[3, 255, 255, 255, 255]).is_err());
+    assert!(read(&[3, 2, 0, 0, 0, 1, b'a']).is_err());
+    assert_eq!(read(&[3, 2, 0, 0, 0, 1, b'a', 1, b'b']).unwrap(), 2);
+}
+
+#[test]
+fn witness_serialization() {
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 4);
+    for character in "abcde".chars() {
+        tree.add(character.to_string());
+    }
+    let hasher = tree.hasher();
+    let witness = tree.witness(3).expect("path exists");
+    let mut bytes = vec![];
+    witness
+        .write(&hasher, &mut bytes)
+        .expect("should be able to write bytes");
+    let read_back = Witness::read(&*hasher, &mut bytes[..].as_ref()).expect("can read witness");
+    assert_eq!(read_back, witness);
+    assert!(read_back.verify(&hasher, &"d".to_string()));
+
+    // truncated input
+    assert!(Witness::read(&*hasher, &mut bytes[..bytes.len() - 1].as_ref()).is_err());
+    // empty tree
+    let mut zero_size = vec![0; 12];
+    zero_size.extend_from_slice(&bytes[4..]);
+    assert!(Witness::read(&*hasher, &mut zero_size[..].as_ref()).is_err());
+    // unknown side byte after the root hash and path length
+    let root_length = 4 + witness.root_hash.len();
+    let mut bad_side = bytes.clone();
+    bad_side[4 + root_length + 1] = 7;
+    assert!(Witness::read(&*hasher, &mut bad_side[..].as_ref()).is_err());
+    // absurdly long path
+    let mut long_path = bytes;
+    long_path[4 + root_length] = 200;
+    assert!(Witness::read(&*hasher, &mut long_path[..].as_ref()).is_err());
+    // and a path that long can't be written either, rather than wrapping
+    let too_long = Witness {
+        auth_path: (0..300)
+            .map(|_| WitnessNode::Left("a".to_string()))
+            .collect(),
+        ..witness
+    };
+    let mut nothing = vec![];
+    assert!(too_long.write(&hasher, &mut nothing).is_err());
+    assert!(nothing.is_empty());
+}
+
+#[test]
+fn large_witness_serialization() {
+    let mut tree = LinkedMerkleTree::new_with_size(MixHasher::new(), 40);
+    tree.add(1);
+    let hasher = tree.hasher();
+    let small = tree.witness(0).expect("path exists");
+    let mut small_bytes = vec![];
+    small.write(&hasher, &mut small_bytes).unwrap();
+    assert_eq!(&small_bytes[0..4], &[1, 0, 0, 0]);
+
+    // a tree size past u32::MAX is written after a zero u32
+    let large = Witness {
+        tree_size: 1 << 36,
+        ..small
+    };
+    let mut large_bytes = vec![];
+    large.write(&hasher, &mut large_bytes).unwrap();
+    assert_eq!(large_bytes.len(), small_bytes.len() + 8);
+    assert_eq!(&large_bytes[0..12], &[0, 0, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0]);
+    let read_back = Witness::read(&*hasher, &mut large_bytes[..].as_ref()).unwrap();
+    assert_eq!(read_back, large);
+
+    // a size that fits in the u32 is never escaped, so one that is won't
+    // read back to the same bytes and is rejected
+    for &size in [1u64, u64::from(u32::MAX)].iter() {
+        let mut escaped = vec![0; 4];
+        escaped.extend(&size.to_le_bytes());
+        escaped.extend(&small_bytes[4..]);
+        let error = Witness::read(&*hasher, &mut escaped[..].as_ref()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn node_indices_past_u32() {
+    let index = NodeIndex::from(u32::MAX as usize + 1);
+    assert_eq!(index.0, 1 << 32);
+    assert_eq!(index + 1, NodeIndex((1 << 32) + 1));
+}
+
+#[test]
+fn deep_tree_serialization() {
+    let mut tree = LinkedMerkleTree::new_with_size(MixHasher::new(), 40);
+    for element in 1..=3 {
+        tree.add(element);
+    }
+    let mut bytes = vec![];
+    tree.write(&mut bytes).unwrap();
+    // trees deeper than 33 levels write their leaf count as a u64
+    assert_eq!(&bytes[0..9], &[40, 3, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(bytes.len(), 9 + 3 * 8);
+    let read_back = LinkedMerkleTree::read(MixHasher::new(), &mut bytes[..].as_ref()).unwrap();
+    assert_eq!(read_back.root_hash(), tree.root_hash());
+    assert_eq!(read_back.witness(2).unwrap().auth_path.len(), 39);
+
+    // and 33 levels or fewer keep the u32 count
+    let mut shallow = LinkedMerkleTree::new_with_size(MixHasher::new(), 33);
+    shallow.add(1);
+    let mut bytes = vec![];
+    shallow.write(&mut bytes).unwrap();
+    assert_eq!(bytes.len(), 5 + 8);
+    assert_eq!(&bytes[0..5], &[33, 1, 0, 0, 0]);
+}
+
+#[test]
+fn past_witness() {
+    let characters = "abcdefghijk";
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in characters.chars() {
+        tree.add(character.to_string());
+    }
+    assert!(tree.past_witness(0, 0).is_none());
+    assert!(tree.past_witness(3, 3).is_none());
+    assert!(tree.past_witness(0, 12).is_none());
+
+    for past_size in 1..=characters.len() {
+        let mut past_tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+        for character in characters[..past_size].chars() {
+            past_tree.add(character.to_string());
+        }
+        for position in 0..past_size {
+            let witness = tree.past_witness(position, past_size).expect("leaf exists");
+            assert_eq!(Some(witness), past_tree.witness(position));
+        }
+    }
+}
+
+#[test]
+fn checkpoints() {
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in "abc".chars() {
+        tree.add(character.to_string());
+    }
+    tree.checkpoint(b"first").unwrap();
+    let root = tree.root_hash();
+    tree.add("d".to_string());
+    tree.checkpoint(b"second").unwrap();
+    tree.add("e".to_string());
+    tree.checkpoint(b"third").unwrap();
+    assert_eq!(
+        tree.checkpoints().unwrap(),
+        vec![
+            (b"first".to_vec(), 3),
+            (b"second".to_vec(), 4),
+            (b"third".to_vec(), 5)
+        ]
+    );
+
+    assert!(!tree.rewind_to(b"missing").unwrap());
+    assert_eq!(tree.len(), 5);
+    assert!(tree.rewind_to(b"first").unwrap());
+    assert_eq!(tree.len(), 3);
+    assert_eq!(tree.root_hash(), root);
+    assert_eq!(tree.checkpoints().unwrap(), vec![(b"first".to_vec(), 3)]);
+
+    tree.add("x".to_string());
+    tree.checkpoint(b"second").unwrap();
+    tree.truncate(2).unwrap();
+    assert!(tree.checkpoints().unwrap().is_empty());
+
+    tree.set_reorg_depth(1).unwrap();
+    tree.checkpoint(b"first").unwrap();
+    tree.add("y".to_string());
+    tree.checkpoint(b"second").unwrap();
+    assert_eq!(tree.checkpoints().unwrap(), vec![(b"second".to_vec(), 3)]);
+}
+
+#[test]
+fn get_retains_elements() {
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    tree.add("a".to_string());
+    tree.add("b".to_string());
+    assert_eq!(tree.get(1), Ok(Some("b".to_string())));
+    assert_eq!(tree.get(2), Ok(None));
+    assert_eq!(
+        tree.iter_notes().collect::<Vec<_>>(),
+        vec![Ok("a".to_string()), Ok("b".to_string())]
+    );
+}
+
+#[test]
+fn prune() {
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    for character in "abcdef".chars() {
+        tree.add(character.to_string());
+    }
+    tree.checkpoint(b"six").unwrap();
+    for character in "ghijk".chars() {
+        tree.add(character.to_string());
+    }
+    assert!(tree.mark(2));
+    assert!(tree.mark(7));
+    assert!(!tree.mark(11));
+    let mut full = tree.clone();
+    tree.prune();
+
+    assert!(matches!(*full.leaves, SparseVec::Dense(_)));
+    assert!(matches!(*tree.leaves, SparseVec::Sparse { .. }));
+    assert!(tree.leaves.iter().flatten().count() < full.leaves.iter().flatten().count());
+    assert_eq!(tree.len(), 11);
+    assert_eq!(tree.get(2), Ok(Some("c".to_string())));
+    assert_eq!(tree.get(3), Ok(Some("d".to_string())));
+    assert_eq!(tree.get(0), Err(NotRetained { position: 0 }));
+    let pruned_range = vec![
+        Err(NotRetained { position: 0 }),
+        Err(NotRetained { position: 1 }),
+        Ok("c".to_string()),
+        Ok("d".to_string()),
+    ];
+    assert_eq!(tree.iter_range(0..4).collect::<Vec<_>>(), pruned_range);
+    assert_eq!(
+        tree.iter_range(0..4).rev().collect::<Vec<_>>(),
+        pruned_range.into_iter().rev().collect::<Vec<_>>()
+    );
+    assert_eq!(tree.get(11), Ok(None));
+    assert_eq!(tree.witness(0), None);
+    assert!(!tree.mark(0));
+    assert!(!tree.contains(&"a".to_string()));
+    assert!(tree.contains(&"h".to_string()));
+    assert!(tree.write(&mut vec![]).is_err());
+    assert_eq!(tree.root_hash(), full.root_hash());
+
+    for character in "lmnopqrstu".chars() {
+        tree.add(character.to_string());
+        full.add(character.to_string());
+        assert_eq!(tree.root_hash(), full.root_hash());
+        for position in [2, 3, 6, 7].iter() {
+            assert_eq!(tree.witness(*position), full.witness(*position));
+        }
+    }
+    assert_eq!(tree.past_root(11), full.past_root(11));
+    assert_eq!(tree.past_witness(7, 11), full.past_witness(7, 11));
+    assert_eq!(tree.past_root(9), None);
+
+    tree.prune();
+    assert_eq!(tree.witness(7), full.witness(7));
+    assert!(tree.rewind_to(b"six").unwrap());
+    full.truncate(6).unwrap();
+    assert_eq!(tree.root_hash(), full.root_hash());
+    assert_eq!(tree.witness(2), full.witness(2));
+    tree.add("x".to_string());
+    full.add("x".to_string());
+    assert_eq!(tree.root_hash(), full.root_hash());
+    assert_eq!(tree.witness(2), full.witness(2));
+}
+
+#[test]
+fn truncate_pruned() {
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in "abcdef".chars() {
+        tree.add(character.to_string());
+    }
+    tree.prune();
+    let root_hash = tree.root_hash();
+    assert_eq!(tree.truncate(2), Err(NotRetained { position: 1 }));
+    assert_eq!(tree.len(), 6);
+    assert_eq!(tree.root_hash(), root_hash);
+    tree.truncate(6).unwrap();
+    tree.add("g".to_string());
+    tree.truncate(6).unwrap();
+    assert_eq!(tree.root_hash(), root_hash);
+}
+
+#[test]
+fn update() {
+    let characters = "abcdefghijk";
+    for size in 1..=characters.len() {
+        for position in 0..size {
+            let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+            let mut expected = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+            for (index, character) in characters[..size].chars().enumerate() {
+                tree.add(character.to_string());
+                if index == position {
+                    expected.add("z".to_string());
+                } else {
+                    expected.add(character.to_string());
+                }
+            }
+            let snapshot = tree.snapshot();
+            let before = tree.root_hash();
+            tree.update(position, "z".to_string()).unwrap();
+            assert_eq!(snapshot.root_hash(), before);
+            assert_ne!(snapshot.get(position), Ok(Some("z".to_string())));
+            assert_eq!(tree.get(position), Ok(Some("z".to_string())));
+            assert_eq!(tree.root_hash(), expected.root_hash());
+            for past_size in 1..=size {
+                assert_eq!(tree.past_root(past_size), expected.past_root(past_size));
+            }
+            for witnessed in 0..size {
+                assert_eq!(tree.witness(witnessed), expected.witness(witnessed));
+            }
+            tree.add("y".to_string());
+            expected.add("y".to_string());
+            assert_eq!(tree.root_hash(), expected.root_hash());
+            assert_eq!(tree.witness(position), expected.witness(position));
+        }
+    }
+}
+
+#[test]
+fn update_errors() {
+    let mut full = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in "abcdefghijk".chars() {
+        full.add(character.to_string());
+    }
+    let mut tree = full.clone();
+    assert!(tree.mark(7));
+    tree.prune();
+    let root_hash = tree.root_hash();
+
+    assert_eq!(
+        tree.update(11, "z".to_string()),
+        Err(UpdateError::OutOfRange {
+            position: 11,
+            len: 11
+        })
+    );
+    assert_eq!(
+        tree.update(0, "z".to_string()),
+        Err(UpdateError::NotRetained(NotRetained { position: 0 }))
+    );
+    // rehashing above 7 needs the hash of the subtree starting at 8
+    assert_eq!(
+        tree.update(7, "z".to_string()),
+        Err(UpdateError::NotRetained(NotRetained { position: 8 }))
+    );
+    assert_eq!(tree.root_hash(), root_hash);
+    assert_eq!(tree.get(7), Ok(Some("h".to_string())));
+
+    let mut tree = full.clone();
+    assert!(tree.mark(9));
+    tree.prune();
+    tree.update(9, "z".to_string()).unwrap();
+    full.update(9, "z".to_string()).unwrap();
+    assert_eq!(tree.root_hash(), full.root_hash());
+    assert_eq!(tree.witness(9), full.witness(9));
+}
+
+#[test]
+fn chunked_vec() {
+    let mut items: ChunkedVec<usize> = (0..2000).collect();
+    assert_eq!(items.len(), 2000);
+    assert!(items.iter().copied().eq(0..2000));
+    assert_eq!(items.get(2000), None);
+
+    // clones keep their own items when either one is modified
+    let copy = items.clone();
+    *items.get_mut(1500).unwrap() = 0;
+    items.push(2000);
+    assert_eq!(copy[1500], 1500);
+    assert_eq!(copy.len(), 2000);
+    assert_eq!(items[1500], 0);
+    assert_eq!(items[2000], 2000);
+
+    for &len in [1025, 1024, 33, 32, 1].iter() {
+        items.truncate(len);
+        assert_eq!(items.len(), len);
+        assert_eq!(items.get(len), None);
+        assert_eq!(items[len - 1], len - 1);
+        items.push(len);
+        items.truncate(len);
+    }
+    items.truncate(0);
+    assert_eq!(items, ChunkedVec::new());
+    for item in 0..40 {
+        items.push(item);
+    }
+    assert!(items.iter().copied().eq(0..40));
+    assert!(copy.iter().copied().eq(0..2000));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde() {
+    use crate::TreeSeed;
+    use serde::de::DeserializeSeed;
+
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    for character in "abcde".chars() {
+        tree.add(character.to_string());
+    }
+    let json = serde_json::to_string(&tree).unwrap();
+    let read_back: Box<LinkedMerkleTree<StringHasher>> = TreeSeed::new(StringHasher::new())
+        .deserialize(&mut serde_json::Deserializer::from_str(&json))
+        .unwrap();
+    assert_eq!(read_back.len(), 5);
+    assert_eq!(read_back.root_hash(), tree.root_hash());
+
+    let witness = tree.witness(3).unwrap();
+    let json = serde_json::to_string(&witness).unwrap();
+    let read_back: Witness<StringHasher> = serde_json::from_str(&json).unwrap();
+    assert_eq!(read_back, witness);
+
+    tree.mark(3);
+    tree.prune();
+    assert!(serde_json::to_string(&tree).is_err());
+    assert!(
+        TreeSeed::<LinkedMerkleTree<StringHasher>>::new(StringHasher::new())
+            .deserialize(&mut serde_json::Deserializer::from_str("[6, 1, 0]"))
+            .is_err()
+    );
+}
+
+#[test]
+fn depth_at_leaf_count() {
+    use super::depth_at_leaf_count;
+    // including counts that f32 can't represent exactly
+    for &(count, depth) in [
+        (0, 0),
+        (1, 1),
+        (2, 2),
+        (3, 3),
+        (4, 3),
+        (5, 4),
+        (1 << 25, 26),
+        ((1 << 25) + 1, 27),
+        (1 << 33, 34),
+        ((1 << 33) + 1, 35),
+    ]
+    .iter()
+    {
+        assert_eq!(depth_at_leaf_count(count), depth, "{} leaves", count);
+    }
+}
@@ -0,0 +1,395 @@
+//! A Merkle Mountain Range: an append-only Merkle tree with no fixed depth.
+//!
+//! The leaves are split into perfect binary trees ("mountains"), one for each
+//! bit set in the number of leaves, from the tallest on the left to the
+//! shortest on the right. Adding a leaf only creates new nodes; when two
+//! mountains reach the same height they are merged under a new peak. Nothing
+//! is hashed to pad the tree out to a fixed depth, and the size is only
+//! limited by memory.
+//!
+//! The root hash is calculated by "bagging" the peaks from right to left.
+//! The bag starts as the rightmost peak. To add the peak to its left, whose
+//! mountain has height h, the bag is first raised to height h by hashing it
+//! with itself (at the depth it is at), and then combined with that peak at
+//! depth h, giving a bag of height h + 1. This is the same rule the fixed
+//! depth trees use for a node without a right sibling, so an MMR has the
+//! same root hash as a `LinkedMerkleTree` of the same leaves that is just
+//! deep enough to hold them (apart from a single leaf, which is its own
+//! root).
+//!
+//! A `Witness` for a leaf is an ordinary authentication path, and verifies
+//! with `Witness::verify`. Its nodes are, in order:
+//!
+//!  1. The siblings on the path from the leaf to the peak of its mountain,
+//!     as Left or Right like any other tree.
+//!  2. If there are mountains to the right, `Left` of the bag of their peaks,
+//!     raised to the height of the leaf's mountain.
+//!  3. For each mountain to the left, nearest first: a `Left` of the current
+//!     hash for each time it has to be hashed with itself to reach the height
+//!     of that mountain, and then `Right` of that mountain's peak.
+//!
+//! The nodes are stored in a vector in post-order: each node follows its
+//! children, so a tree of any past size is a prefix of the current one.
+
+use super::checkpoint::{Checkpointed, Checkpoints};
+use super::snapshot::Snapshot;
+use super::{
+    clamp_range, write_state, HashableElement, LeafEntry, MerkleHasher, MerkleTree, NotRetained,
+    Witness, WitnessNode,
+};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+
+type Hash<T> = <<T as MerkleHasher>::Element as HashableElement>::Hash;
+
+/// Merkle Mountain Range implementation of the MerkleTree trait, held in
+/// memory.
+///
+/// The lists are shared copy-on-write between clones of the tree, so cloning
+/// is cheap. The first modification after a clone copies the lists.
+pub struct MmrTree<T: MerkleHasher> {
+    hasher: Arc<T>,
+    leaves: Arc<Vec<T::Element>>,
+    /// The hash of every node, leaves included, in post-order.
+    nodes: Arc<Vec<Hash<T>>>,
+    checkpoints: Checkpoints,
+}
+
+impl<T: MerkleHasher> Clone for MmrTree<T> {
+    fn clone(&self) -> Self {
+        MmrTree {
+            hasher: self.hasher.clone(),
+            leaves: self.leaves.clone(),
+            nodes: self.nodes.clone(),
+            checkpoints: self.checkpoints.clone(),
+        }
+    }
+}
+
+/// One of the mountains in a range of a given size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Mountain {
+    height: usize,
+    /// The position of its first leaf.
+    first_leaf: usize,
+    /// The index of its first node in the post-order node list.
+    first_node: usize,
+}
+
+impl Mountain {
+    fn contains(&self, position: usize) -> bool {
+        position >= self.first_leaf && position < self.first_leaf + (1 << self.height)
+    }
+
+    /// The index of the peak in the post-order node list.
+    fn peak(&self) -> usize {
+        self.first_node + (1 << (self.height + 1)) - 2
+    }
+}
+
+impl<T: MerkleHasher> MmrTree<T> {
+    /// Construct a new, empty tree on the heap and return a Box pointing
+    /// to it.
+    pub fn new(hasher: Arc<T>) -> Box<Self> {
+        Box::new(MmrTree {
+            hasher,
+            leaves: Arc::new(vec![]),
+            nodes: Arc::new(vec![]),
+            checkpoints: Checkpoints::default(),
+        })
+    }
+
+    /// Take a read-only view of the tree at its current size. Later changes
+    /// to this tree are not visible through the snapshot.
+    pub fn snapshot(&self) -> Snapshot<Self> {
+        Snapshot::new(self.clone())
+    }
+
+    /// The hashes of the peaks when the tree had past_size leaves, from the
+    /// tallest mountain to the shortest.
+    pub fn peaks(&self, past_size: usize) -> Vec<Hash<T>> {
+        if past_size > self.len() {
+            return vec![];
+        }
+        mountains(past_size)
+            .iter()
+            .map(|mountain| self.nodes[mountain.peak()].clone())
+            .collect()
+    }
+
+    /// Bag the peaks of the given mountains, returning the hash and the
+    /// height of the bag.
+    fn bag(&self, mountains: &[Mountain]) -> Option<(Hash<T>, usize)> {
+        let (last, rest) = mountains.split_last()?;
+        let mut hash = self.nodes[last.peak()].clone();
+        let mut height = last.height;
+        for mountain in rest.iter().rev() {
+            hash = self.raise(hash, height, mountain.height);
+            hash = self
+                .hasher
+                .combine_hash(mountain.height, &self.nodes[mountain.peak()], &hash);
+            height = mountain.height + 1;
+        }
+        Some((hash, height))
+    }
+
+    /// Hash a node of the given height with itself until it reaches
+    /// target_height.
+    fn raise(&self, mut hash: Hash<T>, height: usize, target_height: usize) -> Hash<T> {
+        for depth in height..target_height {
+            hash = self.hasher.combine_hash(depth, &hash, &hash);
+        }
+        hash
+    }
+}
+
+impl<T: MerkleHasher> MerkleTree for MmrTree<T> {
+    type Hasher = T;
+
+    /// Load a tree that was written with `MmrTree::write`.
+    fn read<R: io::Read>(hasher: Arc<T>, reader: &mut R) -> io::Result<Box<Self>> {
+        let num_leaves = reader.read_u64::<LittleEndian>()?;
+        let mut tree = MmrTree::new(hasher);
+        for _ in 0..num_leaves {
+            tree.add(tree.hasher.read_element(reader)?);
+        }
+        Ok(tree)
+    }
+
+    /// Expose the hasher
+    fn hasher(&self) -> Arc<T> {
+        self.hasher.clone()
+    }
+
+    /// Append a leaf, and merge mountains of equal height under new peaks.
+    fn add(&mut self, element: T::Element) {
+        let position = self.leaves.len();
+        let nodes = Arc::make_mut(&mut self.nodes);
+        nodes.push(element.merkle_hash());
+        let mut height = 0;
+        while (position >> height) & 1 == 1 {
+            let right = nodes.len() - 1;
+            let left = right + 1 - (1 << (height + 1));
+            let parent = self
+                .hasher
+                .combine_hash(height, &nodes[left], &nodes[right]);
+            nodes.push(parent);
+            height += 1;
+        }
+        Arc::make_mut(&mut self.leaves).push(element);
+    }
+
+    /// Get the leaf element at a specific position.
+    fn get(&self, position: usize) -> Result<Option<T::Element>, NotRetained> {
+        Ok(self.leaves.get(position).cloned())
+    }
+
+    /// Get the number of leaf nodes in the tree
+    fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// Truncate the tree to the values it contained when it had past_size
+    /// leaves. Since the nodes are in post-order, this just shortens both
+    /// lists.
+    fn truncate(&mut self, past_size: usize) -> Result<(), NotRetained> {
+        if past_size < self.len() {
+            self.checkpoints.truncated(past_size);
+            Arc::make_mut(&mut self.leaves).truncate(past_size);
+            Arc::make_mut(&mut self.nodes).truncate(num_nodes(past_size));
+        }
+        Ok(())
+    }
+
+    /// Iterate over clones of all leaf notes in the tree, without consuming
+    /// the tree.
+    fn iter_notes<'a>(&'a self) -> Box<dyn Iterator<Item = Result<T::Element, NotRetained>> + 'a> {
+        Box::new(self.leaves.iter().cloned().map(Ok))
+    }
+
+    /// Iterate over clones of the leaf notes at the positions in range,
+    /// from either end.
+    fn iter_range<'a>(
+        &'a self,
+        range: Range<usize>,
+    ) -> Box<dyn DoubleEndedIterator<Item = Result<T::Element, NotRetained>> + 'a> {
+        Box::new(
+            self.leaves[clamp_range(range, self.len())]
+                .iter()
+                .cloned()
+                .map(Ok),
+        )
+    }
+
+    /// Iterate over the leaves, reading each hash from its leaf node.
+    fn iter_leaves<'a>(&'a self) -> Box<dyn Iterator<Item = io::Result<LeafEntry<T>>> + 'a> {
+        Box::new(
+            self.leaves
+                .iter()
+                .enumerate()
+                .map(move |(position, element)| {
+                    Ok(LeafEntry {
+                        position,
+                        hash: self.nodes[num_nodes(position)].clone(),
+                        element: Some(element.clone()),
+                    })
+                }),
+        )
+    }
+
+    /// The bag of the current peaks.
+    fn root_hash(&self) -> Option<Hash<T>> {
+        self.past_root(self.len())
+    }
+
+    /// The bag of the peaks the tree had when it contained past_size leaves.
+    fn past_root(&self, past_size: usize) -> Option<Hash<T>> {
+        if past_size > self.len() {
+            return None;
+        }
+        self.bag(&mountains(past_size)).map(|(hash, _)| hash)
+    }
+
+    /// This is a linear scan.
+    fn contained(&self, value: &T::Element, past_size: usize) -> bool {
+        self.leaves.iter().take(past_size).any(|leaf| leaf == value)
+    }
+
+    /// Construct the proof that the leaf at position exists. See the module
+    /// documentation for how peak membership is encoded.
+    fn witness(&self, position: usize) -> Option<Witness<T>> {
+        self.past_witness(position, self.len())
+    }
+
+    fn past_witness(&self, position: usize, past_size: usize) -> Option<Witness<T>> {
+        if position >= past_size || past_size > self.len() {
+            return None;
+        }
+        let mountains = mountains(past_size);
+        let index = mountains
+            .iter()
+            .position(|mountain| mountain.contains(position))
+            .expect("every leaf is in a mountain");
+        let mountain = mountains[index];
+
+        // Walk down from the peak to the leaf, then reverse.
+        let mut auth_path = vec![];
+        let mut first_leaf = mountain.first_leaf;
+        let mut first_node = mountain.first_node;
+        for height in (1..=mountain.height).rev() {
+            let half = 1 << (height - 1);
+            let left_root = first_node + (1 << height) - 2;
+            let right_first_node = first_node + (1 << height) - 1;
+            let right_root = right_first_node + (1 << height) - 2;
+            if position < first_leaf + half {
+                auth_path.push(WitnessNode::Left(self.nodes[right_root].clone()));
+            } else {
+                auth_path.push(WitnessNode::Right(self.nodes[left_root].clone()));
+                first_leaf += half;
+                first_node = right_first_node;
+            }
+        }
+        auth_path.reverse();
+
+        let mut hash = self.nodes[mountain.peak()].clone();
+        let mut height = mountain.height;
+        if let Some((bag, bag_height)) = self.bag(&mountains[index + 1..]) {
+            let bag = self.raise(bag, bag_height, height);
+            hash = self.hasher.combine_hash(height, &hash, &bag);
+            auth_path.push(WitnessNode::Left(bag));
+            height += 1;
+        }
+        for left in mountains[..index].iter().rev() {
+            while height < left.height {
+                auth_path.push(WitnessNode::Left(hash.clone()));
+                hash = self.hasher.combine_hash(height, &hash, &hash);
+                height += 1;
+            }
+            let peak = &self.nodes[left.peak()];
+            auth_path.push(WitnessNode::Right(peak.clone()));
+            hash = self.hasher.combine_hash(left.height, peak, &hash);
+            height = left.height + 1;
+        }
+
+        Some(Witness {
+            tree_size: past_size,
+            root_hash: hash,
+            auth_path,
+        })
+    }
+
+    /// Write the tree as the number of leaves, as a little endian u64,
+    /// followed by each element. There is no depth to record.
+    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(self.len() as u64)?;
+        for element in self.leaves.iter() {
+            element.write(writer)?;
+        }
+        Ok(())
+    }
+
+    /// The frontier is the peaks, shortest first.
+    fn export_state<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut frontier = self.peaks(self.len());
+        frontier.reverse();
+        write_state(&*self.hasher, self.len(), &frontier, writer)
+    }
+}
+
+impl<T: MerkleHasher> Checkpointed for MmrTree<T> {
+    /// Record the current size under id. Checkpoints are not included when
+    /// the tree is written.
+    fn checkpoint(&mut self, id: &[u8]) -> io::Result<()> {
+        let size = self.len();
+        self.checkpoints.record(id, size);
+        Ok(())
+    }
+
+    fn rewind_to(&mut self, id: &[u8]) -> io::Result<bool> {
+        match self.checkpoints.rewind(id) {
+            Some(size) => {
+                self.truncate(size).expect("The tree keeps every leaf");
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn checkpoints(&self) -> io::Result<Vec<(Vec<u8>, usize)>> {
+        Ok(self.checkpoints.list())
+    }
+
+    fn set_reorg_depth(&mut self, reorg_depth: usize) -> io::Result<()> {
+        self.checkpoints.set_reorg_depth(reorg_depth);
+        Ok(())
+    }
+}
+
+/// The mountains of a range with num_leaves leaves, tallest first.
+fn mountains(num_leaves: usize) -> Vec<Mountain> {
+    let mut mountains = vec![];
+    let mut first_leaf = 0;
+    let mut first_node = 0;
+    for height in (0..usize::BITS as usize).rev() {
+        if (num_leaves >> height) & 1 == 1 {
+            mountains.push(Mountain {
+                height,
+                first_leaf,
+                first_node,
+            });
+            first_leaf += 1 << height;
+            first_node += (1 << (height + 1)) - 1;
+        }
+    }
+    mountains
+}
+
+/// The number of nodes, leaves included, in a range with num_leaves leaves.
+fn num_nodes(num_leaves: usize) -> usize {
+    2 * num_leaves - num_leaves.count_ones() as usize
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,218 @@
+use super::{mountains, num_nodes, MmrTree, Mountain};
+use crate::checkpoint::Checkpointed;
+use crate::linked::LinkedMerkleTree;
+use crate::test_helper::{MixHasher, StringHasher};
+use crate::{HashableElement, MerkleTree, Witness, WitnessNode};
+
+fn make_tree(characters: &str) -> Box<MmrTree<StringHasher>> {
+    let mut tree = MmrTree::new(StringHasher::new());
+    for character in characters.chars() {
+        tree.add(character.to_string());
+    }
+    tree
+}
+
+#[test]
+fn mountain_layout() {
+    assert_eq!(mountains(0), vec![]);
+    assert_eq!(
+        mountains(11),
+        vec![
+            Mountain {
+                height: 3,
+                first_leaf: 0,
+                first_node: 0
+            },
+            Mountain {
+                height: 1,
+                first_leaf: 8,
+                first_node: 15
+            },
+            Mountain {
+                height: 0,
+                first_leaf: 10,
+                first_node: 18
+            },
+        ]
+    );
+    assert_eq!(num_nodes(11), 19);
+    let tree = make_tree("abcdefghijk");
+    assert_eq!(tree.nodes.len(), 19);
+    assert_eq!(tree.nodes[2], "<a|b-0>");
+    assert_eq!(tree.nodes[17], "<i|j-0>");
+    assert_eq!(
+        tree.peaks(11)[1..],
+        ["<i|j-0>".to_string(), "k".to_string()]
+    );
+}
+
+#[test]
+fn root_hash() {
+    let tree = make_tree("");
+    assert_eq!(tree.root_hash(), None);
+    let tree = make_tree("a");
+    assert_eq!(tree.root_hash(), Some("a".to_string()));
+    let tree = make_tree("ab");
+    assert_eq!(tree.root_hash(), Some("<a|b-0>".to_string()));
+    let tree = make_tree("abc");
+    assert_eq!(tree.root_hash(), Some("<<a|b-0>|<c|c-0>-1>".to_string()));
+    let tree = make_tree("abcde");
+    assert_eq!(
+        tree.root_hash(),
+        Some("<<<a|b-0>|<c|d-0>-1>|<<e|e-0>|<e|e-0>-1>-2>".to_string())
+    );
+    assert_eq!(tree.past_root(3), Some("<<a|b-0>|<c|c-0>-1>".to_string()));
+    assert_eq!(tree.past_root(0), None);
+    assert_eq!(tree.past_root(6), None);
+}
+
+#[test]
+fn witness_path() {
+    let tree = make_tree("abcde");
+    let witness = tree.witness(4).unwrap();
+    assert_eq!(
+        witness.auth_path,
+        vec![
+            WitnessNode::Left("e".to_string()),
+            WitnessNode::Left("<e|e-0>".to_string()),
+            WitnessNode::Right("<<a|b-0>|<c|d-0>-1>".to_string()),
+        ]
+    );
+    let witness = tree.witness(2).unwrap();
+    assert_eq!(
+        witness.auth_path,
+        vec![
+            WitnessNode::Left("d".to_string()),
+            WitnessNode::Right("<a|b-0>".to_string()),
+            WitnessNode::Left("<<e|e-0>|<e|e-0>-1>".to_string()),
+        ]
+    );
+    assert!(witness.verify(&StringHasher {}, &"c".to_string()));
+    assert_eq!(tree.witness(5), None);
+    assert_eq!(make_tree("a").witness(0).unwrap().auth_path, vec![]);
+}
+
+#[test]
+fn matches_linked_tree_of_minimal_depth() {
+    let hasher = MixHasher::new();
+    for size in 2..40 {
+        let mut tree = MmrTree::new(hasher.clone());
+        let depth = ((size - 1) as f32).log2() as usize + 2;
+        let mut linked = LinkedMerkleTree::new_with_size(hasher.clone(), depth);
+        for value in 0..size as u64 {
+            tree.add(value * 31 + 7);
+            linked.add(value * 31 + 7);
+        }
+        assert_eq!(tree.root_hash(), linked.root_hash());
+        for position in 0..size {
+            assert_eq!(tree.witness(position), linked.witness(position));
+        }
+    }
+}
+
+#[test]
+fn past_roots_and_witnesses() {
+    let hasher = MixHasher::new();
+    let mut tree = MmrTree::new(hasher.clone());
+    let mut roots = vec![None];
+    let mut witnesses: Vec<Vec<Witness<MixHasher>>> = vec![vec![]];
+    for value in 0..70u64 {
+        tree.add(value);
+        roots.push(tree.root_hash());
+        witnesses.push(
+            (0..tree.len())
+                .map(|position| tree.witness(position).unwrap())
+                .collect(),
+        );
+    }
+    for (past_size, root) in roots.iter().enumerate() {
+        assert_eq!(tree.past_root(past_size), *root);
+        for (position, expected) in witnesses[past_size].iter().enumerate() {
+            let witness = tree.past_witness(position, past_size).unwrap();
+            assert!(witness.verify(&hasher, &(position as u64).merkle_hash()));
+            assert_eq!(witness, *expected);
+        }
+        assert_eq!(tree.past_witness(past_size, past_size), None);
+    }
+}
+
+#[test]
+fn truncate() {
+    let mut tree = make_tree("abcdefghijk");
+    let expected = make_tree("abcdef");
+    tree.truncate(6).unwrap();
+    assert_eq!(tree.len(), 6);
+    assert_eq!(tree.nodes, expected.nodes);
+    assert_eq!(tree.root_hash(), expected.root_hash());
+    tree.add("x".to_string());
+    assert_eq!(tree.root_hash(), make_tree("abcdefx").root_hash());
+    tree.truncate(0).unwrap();
+    assert!(tree.is_empty());
+    assert_eq!(tree.root_hash(), None);
+}
+
+#[test]
+fn checkpoints() {
+    let mut tree = make_tree("abc");
+    tree.checkpoint(b"three").unwrap();
+    tree.add("d".to_string());
+    tree.checkpoint(b"four").unwrap();
+    tree.add("e".to_string());
+    assert!(tree.rewind_to(b"three").unwrap());
+    assert_eq!(tree.len(), 3);
+    assert_eq!(tree.checkpoints().unwrap(), vec![(b"three".to_vec(), 3)]);
+    assert!(!tree.rewind_to(b"four").unwrap());
+}
+
+#[test]
+fn get_and_contained() {
+    let tree = make_tree("abcde");
+    assert_eq!(tree.get(1), Ok(Some("b".to_string())));
+    assert_eq!(tree.get(5), Ok(None));
+    assert!(tree.contains(&"e".to_string()));
+    assert!(!tree.contained(&"e".to_string(), 4));
+    assert_eq!(
+        tree.iter_notes().collect::<Result<Vec<_>, _>>().unwrap(),
+        vec!["a", "b", "c", "d", "e"]
+    );
+    assert_eq!(
+        tree.iter_range(1..4).rev().collect::<Result<Vec<_>, _>>(),
+        Ok(vec!["d".to_string(), "c".to_string(), "b".to_string()])
+    );
+    assert_eq!(
+        tree.iter_range(3..9).collect::<Result<Vec<_>, _>>(),
+        Ok(vec!["d".to_string(), "e".to_string()])
+    );
+    for (position, leaf) in tree.iter_leaves().map(Result::unwrap).enumerate() {
+        assert_eq!(leaf.position, position);
+        let element = tree.get(position).unwrap().unwrap();
+        assert_eq!(leaf.hash, element.merkle_hash());
+        assert_eq!(leaf.element, Some(element));
+    }
+}
+
+#[test]
+fn serialization() {
+    let tree = make_tree("abcdefg");
+    let mut bytes = vec![];
+    tree.write(&mut bytes).unwrap();
+    let read_back = MmrTree::read(StringHasher::new(), &mut &bytes[..]).unwrap();
+    assert_eq!(read_back.root_hash(), tree.root_hash());
+    assert_eq!(read_back.len(), 7);
+    assert!(MmrTree::read(StringHasher::new(), &mut &bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn export_state() {
+    let mut tree = MmrTree::new(StringHasher::new());
+    let mut linked = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    for character in "abcdefghijklmnopqrstu".chars() {
+        let mut state = vec![];
+        tree.export_state(&mut state).unwrap();
+        let mut expected = vec![];
+        linked.export_state(&mut expected).unwrap();
+        assert_eq!(state, expected);
+        tree.add(character.to_string());
+        linked.add(character.to_string());
+    }
+}
@@ -0,0 +1,73 @@
+//! A hasher for 32 byte notes and 32 byte Blake2b hashes, which is the shape
+//! of the trees our nodes keep. The `merkle-notes` binary opens trees with
+//! it, and the benchmarks build trees with it so that leaves are about the
+//! size of a real commitment.
+//!
+//! Each level is hashed with its own Blake2b personalization, so the cost of
+//! each combine is close to what a production hasher pays.
+
+use super::{HashableElement, MerkleHasher};
+use blake2b_simd::Params;
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io;
+
+/// A note with 32 bytes of content.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Note(pub [u8; 32]);
+
+impl HashableElement for Note {
+    type Hash = [u8; 32];
+
+    fn merkle_hash(&self) -> [u8; 32] {
+        blake2b(b"merkle_note_leaf", &[&self.0])
+    }
+
+    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.0)
+    }
+}
+
+/// Hasher producing 32 byte Blake2b hashes, personalized by depth.
+#[derive(Debug, Default)]
+pub struct NoteHasher {}
+
+impl MerkleHasher for NoteHasher {
+    type Element = Note;
+
+    fn read_element<R: io::Read>(&self, reader: &mut R) -> io::Result<Note> {
+        let mut bytes = [0; 32];
+        reader.read_exact(&mut bytes)?;
+        Ok(Note(bytes))
+    }
+
+    fn read_hash<R: io::Read>(&self, reader: &mut R) -> io::Result<[u8; 32]> {
+        let mut bytes = [0; 32];
+        reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write_hash<W: io::Write>(&self, hash: &[u8; 32], writer: &mut W) -> io::Result<()> {
+        writer.write_all(hash)
+    }
+
+    fn combine_hash(&self, depth: usize, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        let mut personal = *b"merkle_node_\0\0\0\0";
+        (&mut personal[12..])
+            .write_u32::<LittleEndian>(depth as u32)
+            .unwrap();
+        blake2b(&personal, &[left, right])
+    }
+}
+
+fn blake2b(personal: &[u8; 16], inputs: &[&[u8; 32]]) -> [u8; 32] {
+    let mut state = Params::new().hash_length(32).personal(personal).to_state();
+    for input in inputs {
+        state.update(&input[..]);
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(state.finalize().as_bytes());
+    hash
+}
+
+#[cfg(test)]
+mod tests;
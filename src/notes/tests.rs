@@ -0,0 +1,36 @@
+use super::{Note, NoteHasher};
+use crate::linked::LinkedMerkleTree;
+use crate::{HashableElement, MerkleHasher, MerkleTree};
+use std::sync::Arc;
+
+#[test]
+fn hashes_depend_on_depth() {
+    let hasher = NoteHasher {};
+    let left = Note([1; 32]).merkle_hash();
+    let right = Note([2; 32]).merkle_hash();
+    assert_ne!(left, right);
+    assert_ne!(
+        hasher.combine_hash(0, &left, &right),
+        hasher.combine_hash(1, &left, &right)
+    );
+    assert_ne!(
+        hasher.combine_hash(0, &left, &right),
+        hasher.combine_hash(0, &right, &left)
+    );
+}
+
+#[test]
+fn tree_of_notes() {
+    let hasher = Arc::new(NoteHasher {});
+    let mut tree = LinkedMerkleTree::new_with_size(hasher.clone(), 5);
+    for byte in 0..5 {
+        tree.add(Note([byte; 32]));
+    }
+    let mut bytes = vec![];
+    tree.write(&mut bytes).unwrap();
+    let read_back = LinkedMerkleTree::read(hasher.clone(), &mut &bytes[..]).unwrap();
+    assert_eq!(read_back.root_hash(), tree.root_hash());
+    assert_eq!(read_back.get(3), Ok(Some(Note([3; 32]))));
+    let witness = tree.witness(3).unwrap();
+    assert!(witness.verify(&hasher, &Note([3; 32]).merkle_hash()));
+}
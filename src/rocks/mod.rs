@@ -1,7 +1,17 @@
-use super::{HashableElement, MerkleHasher, MerkleTree, Witness, WitnessNode};
+use super::checkpoint::{Checkpointed, Checkpoints};
+use super::config::{ConfigError, TreeConfig};
+use super::integrity::{check_rows, IntegrityReport};
+use super::snapshot::Snapshot;
+use super::{
+    clamp_range, frontier_edge, invalid_data, past_witness, read_state, subtree_hash,
+    subtree_start, write_state, write_witnessed_state, HashableElement, LeafEntry, LeafRange,
+    LeafStorage, MerkleHasher, MerkleTree, NotRetained, Witness, WitnessNode,
+};
+use rocksdb::Direction;
+use std::ops::Range;
 use std::{io, sync::Arc};
 mod rocker;
-use rocker::{Leaf, LeafIndex, Node, NodeIndex, Rocker};
+use rocker::{Leaf, LeafIndex, Node, NodeIndex, Rocker, RockerSnapshot, SyncPoint};
 
 /// Merkle tree implementation stored in RocksDB. Based on LinkedMerkleTree,
 /// but data isn't stored wholly in memory, and is saved incrementally,
@@ -16,6 +26,7 @@ pub struct RocksMerkleTree<T: MerkleHasher> {
     hasher: Arc<T>,
     rocker: Rocker<T>,
     tree_depth: u32,
+    leaf_storage: LeafStorage,
 }
 
 impl<T: MerkleHasher> RocksMerkleTree<T> {
@@ -26,23 +37,361 @@ impl<T: MerkleHasher> RocksMerkleTree<T> {
     }
 
     /// Construct a new, empty merkle tree in the given directory with
-    /// the given size
+    /// the given size. An existing tree keeps the leaf storage mode it was
+    /// created with; a new one stores its elements.
+    ///
+    /// Panics if the directory holds a tree created with another depth, or
+    /// the database can't be opened.
     pub fn new_with_size(
         hasher: Arc<T>,
         rocks_directory: &std::path::Path,
         tree_depth: u32,
     ) -> Self {
-        let rocker = Rocker::new(hasher.clone(), rocks_directory);
-        RocksMerkleTree {
+        Self::open(hasher, rocks_directory, tree_depth, None)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Construct a new, empty merkle tree in the given directory with the
+    /// given size, storing either the leaf elements or only their hashes.
+    ///
+    /// Panics if the directory holds a tree created with the other mode or
+    /// another depth, or the database can't be opened.
+    pub fn new_with_storage(
+        hasher: Arc<T>,
+        rocks_directory: &std::path::Path,
+        tree_depth: u32,
+        leaf_storage: LeafStorage,
+    ) -> Self {
+        Self::open(hasher, rocks_directory, tree_depth, Some(leaf_storage))
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Open the tree in the given directory as described by config,
+    /// creating it if necessary.
+    ///
+    /// Fails with InvalidInput wrapping a ConfigError if config isn't
+    /// valid, or if the directory holds a tree created with a different
+    /// depth or leaf storage mode than the one requested. Fails with the
+    /// database's error if it can't be opened or its checkpoints can't be
+    /// read.
+    pub fn with_config(
+        hasher: Arc<T>,
+        rocks_directory: &std::path::Path,
+        config: TreeConfig,
+    ) -> io::Result<Self> {
+        config.validate()?;
+        let mut tree = Self::open(
+            hasher,
+            rocks_directory,
+            config.depth as u32,
+            config.leaf_storage,
+        )?;
+        if let Some(reorg_depth) = config.reorg_depth {
+            tree.set_reorg_depth(reorg_depth)?;
+        }
+        Ok(tree)
+    }
+
+    /// Open the tree in the given directory at the depth it was created
+    /// with, for tools that are handed a tree rather than building one.
+    ///
+    /// Fails if the directory doesn't hold a database, or holds a tree from
+    /// before the depth was recorded; opening that once with
+    /// `new_with_size` records it.
+    pub fn open_existing(hasher: Arc<T>, rocks_directory: &std::path::Path) -> io::Result<Self> {
+        let rocker = Rocker::open_existing(hasher.clone(), rocks_directory)?;
+        // Trees that record their depth were opened since the indices were
+        // widened and the leaf storage mode was recorded
+        let tree_depth = rocker.tree_depth().ok_or_else(|| {
+            invalid_data(format!(
+                "The tree in {} doesn't record its depth",
+                rocks_directory.display()
+            ))
+        })?;
+        let leaf_storage = rocker.leaf_storage().unwrap_or_default();
+        Ok(RocksMerkleTree {
+            hasher,
+            rocker,
+            tree_depth: tree_depth - 1,
+            leaf_storage,
+        })
+    }
+
+    /// The number of levels in the tree, including the root and the leaves.
+    pub fn tree_depth(&self) -> u32 {
+        self.tree_depth + 1
+    }
+
+    fn open(
+        hasher: Arc<T>,
+        rocks_directory: &std::path::Path,
+        tree_depth: u32,
+        requested_storage: Option<LeafStorage>,
+    ) -> io::Result<Self> {
+        let rocker = Rocker::new(hasher.clone(), rocks_directory)?;
+        rocker.migrate_u32_indices();
+        // Trees created before the mode was recorded stored their elements
+        let stored_storage = rocker.leaf_storage().or_else(|| {
+            if rocker.num_leaves() > 0 {
+                Some(LeafStorage::Elements)
+            } else {
+                None
+            }
+        });
+        let leaf_storage = match (stored_storage, requested_storage) {
+            (Some(stored), Some(requested)) if stored != requested => {
+                return Err(ConfigError::LeafStorageMismatch { stored, requested }.into())
+            }
+            (Some(stored), _) => stored,
+            (None, requested) => requested.unwrap_or_default(),
+        };
+        rocker.set_leaf_storage(leaf_storage);
+        match rocker.tree_depth() {
+            Some(stored) if stored != tree_depth => {
+                return Err(ConfigError::DepthMismatch {
+                    stored: stored as usize,
+                    requested: tree_depth as usize,
+                }
+                .into())
+            }
+            Some(_) => {}
+            None => rocker.set_tree_depth(tree_depth),
+        }
+        Ok(RocksMerkleTree {
             hasher,
             rocker,
             tree_depth: tree_depth - 1,
+            leaf_storage,
+        })
+    }
+
+    /// Take a read-only view of the tree at its current size.
+    ///
+    /// It reads from a rocksdb snapshot, so nothing that is done to this tree
+    /// afterwards, including truncating it, changes what it sees.
+    pub fn snapshot(&self) -> Snapshot<Self> {
+        Snapshot::new(RocksMerkleTree {
+            hasher: self.hasher.clone(),
+            rocker: self.rocker.pinned(),
+            tree_depth: self.tree_depth,
+            leaf_storage: self.leaf_storage,
+        })
+    }
+
+    /// Fill an empty tree from a state written by `export_state`, so that it
+    /// can carry on from there. The tree must have the same depth as the one
+    /// that wrote the state. It has none of the leaves before that point, and
+    /// can't be truncated to that point or below it, except to empty it.
+    ///
+    /// Fails without changing anything if the tree isn't empty.
+    pub fn import_state<R: io::Read>(&mut self, reader: &mut R) -> io::Result<()> {
+        if self.len() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Only an empty tree can import a state",
+            ));
+        }
+        let (num_leaves, frontier) = read_state(&*self.hasher, self.tree_depth as usize, reader)?;
+        // Truncating to empty leaves the old rows behind, and they mustn't
+        // pass for the leaves the import skips
+        self.rocker.delete_leaves_and_nodes();
+        if num_leaves > 0 {
+            self.start_from_frontier(num_leaves, frontier);
+        }
+        Ok(())
+    }
+
+    /// Store the right edge of an empty tree with size leaves from its
+    /// frontier, leaving the leaves themselves out.
+    fn start_from_frontier(
+        &mut self,
+        size: usize,
+        frontier: Vec<<T::Element as HashableElement>::Hash>,
+    ) {
+        let edge = frontier_edge(&*self.hasher, self.tree_depth as usize, size, &frontier);
+        // at level 0 the edge starts with the last leaf, which only gets a hash
+        let edge_nodes = &edge.nodes[usize::from(edge.level == 0)..];
+        let mut next_node_index = 1;
+        let mut first = None;
+        for (height, edge_node) in edge_nodes.iter().enumerate() {
+            // a right node is stored after its left sibling
+            let stored = if edge_node.left_sibling.is_some() {
+                2
+            } else {
+                1
+            };
+            let parent = match edge_nodes.get(height + 1) {
+                Some(above) => {
+                    NodeIndex(next_node_index + stored + u64::from(above.left_sibling.is_some()))
+                }
+                None => NodeIndex::empty(),
+            };
+            self.rocker.set_node(
+                NodeIndex(next_node_index),
+                &Node::Left {
+                    hash_of_sibling: edge_node.hash.clone(),
+                    parent,
+                },
+            );
+            if let Some(left_hash) = &edge_node.left_sibling {
+                self.rocker.set_node(
+                    NodeIndex(next_node_index + 1),
+                    &Node::Right {
+                        hash_of_sibling: left_hash.clone(),
+                        left: NodeIndex(next_node_index),
+                    },
+                );
+            }
+            next_node_index += stored;
+            first.get_or_insert(NodeIndex(next_node_index - 1));
         }
+        let node = first.unwrap_or_else(NodeIndex::empty);
+        if edge.level == 0 {
+            self.rocker.set_leaf_metadata(
+                LeafIndex(size as u64 - 1),
+                &Leaf {
+                    parent: node,
+                    hash: edge.nodes[0].hash.clone(),
+                },
+            );
+        }
+        self.rocker.set_num_nodes(next_node_index);
+        self.rocker.set_sync_point(Some(&SyncPoint {
+            size: size as u64,
+            level: edge.level,
+            node,
+            root_hash: edge.root_hash,
+            frontier,
+        }));
+        // The leaf count goes last, so an interrupted import leaves an empty
+        // tree behind
+        self.rocker.set_num_leaves(size as u64);
+    }
+
+    /// The number of internal nodes, counting the empty node above the root
+    /// and any that have been pruned.
+    pub fn num_nodes(&self) -> usize {
+        self.rocker.num_nodes() as usize
+    }
+
+    /// Read every leaf and node row and check that they fit together: that
+    /// links and parents are consistent, the counters match the rows, and
+    /// the stored sibling hashes recompute from their children. Rows that
+    /// can't be decoded are reported rather than panicking; failing to read
+    /// from the database is an error.
+    ///
+    /// The rows are read from a snapshot, so the tree can be written to
+    /// while it is checked.
+    pub fn verify_integrity(&self) -> io::Result<IntegrityReport> {
+        let rows = self.rocker.snapshot().stored_rows()?;
+        Ok(check_rows(&*self.hasher, self.tree_depth as usize, rows))
+    }
+
+    /// The hash_of_sibling stored in each internal node on the path from the
+    /// leaf at leaf_index up to the root.
+    fn stored_siblings(
+        &self,
+        leaf_index: LeafIndex,
+    ) -> Vec<Option<<T::Element as HashableElement>::Hash>> {
+        let mut current_position = self.rocker.get_leaf_parent(leaf_index);
+        (1..self.tree_depth as usize)
+            .map(|_| {
+                match self
+                    .rocker
+                    .get_node(current_position)
+                    .expect("Unable to read node")
+                {
+                    Node::Empty => None,
+                    Node::Left {
+                        parent,
+                        hash_of_sibling,
+                    } => {
+                        current_position = parent;
+                        Some(hash_of_sibling)
+                    }
+                    Node::Right {
+                        left,
+                        hash_of_sibling,
+                    } => {
+                        current_position = self
+                            .rocker
+                            .get_node_parent(left)
+                            .expect("Unable to read node");
+                        Some(hash_of_sibling)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Check that the tree still stores the leaf that would become the last
+    /// one if it were truncated to past_size.
+    fn check_last_leaf(&self, past_size: usize) -> Result<(), NotRetained> {
+        if past_size > 0
+            && past_size < self.len()
+            && self
+                .rocker
+                .get_leaf_metadata(LeafIndex(past_size as u64 - 1))
+                .is_none()
+        {
+            return Err(NotRetained {
+                position: past_size - 1,
+            });
+        }
+        Ok(())
+    }
+
+    /// Remove the leaves after past_size and fix up the nodes. The caller
+    /// checks that the leaf that becomes the last one is stored.
+    fn truncate_leaves(&self, past_size: usize) {
+        if past_size >= self.len() {
+            return;
+        }
+        self.rocker.set_num_leaves(past_size as u64);
+        if past_size == 0 {
+            self.rocker.set_num_nodes(1); // The empty node
+            self.rocker.set_sync_point(None);
+            return;
+        } else if past_size == 1 {
+            let mut first_leaf = self.rocker.get_leaf_metadata(LeafIndex(0)).unwrap();
+            first_leaf.parent = NodeIndex::empty();
+            self.rocker.set_leaf_metadata(LeafIndex(0), &first_leaf);
+            self.rocker.set_num_nodes(1);
+            return;
+        }
+        let depth = depth_at_leaf_count(past_size) - 2;
+        let mut parent = self.rocker.get_leaf_parent(LeafIndex(past_size as u64 - 1));
+        let mut max_parent = parent;
+        for _ in 0..depth {
+            parent = self
+                .rocker
+                .get_node_parent(parent)
+                .expect("Unable to read node");
+            if parent.0 > max_parent.0 {
+                max_parent = parent;
+            }
+        }
+
+        match self.rocker.get_node(parent).expect("Unable to read node") {
+            Node::Left {
+                hash_of_sibling, ..
+            } => self.rocker.set_node(
+                parent,
+                &Node::Left {
+                    hash_of_sibling,
+                    parent: NodeIndex::empty(),
+                },
+            ),
+            _ => panic!("New root expected to be a left node."),
+        }
+
+        self.rocker.set_num_nodes(max_parent.0 + 1);
+        self.rehash_right_path();
     }
 
     /// Recalculate all the hashes between the most recently added leaf in the group
     /// and the root hash.
-    fn rehash_right_path(&mut self) {
+    fn rehash_right_path(&self) {
         let mut depth = 0;
         let leaf_index = LeafIndex(self.rocker.num_leaves() - 1);
         let leaf = self.rocker.get_leaf_metadata(leaf_index).unwrap();
@@ -54,7 +403,10 @@ impl<T: MerkleHasher> RocksMerkleTree<T> {
             self.hasher.combine_hash(depth, &leaf.hash, &leaf.hash)
         };
         loop {
-            let node = self.rocker.get_node(parent_index);
+            let node = self
+                .rocker
+                .get_node(parent_index)
+                .expect("Unable to read node");
             depth += 1;
             match node {
                 Node::Empty => break,
@@ -80,7 +432,10 @@ impl<T: MerkleHasher> RocksMerkleTree<T> {
                     // since this is a new right node we know that we have the correct hash
                     // because we set it correctly when we inserted it. But our left node
                     // needs to have its hash_of_sibling set to our current hash.
-                    parent_index = self.rocker.get_node_parent(left);
+                    parent_index = self
+                        .rocker
+                        .get_node_parent(left)
+                        .expect("Unable to read node");
                     self.rocker.set_node(
                         left,
                         &Node::Left {
@@ -104,6 +459,25 @@ impl<T: MerkleHasher> MerkleTree for RocksMerkleTree<T> {
         Ok(())
     }
 
+    /// A tree that was imported and has had nothing added since writes the
+    /// frontier it started from, rather than witness a last leaf it may not
+    /// have.
+    fn export_state<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self
+            .rocker
+            .sync_point()
+            .filter(|sync_point| sync_point.size == self.rocker.num_leaves())
+        {
+            Some(sync_point) => write_state(
+                &*self.hasher,
+                sync_point.size as usize,
+                &sync_point.frontier,
+                writer,
+            ),
+            None => write_witnessed_state(self, writer),
+        }
+    }
+
     /// Doesn't make sense to read a database that isn't stored in memory.
     /// It's not possible to fake this because we don't have access to the
     /// dbfile here.
@@ -131,7 +505,7 @@ impl<T: MerkleHasher> MerkleTree for RocksMerkleTree<T> {
     /// It needs to. See https://github.com/rust-rocksdb/rust-rocksdb/pull/250
     fn add(&mut self, element: T::Element) {
         let index_of_new_leaf = LeafIndex(self.rocker.num_leaves());
-        if index_of_new_leaf.0 as usize >= 2_usize.pow(self.tree_depth as u32) {
+        if index_of_new_leaf.0 as usize >= 2_usize.pow(self.tree_depth) {
             panic!("Tree is full");
         }
 
@@ -166,11 +540,39 @@ impl<T: MerkleHasher> MerkleTree for RocksMerkleTree<T> {
             let previous_leaf_index = LeafIndex(index_of_new_leaf.0 - 1);
             let mut next_node_index = self.rocker.num_nodes();
             let new_parent_index = NodeIndex(next_node_index);
-            let mut previous_parent_index = self.rocker.get_leaf_parent(previous_leaf_index);
             let mut my_hash = self.hasher.combine_hash(0, &leaf_hash, &leaf_hash);
             let mut depth = 1;
+            let mut previous_parent_index = match self.rocker.get_leaf_metadata(previous_leaf_index)
+            {
+                Some(previous_leaf) => previous_leaf.parent,
+                None => {
+                    // The first leaf after an imported state. The missing
+                    // leaf before it would be a right child all the way up
+                    // to the imported level, so this leaf gets a new left
+                    // node at each depth below that.
+                    let sync_point = self
+                        .rocker
+                        .sync_point()
+                        .expect("must have leaf left of the new left leaf");
+                    while depth < sync_point.level {
+                        my_hash = self.hasher.combine_hash(depth, &my_hash, &my_hash);
+                        let new_node = Node::Left {
+                            parent: NodeIndex(next_node_index + 1),
+                            hash_of_sibling: my_hash.clone(),
+                        };
+                        self.rocker.set_node(NodeIndex(next_node_index), &new_node);
+                        next_node_index += 1;
+                        self.rocker.set_num_nodes(next_node_index);
+                        depth += 1;
+                    }
+                    sync_point.node
+                }
+            };
             loop {
-                let previous_parent = self.rocker.get_node(previous_parent_index);
+                let previous_parent = self
+                    .rocker
+                    .get_node(previous_parent_index)
+                    .expect("Unable to read node");
                 match previous_parent {
                     Node::Left {
                         hash_of_sibling,
@@ -215,7 +617,10 @@ impl<T: MerkleHasher> MerkleTree for RocksMerkleTree<T> {
                         self.rocker.set_node(NodeIndex(next_node_index), &new_node);
                         next_node_index += 1;
                         self.rocker.set_num_nodes(next_node_index);
-                        previous_parent_index = self.rocker.get_node_parent(left);
+                        previous_parent_index = self
+                            .rocker
+                            .get_node_parent(left)
+                            .expect("Unable to read node");
                         depth += 1;
                     }
                     Node::Empty => unimplemented!(),
@@ -229,14 +634,29 @@ impl<T: MerkleHasher> MerkleTree for RocksMerkleTree<T> {
         };
         self.rocker.set_num_leaves(index_of_new_leaf.0 + 1);
         self.rocker.set_leaf_metadata(index_of_new_leaf, &new_leaf);
-        self.rocker.set_leaf_element(index_of_new_leaf, &element);
+        if self.leaf_storage == LeafStorage::Elements {
+            self.rocker.set_leaf_element(index_of_new_leaf, &element);
+        }
 
         self.rehash_right_path();
     }
 
     /// Get the leaf element at the given position.
-    fn get(&self, position: usize) -> Option<<Self::Hasher as MerkleHasher>::Element> {
-        self.rocker.get_leaf_element(LeafIndex(position as u32))
+    ///
+    /// Every leaf of a tree that only stores leaf hashes is a NotRetained
+    /// error.
+    fn get(
+        &self,
+        position: usize,
+    ) -> Result<Option<<Self::Hasher as MerkleHasher>::Element>, NotRetained> {
+        if position >= self.len() {
+            return Ok(None);
+        }
+        let element = match self.leaf_storage {
+            LeafStorage::Elements => self.rocker.get_leaf_element(LeafIndex(position as u64)),
+            LeafStorage::HashesOnly => None,
+        };
+        element.map(Some).ok_or(NotRetained { position })
     }
 
     /// Truncate the tree to the values it contained when it contained past_size
@@ -248,63 +668,131 @@ impl<T: MerkleHasher> MerkleTree for RocksMerkleTree<T> {
     /// This function doesn't do any garbage collection. The old leaves and
     /// nodes stay in rocksdb, but they will be overwritten as the tree grows.
     ///
-    /// TODO: This needs to run inside a rocksdb transaction.
-    fn truncate(&mut self, past_size: usize) {
+    /// Fails without changing anything if the leaf that would become the last
+    /// one isn't stored. The changes are written in one batch, so they all
+    /// happen or none do.
+    fn truncate(&mut self, past_size: usize) -> Result<(), NotRetained> {
         if past_size >= self.len() {
-            return;
-        }
-
-        self.rocker.set_num_leaves(past_size as u32);
-        if past_size == 0 {
-            self.rocker.set_num_nodes(1); // The empty node
-            return;
-        } else if past_size == 1 {
-            let mut first_leaf = self.rocker.get_leaf_metadata(LeafIndex(0)).unwrap();
-            first_leaf.parent = NodeIndex::empty();
-            self.rocker.set_leaf_metadata(LeafIndex(0), &first_leaf);
-            self.rocker.set_num_nodes(1);
-            return;
+            return Ok(());
         }
-        let depth = depth_at_leaf_count(past_size) - 2;
-        let mut parent = self.rocker.get_leaf_parent(LeafIndex(past_size as u32 - 1));
-        let mut max_parent = parent;
-        for _ in 0..depth {
-            parent = self.rocker.get_node_parent(parent);
-            if parent.0 > max_parent.0 {
-                max_parent = parent;
+        self.check_last_leaf(past_size)?;
+        self.rocker.batch(|| {
+            // Checkpoints that can't be read are left as they are, for the
+            // Checkpointed methods to report
+            if let Ok(mut checkpoints) = self.rocker.checkpoints() {
+                if checkpoints.truncated(past_size) {
+                    self.rocker.set_checkpoints(&checkpoints);
+                }
             }
-        }
-
-        match self.rocker.get_node(parent) {
-            Node::Left {
-                hash_of_sibling, ..
-            } => self.rocker.set_node(
-                parent,
-                &Node::Left {
-                    hash_of_sibling,
-                    parent: NodeIndex::empty(),
-                },
-            ),
-            _ => panic!("New root expected to be a left node."),
-        }
-
-        self.rocker.set_num_nodes(max_parent.0 + 1);
-        self.rehash_right_path();
+            self.truncate_leaves(past_size);
+        });
+        Ok(())
     }
 
     /// Iterate over clones of all leaf notes in the tree, without consuming
     /// the tree.
     ///
-    /// note: this is completely undefined behaviour if the tree is modified
-    /// while iteration is happening.
+    /// The iterator reads from a snapshot of the database taken when it was
+    /// created, so it yields the leaves the tree had then even if the tree is
+    /// modified through another handle while it runs.
+    ///
+    /// Every leaf of a tree that only stores leaf hashes yields a
+    /// NotRetained error.
     fn iter_notes<'a>(
         &'a self,
-    ) -> Box<dyn Iterator<Item = <Self::Hasher as MerkleHasher>::Element> + 'a> {
-        let leaf_count = self.rocker.num_leaves();
-        Box::new(
-            (0..leaf_count)
-                .map(move |index| self.rocker.get_leaf_element(LeafIndex(index)).unwrap()),
-        )
+    ) -> Box<dyn Iterator<Item = Result<<Self::Hasher as MerkleHasher>::Element, NotRetained>> + 'a>
+    {
+        Box::new(self.iter_range(0..usize::MAX))
+    }
+
+    /// Iterate over clones of the leaf notes at the positions in range,
+    /// from either end, with a range scan over the leaf element keys. Like
+    /// iter_notes, it reads from a snapshot of the database.
+    ///
+    /// Every leaf of a tree that only stores leaf hashes yields a
+    /// NotRetained error.
+    fn iter_range<'a>(
+        &'a self,
+        range: Range<usize>,
+    ) -> Box<
+        dyn DoubleEndedIterator<Item = Result<<Self::Hasher as MerkleHasher>::Element, NotRetained>>
+            + 'a,
+    > {
+        let snapshot = self.rocker.snapshot();
+        let range = clamp_range(range, snapshot.num_leaves());
+        if self.leaf_storage == LeafStorage::HashesOnly {
+            return Box::new(range.map(|position| Err(NotRetained { position })));
+        }
+        if range.is_empty() {
+            return Box::new(std::iter::empty());
+        }
+        // An element that can't be decoded yields NotRetained, like one that
+        // isn't stored.
+        let front = snapshot
+            .leaf_elements(LeafIndex(range.start as u64), Direction::Forward)
+            .filter_map(Result::ok);
+        let back = snapshot
+            .leaf_elements(LeafIndex(range.end as u64 - 1), Direction::Reverse)
+            .filter_map(Result::ok);
+        Box::new(SnapshotIter {
+            iter: LeafRange::new(front, back, range),
+            _snapshot: snapshot,
+        })
+    }
+
+    /// Iterate over the leaves with a scan over the stored hashes, and a
+    /// second scan over the elements if the tree stores them. Like
+    /// iter_notes, it reads from a snapshot of the database. A row that can't
+    /// be decoded is yielded as an error, and ends the iteration.
+    fn iter_leaves<'a>(&'a self) -> Box<dyn Iterator<Item = io::Result<LeafEntry<T>>> + 'a> {
+        let snapshot = self.rocker.snapshot();
+        let num_leaves = snapshot.num_leaves();
+        let mut elements = match self.leaf_storage {
+            LeafStorage::Elements => Some(
+                snapshot
+                    .leaf_elements(LeafIndex(0), Direction::Forward)
+                    .peekable(),
+            ),
+            LeafStorage::HashesOnly => None,
+        };
+        let iter = snapshot
+            .leaf_hashes()
+            .take_while(move |leaf| !matches!(leaf, Ok((position, _)) if *position >= num_leaves))
+            .map(move |leaf| -> io::Result<LeafEntry<T>> {
+                let (position, hash) = leaf?;
+                let element = match elements.as_mut() {
+                    Some(elements) => {
+                        while elements
+                            .next_if(|found| matches!(found, Ok((found, _)) if *found < position))
+                            .is_some()
+                        {}
+                        // Take an element for this position, or an error
+                        match elements
+                            .next_if(|found| !matches!(found, Ok((found, _)) if *found > position))
+                        {
+                            Some(found) => Some(found?.1),
+                            None => None,
+                        }
+                    }
+                    None => None,
+                };
+                Ok(LeafEntry {
+                    position,
+                    hash,
+                    element,
+                })
+            })
+            .scan(false, |failed, leaf| {
+                if *failed {
+                    return None;
+                }
+                *failed = leaf.is_err();
+                Some(leaf)
+            });
+        Box::new(SnapshotIter {
+            iter,
+            _snapshot: snapshot,
+        })
     }
 
     /// Get the hash of the current root element in the tree.
@@ -324,30 +812,25 @@ impl<T: MerkleHasher> MerkleTree for RocksMerkleTree<T> {
         if self.len() == 0 || past_size > self.len() || past_size == 0 {
             return None;
         }
+        if let Some(sync_point) = self.rocker.sync_point() {
+            if past_size as u64 == sync_point.size {
+                return Some(sync_point.root_hash);
+            }
+        }
         let root_depth = depth_at_leaf_count(past_size);
-        let leaf_index = LeafIndex(past_size as u32 - 1);
-        let mut current_hash;
-        let mut current_node_index = self.rocker.get_leaf_parent(leaf_index);
-        if leaf_index.is_right() {
-            current_hash = self.hasher.combine_hash(
-                0,
-                &self
-                    .rocker
-                    .get_leaf_metadata(leaf_index.sibling())
-                    .unwrap()
-                    .hash,
-                &self.rocker.get_leaf_metadata(leaf_index).unwrap().hash,
-            );
+        let leaf_index = LeafIndex(past_size as u64 - 1);
+        // None before an imported state
+        let leaf = self.rocker.get_leaf_metadata(leaf_index)?;
+        let mut current_node_index = leaf.parent;
+        let mut current_hash = if leaf_index.is_right() {
+            let sibling = self.rocker.get_leaf_metadata(leaf_index.sibling())?;
+            self.hasher.combine_hash(0, &sibling.hash, &leaf.hash)
         } else {
-            current_hash = self.hasher.combine_hash(
-                0,
-                &self.rocker.get_leaf_metadata(leaf_index).unwrap().hash,
-                &self.rocker.get_leaf_metadata(leaf_index).unwrap().hash,
-            );
-        }
+            self.hasher.combine_hash(0, &leaf.hash, &leaf.hash)
+        };
 
         for depth in 1..std::cmp::min(root_depth, self.tree_depth as usize) {
-            match self.rocker.get_node(current_node_index) {
+            match self.rocker.get_node(current_node_index).ok()? {
                 Node::Empty => panic!("depth should not reach empty node"),
                 Node::Left { parent, .. } => {
                     current_hash = self
@@ -362,7 +845,7 @@ impl<T: MerkleHasher> MerkleTree for RocksMerkleTree<T> {
                     current_hash = self
                         .hasher
                         .combine_hash(depth, &hash_of_sibling, &current_hash);
-                    current_node_index = self.rocker.get_node_parent(left);
+                    current_node_index = self.rocker.get_node_parent(left).ok()?;
                 }
             }
         }
@@ -376,17 +859,32 @@ impl<T: MerkleHasher> MerkleTree for RocksMerkleTree<T> {
 
     /// Determine whether a tree contained a value in the past, when it had a specific size.
     ///
-    /// This is an inefficient linear scan.
+    /// This is an inefficient linear scan. It compares leaf hashes, so it
+    /// works whether or not the elements are stored.
     fn contained(&self, value: &T::Element, past_size: usize) -> bool {
-        for (index, candidate) in self.iter_notes().enumerate() {
-            if index == past_size {
-                break;
-            }
-            if candidate == *value {
-                return true;
-            }
-        }
-        false
+        let hash = value.merkle_hash();
+        let size = std::cmp::min(past_size, self.len());
+        (0..size).any(|index| {
+            self.rocker
+                .get_leaf_metadata(LeafIndex(index as u64))
+                .is_some_and(|leaf| leaf.hash == hash)
+        })
+    }
+
+    /// Like the default, but reads the stored leaf hash, so that it works on
+    /// a tree that only stores hashes.
+    fn subtree_root(
+        &self,
+        level: usize,
+        index: usize,
+    ) -> Option<<<Self::Hasher as MerkleHasher>::Element as HashableElement>::Hash> {
+        let position = subtree_start(level, index)?;
+        let witness = self.witness(position)?;
+        let leaf_hash = self
+            .rocker
+            .get_leaf_metadata(LeafIndex(position as u64))?
+            .hash;
+        subtree_hash(&*self.hasher, &witness, level, leaf_hash)
     }
 
     /// Construct the proof that the leaf node at `position` exists.
@@ -403,8 +901,9 @@ impl<T: MerkleHasher> MerkleTree for RocksMerkleTree<T> {
         if self.len() == 0 || position >= self.len() {
             return None;
         }
-        let leaf_index = LeafIndex(position as u32);
-        let leaf_data = self.rocker.get_leaf_metadata(leaf_index).unwrap();
+        let leaf_index = LeafIndex(position as u64);
+        // None before an imported state
+        let leaf_data = self.rocker.get_leaf_metadata(leaf_index)?;
         let mut current_hash = leaf_data.hash;
         let mut current_position = leaf_data.parent;
         let mut authentication_path = vec![];
@@ -423,7 +922,7 @@ impl<T: MerkleHasher> MerkleTree for RocksMerkleTree<T> {
             current_hash = self.hasher.combine_hash(0, &current_hash, &current_hash);
         }
         for depth in 1..self.tree_depth as usize {
-            match self.rocker.get_node(current_position) {
+            match self.rocker.get_node(current_position).ok()? {
                 Node::Empty => {
                     authentication_path.push(WitnessNode::Left(current_hash.clone()));
                     current_hash = self
@@ -448,7 +947,7 @@ impl<T: MerkleHasher> MerkleTree for RocksMerkleTree<T> {
                     current_hash = self
                         .hasher
                         .combine_hash(depth, &hash_of_sibling, &current_hash);
-                    current_position = self.rocker.get_node_parent(left);
+                    current_position = self.rocker.get_node_parent(left).ok()?;
                 }
             }
         }
@@ -458,6 +957,70 @@ impl<T: MerkleHasher> MerkleTree for RocksMerkleTree<T> {
             tree_size: self.len(),
         })
     }
+
+    /// Construct the proof that the leaf node at `position` existed when the
+    /// tree had past_size leaves.
+    fn past_witness(&self, position: usize, past_size: usize) -> Option<Witness<T>> {
+        if position >= past_size || past_size > self.len() {
+            return None;
+        }
+        if self
+            .rocker
+            .get_leaf_metadata(LeafIndex(position as u64))
+            .is_none()
+            || self
+                .rocker
+                .get_leaf_metadata(LeafIndex(past_size as u64 - 1))
+                .is_none()
+        {
+            // before an imported state
+            return None;
+        }
+        Some(past_witness(
+            &*self.hasher,
+            position,
+            past_size,
+            |index| self.rocker.get_leaf_hash(LeafIndex(index as u64)),
+            |index| self.stored_siblings(LeafIndex(index as u64)),
+        ))
+    }
+}
+
+impl<T: MerkleHasher> Checkpointed for RocksMerkleTree<T> {
+    /// Record the current size under id, in the same database as the tree.
+    fn checkpoint(&mut self, id: &[u8]) -> io::Result<()> {
+        let mut checkpoints = self.rocker.checkpoints()?;
+        checkpoints.record(id, self.len());
+        self.rocker.set_checkpoints(&checkpoints);
+        Ok(())
+    }
+
+    /// Rewind to the checkpoint. The checkpoints and the tree are updated in
+    /// one batch.
+    fn rewind_to(&mut self, id: &[u8]) -> io::Result<bool> {
+        let mut checkpoints = self.rocker.checkpoints()?;
+        let past_size = match checkpoints.rewind(id) {
+            Some(past_size) => past_size,
+            None => return Ok(false),
+        };
+        self.check_last_leaf(past_size).map_err(io::Error::other)?;
+        self.rocker.batch(|| {
+            self.rocker.set_checkpoints(&checkpoints);
+            self.truncate_leaves(past_size);
+        });
+        Ok(true)
+    }
+
+    fn checkpoints(&self) -> io::Result<Vec<(Vec<u8>, usize)>> {
+        Ok(self.rocker.checkpoints()?.list())
+    }
+
+    fn set_reorg_depth(&mut self, reorg_depth: usize) -> io::Result<()> {
+        let mut checkpoints = self.rocker.checkpoints()?;
+        checkpoints.set_reorg_depth(reorg_depth);
+        self.rocker.set_checkpoints(&checkpoints);
+        Ok(())
+    }
 }
 
 /// The depth of the tree when it contains a certain
@@ -468,7 +1031,28 @@ fn depth_at_leaf_count(index: usize) -> usize {
     match index {
         0 => 0,
         1 => 1,
-        n => ((n - 1) as f32).log2() as usize + 2,
+        n => (usize::BITS - (n - 1).leading_zeros()) as usize + 1,
+    }
+}
+
+/// Wraps an iterator that reads from a database snapshot, to keep the
+/// snapshot alive until the iterator is done with it.
+struct SnapshotIter<'a, T: MerkleHasher, I> {
+    iter: I,
+    _snapshot: RockerSnapshot<'a, T>,
+}
+
+impl<'a, T: MerkleHasher, I: Iterator> Iterator for SnapshotIter<'a, T, I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        self.iter.next()
+    }
+}
+
+impl<'a, T: MerkleHasher, I: DoubleEndedIterator> DoubleEndedIterator for SnapshotIter<'a, T, I> {
+    fn next_back(&mut self) -> Option<I::Item> {
+        self.iter.next_back()
     }
 }
 
@@ -1,33 +1,47 @@
-use super::{HashableElement, MerkleHasher};
+use super::{Checkpoints, HashableElement, LeafStorage, MerkleHasher};
+use crate::integrity::{IntegrityProblem, Row, StoredNode, StoredRows};
+use crate::invalid_data;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use rocksdb::DB;
+use rocksdb::{Direction, IteratorMode, Options, Snapshot, WriteBatch, DB};
+use std::collections::HashMap;
+use std::io;
+use std::ops::Deref;
+use std::sync::Mutex;
 use std::{path::Path, sync::Arc};
 
 const LEAF_COUNT_KEY: &str = "LeafCount";
 const NODE_COUNT_KEY: &str = "NodeCount";
+const CHECKPOINTS_KEY: &str = "Checkpoints";
+const LEAF_STORAGE_KEY: &str = "LeafStorage";
+const TREE_DEPTH_KEY: &str = "TreeDepth";
+const SYNC_POINT_KEY: &str = "SyncPoint";
 const LEAF_METADATA_PREFIX: &[u8; 8] = b"LeafData";
 const LEAF_ELEMENT_PREFIX: &[u8; 11] = b"LeafElement";
 const NODE_DATA_PREFIX: &[u8; 8] = b"NodeData";
+const INDEX_WIDTH_KEY: &str = "IndexWidth";
+
+/// How many rewritten keys to write at once during a migration.
+const MIGRATION_BATCH_SIZE: usize = 10_000;
 
 /// Newtype wrapper to avoid mixing up leaf and node indexes
 #[derive(Shrinkwrap, Debug, PartialEq, Copy, Clone)]
-pub(crate) struct LeafIndex(pub(crate) u32);
+pub(crate) struct LeafIndex(pub(crate) u64);
 
 impl LeafIndex {
     fn metadata_key(&self) -> Vec<u8> {
         let mut key = LEAF_METADATA_PREFIX.to_owned().to_vec();
-        key.extend(u32_as_bytes(self.0));
+        key.extend(&self.0.to_be_bytes());
         key
     }
     fn element_key(&self) -> Vec<u8> {
         let mut key = LEAF_ELEMENT_PREFIX.to_owned().to_vec();
-        key.extend(u32_as_bytes(self.0));
+        key.extend(&self.0.to_be_bytes());
         key
     }
 }
 
-impl PartialEq<u32> for LeafIndex {
-    fn eq(&self, other: &u32) -> bool {
+impl PartialEq<u64> for LeafIndex {
+    fn eq(&self, other: &u64) -> bool {
         self.0 == *other
     }
 }
@@ -50,7 +64,7 @@ impl LeafIndex {
 
 /// Newtype wrapper to avoid mixing up leaf and node indexes
 #[derive(Shrinkwrap, Debug, PartialEq, Copy, Clone)]
-pub(crate) struct NodeIndex(pub(crate) u32);
+pub(crate) struct NodeIndex(pub(crate) u64);
 impl NodeIndex {
     pub(crate) fn empty() -> Self {
         NodeIndex(0)
@@ -62,7 +76,7 @@ impl NodeIndex {
 
     fn data_key(&self) -> Vec<u8> {
         let mut key = NODE_DATA_PREFIX.to_owned().to_vec();
-        key.extend(u32_as_bytes(self.0));
+        key.extend(&self.0.to_be_bytes());
         key
     }
 }
@@ -101,6 +115,19 @@ pub(crate) enum Node<T: MerkleHasher> {
     Empty,
 }
 
+/// Where an imported state starts. The leaves before size were never added,
+/// so they aren't stored. The frontier has the hashes of the complete
+/// subtrees they make up, the lowest of which is at level. node is the
+/// internal node at that level above the last of the leaves (or its parent,
+/// at level 0), which the first leaf added after them is attached to.
+pub(crate) struct SyncPoint<T: MerkleHasher> {
+    pub(crate) size: u64,
+    pub(crate) level: usize,
+    pub(crate) node: NodeIndex,
+    pub(crate) root_hash: <T::Element as HashableElement>::Hash,
+    pub(crate) frontier: Vec<<T::Element as HashableElement>::Hash>,
+}
+
 /// Rocksdb wrapper that queries and unwraps requests for specific
 /// keys and types useful to the RocksMerkleTree.
 ///
@@ -110,36 +137,321 @@ pub(crate) enum Node<T: MerkleHasher> {
 /// it's unclear how the client code would handle it. So we panic... *sigh*
 pub(crate) struct Rocker<T: MerkleHasher> {
     hasher: Arc<T>,
-    rocksdb: DB,
+    rocksdb: Arc<DB>,
+    /// The writes made inside `batch`, by key, with None for a delete.
+    pending: Mutex<Option<PendingWrites>>,
+    /// The snapshot every read goes through, for a handle made by `pinned`.
+    pinned: Option<PinnedSnapshot>,
+}
+
+/// Writes held back by `Rocker::batch`, by key, with None for a delete.
+type PendingWrites = HashMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// Stops holding writes back when a batch ends, even if it ends by
+/// panicking, so that later writes aren't buffered and never written.
+struct PendingGuard<'a>(&'a Mutex<Option<PendingWrites>>);
+
+impl<'a> Drop for PendingGuard<'a> {
+    fn drop(&mut self) {
+        *self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+    }
+}
+
+/// A database snapshot that keeps the database it was taken from open, so
+/// that a Rocker can own one.
+struct PinnedSnapshot {
+    // Declared first so that it is released before the database it borrows.
+    snapshot: Snapshot<'static>,
+    _rocksdb: Arc<DB>,
+}
+
+impl PinnedSnapshot {
+    fn new(rocksdb: Arc<DB>) -> Self {
+        // SAFETY: the snapshot borrows the DB inside the Arc, which never
+        // moves, and it is dropped before this struct's clone of the Arc.
+        let snapshot =
+            unsafe { std::mem::transmute::<Snapshot<'_>, Snapshot<'static>>(rocksdb.snapshot()) };
+        PinnedSnapshot {
+            snapshot,
+            _rocksdb: rocksdb,
+        }
+    }
+}
+
+/// The snapshot a RockerSnapshot reads from: either its own, or the one its
+/// Rocker is pinned to.
+enum SnapshotRef<'a> {
+    Taken(Snapshot<'a>),
+    Pinned(&'a Snapshot<'a>),
+}
+
+impl<'a> Deref for SnapshotRef<'a> {
+    type Target = Snapshot<'a>;
+
+    fn deref(&self) -> &Snapshot<'a> {
+        match self {
+            SnapshotRef::Taken(snapshot) => snapshot,
+            SnapshotRef::Pinned(snapshot) => snapshot,
+        }
+    }
 }
 
 impl<T: MerkleHasher> Rocker<T> {
-    pub(crate) fn new(hasher: Arc<T>, rocks_directory: &Path) -> Self {
-        Rocker {
+    pub(crate) fn new(hasher: Arc<T>, rocks_directory: &Path) -> io::Result<Self> {
+        let rocksdb = DB::open_default(rocks_directory)
+            .map_err(|error| io::Error::other(error.to_string()))?;
+        Ok(Rocker {
+            hasher,
+            rocksdb: Arc::new(rocksdb),
+            pending: Mutex::new(None),
+            pinned: None,
+        })
+    }
+
+    /// Open the database in the given directory, failing if there isn't one.
+    pub(crate) fn open_existing(hasher: Arc<T>, rocks_directory: &Path) -> io::Result<Self> {
+        let mut options = Options::default();
+        options.create_if_missing(false);
+        let rocksdb = DB::open(&options, rocks_directory)
+            .map_err(|error| io::Error::new(io::ErrorKind::NotFound, error.to_string()))?;
+        Ok(Rocker {
             hasher,
-            rocksdb: DB::open_default(rocks_directory).expect("Unable to load database"),
+            rocksdb: Arc::new(rocksdb),
+            pending: Mutex::new(None),
+            pinned: None,
+        })
+    }
+
+    /// Rewrite a tree created before leaf and node indices were widened to
+    /// u64. Such trees have u32 indices in their keys, in leaf parents and
+    /// node links, and in the leaf and node counts. The indices in keys were
+    /// also little endian; they're now big endian, so that leaves can be
+    /// read with range scans.
+    ///
+    /// Each rewritten key is put in the same batch that deletes its old form,
+    /// and the new keys are longer than the old ones, so a migration that is
+    /// interrupted picks up where it left off the next time the tree is
+    /// opened. The index width is recorded once everything has been
+    /// rewritten, and a new tree records it straight away.
+    pub(crate) fn migrate_u32_indices(&self) {
+        if self.get(INDEX_WIDTH_KEY, |_| ()).is_some() {
+            return;
         }
+        let mut batch = WriteBatch::default();
+        let mut batch_size = 0;
+        for (key, value) in self.rocksdb.iterator(IteratorMode::Start) {
+            let new_entry = if key.len() == LEAF_METADATA_PREFIX.len() + 4
+                && key.starts_with(LEAF_METADATA_PREFIX)
+            {
+                let mut new_value = widen(&value[..4]);
+                new_value.extend(&value[4..]);
+                Some((widen_key(&key, LEAF_METADATA_PREFIX.len()), new_value))
+            } else if key.len() == LEAF_ELEMENT_PREFIX.len() + 4
+                && key.starts_with(LEAF_ELEMENT_PREFIX)
+            {
+                Some((widen_key(&key, LEAF_ELEMENT_PREFIX.len()), value.to_vec()))
+            } else if key.len() == NODE_DATA_PREFIX.len() + 4 && key.starts_with(NODE_DATA_PREFIX) {
+                let mut new_value = vec![value[0]];
+                new_value.extend(widen(&value[1..5]));
+                new_value.extend(&value[5..]);
+                Some((widen_key(&key, NODE_DATA_PREFIX.len()), new_value))
+            } else {
+                None
+            };
+            if let Some((new_key, new_value)) = new_entry {
+                batch.put(new_key, new_value).unwrap();
+                batch.delete(key).unwrap();
+                batch_size += 1;
+                if batch_size == MIGRATION_BATCH_SIZE {
+                    self.rocksdb.write(std::mem::take(&mut batch)).unwrap();
+                    batch_size = 0;
+                }
+            }
+        }
+        self.rocksdb.write(batch).unwrap();
+        for key in [LEAF_COUNT_KEY, NODE_COUNT_KEY].iter() {
+            if let Some(value) = self.get(key, |bytes| bytes.to_vec()) {
+                if value.len() == 4 {
+                    self.rocksdb.put(key, widen(&value)).unwrap();
+                }
+            }
+        }
+        self.rocksdb.put(INDEX_WIDTH_KEY, [8u8]).unwrap();
+    }
+
+    /// Take a consistent view of the database as it is now, or as it was
+    /// when a pinned handle was made.
+    pub(crate) fn snapshot(&self) -> RockerSnapshot<'_, T> {
+        let snapshot = match &self.pinned {
+            Some(pinned) => SnapshotRef::Pinned(&pinned.snapshot),
+            None => SnapshotRef::Taken(self.rocksdb.snapshot()),
+        };
+        RockerSnapshot {
+            hasher: self.hasher.clone(),
+            snapshot,
+        }
+    }
+
+    /// Get a read-only wrapper around the same open database that reads
+    /// everything as it is now, however the database changes later.
+    pub(crate) fn pinned(&self) -> Self {
+        Rocker {
+            hasher: self.hasher.clone(),
+            rocksdb: self.rocksdb.clone(),
+            pending: Mutex::new(None),
+            pinned: Some(PinnedSnapshot::new(self.rocksdb.clone())),
+        }
+    }
+
+    /// Run f with the writes it makes held back, and then write them all in
+    /// one batch, so that either all of them happen or none do. Reads inside
+    /// f see them. This stands in for the transactions the Rust wrapper
+    /// doesn't support.
+    pub(crate) fn batch<R>(&self, f: impl FnOnce() -> R) -> R {
+        *self.pending.lock().unwrap() = Some(HashMap::new());
+        let _guard = PendingGuard(&self.pending);
+        let result = f();
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .take()
+            .expect("batches don't nest");
+        let mut batch = WriteBatch::default();
+        for (key, value) in pending {
+            match value {
+                Some(value) => batch.put(key, value).unwrap(),
+                None => batch.delete(key).unwrap(),
+            }
+        }
+        self.rocksdb.write(batch).unwrap();
+        result
     }
 
     /// Retrieve the number of leaf nodes (notes) in the tree
-    pub(crate) fn num_leaves(&self) -> u32 {
-        self.get_u32(LEAF_COUNT_KEY).unwrap_or(0)
+    pub(crate) fn num_leaves(&self) -> u64 {
+        self.get_u64(LEAF_COUNT_KEY).unwrap_or(0)
     }
 
     /// Set the number of leaf nodes. It may be good to have an atomic increment
     /// operation here, since it only ever goes up by one.
-    pub(crate) fn set_num_leaves(&self, length: u32) {
-        self.set_u32(LEAF_COUNT_KEY, length as u32);
+    pub(crate) fn set_num_leaves(&self, length: u64) {
+        self.set_u64(LEAF_COUNT_KEY, length);
     }
 
     /// Get the number of internal nodes. There is always at least one internal node
     /// (the empty node)
-    pub(crate) fn num_nodes(&self) -> u32 {
-        self.get_u32(NODE_COUNT_KEY).unwrap_or(1)
+    pub(crate) fn num_nodes(&self) -> u64 {
+        self.get_u64(NODE_COUNT_KEY).unwrap_or(1)
+    }
+
+    pub(crate) fn set_num_nodes(&self, count: u64) {
+        self.set_u64(NODE_COUNT_KEY, count);
     }
 
-    pub(crate) fn set_num_nodes(&self, count: u32) {
-        self.set_u32(NODE_COUNT_KEY, count as u32);
+    /// Get the leaf storage mode recorded when the tree was created.
+    pub(crate) fn leaf_storage(&self) -> Option<LeafStorage> {
+        self.get(LEAF_STORAGE_KEY, |bytes| match bytes {
+            [0] => LeafStorage::Elements,
+            [1] => LeafStorage::HashesOnly,
+            _ => panic!("Unknown leaf storage mode"),
+        })
+    }
+
+    pub(crate) fn set_leaf_storage(&self, leaf_storage: LeafStorage) {
+        let byte = match leaf_storage {
+            LeafStorage::Elements => 0u8,
+            LeafStorage::HashesOnly => 1u8,
+        };
+        self.put(LEAF_STORAGE_KEY, [byte]);
+    }
+
+    /// Get the depth recorded when the tree was created. Trees created
+    /// before it was recorded have none.
+    pub(crate) fn tree_depth(&self) -> Option<u32> {
+        self.get_u64(TREE_DEPTH_KEY).map(|depth| depth as u32)
+    }
+
+    pub(crate) fn set_tree_depth(&self, tree_depth: u32) {
+        self.set_u64(TREE_DEPTH_KEY, u64::from(tree_depth));
+    }
+
+    /// Get the checkpoints recorded for this tree, or the error from
+    /// decoding them.
+    pub(crate) fn checkpoints(&self) -> io::Result<Checkpoints> {
+        self.get(CHECKPOINTS_KEY, |mut bytes| Checkpoints::read(&mut bytes))
+            .unwrap_or_else(|| Ok(Checkpoints::default()))
+    }
+
+    pub(crate) fn set_checkpoints(&self, checkpoints: &Checkpoints) {
+        let mut bytes = vec![];
+        checkpoints.write(&mut bytes).unwrap();
+        self.put(CHECKPOINTS_KEY, bytes);
+    }
+
+    /// Get where the tree starts, if it was imported.
+    pub(crate) fn sync_point(&self) -> Option<SyncPoint<T>> {
+        self.get(SYNC_POINT_KEY, |mut bytes| {
+            let size = bytes.read_u64::<LittleEndian>().unwrap();
+            let level = bytes.read_u64::<LittleEndian>().unwrap() as usize;
+            let node = NodeIndex(bytes.read_u64::<LittleEndian>().unwrap());
+            let root_hash = self.hasher.read_hash(&mut bytes).unwrap();
+            let frontier = (0..size.count_ones())
+                .map(|_| self.hasher.read_hash(&mut bytes).unwrap())
+                .collect();
+            SyncPoint {
+                size,
+                level,
+                node,
+                root_hash,
+                frontier,
+            }
+        })
+    }
+
+    pub(crate) fn set_sync_point(&self, sync_point: Option<&SyncPoint<T>>) {
+        match sync_point {
+            Some(sync_point) => {
+                let mut bytes = vec![];
+                bytes.write_u64::<LittleEndian>(sync_point.size).unwrap();
+                bytes
+                    .write_u64::<LittleEndian>(sync_point.level as u64)
+                    .unwrap();
+                bytes.write_u64::<LittleEndian>(sync_point.node.0).unwrap();
+                self.hasher
+                    .write_hash(&sync_point.root_hash, &mut bytes)
+                    .unwrap();
+                for hash in &sync_point.frontier {
+                    self.hasher.write_hash(hash, &mut bytes).unwrap();
+                }
+                self.put(SYNC_POINT_KEY, bytes);
+            }
+            None => self.delete(SYNC_POINT_KEY),
+        }
+    }
+
+    /// Delete every leaf and node row, in one batch.
+    pub(crate) fn delete_leaves_and_nodes(&self) {
+        let mut batch = WriteBatch::default();
+        for prefix in [
+            &LEAF_METADATA_PREFIX[..],
+            &LEAF_ELEMENT_PREFIX[..],
+            &NODE_DATA_PREFIX[..],
+        ]
+        .iter()
+        {
+            for (key, _) in self
+                .rocksdb
+                .iterator(IteratorMode::From(prefix, Direction::Forward))
+                .take_while(|(key, _)| key.len() == prefix.len() + 8 && key.starts_with(prefix))
+            {
+                batch.delete(key).unwrap();
+            }
+        }
+        self.rocksdb.write(batch).unwrap();
     }
 
     /// Get the parent of the leaf node at given index.
@@ -157,7 +469,7 @@ impl<T: MerkleHasher> Rocker<T> {
 
     pub(crate) fn get_leaf_metadata(&self, index: LeafIndex) -> Option<Leaf<T>> {
         self.get(index.metadata_key(), |mut bytes| {
-            let parent = NodeIndex(bytes.read_u32::<LittleEndian>().unwrap());
+            let parent = NodeIndex(bytes.read_u64::<LittleEndian>().unwrap());
             let hash = self.hasher.read_hash(&mut bytes).unwrap();
             Leaf { parent, hash }
         })
@@ -165,9 +477,9 @@ impl<T: MerkleHasher> Rocker<T> {
 
     pub(crate) fn set_leaf_metadata(&self, index: LeafIndex, value: &Leaf<T>) {
         let mut bytes = vec![];
-        bytes.write_u32::<LittleEndian>(value.parent.0).unwrap();
+        bytes.write_u64::<LittleEndian>(value.parent.0).unwrap();
         self.hasher.write_hash(&value.hash, &mut bytes).unwrap();
-        self.rocksdb.put(index.metadata_key(), bytes).unwrap();
+        self.put(index.metadata_key(), bytes);
     }
 
     pub(crate) fn get_leaf_element(&self, index: LeafIndex) -> Option<T::Element> {
@@ -179,42 +491,44 @@ impl<T: MerkleHasher> Rocker<T> {
     /// Get the index of the parent of the given node. If it's a left node, it has a parent,
     /// if it's a right node, we have to get the parent of its left sibling, if it's empty,
     /// the index is zero
-    pub(crate) fn get_node_parent(&self, index: NodeIndex) -> NodeIndex {
-        let node = self.get_node(index);
-        match node {
+    pub(crate) fn get_node_parent(&self, index: NodeIndex) -> io::Result<NodeIndex> {
+        Ok(match self.get_node(index)? {
             Node::Empty => NodeIndex::empty(),
             Node::Left { parent, .. } => parent,
-            Node::Right { left, .. } => self.get_node_parent(left),
-        }
+            Node::Right { left, .. } => self.get_node_parent(left)?,
+        })
     }
 
     /// Get the node at the given index. If index is 0, return Empty,
     /// otherwise load the given node from the database.
     ///
-    /// Panics if the node does not exist. This is crate-internal and should
-    /// never be called in a situation where the node does not exist.
-    pub(crate) fn get_node(&self, index: NodeIndex) -> Node<T> {
+    /// Fails if the node isn't stored, or its row can't be decoded.
+    pub(crate) fn get_node(&self, index: NodeIndex) -> io::Result<Node<T>> {
         if index.0 == 0 {
-            Node::Empty
-        } else {
-            self.get(index.data_key(), |mut bytes| {
-                let node_type = bytes.read_u8().unwrap();
-                let the_other_node = NodeIndex(bytes.read_u32::<LittleEndian>().unwrap());
-                let hash_of_sibling = self.hasher.read_hash(&mut bytes).unwrap();
-                if node_type == 0 {
-                    Node::Left {
-                        parent: the_other_node,
-                        hash_of_sibling,
-                    }
-                } else {
-                    Node::Right {
-                        left: the_other_node,
-                        hash_of_sibling,
-                    }
-                }
-            })
-            .unwrap()
+            return Ok(Node::Empty);
         }
+        self.get(index.data_key(), |mut bytes| -> io::Result<Node<T>> {
+            let node_type = bytes.read_u8()?;
+            let the_other_node = NodeIndex(bytes.read_u64::<LittleEndian>()?);
+            let hash_of_sibling = self.hasher.read_hash(&mut bytes)?;
+            match node_type {
+                0 => Ok(Node::Left {
+                    parent: the_other_node,
+                    hash_of_sibling,
+                }),
+                1 => Ok(Node::Right {
+                    left: the_other_node,
+                    hash_of_sibling,
+                }),
+                _ => Err(invalid_data(format!("Unknown node type {}", node_type))),
+            }
+        })
+        .unwrap_or_else(|| {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Node {} is not stored", index.0),
+            ))
+        })
     }
 
     pub(crate) fn set_node(&self, index: NodeIndex, node: &Node<T>) {
@@ -232,10 +546,8 @@ impl<T: MerkleHasher> Rocker<T> {
                 },
             ) => {
                 bytes.write_u8(0).unwrap();
-                bytes.write_u32::<LittleEndian>(parent.0).unwrap();
-                self.hasher
-                    .write_hash(&hash_of_sibling, &mut bytes)
-                    .unwrap();
+                bytes.write_u64::<LittleEndian>(parent.0).unwrap();
+                self.hasher.write_hash(hash_of_sibling, &mut bytes).unwrap();
             }
             (
                 NodeIndex(_),
@@ -245,40 +557,264 @@ impl<T: MerkleHasher> Rocker<T> {
                 },
             ) => {
                 bytes.write_u8(1).unwrap();
-                bytes.write_u32::<LittleEndian>(left.0).unwrap();
-                self.hasher
-                    .write_hash(&hash_of_sibling, &mut bytes)
-                    .unwrap();
+                bytes.write_u64::<LittleEndian>(left.0).unwrap();
+                self.hasher.write_hash(hash_of_sibling, &mut bytes).unwrap();
             }
         }
-        self.rocksdb.put(data_key, bytes).unwrap();
+        self.put(data_key, bytes);
     }
     pub(crate) fn set_leaf_element(&self, index: LeafIndex, value: &T::Element) {
         let mut bytes = vec![];
         value.write(&mut bytes).unwrap();
-        self.rocksdb.put(index.element_key(), bytes).unwrap();
+        self.put(index.element_key(), bytes);
     }
 
     fn get<K: AsRef<[u8]>, V, F: FnOnce(&[u8]) -> V>(&self, key: K, callback: F) -> Option<V> {
-        self.rocksdb
-            .get_pinned(key)
-            .unwrap()
-            .map(|pinnable_slice| callback(pinnable_slice.as_ref()))
+        if let Some(pending) = self.pending.lock().unwrap().as_ref() {
+            if let Some(value) = pending.get(key.as_ref()) {
+                return value.as_deref().map(callback);
+            }
+        }
+        match &self.pinned {
+            Some(pinned) => pinned
+                .snapshot
+                .get(key)
+                .unwrap()
+                .map(|value| callback(&value)),
+            None => self
+                .rocksdb
+                .get_pinned(key)
+                .unwrap()
+                .map(|pinnable_slice| callback(pinnable_slice.as_ref())),
+        }
+    }
+
+    /// Write value under key, or hold it back if inside `batch`.
+    fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) {
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => {
+                pending.insert(key.as_ref().to_vec(), Some(value.as_ref().to_vec()));
+            }
+            None => self.rocksdb.put(key, value).unwrap(),
+        }
+    }
+
+    /// Delete key, or hold the delete back if inside `batch`.
+    fn delete<K: AsRef<[u8]>>(&self, key: K) {
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => {
+                pending.insert(key.as_ref().to_vec(), None);
+            }
+            None => self.rocksdb.delete(key).unwrap(),
+        }
     }
 
-    fn get_u32(&self, key: &str) -> Option<u32> {
-        self.get(key, |mut bytes| bytes.read_u32::<LittleEndian>().unwrap())
+    fn get_u64(&self, key: &str) -> Option<u64> {
+        self.get(key, |mut bytes| bytes.read_u64::<LittleEndian>().unwrap())
     }
 
-    fn set_u32(&self, key: &str, value: u32) {
+    fn set_u64(&self, key: &str, value: u64) {
         let mut bytes = vec![];
-        bytes.write_u32::<LittleEndian>(value).unwrap();
-        self.rocksdb.put(key, bytes).unwrap();
+        bytes.write_u64::<LittleEndian>(value).unwrap();
+        self.put(key, bytes);
+    }
+}
+
+#[cfg(test)]
+impl<T: MerkleHasher> Rocker<T> {
+    /// Get another wrapper around the same open database, so that a test
+    /// can read the tree while it is being modified.
+    pub(crate) fn handle(&self) -> Self {
+        Rocker {
+            hasher: self.hasher.clone(),
+            rocksdb: self.rocksdb.clone(),
+            pending: Mutex::new(None),
+            pinned: None,
+        }
+    }
+}
+
+/// A view of the database that doesn't change when the tree is modified,
+/// for iterators that need to read a consistent tree.
+pub(crate) struct RockerSnapshot<'a, T: MerkleHasher> {
+    hasher: Arc<T>,
+    snapshot: SnapshotRef<'a>,
+}
+
+impl<'a, T: MerkleHasher + 'a> RockerSnapshot<'a, T> {
+    /// Retrieve the number of leaf nodes (notes) in the tree
+    pub(crate) fn num_leaves(&self) -> usize {
+        self.snapshot
+            .get(LEAF_COUNT_KEY)
+            .unwrap()
+            .map_or(0, |bytes| {
+                (&bytes[..]).read_u64::<LittleEndian>().unwrap() as usize
+            })
+    }
+
+    /// Scan the stored leaf elements in the given direction, starting at the
+    /// one at index, and yield each with its position, or the error from
+    /// decoding it.
+    pub(crate) fn leaf_elements(
+        &self,
+        index: LeafIndex,
+        direction: Direction,
+    ) -> impl Iterator<Item = io::Result<(usize, T::Element)>> + 'a {
+        let hasher = self.hasher.clone();
+        let key = index.element_key();
+        self.snapshot
+            .iterator(IteratorMode::From(&key, direction))
+            .take_while(|(key, _)| {
+                key.len() == LEAF_ELEMENT_PREFIX.len() + 8 && key.starts_with(LEAF_ELEMENT_PREFIX)
+            })
+            .map(move |(key, value)| {
+                let mut index = [0; 8];
+                index.copy_from_slice(&key[LEAF_ELEMENT_PREFIX.len()..]);
+                let element = hasher.read_element(&mut &value[..])?;
+                Ok((u64::from_be_bytes(index) as usize, element))
+            })
+    }
+
+    /// Scan the stored leaf hashes from the first leaf onwards, and yield
+    /// each with its position, or the error from decoding it.
+    pub(crate) fn leaf_hashes(
+        &self,
+    ) -> impl Iterator<Item = io::Result<(usize, <T::Element as HashableElement>::Hash)>> + 'a {
+        let hasher = self.hasher.clone();
+        let key = LeafIndex(0).metadata_key();
+        self.snapshot
+            .iterator(IteratorMode::From(&key, Direction::Forward))
+            .take_while(|(key, _)| {
+                key.len() == LEAF_METADATA_PREFIX.len() + 8 && key.starts_with(LEAF_METADATA_PREFIX)
+            })
+            .map(move |(key, value)| {
+                let mut index = [0; 8];
+                index.copy_from_slice(&key[LEAF_METADATA_PREFIX.len()..]);
+                let hash = hasher.read_hash(&mut value.get(8..).unwrap_or_default())?;
+                Ok((u64::from_be_bytes(index) as usize, hash))
+            })
+    }
+
+    /// Read every leaf and node row, for checking. Rows that can't be
+    /// decoded don't panic; they are left out and recorded as problems
+    /// instead. Failing to read the counters is an error.
+    pub(crate) fn stored_rows(
+        &self,
+    ) -> io::Result<StoredRows<<T::Element as HashableElement>::Hash>> {
+        let mut problems = vec![];
+        let mut counter = |key: &'static str, default: u64| -> io::Result<u64> {
+            let value = self
+                .snapshot
+                .get(key)
+                .map_err(|error| io::Error::other(error.to_string()))?;
+            Ok(match value {
+                Some(bytes) if bytes.len() == 8 => (&bytes[..]).read_u64::<LittleEndian>()?,
+                Some(_) => {
+                    problems.push(IntegrityProblem::Unreadable(Row::Counter(key)));
+                    default
+                }
+                None => default,
+            })
+        };
+        let mut rows = StoredRows::new(counter(LEAF_COUNT_KEY, 0)?, counter(NODE_COUNT_KEY, 1)?);
+        let sync_point = self
+            .snapshot
+            .get(SYNC_POINT_KEY)
+            .map_err(|error| io::Error::other(error.to_string()))?;
+        if let Some(bytes) = sync_point {
+            let mut bytes = &bytes[..];
+            let sync_point = (|| -> io::Result<(u64, usize, u64)> {
+                let size = bytes.read_u64::<LittleEndian>()?;
+                let level = bytes.read_u64::<LittleEndian>()? as usize;
+                let node = bytes.read_u64::<LittleEndian>()?;
+                Ok((size, level, node))
+            })();
+            match sync_point {
+                Ok(sync_point) => rows.sync_point = Some(sync_point),
+                Err(_) => problems.push(IntegrityProblem::Unreadable(Row::SyncPoint)),
+            }
+        }
+        for (index, value) in self.scan(LEAF_METADATA_PREFIX) {
+            let mut bytes = &value[..];
+            let leaf = rows.leaves.entry(index).or_default();
+            let decoded = (|| -> io::Result<()> {
+                leaf.parent = Some(bytes.read_u64::<LittleEndian>()?);
+                leaf.hash = Some(self.hasher.read_hash(&mut bytes)?);
+                Ok(())
+            })();
+            if decoded.is_err() {
+                problems.push(IntegrityProblem::Unreadable(Row::Leaf(index)));
+            }
+        }
+        for (index, value) in self.scan(LEAF_ELEMENT_PREFIX) {
+            match self.hasher.read_element(&mut &value[..]) {
+                Ok(element) => {
+                    rows.leaves.entry(index).or_default().element_hash =
+                        Some(element.merkle_hash());
+                }
+                Err(_) => problems.push(IntegrityProblem::Unreadable(Row::Leaf(index))),
+            }
+        }
+        for (index, value) in self.scan(NODE_DATA_PREFIX) {
+            let mut bytes = &value[..];
+            let node = (|| -> io::Result<_> {
+                let node_type = bytes.read_u8()?;
+                let the_other_node = bytes.read_u64::<LittleEndian>()?;
+                let hash_of_sibling = self.hasher.read_hash(&mut bytes)?;
+                Ok(match node_type {
+                    0 => StoredNode::Left {
+                        hash_of_sibling,
+                        parent: the_other_node,
+                    },
+                    1 => StoredNode::Right {
+                        hash_of_sibling,
+                        left: the_other_node,
+                    },
+                    _ => return Err(invalid_data(format!("Unknown node type {}", node_type))),
+                })
+            })();
+            match node {
+                Ok(node) => {
+                    rows.nodes.insert(index, node);
+                }
+                Err(_) => problems.push(IntegrityProblem::Unreadable(Row::Node(index))),
+            }
+        }
+        rows.problems = problems;
+        Ok(rows)
+    }
+
+    /// Scan the rows whose keys are prefix followed by an index, and yield
+    /// each value with its index.
+    fn scan(&self, prefix: &'static [u8]) -> impl Iterator<Item = (u64, Box<[u8]>)> + '_ {
+        self.snapshot
+            .iterator(IteratorMode::From(prefix, Direction::Forward))
+            .take_while(move |(key, _)| key.len() == prefix.len() + 8 && key.starts_with(prefix))
+            .map(move |(key, value)| {
+                let mut index = [0; 8];
+                index.copy_from_slice(&key[prefix.len()..]);
+                (u64::from_be_bytes(index), value)
+            })
     }
 }
 
-fn u32_as_bytes(value: u32) -> Vec<u8> {
+fn u64_as_bytes(value: u64) -> Vec<u8> {
     let mut bytes: Vec<u8> = vec![];
-    bytes.write_u32::<LittleEndian>(value).unwrap();
+    bytes.write_u64::<LittleEndian>(value).unwrap();
     bytes
 }
+
+/// Convert a little endian u32 to a little endian u64.
+fn widen(bytes: &[u8]) -> Vec<u8> {
+    let value = (&bytes[..4]).read_u32::<LittleEndian>().unwrap();
+    u64_as_bytes(u64::from(value))
+}
+
+/// Widen the little endian u32 index that follows a prefix of the given
+/// length in a key to a big endian u64, so keys sort by index.
+fn widen_key(key: &[u8], prefix_length: usize) -> Vec<u8> {
+    let index = (&key[prefix_length..]).read_u32::<LittleEndian>().unwrap();
+    let mut new_key = key[..prefix_length].to_vec();
+    new_key.extend(&u64::from(index).to_be_bytes());
+    new_key
+}
@@ -1,6 +1,7 @@
 use super::rocker::{LeafIndex, Node, NodeIndex};
 use super::RocksMerkleTree;
-use crate::{test_helper::StringHasher, MerkleTree, WitnessNode};
+use crate::checkpoint::Checkpointed;
+use crate::{test_helper::StringHasher, LeafStorage, MerkleTree, NotRetained, WitnessNode};
 use tempfile::tempdir;
 
 fn make_tree(characters: &str) -> RocksMerkleTree<StringHasher> {
@@ -21,7 +22,7 @@ fn assert_tree(tree: &RocksMerkleTree<StringHasher>, characters: &str) {
     assert_eq!(tree.len(), expected.len());
     assert_eq!(tree.rocker.num_nodes(), expected.rocker.num_nodes());
     for idx in 0..tree.len() {
-        let index = LeafIndex(idx as u32);
+        let index = LeafIndex(idx as u64);
         assert_eq!(
             tree.rocker.get_leaf_element(index),
             expected.rocker.get_leaf_element(index)
@@ -32,20 +33,23 @@ fn assert_tree(tree: &RocksMerkleTree<StringHasher>, characters: &str) {
         );
     }
     for idx in 0..tree.rocker.num_nodes() {
-        let index = NodeIndex(idx as u32);
-        assert_eq!(tree.rocker.get_node(index), expected.rocker.get_node(index));
+        let index = NodeIndex(idx);
+        assert_eq!(
+            tree.rocker.get_node(index).unwrap(),
+            expected.rocker.get_node(index).unwrap()
+        );
     }
 }
 
-fn assert_leaves(tree: &RocksMerkleTree<StringHasher>, characters: &str, parents: &[u32]) {
+fn assert_leaves(tree: &RocksMerkleTree<StringHasher>, characters: &str, parents: &[u64]) {
     assert_eq!(tree.len(), characters.len());
     assert_eq!(tree.len(), parents.len());
     for (index, (character, parent)) in characters.chars().zip(parents).enumerate() {
-        let leaf_index = LeafIndex(index as u32);
+        let leaf_index = LeafIndex(index as u64);
         let element = tree
             .rocker
             .get_leaf_element(leaf_index)
-            .expect(&format!("'{}' element should exist in tree", character));
+            .unwrap_or_else(|| panic!("'{}' element should exist in tree", character));
         let leaf_data = tree
             .rocker
             .get_leaf_metadata(leaf_index)
@@ -58,12 +62,12 @@ fn assert_leaves(tree: &RocksMerkleTree<StringHasher>, characters: &str, parents
 
 fn node_matches(
     tree: &RocksMerkleTree<StringHasher>,
-    my_index: u32,
+    my_index: u64,
     is_left: bool,
-    other_index: u32, // parent or left, depending
+    other_index: u64, // parent or left, depending
     expected_hash_of_sibling: &str,
 ) -> bool {
-    let node = tree.rocker.get_node(NodeIndex(my_index));
+    let node = tree.rocker.get_node(NodeIndex(my_index)).unwrap();
     let is_match = match &node {
         Node::Empty => panic!("node_matches not expected on empty node"),
         Node::Left {
@@ -72,7 +76,7 @@ fn node_matches(
         } => {
             is_left
                 && *parent == NodeIndex(other_index)
-                && *hash_of_sibling == expected_hash_of_sibling.to_string()
+                && *hash_of_sibling == expected_hash_of_sibling
         }
         Node::Right {
             left,
@@ -80,7 +84,7 @@ fn node_matches(
         } => {
             !is_left
                 && *left == NodeIndex(other_index)
-                && *hash_of_sibling == expected_hash_of_sibling.to_string()
+                && *hash_of_sibling == expected_hash_of_sibling
         }
     };
     if !is_match {
@@ -99,30 +103,30 @@ fn add() {
     tree.add("a".to_string());
     assert_leaves(&tree, "a", &[0]);
     assert_eq!(tree.rocker.num_nodes(), 1);
-    assert_matches!(tree.rocker.get_node(NodeIndex(0)), Node::Empty);
+    assert_matches!(tree.rocker.get_node(NodeIndex(0)).unwrap(), Node::Empty);
     tree.add("b".to_string());
     assert_leaves(&tree, "ab", &[1, 1]);
     assert_eq!(tree.rocker.num_nodes(), 2);
-    assert_matches!(tree.rocker.get_node(NodeIndex(0)), Node::Empty);
+    assert_matches!(tree.rocker.get_node(NodeIndex(0)).unwrap(), Node::Empty);
     assert!(node_matches(&tree, 1, true, 0, "<a|b-0>"));
     tree.add("c".to_string());
     assert_leaves(&tree, "abc", &[1, 1, 2]);
     assert_eq!(tree.rocker.num_nodes(), 4);
-    assert_matches!(tree.rocker.get_node(NodeIndex(0)), Node::Empty);
+    assert_matches!(tree.rocker.get_node(NodeIndex(0)).unwrap(), Node::Empty);
     assert!(node_matches(&tree, 1, true, 3, "<c|c-0>"));
     assert!(node_matches(&tree, 2, false, 1, "<a|b-0>"));
     assert!(node_matches(&tree, 3, true, 0, "<<a|b-0>|<c|c-0>-1>"));
     tree.add("d".to_string());
     assert_leaves(&tree, "abcd", &[1, 1, 2, 2]);
     assert_eq!(tree.rocker.num_nodes(), 4);
-    assert_matches!(tree.rocker.get_node(NodeIndex(0)), Node::Empty);
+    assert_matches!(tree.rocker.get_node(NodeIndex(0)).unwrap(), Node::Empty);
     assert!(node_matches(&tree, 1, true, 3, "<c|d-0>"));
     assert!(node_matches(&tree, 2, false, 1, "<a|b-0>"));
     assert!(node_matches(&tree, 3, true, 0, "<<a|b-0>|<c|d-0>-1>"));
     tree.add("e".to_string());
     assert_leaves(&tree, "abcde", &[1, 1, 2, 2, 4]);
     assert_eq!(tree.rocker.num_nodes(), 7);
-    assert_matches!(tree.rocker.get_node(NodeIndex(0)), Node::Empty);
+    assert_matches!(tree.rocker.get_node(NodeIndex(0)).unwrap(), Node::Empty);
     assert!(node_matches(&tree, 1, true, 3, "<c|d-0>"));
     assert!(node_matches(&tree, 2, false, 1, "<a|b-0>"));
     assert!(node_matches(&tree, 3, true, 6, "<<e|e-0>|<e|e-0>-1>"));
@@ -138,7 +142,7 @@ fn add() {
     tree.add("f".to_string());
     assert_leaves(&tree, "abcdef", &[1, 1, 2, 2, 4, 4]);
     assert_eq!(tree.rocker.num_nodes(), 7);
-    assert_matches!(tree.rocker.get_node(NodeIndex(0)), Node::Empty);
+    assert_matches!(tree.rocker.get_node(NodeIndex(0)).unwrap(), Node::Empty);
     assert!(node_matches(&tree, 1, true, 3, "<c|d-0>"));
     assert!(node_matches(&tree, 2, false, 1, "<a|b-0>"));
     assert!(node_matches(&tree, 3, true, 6, "<<e|f-0>|<e|f-0>-1>"));
@@ -154,7 +158,7 @@ fn add() {
     tree.add("g".to_string());
     assert_leaves(&tree, "abcdefg", &[1, 1, 2, 2, 4, 4, 7]);
     assert_eq!(tree.rocker.num_nodes(), 8);
-    assert_matches!(tree.rocker.get_node(NodeIndex(0)), Node::Empty);
+    assert_matches!(tree.rocker.get_node(NodeIndex(0)).unwrap(), Node::Empty);
     assert!(node_matches(&tree, 1, true, 3, "<c|d-0>"));
     assert!(node_matches(&tree, 2, false, 1, "<a|b-0>"));
     assert!(node_matches(&tree, 3, true, 6, "<<e|f-0>|<g|g-0>-1>"));
@@ -171,7 +175,7 @@ fn add() {
     tree.add("h".to_string());
     assert_leaves(&tree, "abcdefgh", &[1, 1, 2, 2, 4, 4, 7, 7]);
     assert_eq!(tree.rocker.num_nodes(), 8);
-    assert_matches!(tree.rocker.get_node(NodeIndex(0)), Node::Empty);
+    assert_matches!(tree.rocker.get_node(NodeIndex(0)).unwrap(), Node::Empty);
     assert!(node_matches(&tree, 1, true, 3, "<c|d-0>"));
     assert!(node_matches(&tree, 2, false, 1, "<a|b-0>"));
     assert!(node_matches(&tree, 3, true, 6, "<<e|f-0>|<g|h-0>-1>"));
@@ -188,7 +192,7 @@ fn add() {
     tree.add("i".to_string());
     assert_leaves(&tree, "abcdefghi", &[1, 1, 2, 2, 4, 4, 7, 7, 8]);
     assert_eq!(tree.rocker.num_nodes(), 12);
-    assert_matches!(tree.rocker.get_node(NodeIndex(0)), Node::Empty);
+    assert_matches!(tree.rocker.get_node(NodeIndex(0)).unwrap(), Node::Empty);
     assert!(node_matches(&tree, 1, true, 3, "<c|d-0>"));
     assert!(node_matches(&tree, 2, false, 1, "<a|b-0>"));
     assert!(node_matches(&tree, 3, true, 6, "<<e|f-0>|<g|h-0>-1>"));
@@ -218,61 +222,61 @@ fn add() {
 fn truncate() {
     color_backtrace::install();
     let mut tree = make_tree("");
-    tree.truncate(0);
+    tree.truncate(0).unwrap();
     assert_tree(&tree, "");
     tree = make_full_tree();
-    tree.truncate(0);
+    tree.truncate(0).unwrap();
     assert_tree(&tree, "");
     tree = make_full_tree();
-    tree.truncate(1);
+    tree.truncate(1).unwrap();
     assert_tree(&tree, "a");
     tree = make_full_tree();
-    tree.truncate(2);
+    tree.truncate(2).unwrap();
     assert_tree(&tree, "ab");
     tree = make_full_tree();
-    tree.truncate(3);
+    tree.truncate(3).unwrap();
     assert_tree(&tree, "abc");
     tree = make_full_tree();
-    tree.truncate(4);
+    tree.truncate(4).unwrap();
     assert_tree(&tree, "abcd");
     tree = make_full_tree();
-    tree.truncate(5);
+    tree.truncate(5).unwrap();
     assert_tree(&tree, "abcde");
     tree = make_full_tree();
-    tree.truncate(6);
+    tree.truncate(6).unwrap();
     assert_tree(&tree, "abcdef");
     tree = make_full_tree();
-    tree.truncate(7);
+    tree.truncate(7).unwrap();
     assert_tree(&tree, "abcdefg");
     tree = make_full_tree();
-    tree.truncate(8);
+    tree.truncate(8).unwrap();
     assert_tree(&tree, "abcdefgh");
     tree = make_full_tree();
-    tree.truncate(9);
+    tree.truncate(9).unwrap();
     assert_tree(&tree, "abcdefghi");
     tree = make_full_tree();
-    tree.truncate(10);
+    tree.truncate(10).unwrap();
     assert_tree(&tree, "abcdefghij");
     tree = make_full_tree();
-    tree.truncate(11);
+    tree.truncate(11).unwrap();
     assert_tree(&tree, "abcdefghijk");
     tree = make_full_tree();
-    tree.truncate(12);
+    tree.truncate(12).unwrap();
     assert_tree(&tree, "abcdefghijkl");
     tree = make_full_tree();
-    tree.truncate(13);
+    tree.truncate(13).unwrap();
     assert_tree(&tree, "abcdefghijklm");
     tree = make_full_tree();
-    tree.truncate(14);
+    tree.truncate(14).unwrap();
     assert_tree(&tree, "abcdefghijklmn");
     tree = make_full_tree();
-    tree.truncate(15);
+    tree.truncate(15).unwrap();
     assert_tree(&tree, "abcdefghijklmno");
     tree = make_full_tree();
-    tree.truncate(16);
+    tree.truncate(16).unwrap();
     assert_tree(&tree, "abcdefghijklmnop");
     tree = make_full_tree();
-    tree.truncate(17);
+    tree.truncate(17).unwrap();
     assert_tree(&tree, "abcdefghijklmnop");
 }
 
@@ -292,37 +296,37 @@ fn iteration_and_get() {
     color_backtrace::install();
     let mut tree = make_tree("");
     {
-        assert_eq!(tree.get(0), None);
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(0), Ok(None));
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), None);
     }
 
     tree.add("a".to_string());
     {
-        assert_eq!(tree.get(1), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(1), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), None);
     }
 
     tree.add("b".to_string());
     {
-        assert_eq!(tree.get(2), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(2), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), None);
     }
     tree.add("c".to_string());
     {
-        assert_eq!(tree.get(3), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(3), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -330,12 +334,12 @@ fn iteration_and_get() {
     }
     tree.add("d".to_string());
     {
-        assert_eq!(tree.get(4), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        assert_eq!(*tree.get(3).unwrap(), "d".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(4), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        assert_eq!(*tree.get(3).unwrap().unwrap(), "d".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -345,13 +349,13 @@ fn iteration_and_get() {
 
     tree.add("e".to_string());
     {
-        assert_eq!(tree.get(5), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        assert_eq!(*tree.get(3).unwrap(), "d".to_string());
-        assert_eq!(*tree.get(4).unwrap(), "e".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(5), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        assert_eq!(*tree.get(3).unwrap().unwrap(), "d".to_string());
+        assert_eq!(*tree.get(4).unwrap().unwrap(), "e".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -362,14 +366,14 @@ fn iteration_and_get() {
 
     tree.add("f".to_string());
     {
-        assert_eq!(tree.get(6), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        assert_eq!(*tree.get(3).unwrap(), "d".to_string());
-        assert_eq!(*tree.get(4).unwrap(), "e".to_string());
-        assert_eq!(*tree.get(5).unwrap(), "f".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(6), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        assert_eq!(*tree.get(3).unwrap().unwrap(), "d".to_string());
+        assert_eq!(*tree.get(4).unwrap().unwrap(), "e".to_string());
+        assert_eq!(*tree.get(5).unwrap().unwrap(), "f".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -382,7 +386,7 @@ fn iteration_and_get() {
     for i in 0..100 {
         tree.add(i.to_string());
     }
-    let mut iter = tree.iter_notes();
+    let mut iter = tree.iter_notes().map(Result::unwrap);
     for char in ["a", "b", "c", "d", "e", "f"].iter() {
         assert_eq!(iter.next(), Some(char.to_string()));
     }
@@ -637,3 +641,552 @@ fn witness_path() {
         ]
     );
 }
+
+#[test]
+fn past_witness() {
+    let characters = "abcdefghijk";
+    let directory = tempdir().unwrap();
+    let mut tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in characters.chars() {
+        tree.add(character.to_string());
+    }
+    assert!(tree.past_witness(0, 0).is_none());
+    assert!(tree.past_witness(3, 3).is_none());
+    assert!(tree.past_witness(0, 12).is_none());
+
+    for past_size in 1..=characters.len() {
+        let past_directory = tempdir().unwrap();
+        let mut past_tree =
+            RocksMerkleTree::new_with_size(StringHasher::new(), past_directory.path(), 5);
+        for character in characters[..past_size].chars() {
+            past_tree.add(character.to_string());
+        }
+        for position in 0..past_size {
+            let witness = tree.past_witness(position, past_size).expect("leaf exists");
+            assert_eq!(Some(witness), past_tree.witness(position));
+        }
+    }
+}
+
+#[test]
+fn snapshot() {
+    let directory = tempdir().unwrap();
+    let mut tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in "abcde".chars() {
+        tree.add(character.to_string());
+    }
+    let root = tree.root_hash();
+    let witness = tree.witness(2);
+    let snapshot = tree.snapshot();
+
+    tree.add("f".to_string());
+    assert_eq!(snapshot.len(), 5);
+    assert_eq!(snapshot.root_hash(), root);
+    assert_eq!(snapshot.witness(2), witness);
+    assert_eq!(snapshot.get(4), Ok(Some("e".to_string())));
+    assert_eq!(snapshot.get(5), Ok(None));
+    assert!(!snapshot.contains(&"f".to_string()));
+    assert_eq!(snapshot.iter_notes().count(), 5);
+
+    // truncating the tree doesn't change what the snapshot sees
+    tree.truncate(3).unwrap();
+    tree.add("x".to_string());
+    assert_eq!(snapshot.root_hash(), root);
+    assert_eq!(snapshot.witness(2), witness);
+    assert_eq!(snapshot.get(3), Ok(Some("d".to_string())));
+    assert_eq!(
+        snapshot
+            .iter_notes()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>(),
+        vec!["a", "b", "c", "d", "e"]
+    );
+}
+
+#[test]
+fn checkpoints() {
+    let directory = tempdir().unwrap();
+    let mut tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in "abc".chars() {
+        tree.add(character.to_string());
+    }
+    tree.checkpoint(b"first").unwrap();
+    let root = tree.root_hash();
+    tree.add("d".to_string());
+    tree.checkpoint(b"second").unwrap();
+    tree.add("e".to_string());
+    tree.checkpoint(b"third").unwrap();
+    assert_eq!(
+        tree.checkpoints().unwrap(),
+        vec![
+            (b"first".to_vec(), 3),
+            (b"second".to_vec(), 4),
+            (b"third".to_vec(), 5)
+        ]
+    );
+
+    assert!(!tree.rewind_to(b"missing").unwrap());
+    assert_eq!(tree.len(), 5);
+    assert!(tree.rewind_to(b"first").unwrap());
+    assert_eq!(tree.len(), 3);
+    assert_eq!(tree.root_hash(), root);
+    assert_eq!(tree.checkpoints().unwrap(), vec![(b"first".to_vec(), 3)]);
+
+    tree.add("x".to_string());
+    tree.checkpoint(b"second").unwrap();
+    tree.truncate(2).unwrap();
+    assert!(tree.checkpoints().unwrap().is_empty());
+
+    tree.set_reorg_depth(1).unwrap();
+    tree.checkpoint(b"first").unwrap();
+    tree.add("y".to_string());
+    tree.checkpoint(b"second").unwrap();
+    assert_eq!(tree.checkpoints().unwrap(), vec![(b"second".to_vec(), 3)]);
+}
+
+#[test]
+fn panicking_batch() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let directory = tempdir().unwrap();
+    let mut tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    tree.add("a".to_string());
+    let panicked = catch_unwind(AssertUnwindSafe(|| {
+        tree.rocker.batch(|| {
+            tree.rocker.set_num_leaves(0);
+            panic!("batch failed");
+        })
+    }));
+    assert!(panicked.is_err());
+    assert_eq!(tree.len(), 1);
+
+    // writes after the failed batch aren't held back
+    tree.add("b".to_string());
+    drop(tree);
+    let tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    assert_eq!(tree.len(), 2);
+}
+
+#[test]
+fn hashes_only() {
+    let directory = tempdir().unwrap();
+    let full_directory = tempdir().unwrap();
+    let mut tree = RocksMerkleTree::new_with_storage(
+        StringHasher::new(),
+        directory.path(),
+        5,
+        LeafStorage::HashesOnly,
+    );
+    let mut full = RocksMerkleTree::new_with_size(StringHasher::new(), full_directory.path(), 5);
+    for character in "abcde".chars() {
+        tree.add(character.to_string());
+        full.add(character.to_string());
+    }
+    assert_eq!(tree.root_hash(), full.root_hash());
+    assert_eq!(tree.past_root(3), full.past_root(3));
+    assert_eq!(tree.witness(2), full.witness(2));
+    assert_eq!(tree.past_witness(1, 3), full.past_witness(1, 3));
+    assert!(tree.contains(&"c".to_string()));
+    assert!(!tree.contained(&"e".to_string(), 4));
+
+    assert_eq!(tree.get(2), Err(NotRetained { position: 2 }));
+    assert_eq!(tree.get(5), Ok(None));
+    assert_eq!(full.get(2), Ok(Some("c".to_string())));
+    assert_eq!(
+        tree.iter_notes().collect::<Vec<_>>(),
+        (0..5)
+            .map(|position| Err(NotRetained { position }))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        tree.iter_range(1..3).rev().collect::<Vec<_>>(),
+        vec![
+            Err(NotRetained { position: 2 }),
+            Err(NotRetained { position: 1 })
+        ]
+    );
+
+    tree.truncate(3).unwrap();
+    full.truncate(3).unwrap();
+    tree.add("x".to_string());
+    full.add("x".to_string());
+    assert_eq!(tree.root_hash(), full.root_hash());
+    drop(tree);
+
+    let reopened = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    assert_eq!(reopened.get(0), Err(NotRetained { position: 0 }));
+}
+
+#[test]
+#[should_panic(expected = "leaf storage")]
+fn storage_mode_mismatch() {
+    let directory = tempdir().unwrap();
+    let mut tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    tree.add("a".to_string());
+    drop(tree);
+    RocksMerkleTree::new_with_storage(
+        StringHasher::new(),
+        directory.path(),
+        5,
+        LeafStorage::HashesOnly,
+    );
+}
+
+#[test]
+fn with_config() {
+    use crate::config::{ConfigError, TreeConfig};
+
+    let directory = tempdir().unwrap();
+    let config = TreeConfig {
+        depth: 5,
+        reorg_depth: Some(1),
+        leaf_storage: Some(LeafStorage::HashesOnly),
+    };
+    let mut tree =
+        RocksMerkleTree::with_config(StringHasher::new(), directory.path(), config).unwrap();
+    for character in "abc".chars() {
+        tree.add(character.to_string());
+    }
+    assert_eq!(tree.get(0), Err(NotRetained { position: 0 }));
+    assert_eq!(tree.witness(0).unwrap().auth_path.len(), 4);
+    tree.checkpoint(b"one").unwrap();
+    tree.checkpoint(b"two").unwrap();
+    assert_eq!(tree.checkpoints().unwrap(), vec![(b"two".to_vec(), 3)]);
+
+    let too_deep = TreeConfig {
+        depth: 65,
+        ..TreeConfig::default()
+    };
+    let other_directory = tempdir().unwrap();
+    let error = RocksMerkleTree::with_config(StringHasher::new(), other_directory.path(), too_deep)
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(
+        error.into_inner().unwrap().downcast_ref::<ConfigError>(),
+        Some(&ConfigError::UnsupportedDepth(65))
+    );
+}
+
+#[test]
+fn migrates_u32_indices() {
+    use rocksdb::{IteratorMode, DB};
+
+    let directory = tempdir().unwrap();
+    let mut tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in "abcdefg".chars() {
+        tree.add(character.to_string());
+    }
+    let root_hash = tree.root_hash();
+    let witnesses: Vec<_> = (0..7).map(|position| tree.witness(position)).collect();
+    drop(tree);
+
+    // Rewrite the database the way it was stored before indices were u64
+    {
+        let db = DB::open_default(directory.path()).unwrap();
+        // keys had little endian u32 indices
+        let narrow_key = |key: &[u8], prefix_length: usize| {
+            let mut index = [0; 8];
+            index.copy_from_slice(&key[prefix_length..]);
+            let mut old_key = key[..prefix_length].to_vec();
+            old_key.extend(&(u64::from_be_bytes(index) as u32).to_le_bytes());
+            old_key
+        };
+        let entries: Vec<_> = db.iterator(IteratorMode::Start).collect();
+        for (key, value) in entries {
+            let (old_key, old_value) = if key.starts_with(b"LeafData") {
+                let mut old_value = value[..4].to_vec();
+                old_value.extend(&value[8..]);
+                (narrow_key(&key, 8), old_value)
+            } else if key.starts_with(b"LeafElement") {
+                (narrow_key(&key, 11), value.to_vec())
+            } else if key.starts_with(b"NodeData") {
+                let mut old_value = value[..5].to_vec();
+                old_value.extend(&value[9..]);
+                (narrow_key(&key, 8), old_value)
+            } else if &key[..] == b"LeafCount" || &key[..] == b"NodeCount" {
+                (key.to_vec(), value[..4].to_vec())
+            } else {
+                continue;
+            };
+            db.delete(&key).unwrap();
+            db.put(old_key, old_value).unwrap();
+        }
+        db.delete("IndexWidth").unwrap();
+    }
+
+    let mut tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    assert_eq!(tree.len(), 7);
+    assert_eq!(tree.root_hash(), root_hash);
+    for (position, witness) in witnesses.iter().enumerate() {
+        assert_eq!(&tree.witness(position), witness);
+    }
+    assert_eq!(tree.get(6), Ok(Some("g".to_string())));
+    tree.add("h".to_string());
+    assert_eq!(tree.len(), 8);
+}
+
+#[test]
+fn iter_range() {
+    let tree = make_tree("abcdefghijk");
+    let collect = |iter: &mut dyn Iterator<Item = Result<String, NotRetained>>| {
+        iter.collect::<Result<String, _>>().unwrap()
+    };
+    assert_eq!(collect(&mut tree.iter_range(3..7)), "defg");
+    assert_eq!(collect(&mut tree.iter_range(3..7).rev()), "gfed");
+    assert_eq!(collect(&mut tree.iter_range(8..usize::MAX)), "ijk");
+    assert_eq!(tree.iter_range(11..20).count(), 0);
+
+    let mut iter = tree.iter_range(0..3);
+    assert_eq!(iter.next_back(), Some(Ok("c".to_string())));
+    assert_eq!(iter.next(), Some(Ok("a".to_string())));
+    assert_eq!(iter.next(), Some(Ok("b".to_string())));
+    assert_eq!(iter.next_back(), None);
+}
+
+#[test]
+fn iter_leaves() {
+    let tree = make_tree("abcdefghijk");
+    let leaves: Vec<_> = tree.iter_leaves().map(Result::unwrap).collect();
+    assert_eq!(leaves.len(), 11);
+    for (position, leaf) in leaves.iter().enumerate() {
+        assert_eq!(leaf.position, position);
+        assert_eq!(leaf.hash, tree.get(position).unwrap().unwrap());
+        assert_eq!(leaf.element, tree.get(position).unwrap());
+    }
+
+    let directory = tempdir().unwrap();
+    let mut hashes_only = RocksMerkleTree::new_with_storage(
+        StringHasher::new(),
+        directory.path(),
+        5,
+        LeafStorage::HashesOnly,
+    );
+    for character in "abc".chars() {
+        hashes_only.add(character.to_string());
+    }
+    hashes_only.truncate(2).unwrap();
+    let leaves: Vec<_> = hashes_only.iter_leaves().map(Result::unwrap).collect();
+    assert_eq!(leaves.len(), 2);
+    assert_eq!(leaves[1].position, 1);
+    assert_eq!(leaves[1].hash, "b".to_string());
+    assert_eq!(leaves[1].element, None);
+}
+
+#[test]
+fn iterators_during_modification() {
+    let directory = tempdir().unwrap();
+    let mut tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in "abcdef".chars() {
+        tree.add(character.to_string());
+    }
+    let reader = RocksMerkleTree {
+        hasher: tree.hasher.clone(),
+        rocker: tree.rocker.handle(),
+        tree_depth: tree.tree_depth,
+        leaf_storage: tree.leaf_storage,
+    };
+
+    // leaves added after the iterator was created aren't yielded
+    let leaves = reader.iter_leaves();
+    tree.add("g".to_string());
+    assert_eq!(leaves.count(), 6);
+
+    // the iterators read the tree as it was when they were created
+    let mut notes = reader.iter_notes().map(Result::unwrap);
+    let mut range = reader.iter_range(0..7);
+    assert_eq!(notes.next(), Some("a".to_string()));
+    assert_eq!(range.next_back(), Some(Ok("g".to_string())));
+    tree.truncate(3).unwrap();
+    tree.add("x".to_string());
+    assert_eq!(notes.collect::<String>(), "bcdefg");
+    assert_eq!(range.next_back(), Some(Ok("f".to_string())));
+    assert_eq!(range.next(), Some(Ok("a".to_string())));
+
+    assert_eq!(
+        reader.iter_notes().collect::<Result<String, _>>(),
+        Ok("abcx".to_string())
+    );
+}
+
+#[test]
+fn subtree_root() {
+    use crate::linked::LinkedMerkleTree;
+
+    let directory = tempdir().unwrap();
+    let mut tree = RocksMerkleTree::new_with_storage(
+        StringHasher::new(),
+        directory.path(),
+        5,
+        LeafStorage::HashesOnly,
+    );
+    let mut expected = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in "abcdefghijk".chars() {
+        tree.add(character.to_string());
+        expected.add(character.to_string());
+    }
+    for level in 0..5 {
+        for index in 0..3 {
+            assert_eq!(
+                tree.subtree_root(level, index),
+                expected.subtree_root(level, index)
+            );
+            assert_eq!(
+                tree.subtree_witness(level, index),
+                expected.subtree_witness(level, index)
+            );
+        }
+    }
+    let witness = tree.subtree_witness(2, 1).unwrap();
+    let root = tree.subtree_root(2, 1).unwrap();
+    assert!(witness.verify_subtree(&StringHasher {}, 2, &root));
+}
+
+#[test]
+fn verify_integrity() {
+    use crate::integrity::IntegrityProblem;
+
+    let directory = tempdir().unwrap();
+    let mut tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 6);
+    for character in "abcdefghijklm".chars() {
+        tree.add(character.to_string());
+    }
+    let report = tree.verify_integrity().unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert_eq!(report.leaves_checked, 13);
+
+    let parent = tree.rocker.get_leaf_parent(LeafIndex(12));
+    if let Node::Left { parent: above, .. } = tree.rocker.get_node(parent).unwrap() {
+        tree.rocker.set_node(
+            parent,
+            &Node::Left {
+                hash_of_sibling: "x".to_string(),
+                parent: above,
+            },
+        );
+    }
+    assert!(tree
+        .verify_integrity()
+        .unwrap()
+        .problems
+        .contains(&IntegrityProblem::HashMismatch(parent.0)));
+
+    tree.rocker.set_num_nodes(tree.rocker.num_nodes() + 1);
+    assert!(tree
+        .verify_integrity()
+        .unwrap()
+        .problems
+        .iter()
+        .any(|problem| matches!(problem, IntegrityProblem::CounterMismatch { .. })));
+}
+
+#[test]
+fn export_and_import_state() {
+    use crate::linked::LinkedMerkleTree;
+
+    let characters = "abcdefghijklmnopqrstu";
+    let mut full = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    for character in characters.chars() {
+        full.add(character.to_string());
+    }
+    for num_leaves in [0, 1, 7, 8, 13] {
+        let mut expected = full.clone();
+        expected.truncate(num_leaves).unwrap();
+        let mut state = vec![];
+        expected.export_state(&mut state).unwrap();
+
+        // leaves left behind by truncating to empty aren't mistaken for
+        // the ones the import skips
+        let directory = tempdir().unwrap();
+        let mut tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 6);
+        for character in "xyzxyzxyzxyzxyz".chars() {
+            tree.add(character.to_string());
+        }
+        tree.truncate(0).unwrap();
+        tree.import_state(&mut &state[..]).unwrap();
+        assert_eq!(tree.len(), num_leaves);
+        assert_eq!(tree.root_hash(), expected.root_hash());
+        assert_eq!(tree.witness(0).is_some(), num_leaves == 1);
+        let report = tree.verify_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        let mut exported = vec![];
+        tree.export_state(&mut exported).unwrap();
+        assert_eq!(exported, state);
+
+        // the imported state survives reopening
+        drop(tree);
+        let mut tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 6);
+        for character in characters.chars().skip(num_leaves) {
+            tree.add(character.to_string());
+        }
+        assert_eq!(tree.root_hash(), full.root_hash());
+        let error = tree.import_state(&mut &state[..]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+        for past_size in num_leaves.max(1)..=characters.len() {
+            assert_eq!(tree.past_root(past_size), full.past_root(past_size));
+        }
+        for position in num_leaves..characters.len() {
+            assert_eq!(tree.witness(position), full.witness(position));
+        }
+        let report = tree.verify_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        let mut exported = vec![];
+        tree.export_state(&mut exported).unwrap();
+        let mut expected_state = vec![];
+        full.export_state(&mut expected_state).unwrap();
+        assert_eq!(exported, expected_state);
+        if num_leaves == 8 {
+            assert_eq!(tree.truncate(8), Err(NotRetained { position: 7 }));
+        }
+    }
+}
+
+#[test]
+fn malformed_node_rows() {
+    let directory = tempdir().unwrap();
+    let mut tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in "abcde".chars() {
+        tree.add(character.to_string());
+    }
+    drop(tree);
+    {
+        let db = rocksdb::DB::open_default(directory.path()).unwrap();
+        let mut unknown_type = b"NodeData".to_vec();
+        unknown_type.extend(&1u64.to_be_bytes());
+        let mut value = db.get(&unknown_type).unwrap().unwrap();
+        value[0] = 7;
+        db.put(&unknown_type, value).unwrap();
+        let mut cut_short = b"NodeData".to_vec();
+        cut_short.extend(&2u64.to_be_bytes());
+        db.put(&cut_short, [1, 0, 0]).unwrap();
+    }
+
+    let tree = RocksMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    assert!(tree.rocker.get_node(NodeIndex(1)).is_err());
+    assert!(tree.rocker.get_node(NodeIndex(2)).is_err());
+    assert!(tree.rocker.get_node(NodeIndex(1000)).is_err());
+    assert_eq!(tree.witness(0), None);
+    assert_eq!(tree.witness(2), None);
+}
+
+#[test]
+fn depth_at_leaf_count() {
+    use super::depth_at_leaf_count;
+    // including counts that f32 can't represent exactly
+    for &(count, depth) in [
+        (0, 0),
+        (1, 1),
+        (2, 2),
+        (3, 3),
+        (4, 3),
+        (5, 4),
+        (1 << 25, 26),
+        ((1 << 25) + 1, 27),
+        (1 << 33, 34),
+        ((1 << 33) + 1, 35),
+    ]
+    .iter()
+    {
+        assert_eq!(depth_at_leaf_count(count), depth, "{} leaves", count);
+    }
+}
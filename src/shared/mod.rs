@@ -0,0 +1,132 @@
+//! A handle that lets several threads use one Merkle tree at once.
+//!
+//! The MerkleTree trait needs `&mut self` to add leaves, so a server that
+//! builds witnesses for many clients while a block importer appends would
+//! otherwise have to put the whole tree behind a Mutex. SharedMerkleTree
+//! publishes versions of the tree instead: a reader takes the latest one and
+//! works on it without holding any lock, while a writer modifies a
+//! copy-on-write clone and then swaps it in. Readers never wait for a
+//! writer, and a writer never waits for readers.
+//!
+//! Only trees that are cheap to clone, which are the in-memory ones, can be
+//! shared this way. Each write clones the tree, so it should also be cheap
+//! to modify a clone: LinkedMerkleTree only copies the parts it changes.
+
+use super::{HashableElement, MerkleHasher, MerkleTree, NotRetained, Witness};
+use std::sync::{Arc, Mutex, PoisonError};
+
+type Element<M> = <<M as MerkleTree>::Hasher as MerkleHasher>::Element;
+type Hash<M> = <Element<M> as HashableElement>::Hash;
+
+/// Clonable, thread-safe handle to a Merkle tree. Every clone refers to the
+/// same underlying tree.
+///
+/// The convenience methods each read the latest version, so two consecutive
+/// calls may observe different trees if a writer got in between them. To
+/// answer several questions about the same version of the tree (for example,
+/// a witness together with the number of notes it covers), hold on to the
+/// tree returned by `read` for the duration.
+pub struct SharedMerkleTree<M: MerkleTree + Clone> {
+    /// The latest version of the tree. The lock is only held long enough to
+    /// clone or replace the Arc.
+    current: Arc<Mutex<Arc<M>>>,
+    /// Held by the writer for the whole of a modification, so that two
+    /// writers can't both start from the same version and lose an update.
+    writer: Arc<Mutex<()>>,
+}
+
+impl<M: MerkleTree + Clone> SharedMerkleTree<M> {
+    /// Take ownership of the tree and wrap it so it can be shared.
+    pub fn new(tree: Box<M>) -> Self {
+        SharedMerkleTree {
+            current: Arc::new(Mutex::new(Arc::from(tree))),
+            writer: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Get the latest version of the tree. Later writes don't change it, and
+    /// don't wait for it to be dropped.
+    pub fn read(&self) -> Arc<M> {
+        // The lock only guards an Arc, which is never left half replaced
+        self.current
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Modify a clone of the latest version of the tree, and then publish
+    /// it, waiting for any other writer to finish first. Readers keep seeing
+    /// the previous version until `update` returns.
+    ///
+    /// If modify panics, nothing is published and the tree is unchanged.
+    pub fn update<R>(&self, modify: impl FnOnce(&mut M) -> R) -> R {
+        let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut tree = self.read();
+        let result = modify(Arc::make_mut(&mut tree));
+        *self.current.lock().unwrap_or_else(PoisonError::into_inner) = tree;
+        result
+    }
+
+    /// Append a new leaf element to the tree.
+    pub fn add(&self, element: Element<M>) {
+        self.update(|tree| tree.add(element))
+    }
+
+    /// Truncate the tree to the values it contained when it had past_size
+    /// elements.
+    pub fn truncate(&self, past_size: usize) -> Result<(), NotRetained> {
+        self.update(|tree| tree.truncate(past_size))
+    }
+
+    /// Get a clone of the element at position.
+    pub fn get(&self, position: usize) -> Result<Option<Element<M>>, NotRetained> {
+        self.read().get(position)
+    }
+
+    /// Get the number of leaf nodes in the tree.
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    /// Determine whether the tree has no leaf nodes.
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    /// Get the hash of the current root element in the tree.
+    pub fn root_hash(&self) -> Option<Hash<M>> {
+        self.read().root_hash()
+    }
+
+    /// Calculate what the root hash was when the tree contained `past_size`
+    /// elements.
+    pub fn past_root(&self, past_size: usize) -> Option<Hash<M>> {
+        self.read().past_root(past_size)
+    }
+
+    /// Determine whether the tree contains a value at its current size.
+    pub fn contains(&self, value: &Element<M>) -> bool {
+        self.read().contains(value)
+    }
+
+    /// Construct the proof that the leaf node at `position` exists.
+    ///
+    /// The witness carries the size and root hash of the tree it was
+    /// generated against, so it stays verifiable (using `past_root`)
+    /// after more notes are added.
+    pub fn witness(&self, position: usize) -> Option<Witness<M::Hasher>> {
+        self.read().witness(position)
+    }
+}
+
+impl<M: MerkleTree + Clone> Clone for SharedMerkleTree<M> {
+    fn clone(&self) -> Self {
+        SharedMerkleTree {
+            current: self.current.clone(),
+            writer: self.writer.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
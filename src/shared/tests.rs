@@ -0,0 +1,148 @@
+use super::SharedMerkleTree;
+use crate::linked::LinkedMerkleTree;
+use crate::test_helper::{MixHasher, StringHasher};
+use crate::{HashableElement, MerkleTree};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+fn make_tree() -> SharedMerkleTree<LinkedMerkleTree<StringHasher>> {
+    SharedMerkleTree::new(LinkedMerkleTree::new_with_size(StringHasher::new(), 8))
+}
+
+#[test]
+fn clones_share_a_tree() {
+    let tree = make_tree();
+    let other = tree.clone();
+    assert!(other.is_empty());
+    tree.add("a".to_string());
+    tree.add("b".to_string());
+    assert_eq!(other.len(), 2);
+    assert_eq!(other.get(1), Ok(Some("b".to_string())));
+    assert!(other.contains(&"a".to_string()));
+    assert_eq!(other.root_hash(), tree.read().root_hash());
+
+    let witness = other.witness(0).expect("leaf should exist");
+    assert!(witness.verify(&StringHasher {}, &"a".to_string()));
+    other.add("c".to_string());
+    assert_eq!(tree.past_root(witness.tree_size), Some(witness.root_hash));
+
+    tree.truncate(1).unwrap();
+    assert_eq!(other.len(), 1);
+}
+
+#[test]
+fn readers_see_consistent_trees_while_writing() {
+    let tree = SharedMerkleTree::new(LinkedMerkleTree::new_with_size(MixHasher::new(), 16));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let tree = tree.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let hasher = MixHasher {};
+                let mut roots = HashMap::new();
+                while !done.load(Ordering::SeqCst) {
+                    let guard = tree.read();
+                    let len = guard.len();
+                    if len == 0 {
+                        continue;
+                    }
+                    let position = roots.len() % len;
+                    let element = guard.get(position).unwrap().expect("leaf should exist");
+                    assert_eq!(element, position as u64);
+                    let witness = guard.witness(position).expect("leaf should exist");
+                    let root = guard.root_hash().expect("tree isn't empty");
+                    assert_eq!(witness.tree_size, len);
+                    assert_eq!(witness.root_hash, root);
+                    assert!(witness.verify(&hasher, &element.merkle_hash()));
+                    roots.insert(len, root);
+
+                    // the convenience methods may see a later version, but
+                    // never one that disagrees with this one
+                    let latest = tree.witness(position).expect("leaf should exist");
+                    assert!(latest.tree_size >= len);
+                    assert_eq!(tree.get(position), Ok(Some(element)));
+                }
+                roots
+            })
+        })
+        .collect();
+
+    for index in 0..2000 {
+        tree.add(index);
+        if index % 10 == 9 {
+            thread::yield_now();
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+
+    // every root a reader saw is the one the tree had at that size, so the
+    // readers agree with each other
+    for reader in readers {
+        let roots = reader.join().expect("reader thread panicked");
+        for (len, root) in roots {
+            assert_eq!(tree.past_root(len), Some(root));
+        }
+    }
+    assert_eq!(tree.len(), 2000);
+}
+
+#[test]
+fn writers_dont_wait_for_readers() {
+    let tree = make_tree();
+    tree.add("a".to_string());
+    let version = tree.read();
+    let root = version.root_hash();
+
+    // with the old version still held, the writer carries on regardless
+    tree.add("b".to_string());
+    tree.truncate(0).unwrap();
+    tree.add("c".to_string());
+    assert_eq!(version.len(), 1);
+    assert_eq!(version.root_hash(), root);
+    assert_eq!(tree.get(0), Ok(Some("c".to_string())));
+}
+
+#[test]
+fn failed_update_publishes_nothing() {
+    let tree = make_tree();
+    tree.add("a".to_string());
+    let other = tree.clone();
+    let result = thread::spawn(move || {
+        other.update(|tree| {
+            tree.add("b".to_string());
+            panic!("writer failed");
+        })
+    })
+    .join();
+    assert!(result.is_err());
+    assert_eq!(tree.len(), 1);
+    tree.add("c".to_string());
+    assert_eq!(tree.get(1), Ok(Some("c".to_string())));
+}
+
+#[test]
+fn appends_dont_copy_the_tree() {
+    let tree = SharedMerkleTree::new(LinkedMerkleTree::new_with_size(MixHasher::new(), 20));
+    for index in 0..10_000 {
+        tree.add(index);
+    }
+    let held = tree.read();
+    tree.add(10_000);
+    let next = tree.read();
+    assert_eq!(held.len(), 10_000);
+
+    // the append copies the chunks on the path to the new leaf, and shares
+    // every other chunk with the version that is still held
+    let (shared, total) = next.shared_chunks(&held);
+    assert!(total > 600, "{} chunks", total);
+    assert!(
+        total - shared <= 20,
+        "{} of {} chunks copied",
+        total - shared,
+        total
+    );
+}
@@ -1,19 +1,32 @@
-use super::{HashableElement, MerkleHasher, MerkleTree, Witness, WitnessNode};
+use super::checkpoint::{Checkpointed, Checkpoints};
+use super::config::{ConfigError, TreeConfig};
+use super::integrity::{check_rows, IntegrityReport};
+use super::snapshot::{Snapshot, SnapshotRegistry};
+use super::{
+    check_update_leaves, clamp_range, frontier_edge, frontier_from_roots, invalid_data,
+    past_witness, read_state, subtree_hash, subtree_start, write_state, write_witnessed_state,
+    HashableElement, LeafEntry, LeafRange, LeafStorage, MerkleHasher, MerkleTree, NotRetained,
+    UpdateError, Witness, WitnessNode,
+};
+use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::atomic::Ordering;
 use std::{io, sync::Arc};
 mod sledder;
 use sled;
 use sled::Transactional;
-use sledder::{LeafIndex, Node, NodeIndex, Sledder};
+use sledder::{transaction_error, LeafIndex, Node, NodeIndex, Sledder, SyncPoint};
 
 /// Merkle tree implementation stored in Sled. Based on LinkedMerkleTree,
 /// but data isn't stored wholly in memory, and is saved incrementally,
 /// instead of only on shutdown.
 ///
-/// Note; this struct naively unwraps all queries and panics if there is a
-/// problem in the database. This was necessary to fulfill the contract of
-/// the MerkleTree trait. We should probably change the trait, but I'm not
-/// sure what the right layer of abstraction is to recover from such issues
-/// is, anyway.
+/// Note; the MerkleTree methods have no way to report a problem in the
+/// database, so they unwrap their queries and panic if there is one. So do
+/// `mark`, `update`, `num_nodes` and the `new` constructors, and
+/// `sync_from_subtree_roots` while it adds the leaves after the roots.
+/// `with_config`, `open_existing`, `snapshot`, `prune`, `import_state`, the
+/// Checkpointed methods and `verify_integrity` return an io::Error instead.
 ///
 /// Development note: Transactions are not reentrant. If you try to, for example,
 /// call len() or past_root() from inside witness_path or add(), you will
@@ -25,6 +38,8 @@ pub struct SledMerkleTree<T: MerkleHasher> {
     leaves: sled::Tree,
     nodes: sled::Tree,
     tree_depth: u32,
+    leaf_storage: LeafStorage,
+    snapshots: Arc<SnapshotRegistry>,
 }
 
 impl<T: MerkleHasher> SledMerkleTree<T> {
@@ -35,26 +50,700 @@ impl<T: MerkleHasher> SledMerkleTree<T> {
     }
 
     /// Construct a new, empty merkle tree in the given directory with
-    /// the given size
+    /// the given size. An existing tree keeps the leaf storage mode it was
+    /// created with; a new one stores its elements.
+    ///
+    /// Panics if the directory holds a tree created with another depth, or
+    /// the database can't be opened.
     pub fn new_with_size(
         hasher: Arc<T>,
         sled_directory: &std::path::Path,
         tree_depth: u32,
     ) -> Self {
-        let _db = sled::open(sled_directory).unwrap();
-        let counters = _db.open_tree(b"counters").unwrap();
-        let leaves = _db.open_tree(b"leaves").unwrap();
-        let nodes = _db.open_tree(b"nodes").unwrap();
-        SledMerkleTree {
+        Self::open(hasher, sled_directory, tree_depth, None)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Construct a new, empty merkle tree in the given directory with the
+    /// given size, storing either the leaf elements or only their hashes.
+    ///
+    /// Panics if the directory holds a tree created with the other mode or
+    /// another depth, or the database can't be opened.
+    pub fn new_with_storage(
+        hasher: Arc<T>,
+        sled_directory: &std::path::Path,
+        tree_depth: u32,
+        leaf_storage: LeafStorage,
+    ) -> Self {
+        Self::open(hasher, sled_directory, tree_depth, Some(leaf_storage))
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Open the tree in the given directory as described by config,
+    /// creating it if necessary.
+    ///
+    /// Fails with InvalidInput wrapping a ConfigError if config isn't
+    /// valid, or if the directory holds a tree created with a different
+    /// depth or leaf storage mode than the one requested. Fails with the
+    /// database's error if it can't be opened or its checkpoints can't be
+    /// read.
+    pub fn with_config(
+        hasher: Arc<T>,
+        sled_directory: &std::path::Path,
+        config: TreeConfig,
+    ) -> io::Result<Self> {
+        config.validate()?;
+        let mut tree = Self::open(
+            hasher,
+            sled_directory,
+            config.depth as u32,
+            config.leaf_storage,
+        )?;
+        if let Some(reorg_depth) = config.reorg_depth {
+            tree.set_reorg_depth(reorg_depth)?;
+        }
+        Ok(tree)
+    }
+
+    /// Open the tree in the given directory at the depth it was created
+    /// with, for tools that are handed a tree rather than building one.
+    /// Nothing is written to the tree until it is changed.
+    ///
+    /// Sled 0.30 can't open a database read-only (it asks to create the
+    /// files without write access), so sled still locks the tree against
+    /// other processes while it is open.
+    ///
+    /// Fails if the directory doesn't exist, or holds a tree from before the
+    /// depth was recorded; opening that once with `new_with_size` records it.
+    pub fn open_existing(hasher: Arc<T>, sled_directory: &std::path::Path) -> io::Result<Self> {
+        if !sled_directory.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No tree in {}", sled_directory.display()),
+            ));
+        }
+        let _db = sled::open(sled_directory).map_err(sledder::io_error)?;
+        let counters = _db.open_tree(b"counters").map_err(sledder::io_error)?;
+        let leaves = _db.open_tree(b"leaves").map_err(sledder::io_error)?;
+        let nodes = _db.open_tree(b"nodes").map_err(sledder::io_error)?;
+        // Trees that record their depth were opened since the indices were
+        // widened and the leaf storage mode was recorded, so there is
+        // nothing to write here
+        let tree_depth = sledder::tree_depth(&counters)?.ok_or_else(|| {
+            invalid_data(format!(
+                "The tree in {} doesn't record its depth",
+                sled_directory.display()
+            ))
+        })?;
+        let leaf_storage = sledder::leaf_storage(&counters)?.unwrap_or_default();
+        Ok(SledMerkleTree {
+            hasher,
+            _db,
+            counters,
+            leaves,
+            nodes,
+            tree_depth: tree_depth - 1,
+            leaf_storage,
+            snapshots: Arc::new(SnapshotRegistry::default()),
+        })
+    }
+
+    /// The number of levels in the tree, including the root and the leaves.
+    pub fn tree_depth(&self) -> u32 {
+        self.tree_depth + 1
+    }
+
+    fn open(
+        hasher: Arc<T>,
+        sled_directory: &std::path::Path,
+        tree_depth: u32,
+        requested_storage: Option<LeafStorage>,
+    ) -> io::Result<Self> {
+        let _db = sled::open(sled_directory).map_err(sledder::io_error)?;
+        let counters = _db.open_tree(b"counters").map_err(sledder::io_error)?;
+        let leaves = _db.open_tree(b"leaves").map_err(sledder::io_error)?;
+        let nodes = _db.open_tree(b"nodes").map_err(sledder::io_error)?;
+        sledder::migrate_u32_indices(&counters, &leaves, &nodes)?;
+        // Trees created before the mode was recorded stored their elements
+        let stored_storage = match sledder::leaf_storage(&counters)? {
+            None if sledder::num_leaves(&counters)? > 0 => Some(LeafStorage::Elements),
+            stored => stored,
+        };
+        let leaf_storage = match (stored_storage, requested_storage) {
+            (Some(stored), Some(requested)) if stored != requested => {
+                return Err(ConfigError::LeafStorageMismatch { stored, requested }.into())
+            }
+            (Some(stored), _) => stored,
+            (None, requested) => requested.unwrap_or_default(),
+        };
+        sledder::set_leaf_storage(&counters, leaf_storage)?;
+        match sledder::tree_depth(&counters)? {
+            Some(stored) if stored != tree_depth => {
+                return Err(ConfigError::DepthMismatch {
+                    stored: stored as usize,
+                    requested: tree_depth as usize,
+                }
+                .into())
+            }
+            Some(_) => {}
+            None => sledder::set_tree_depth(&counters, tree_depth)?,
+        }
+        Ok(SledMerkleTree {
             hasher,
             _db,
             counters,
             leaves,
             nodes,
             tree_depth: tree_depth - 1,
+            leaf_storage,
+            snapshots: Arc::new(SnapshotRegistry::default()),
+        })
+    }
+
+    /// Take a read-only snapshot of the tree at its current size.
+    ///
+    /// sled 0.30, the version this crate uses, can't take a snapshot of a
+    /// database, so this copies every row of the tree into a temporary
+    /// database that is deleted when the snapshot is dropped. That takes
+    /// time and space in proportion to the size of the tree, but nothing
+    /// that happens to this tree afterwards can affect the copy.
+    pub fn snapshot(&self) -> io::Result<Snapshot<Self>> {
+        let _db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .map_err(sledder::io_error)?;
+        let counters = _db.open_tree(b"counters").map_err(sledder::io_error)?;
+        let leaves = _db.open_tree(b"leaves").map_err(sledder::io_error)?;
+        let nodes = _db.open_tree(b"nodes").map_err(sledder::io_error)?;
+        copy_rows(&self.counters, &counters)?;
+        copy_rows(&self.leaves, &leaves)?;
+        copy_rows(&self.nodes, &nodes)?;
+        Ok(Snapshot::new(SledMerkleTree {
+            hasher: self.hasher.clone(),
+            _db,
+            counters,
+            leaves,
+            nodes,
+            tree_depth: self.tree_depth,
+            leaf_storage: self.leaf_storage,
+            snapshots: Arc::new(SnapshotRegistry::default()),
+        }))
+    }
+
+    /// The number of internal nodes, counting the empty node above the root
+    /// and any that have been pruned.
+    pub fn num_nodes(&self) -> usize {
+        (&self.counters, &self.leaves, &self.nodes)
+            .transaction(|(counters, leaves, nodes)| {
+                Ok(Sledder::new(self.hasher.clone(), counters, leaves, nodes).num_nodes() as usize)
+            })
+            .unwrap()
+    }
+
+    /// Read every leaf and node row and check that they fit together: that
+    /// links and parents are consistent, the counters match the rows, and
+    /// the stored sibling hashes recompute from their children. Rows that
+    /// can't be decoded are reported rather than panicking; failing to read
+    /// from the database is an error.
+    ///
+    /// Sled can't iterate inside a transaction, so nothing should write to
+    /// the tree while it is checked.
+    pub fn verify_integrity(&self) -> io::Result<IntegrityReport> {
+        let rows = sledder::stored_rows(&*self.hasher, &self.counters, &self.leaves, &self.nodes)?;
+        Ok(check_rows(&*self.hasher, self.tree_depth as usize, rows))
+    }
+
+    /// Get another handle to the same database. Handles share their
+    /// snapshot registry, so that iterators over one can tell when another
+    /// truncates the tree.
+    #[cfg(test)]
+    fn handle(&self) -> Self {
+        SledMerkleTree {
+            hasher: self.hasher.clone(),
+            _db: self._db.clone(),
+            counters: self.counters.clone(),
+            leaves: self.leaves.clone(),
+            nodes: self.nodes.clone(),
+            tree_depth: self.tree_depth,
+            leaf_storage: self.leaf_storage,
+            snapshots: self.snapshots.clone(),
         }
     }
 
+    /// Fill an empty tree from the roots of its first complete subtrees at
+    /// level, such as another copy's `subtree_root(level, index)`, and the
+    /// leaves after them, instead of adding every leaf.
+    ///
+    /// The tree then has `roots.len() << level` leaves before the given ones.
+    /// Those look pruned: `get` and `witness` return None for them, and the
+    /// tree can't be truncated to the sync point or below it, except to empty
+    /// it. The root hash, past roots from the sync point on, and witnesses of
+    /// the given leaves match the fully built tree's. Leftovers from earlier
+    /// truncations are deleted first, so they can't pass for the skipped
+    /// leaves.
+    ///
+    /// Fails without changing anything if the tree isn't empty, if level is
+    /// 0 or deeper than the tree, or if the roots and leaves don't fit in the
+    /// tree.
+    pub fn sync_from_subtree_roots(
+        &mut self,
+        level: usize,
+        roots: &[<T::Element as HashableElement>::Hash],
+        leaves: Vec<T::Element>,
+    ) -> io::Result<()> {
+        if self.len() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Only an empty tree can be synced",
+            ));
+        }
+        let (size, frontier) = frontier_from_roots(
+            &*self.hasher,
+            self.tree_depth as usize,
+            level,
+            roots,
+            leaves.len(),
+        )?;
+        self.clear_leftovers()?;
+        if size > 0 {
+            self.start_from_frontier(size, frontier)?;
+        }
+        for element in leaves {
+            self.add(element);
+        }
+        Ok(())
+    }
+
+    /// Fill an empty tree from a state written by `export_state`, so that it
+    /// can carry on from there. The tree must have the same depth as the one
+    /// that wrote the state. Like a tree synced from subtree roots, it has
+    /// none of the leaves before that point, and can't be truncated to that
+    /// point or below it, except to empty it.
+    ///
+    /// Fails without changing anything if the tree isn't empty.
+    pub fn import_state<R: io::Read>(&mut self, reader: &mut R) -> io::Result<()> {
+        if self.len() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Only an empty tree can import a state",
+            ));
+        }
+        let (num_leaves, frontier) = read_state(&*self.hasher, self.tree_depth as usize, reader)?;
+        self.clear_leftovers()?;
+        if num_leaves > 0 {
+            self.start_from_frontier(num_leaves, frontier)?;
+        }
+        Ok(())
+    }
+
+    /// Delete the leaves and nodes left behind by truncating to empty, so
+    /// they can't pass for the leaves a sync or import skips.
+    fn clear_leftovers(&self) -> io::Result<()> {
+        self.leaves.clear().map_err(sledder::io_error)?;
+        self.nodes.clear().map_err(sledder::io_error)
+    }
+
+    /// Store the right edge of an empty tree with size leaves from its
+    /// frontier, leaving the leaves themselves out.
+    fn start_from_frontier(
+        &mut self,
+        size: usize,
+        frontier: Vec<<T::Element as HashableElement>::Hash>,
+    ) -> io::Result<()> {
+        let edge = frontier_edge(&*self.hasher, self.tree_depth as usize, size, &frontier);
+        // at level 0 the edge starts with the last leaf, which only gets a hash
+        let edge_nodes = &edge.nodes[usize::from(edge.level == 0)..];
+        (&self.counters, &self.leaves, &self.nodes)
+            .transaction(|(counters, leaves, nodes)| {
+                let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
+                let mut next_node_index = 1;
+                let mut first = None;
+                for (height, edge_node) in edge_nodes.iter().enumerate() {
+                    // a right node is stored after its left sibling
+                    let stored = if edge_node.left_sibling.is_some() {
+                        2
+                    } else {
+                        1
+                    };
+                    let parent = match edge_nodes.get(height + 1) {
+                        Some(above) => NodeIndex(
+                            next_node_index + stored + u64::from(above.left_sibling.is_some()),
+                        ),
+                        None => NodeIndex::empty(),
+                    };
+                    sledder.set_node(
+                        NodeIndex(next_node_index),
+                        &Node::Left {
+                            hash_of_sibling: edge_node.hash.clone(),
+                            parent,
+                        },
+                    );
+                    if let Some(left_hash) = &edge_node.left_sibling {
+                        sledder.set_node(
+                            NodeIndex(next_node_index + 1),
+                            &Node::Right {
+                                hash_of_sibling: left_hash.clone(),
+                                left: NodeIndex(next_node_index),
+                            },
+                        );
+                    }
+                    next_node_index += stored;
+                    first.get_or_insert(NodeIndex(next_node_index - 1));
+                }
+                let node = first.unwrap_or_else(NodeIndex::empty);
+                if edge.level == 0 {
+                    let last_leaf = LeafIndex(size as u64 - 1);
+                    sledder.set_leaf_hash(last_leaf, &edge.nodes[0].hash);
+                    sledder.set_leaf_parent(last_leaf, node);
+                }
+                sledder.set_num_nodes(next_node_index);
+                sledder.set_num_leaves(size as u64);
+                sledder.set_sync_point(Some(&SyncPoint {
+                    size: size as u64,
+                    level: edge.level,
+                    node,
+                    root_hash: edge.root_hash.clone(),
+                    frontier: frontier.clone(),
+                }));
+                Ok(())
+            })
+            .map_err(transaction_error)
+    }
+
+    /// Mark the leaf at position as one whose witness will be needed later,
+    /// so that `prune` keeps it. Returns false if there is no such leaf, or
+    /// it has already been pruned.
+    pub fn mark(&mut self, position: usize) -> bool {
+        (&self.counters, &self.leaves, &self.nodes)
+            .transaction(|(counters, leaves, nodes)| {
+                let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
+                let leaf_index = LeafIndex(position as u64);
+                if position >= sledder.num_leaves() as usize
+                    || sledder.leaf_hash(leaf_index).is_none()
+                {
+                    return Ok(false);
+                }
+                sledder.set_leaf_marked(leaf_index, true);
+                Ok(true)
+            })
+            .unwrap()
+    }
+
+    /// Delete the leaves and nodes that aren't needed to witness a marked
+    /// leaf or to keep adding to the tree. What survives is the path of each
+    /// marked leaf and its sibling, plus the paths of the last leaf and of
+    /// the last leaf at each checkpoint, so that the tree can still be
+    /// rewound to any of its checkpoints. Leftovers from earlier truncations
+    /// are deleted too.
+    ///
+    /// Afterwards, `get` returns a NotRetained error for the leaves that were
+    /// deleted, and witnesses and past roots that would need them are None.
+    ///
+    /// Fails without deleting anything if the checkpoints can't be read.
+    pub fn prune(&mut self) -> io::Result<()> {
+        // Transactions can't scan, so find the marks first.
+        let mut marked = vec![];
+        for key in self.leaves.scan_prefix(b"Leaf").keys() {
+            let key = key.map_err(sledder::io_error)?;
+            if LeafIndex::is_marked_key(&key) {
+                marked.extend(LeafIndex::from_key(&key));
+            }
+        }
+        let checkpoints = Checkpointed::checkpoints(self)?;
+
+        let (keep_leaves, keep_nodes) = (&self.counters, &self.leaves, &self.nodes)
+            .transaction(|(counters, leaves, nodes)| {
+                let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
+                let num_leaves = sledder.num_leaves();
+                let mut keep_leaves: HashSet<u64> = marked
+                    .iter()
+                    .map(|index| index.0)
+                    .filter(|index| *index < num_leaves)
+                    .collect();
+                if num_leaves > 0 {
+                    keep_leaves.insert(num_leaves - 1);
+                }
+                for (_, size) in checkpoints.iter() {
+                    if *size > 0 && *size <= num_leaves as usize {
+                        keep_leaves.insert(*size as u64 - 1);
+                    }
+                }
+                let siblings: Vec<u64> = keep_leaves
+                    .iter()
+                    .map(|index| LeafIndex(*index).sibling().0)
+                    .filter(|sibling| *sibling < num_leaves)
+                    .collect();
+                keep_leaves.extend(siblings);
+
+                // a synced tree doesn't have the leaves before the sync point
+                let mut starts: Vec<NodeIndex> = keep_leaves
+                    .iter()
+                    .filter_map(|index| sledder.leaf_parent(LeafIndex(*index)))
+                    .collect();
+                if let Some(sync_point) = sledder.sync_point() {
+                    if sync_point.size == num_leaves {
+                        starts.push(sync_point.node);
+                    }
+                }
+
+                let mut keep_nodes = HashSet::new();
+                keep_nodes.insert(0);
+                for start in starts {
+                    let mut current = start;
+                    while keep_nodes.insert(current.0) {
+                        current = match sledder.node(current) {
+                            Node::Empty => break,
+                            Node::Left { parent, .. } => parent,
+                            Node::Right { left, .. } => {
+                                keep_nodes.insert(left.0);
+                                sledder.node_parent(left)
+                            }
+                        }
+                    }
+                }
+                Ok((keep_leaves, keep_nodes))
+            })
+            .map_err(transaction_error)?;
+
+        // Nothing refers to the deleted keys, so they can be removed one at a
+        // time instead of in one enormous transaction.
+        for key in self.leaves.iter().keys() {
+            let key = key.map_err(sledder::io_error)?;
+            if let Some(index) = LeafIndex::from_key(&key) {
+                if !keep_leaves.contains(&index.0) {
+                    self.leaves.remove(key).map_err(sledder::io_error)?;
+                }
+            }
+        }
+        for key in self.nodes.iter().keys() {
+            let key = key.map_err(sledder::io_error)?;
+            if let Some(index) = NodeIndex::from_key(&key) {
+                if !keep_nodes.contains(&index.0) {
+                    self.nodes.remove(key).map_err(sledder::io_error)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the element at position and rehash the path from it up to the
+    /// root in a single transaction, so that the root hash and witnesses
+    /// reflect the new element. This takes O(depth) time, however far
+    /// position is from the end.
+    ///
+    /// Only the current nodes are stored, so `past_root` and `past_witness`
+    /// are undefined across an update: they are calculated with the new
+    /// element, and don't match the roots the tree actually had before it.
+    ///
+    /// Fails without changing anything if there is no leaf at position, or
+    /// if a leaf needed to rehash the path has been pruned.
+    pub fn update(&mut self, position: usize, element: T::Element) -> Result<(), UpdateError> {
+        (&self.counters, &self.leaves, &self.nodes)
+            .transaction(|(counters, leaves, nodes)| {
+                let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
+                let num_leaves = sledder.num_leaves() as usize;
+                let checked =
+                    check_update_leaves(position, num_leaves, self.tree_depth as usize, |leaf| {
+                        sledder.leaf_parent(LeafIndex(leaf as u64)).is_some()
+                    });
+                if let Err(error) = checked {
+                    return Ok(Err(error));
+                }
+                let leaf_index = LeafIndex(position as u64);
+                let leaf_hash = element.merkle_hash();
+                sledder.set_leaf_hash(leaf_index, &leaf_hash);
+                if self.leaf_storage == LeafStorage::Elements {
+                    sledder.set_leaf_element(leaf_index, &element);
+                }
+
+                let mut current_hash = if leaf_index.is_right() {
+                    let sibling_hash = self.pruned_leaf_hash(&sledder, leaf_index.sibling());
+                    self.hasher.combine_hash(0, &sibling_hash, &leaf_hash)
+                } else if position < num_leaves - 1 {
+                    let sibling_hash = self.pruned_leaf_hash(&sledder, leaf_index.sibling());
+                    self.hasher.combine_hash(0, &leaf_hash, &sibling_hash)
+                } else {
+                    self.hasher.combine_hash(0, &leaf_hash, &leaf_hash)
+                };
+                let mut current_index = self.pruned_leaf_parent(&sledder, leaf_index);
+                for depth in 1..self.tree_depth as usize {
+                    match sledder.node(current_index) {
+                        Node::Empty => break,
+                        Node::Left {
+                            parent,
+                            hash_of_sibling,
+                        } => {
+                            let right_start = ((position >> depth) + 1) << depth;
+                            if right_start < num_leaves {
+                                // the right sibling is the node at this depth
+                                // above the first leaf after our subtree
+                                let mut right_index = self
+                                    .pruned_leaf_parent(&sledder, LeafIndex(right_start as u64));
+                                for _ in 1..depth {
+                                    right_index = sledder.node_parent(right_index);
+                                }
+                                if let Node::Right { left, .. } = sledder.node(right_index) {
+                                    sledder.set_node(
+                                        right_index,
+                                        &Node::Right {
+                                            left,
+                                            hash_of_sibling: current_hash.clone(),
+                                        },
+                                    );
+                                }
+                                current_hash = self.hasher.combine_hash(
+                                    depth,
+                                    &current_hash,
+                                    &hash_of_sibling,
+                                );
+                            } else {
+                                sledder.set_node(
+                                    current_index,
+                                    &Node::Left {
+                                        parent,
+                                        hash_of_sibling: current_hash.clone(),
+                                    },
+                                );
+                                current_hash =
+                                    self.hasher
+                                        .combine_hash(depth, &current_hash, &current_hash);
+                            }
+                            current_index = parent;
+                        }
+                        Node::Right {
+                            left,
+                            hash_of_sibling,
+                        } => {
+                            let parent = sledder.node_parent(left);
+                            sledder.set_node(
+                                left,
+                                &Node::Left {
+                                    parent,
+                                    hash_of_sibling: current_hash.clone(),
+                                },
+                            );
+                            current_hash =
+                                self.hasher
+                                    .combine_hash(depth, &hash_of_sibling, &current_hash);
+                            current_index = parent;
+                        }
+                    }
+                }
+                Ok(Ok(()))
+            })
+            .unwrap()
+    }
+
+    /// Check that the tree still stores the leaf that would become the last
+    /// one if it were truncated to past_size.
+    fn check_last_leaf(&self, past_size: usize) -> Result<(), NotRetained> {
+        if past_size > 0
+            && past_size < self.len()
+            && sledder::leaf_hash(&*self.hasher, &self.leaves, LeafIndex(past_size as u64 - 1))
+                .is_none()
+        {
+            return Err(NotRetained {
+                position: past_size - 1,
+            });
+        }
+        Ok(())
+    }
+
+    /// The hash of a leaf that update needs, which it has checked is there.
+    fn pruned_leaf_hash(
+        &self,
+        sledder: &Sledder<T>,
+        index: LeafIndex,
+    ) -> <T::Element as HashableElement>::Hash {
+        sledder
+            .leaf_hash(index)
+            .unwrap_or_else(|| panic!("Leaf {} is needed for the update but was pruned", index.0))
+    }
+
+    /// The parent of a leaf that update needs, which it has checked is there.
+    fn pruned_leaf_parent(&self, sledder: &Sledder<T>, index: LeafIndex) -> NodeIndex {
+        sledder
+            .leaf_parent(index)
+            .unwrap_or_else(|| panic!("Leaf {} is needed for the update but was pruned", index.0))
+    }
+
+    /// Remove the leaves after past_size and fix up the nodes, inside the
+    /// caller's transaction. The caller checks that the leaf that becomes the
+    /// last one is still stored.
+    fn truncate_leaves(&self, sledder: &Sledder<T>, past_size: usize) {
+        if past_size >= sledder.num_leaves() as usize {
+            return;
+        }
+
+        sledder.set_num_leaves(past_size as u64);
+        if past_size == 0 {
+            sledder.set_num_leaves(0);
+            sledder.set_num_nodes(1); // The empty node
+            sledder.set_sync_point(None);
+            return;
+        } else if past_size == 1 {
+            sledder.set_leaf_parent(LeafIndex(0), NodeIndex::empty());
+            sledder.set_num_nodes(1);
+            return;
+        }
+        let depth = depth_at_leaf_count(past_size) - 2;
+        let mut parent = sledder
+            .leaf_parent(LeafIndex(past_size as u64 - 1))
+            .unwrap();
+        let mut max_parent = parent;
+        for _ in 0..depth {
+            parent = sledder.node_parent(parent);
+            if parent.0 > max_parent.0 {
+                max_parent = parent;
+            }
+        }
+
+        match sledder.node(parent) {
+            Node::Left {
+                hash_of_sibling, ..
+            } => sledder.set_node(
+                parent,
+                &Node::Left {
+                    hash_of_sibling,
+                    parent: NodeIndex::empty(),
+                },
+            ),
+            _ => panic!("New root expected to be a left node."),
+        }
+
+        sledder.set_num_nodes(max_parent.0 + 1);
+        self.rehash_right_path(sledder);
+    }
+
+    /// The hash_of_sibling stored in each internal node on the path from the
+    /// leaf at leaf_index up to the root.
+    fn stored_siblings(
+        &self,
+        sledder: &Sledder<T>,
+        leaf_index: LeafIndex,
+    ) -> Vec<Option<<T::Element as HashableElement>::Hash>> {
+        let mut current_position = sledder.leaf_parent(leaf_index).unwrap();
+        (1..self.tree_depth as usize)
+            .map(|_| match sledder.node(current_position) {
+                Node::Empty => None,
+                Node::Left {
+                    parent,
+                    hash_of_sibling,
+                } => {
+                    current_position = parent;
+                    Some(hash_of_sibling)
+                }
+                Node::Right {
+                    left,
+                    hash_of_sibling,
+                } => {
+                    current_position = sledder.node_parent(left);
+                    Some(hash_of_sibling)
+                }
+            })
+            .collect()
+    }
+
     /// Recalculate all the hashes between the most recently added leaf in the group
     /// and the root hash.
     fn rehash_right_path(&self, sledder: &Sledder<T>) {
@@ -121,6 +810,29 @@ impl<T: MerkleHasher> MerkleTree for SledMerkleTree<T> {
         Ok(())
     }
 
+    /// A tree that was synced or imported and has had nothing added since
+    /// writes the frontier it started from, rather than witness a last leaf
+    /// it may not have.
+    fn export_state<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let sync_point = (&self.counters, &self.leaves, &self.nodes)
+            .transaction(|(counters, leaves, nodes)| {
+                let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
+                Ok(sledder
+                    .sync_point()
+                    .filter(|sync_point| sync_point.size == sledder.num_leaves()))
+            })
+            .unwrap();
+        match sync_point {
+            Some(sync_point) => write_state(
+                &*self.hasher,
+                sync_point.size as usize,
+                &sync_point.frontier,
+                writer,
+            ),
+            None => write_witnessed_state(self, writer),
+        }
+    }
+
     /// Doesn't make sense to read a database that isn't stored in memory.
     /// It's not possible to fake this because we don't have access to the
     /// dbfile here.
@@ -154,7 +866,7 @@ impl<T: MerkleHasher> MerkleTree for SledMerkleTree<T> {
             .transaction(|(counters, leaves, nodes)| {
                 let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
                 let index_of_new_leaf = LeafIndex(sledder.num_leaves());
-                if index_of_new_leaf.0 as usize >= 2_usize.pow(self.tree_depth as u32) {
+                if index_of_new_leaf.0 as usize >= 2_usize.pow(self.tree_depth) {
                     panic!("Tree is full");
                 }
 
@@ -187,10 +899,32 @@ impl<T: MerkleHasher> MerkleTree for SledMerkleTree<T> {
                     let previous_leaf_index = LeafIndex(index_of_new_leaf.0 - 1);
                     let mut next_node_index = sledder.num_nodes();
                     let new_parent_index = NodeIndex(next_node_index);
-                    let mut previous_parent_index =
-                        sledder.leaf_parent(previous_leaf_index).unwrap();
                     let mut my_hash = self.hasher.combine_hash(0, &leaf_hash, &leaf_hash);
                     let mut depth = 1;
+                    let mut previous_parent_index = match sledder.leaf_parent(previous_leaf_index) {
+                        Some(parent) => parent,
+                        None => {
+                            // The first leaf after the sync point. The missing
+                            // leaf before it would be a right child all the way
+                            // up to the synced level, so this leaf gets a new
+                            // left node at each depth below that.
+                            let sync_point = sledder
+                                .sync_point()
+                                .expect("must have leaf left of the new left leaf");
+                            while depth < sync_point.level {
+                                my_hash = self.hasher.combine_hash(depth, &my_hash, &my_hash);
+                                let new_node = Node::Left {
+                                    parent: NodeIndex(next_node_index + 1),
+                                    hash_of_sibling: my_hash.clone(),
+                                };
+                                sledder.set_node(NodeIndex(next_node_index), &new_node);
+                                next_node_index += 1;
+                                sledder.set_num_nodes(next_node_index);
+                                depth += 1;
+                            }
+                            sync_point.node
+                        }
+                    };
                     loop {
                         let previous_parent = sledder.node(previous_parent_index);
                         match previous_parent {
@@ -247,7 +981,11 @@ impl<T: MerkleHasher> MerkleTree for SledMerkleTree<T> {
                 sledder.set_num_leaves(index_of_new_leaf.0 + 1);
                 sledder.set_leaf_parent(index_of_new_leaf, new_parent_index);
                 sledder.set_leaf_hash(index_of_new_leaf, &leaf_hash);
-                sledder.set_leaf_element(index_of_new_leaf, &element);
+                // clear any mark left behind by a truncated leaf in this position
+                sledder.set_leaf_marked(index_of_new_leaf, false);
+                if self.leaf_storage == LeafStorage::Elements {
+                    sledder.set_leaf_element(index_of_new_leaf, &element);
+                }
                 self.rehash_right_path(&sledder);
                 Ok(())
             })
@@ -255,11 +993,24 @@ impl<T: MerkleHasher> MerkleTree for SledMerkleTree<T> {
     }
 
     /// Get the leaf element at the given position.
-    fn get(&self, position: usize) -> Option<<Self::Hasher as MerkleHasher>::Element> {
+    ///
+    /// Every leaf of a tree that only stores leaf hashes, and every leaf that
+    /// has been pruned, is a NotRetained error.
+    fn get(
+        &self,
+        position: usize,
+    ) -> Result<Option<<Self::Hasher as MerkleHasher>::Element>, NotRetained> {
         (&self.counters, &self.leaves, &self.nodes)
             .transaction(|(counters, leaves, nodes)| {
-                Ok(Sledder::new(self.hasher.clone(), counters, leaves, nodes)
-                    .leaf_element(LeafIndex(position as u32)))
+                let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
+                if position as u64 >= sledder.num_leaves() {
+                    return Ok(Ok(None));
+                }
+                let element = match self.leaf_storage {
+                    LeafStorage::Elements => sledder.leaf_element(LeafIndex(position as u64)),
+                    LeafStorage::HashesOnly => None,
+                };
+                Ok(element.map(Some).ok_or(NotRetained { position }))
             })
             .unwrap()
     }
@@ -272,70 +1023,165 @@ impl<T: MerkleHasher> MerkleTree for SledMerkleTree<T> {
     ///
     /// This function doesn't do any garbage collection. The old leaves and
     /// nodes stay in sled, but they will be overwritten as the tree grows.
-    fn truncate(&mut self, past_size: usize) {
+    /// Iterators that haven't yet reached past_size are told before anything
+    /// is written, and end there.
+    ///
+    /// Fails without changing anything if the leaf that would become the last
+    /// one was pruned or skipped by a sync.
+    fn truncate(&mut self, past_size: usize) -> Result<(), NotRetained> {
+        if past_size >= self.len() {
+            return Ok(());
+        }
+        self.check_last_leaf(past_size)?;
+        self.snapshots.truncating(past_size);
         (&self.counters, &self.leaves, &self.nodes)
             .transaction(|(counters, leaves, nodes)| {
                 let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
-                if past_size >= sledder.num_leaves() as usize {
-                    return Ok(());
-                }
-
-                sledder.set_num_leaves(past_size as u32);
-                if past_size == 0 {
-                    sledder.set_num_leaves(0);
-                    sledder.set_num_nodes(1); // The empty node
-                    return Ok(());
-                } else if past_size == 1 {
-                    sledder.set_leaf_parent(LeafIndex(0), NodeIndex::empty());
-                    sledder.set_num_nodes(1);
-                    return Ok(());
-                }
-                let depth = depth_at_leaf_count(past_size) - 2;
-                let mut parent = sledder
-                    .leaf_parent(LeafIndex(past_size as u32 - 1))
-                    .unwrap();
-                let mut max_parent = parent;
-                for _ in 0..depth {
-                    parent = sledder.node_parent(parent);
-                    if parent.0 > max_parent.0 {
-                        max_parent = parent;
+                // Checkpoints that can't be read are left as they are, for
+                // the Checkpointed methods to report
+                if let Ok(mut checkpoints) = sledder.checkpoints() {
+                    if checkpoints.truncated(past_size) {
+                        sledder.set_checkpoints(&checkpoints);
                     }
                 }
-
-                match sledder.node(parent) {
-                    Node::Left {
-                        hash_of_sibling, ..
-                    } => sledder.set_node(
-                        parent,
-                        &Node::Left {
-                            hash_of_sibling,
-                            parent: NodeIndex::empty(),
-                        },
-                    ),
-                    _ => panic!("New root expected to be a left node."),
-                }
-
-                sledder.set_num_nodes(max_parent.0 + 1);
-                self.rehash_right_path(&sledder);
+                self.truncate_leaves(&sledder, past_size);
                 Ok(())
             })
             .unwrap();
+        Ok(())
     }
 
     /// Iterate over clones of all leaf notes in the tree, without consuming
     /// the tree.
     ///
-    /// note: this is completely undefined behaviour if the tree is modified
-    /// while iteration is happening. This cannot happen in a transaction.
+    /// sled 0.30, the version this crate uses, can't take a snapshot of the
+    /// database, so unlike the RocksDB tree's iterators, this one can't be
+    /// isolated from changes made through another handle while it runs. It yields at most the
+    /// leaves the tree had when it was created, and ends early if the tree is
+    /// truncated below the leaf it just read, so what it yields is always a
+    /// prefix of that tree.
+    ///
+    /// Every leaf of a tree that only stores leaf hashes, and every leaf that
+    /// has been pruned, yields a NotRetained error.
     fn iter_notes<'a>(
         &'a self,
-    ) -> Box<dyn Iterator<Item = <Self::Hasher as MerkleHasher>::Element> + 'a> {
-        let leaf_count = sledder::num_leaves(&self.counters);
-        Box::new((0..leaf_count).map(move |index| {
-            sledder::leaf_element(self.hasher.clone(), &self.leaves, LeafIndex(index))
+    ) -> Box<dyn Iterator<Item = Result<<Self::Hasher as MerkleHasher>::Element, NotRetained>> + 'a>
+    {
+        let truncated_to = self.snapshots.register();
+        let leaf_count = sledder::num_leaves(&self.counters).unwrap();
+        let leaf_storage = self.leaf_storage;
+        Box::new((0..leaf_count).map_while(move |index| {
+            let element = match leaf_storage {
+                LeafStorage::Elements => {
+                    sledder::leaf_element(self.hasher.clone(), &self.leaves, LeafIndex(index))
+                }
+                LeafStorage::HashesOnly => None,
+            };
+            if truncated_to.load(Ordering::SeqCst) <= index as usize {
+                return None;
+            }
+            Some(element.ok_or(NotRetained {
+                position: index as usize,
+            }))
         }))
     }
 
+    /// Iterate over clones of the leaf notes at the positions in range,
+    /// from either end, with a range scan over the leaf keys. Like
+    /// iter_notes, it ends early if the tree is truncated while it runs.
+    ///
+    /// Leaves that have been pruned, and every leaf of a tree that only
+    /// stores leaf hashes, yield a NotRetained error.
+    fn iter_range<'a>(
+        &'a self,
+        range: Range<usize>,
+    ) -> Box<
+        dyn DoubleEndedIterator<Item = Result<<Self::Hasher as MerkleHasher>::Element, NotRetained>>
+            + 'a,
+    > {
+        let range = clamp_range(range, self.len());
+        if self.leaf_storage == LeafStorage::HashesOnly {
+            return Box::new(range.map(|position| Err(NotRetained { position })));
+        }
+        let truncated_to = self.snapshots.register();
+        if range.is_empty() {
+            return Box::new(std::iter::empty());
+        }
+        let first = LeafIndex(range.start as u64).element_key();
+        let last = LeafIndex(range.end as u64 - 1).element_key();
+        let front = self
+            .leaves
+            .range(first..)
+            .filter_map(move |entry| sledder::leaf_element_entry(&*self.hasher, entry));
+        let back = self
+            .leaves
+            .range(..=last)
+            .rev()
+            .filter_map(move |entry| sledder::leaf_element_entry(&*self.hasher, entry));
+        Box::new(LeafRange::new(front, back, range).watch(truncated_to))
+    }
+
+    /// Iterate over the leaves with a scan over the leaf keys, reading the
+    /// stored hashes. The element of each leaf is stored just before its
+    /// hash. Like iter_notes, it ends early if the tree is truncated while it
+    /// runs. A row that can't be read or decoded is yielded as an error, and
+    /// ends the iteration.
+    fn iter_leaves<'a>(&'a self) -> Box<dyn Iterator<Item = io::Result<LeafEntry<T>>> + 'a> {
+        let truncated_to = self.snapshots.register();
+        let num_leaves = self.len();
+        Box::new(
+            self.leaves
+                .iter()
+                .scan((None, false), move |(pending, failed), entry| {
+                    if *failed {
+                        return None;
+                    }
+                    let (key, value) = match entry {
+                        Ok(entry) => entry,
+                        Err(error) => {
+                            *failed = true;
+                            return Some(Some(Err(sledder::io_error(error))));
+                        }
+                    };
+                    let index = LeafIndex::from_key(&key)?;
+                    if index.0 as usize >= num_leaves {
+                        return None;
+                    }
+                    let mut bytes = &value[..];
+                    if key.ends_with(b"Element") {
+                        match self.hasher.read_element(&mut bytes) {
+                            Ok(element) => *pending = Some((index, element)),
+                            Err(error) => {
+                                *failed = true;
+                                return Some(Some(Err(error)));
+                            }
+                        }
+                        Some(None)
+                    } else if key.ends_with(b"Hash") {
+                        if truncated_to.load(Ordering::SeqCst) <= index.0 as usize {
+                            return None;
+                        }
+                        let element = match pending.take() {
+                            Some((pending_index, element)) if pending_index == index => {
+                                Some(element)
+                            }
+                            _ => None,
+                        };
+                        let leaf = self.hasher.read_hash(&mut bytes).map(|hash| LeafEntry {
+                            position: index.0 as usize,
+                            hash,
+                            element,
+                        });
+                        *failed = leaf.is_err();
+                        Some(Some(leaf))
+                    } else {
+                        Some(None)
+                    }
+                })
+                .flatten(),
+        )
+    }
+
     /// Get the hash of the current root element in the tree.
     fn root_hash(
         &self,
@@ -357,9 +1203,17 @@ impl<T: MerkleHasher> MerkleTree for SledMerkleTree<T> {
                 if num_leaves == 0 || past_size > num_leaves || past_size == 0 {
                     return Ok(None);
                 }
+                if let Some(sync_point) = sledder.sync_point() {
+                    if past_size as u64 == sync_point.size {
+                        return Ok(Some(sync_point.root_hash));
+                    }
+                }
                 let root_depth = depth_at_leaf_count(past_size);
-                let leaf_index = LeafIndex(past_size as u32 - 1);
-                let mut current_hash = sledder.leaf_hash(leaf_index).unwrap();
+                let leaf_index = LeafIndex(past_size as u64 - 1);
+                let mut current_hash = match sledder.leaf_hash(leaf_index) {
+                    Some(hash) => hash,
+                    None => return Ok(None), // pruned
+                };
                 let mut current_node_index = sledder.leaf_parent(leaf_index).unwrap();
                 current_hash = if leaf_index.is_right() {
                     let sibling_hash = sledder.leaf_hash(leaf_index.sibling()).unwrap();
@@ -400,17 +1254,33 @@ impl<T: MerkleHasher> MerkleTree for SledMerkleTree<T> {
 
     /// Determine whether a tree contained a value in the past, when it had a specific size.
     ///
-    /// This is an inefficient linear scan.
+    /// This is an inefficient linear scan. It compares leaf hashes, so it
+    /// works whether or not the elements are stored.
     fn contained(&self, value: &T::Element, past_size: usize) -> bool {
-        for (index, candidate) in self.iter_notes().enumerate() {
-            if index == past_size {
-                break;
-            }
-            if candidate == *value {
-                return true;
-            }
-        }
-        false
+        let hash = value.merkle_hash();
+        (&self.counters, &self.leaves, &self.nodes)
+            .transaction(|(counters, leaves, nodes)| {
+                let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
+                let size = std::cmp::min(past_size, sledder.num_leaves() as usize);
+                Ok((0..size).any(|index| {
+                    sledder.leaf_hash(LeafIndex(index as u64)).as_ref() == Some(&hash)
+                }))
+            })
+            .unwrap()
+    }
+
+    /// Like the default, but reads the stored leaf hash, so that it works on
+    /// a tree that only stores hashes.
+    fn subtree_root(
+        &self,
+        level: usize,
+        index: usize,
+    ) -> Option<<<Self::Hasher as MerkleHasher>::Element as HashableElement>::Hash> {
+        let position = subtree_start(level, index)?;
+        let witness = self.witness(position)?;
+        let leaf_hash =
+            sledder::leaf_hash(&*self.hasher, &self.leaves, LeafIndex(position as u64))?;
+        subtree_hash(&*self.hasher, &witness, level, leaf_hash)
     }
 
     /// Construct the proof that the leaf node at `position` exists.
@@ -431,8 +1301,11 @@ impl<T: MerkleHasher> MerkleTree for SledMerkleTree<T> {
                 if num_leaves == 0 || position >= num_leaves {
                     return Ok(None);
                 }
-                let leaf_index = LeafIndex(position as u32);
-                let mut current_hash = sledder.leaf_hash(leaf_index).unwrap();
+                let leaf_index = LeafIndex(position as u64);
+                let mut current_hash = match sledder.leaf_hash(leaf_index) {
+                    Some(hash) => hash,
+                    None => return Ok(None), // pruned
+                };
                 let mut current_position = sledder.leaf_parent(leaf_index).unwrap();
                 let mut authentication_path = vec![];
                 if leaf_index.is_right() {
@@ -488,6 +1361,112 @@ impl<T: MerkleHasher> MerkleTree for SledMerkleTree<T> {
             })
             .unwrap()
     }
+
+    /// Construct the proof that the leaf node at `position` existed when the
+    /// tree had past_size leaves.
+    fn past_witness(&self, position: usize, past_size: usize) -> Option<Witness<T>> {
+        (&self.counters, &self.leaves, &self.nodes)
+            .transaction(|(counters, leaves, nodes)| {
+                let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
+                if position >= past_size || past_size > sledder.num_leaves() as usize {
+                    return Ok(None);
+                }
+                if sledder.leaf_hash(LeafIndex(position as u64)).is_none()
+                    || sledder.leaf_hash(LeafIndex(past_size as u64 - 1)).is_none()
+                {
+                    // pruned
+                    return Ok(None);
+                }
+                Ok(Some(past_witness(
+                    &*self.hasher,
+                    position,
+                    past_size,
+                    |index| sledder.leaf_hash(LeafIndex(index as u64)).unwrap(),
+                    |index| self.stored_siblings(&sledder, LeafIndex(index as u64)),
+                )))
+            })
+            .unwrap()
+    }
+}
+
+impl<T: MerkleHasher> Checkpointed for SledMerkleTree<T> {
+    /// Record the current size under id, in the same database as the tree.
+    fn checkpoint(&mut self, id: &[u8]) -> io::Result<()> {
+        (&self.counters, &self.leaves, &self.nodes)
+            .transaction(|(counters, leaves, nodes)| {
+                let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
+                let mut checkpoints = match sledder.checkpoints() {
+                    Ok(checkpoints) => checkpoints,
+                    Err(error) => return Ok(Err(error)),
+                };
+                checkpoints.record(id, sledder.num_leaves() as usize);
+                sledder.set_checkpoints(&checkpoints);
+                Ok(Ok(()))
+            })
+            .map_err(transaction_error)?
+    }
+
+    /// Rewind to the checkpoint. The checkpoints and the tree are updated in a
+    /// single transaction.
+    fn rewind_to(&mut self, id: &[u8]) -> io::Result<bool> {
+        let past_size = match self
+            .checkpoints()?
+            .into_iter()
+            .find(|(existing, _)| existing[..] == *id)
+        {
+            Some((_, size)) => size,
+            None => return Ok(false),
+        };
+        self.check_last_leaf(past_size).map_err(io::Error::other)?;
+        self.snapshots.truncating(past_size);
+        (&self.counters, &self.leaves, &self.nodes)
+            .transaction(|(counters, leaves, nodes)| {
+                let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
+                let mut checkpoints = match sledder.checkpoints() {
+                    Ok(checkpoints) => checkpoints,
+                    Err(error) => return Ok(Err(error)),
+                };
+                checkpoints.rewind(id);
+                sledder.set_checkpoints(&checkpoints);
+                self.truncate_leaves(&sledder, past_size);
+                Ok(Ok(()))
+            })
+            .map_err(transaction_error)??;
+        Ok(true)
+    }
+
+    fn checkpoints(&self) -> io::Result<Vec<(Vec<u8>, usize)>> {
+        (&self.counters, &self.leaves, &self.nodes)
+            .transaction(|(counters, leaves, nodes)| {
+                let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
+                Ok(sledder.checkpoints().map(|checkpoints| checkpoints.list()))
+            })
+            .map_err(transaction_error)?
+    }
+
+    fn set_reorg_depth(&mut self, reorg_depth: usize) -> io::Result<()> {
+        (&self.counters, &self.leaves, &self.nodes)
+            .transaction(|(counters, leaves, nodes)| {
+                let sledder = Sledder::new(self.hasher.clone(), counters, leaves, nodes);
+                let mut checkpoints = match sledder.checkpoints() {
+                    Ok(checkpoints) => checkpoints,
+                    Err(error) => return Ok(Err(error)),
+                };
+                checkpoints.set_reorg_depth(reorg_depth);
+                sledder.set_checkpoints(&checkpoints);
+                Ok(Ok(()))
+            })
+            .map_err(transaction_error)?
+    }
+}
+
+/// Copy every row of one sled tree into another.
+fn copy_rows(from: &sled::Tree, to: &sled::Tree) -> io::Result<()> {
+    for row in from.iter() {
+        let (key, value) = row.map_err(sledder::io_error)?;
+        to.insert(key, value).map_err(sledder::io_error)?;
+    }
+    Ok(())
 }
 
 /// The depth of the tree when it contains a certain
@@ -498,7 +1477,7 @@ fn depth_at_leaf_count(index: usize) -> usize {
     match index {
         0 => 0,
         1 => 1,
-        n => ((n - 1) as f32).log2() as usize + 2,
+        n => (usize::BITS - (n - 1).leading_zeros()) as usize + 1,
     }
 }
 
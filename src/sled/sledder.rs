@@ -1,20 +1,28 @@
 //! Sled wrapper that queries and unwraps requests for specific
 //! keys and types useful to the SledMerkleTree.
 //!
-//! This module uses expect() everywhere. That's not particularly safe,
-//! but I don't have a better idea. We can't expose the errors upward
-//! because the MerkleTree trait doesn't have Results on it, and even if we could,
-//! it's unclear how the client code would handle it. So we panic... *sigh*
+//! The queries made inside a transaction unwrap everything. That's not
+//! particularly safe, but I don't have a better idea. We can't expose the
+//! errors upward because the MerkleTree trait doesn't have Results on it, and
+//! even if we could, it's unclear how the client code would handle it. So we
+//! panic... *sigh*
+//!
+//! The helpers used while opening a tree, before any MerkleTree method is
+//! involved, return io::Results instead.
 //!
 //! Accepts db from
-use super::{HashableElement, MerkleHasher};
+use super::{Checkpoints, HashableElement, LeafStorage, MerkleHasher};
+use crate::integrity::{IntegrityProblem, Row, StoredNode, StoredRows};
+use crate::invalid_data;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use sled;
+use sled::TransactionError;
+use std::io;
 use std::sync::Arc;
 
 /// Newtype wrapper to avoid mixing up leaf and node indexes
 #[derive(Shrinkwrap, Debug, PartialEq, Copy, Clone)]
-pub(crate) struct LeafIndex(pub(crate) u32);
+pub(crate) struct LeafIndex(pub(crate) u64);
 
 impl LeafIndex {
     fn key(&self, suffix: &[u8]) -> Vec<u8> {
@@ -28,16 +36,34 @@ impl LeafIndex {
         self.key(b"Parent")
     }
 
-    fn element_key(&self) -> Vec<u8> {
+    pub(crate) fn element_key(&self) -> Vec<u8> {
         self.key(b"Element")
     }
     fn hash_key(&self) -> Vec<u8> {
         self.key(b"Hash")
     }
+    fn marked_key(&self) -> Vec<u8> {
+        self.key(b"Marked")
+    }
+
+    /// Get the index of the leaf that a key in the leaves tree belongs to.
+    pub(crate) fn from_key(key: &[u8]) -> Option<LeafIndex> {
+        if key.len() < 12 || &key[..4] != b"Leaf" {
+            return None;
+        }
+        let mut bytes = [0; 8];
+        bytes.clone_from_slice(&key[4..12]);
+        Some(LeafIndex(u64::from_be_bytes(bytes)))
+    }
+
+    /// Determine whether a key in the leaves tree is a mark.
+    pub(crate) fn is_marked_key(key: &[u8]) -> bool {
+        key.ends_with(b"Marked")
+    }
 }
 
-impl PartialEq<u32> for LeafIndex {
-    fn eq(&self, other: &u32) -> bool {
+impl PartialEq<u64> for LeafIndex {
+    fn eq(&self, other: &u64) -> bool {
         self.0 == *other
     }
 }
@@ -60,7 +86,7 @@ impl LeafIndex {
 
 /// Newtype wrapper to avoid mixing up leaf and node indexes
 #[derive(Shrinkwrap, Debug, PartialEq, Copy, Clone)]
-pub(crate) struct NodeIndex(pub(crate) u32);
+pub(crate) struct NodeIndex(pub(crate) u64);
 impl NodeIndex {
     pub(crate) fn empty() -> Self {
         NodeIndex(0)
@@ -75,6 +101,16 @@ impl NodeIndex {
         key.extend(&self.0.to_be_bytes());
         key
     }
+
+    /// Get the index of the node stored under a key in the nodes tree.
+    pub(crate) fn from_key(key: &[u8]) -> Option<NodeIndex> {
+        if key.len() != 12 || &key[..4] != b"Node" {
+            return None;
+        }
+        let mut bytes = [0; 8];
+        bytes.clone_from_slice(&key[4..12]);
+        Some(NodeIndex(u64::from_be_bytes(bytes)))
+    }
 }
 
 /// Represent an internal node in the tree of nodes. To make it easy
@@ -103,6 +139,20 @@ pub(crate) enum Node<T: MerkleHasher> {
     Empty,
 }
 
+/// Where a tree synced from subtree roots or an imported state starts. The
+/// leaves before size were never added, so they look pruned. The frontier
+/// has the hashes of the complete subtrees they make up, the lowest of which
+/// is at level. node is the internal node at that level above the last of
+/// the leaves (or its parent, at level 0), which the first leaf added after
+/// them is attached to.
+pub(crate) struct SyncPoint<T: MerkleHasher> {
+    pub(crate) size: u64,
+    pub(crate) level: usize,
+    pub(crate) node: NodeIndex,
+    pub(crate) root_hash: <T::Element as HashableElement>::Hash,
+    pub(crate) frontier: Vec<<T::Element as HashableElement>::Hash>,
+}
+
 pub(crate) struct Sledder<'a, T: MerkleHasher> {
     hasher: Arc<T>,
     counters: &'a sled::TransactionalTree,
@@ -125,34 +175,97 @@ impl<'a, T: MerkleHasher> Sledder<'a, T> {
         }
     }
     /// Retrieve the number of leaf nodes (notes) in the tree
-    pub(crate) fn num_leaves(&self) -> u32 {
-        self.get_u32(self.counters, b"LeafCount").unwrap_or(0)
+    pub(crate) fn num_leaves(&self) -> u64 {
+        self.get_u64(self.counters, b"LeafCount").unwrap_or(0)
     }
 
     /// Set the number of leaf nodes. .
-    pub(crate) fn set_num_leaves(&self, length: u32) {
-        self.set_u32(self.counters, b"LeafCount", length as u32);
+    pub(crate) fn set_num_leaves(&self, length: u64) {
+        self.set_u64(self.counters, b"LeafCount", length);
     }
 
     /// Get the number of internal nodes. There is always at least one internal node
     /// (the empty node)
-    pub(crate) fn num_nodes(&self) -> u32 {
-        self.get_u32(self.counters, b"NodeCount").unwrap_or(1)
+    pub(crate) fn num_nodes(&self) -> u64 {
+        self.get_u64(self.counters, b"NodeCount").unwrap_or(1)
     }
 
-    pub(crate) fn set_num_nodes(&self, count: u32) {
-        self.set_u32(self.counters, b"NodeCount", count as u32);
+    pub(crate) fn set_num_nodes(&self, count: u64) {
+        self.set_u64(self.counters, b"NodeCount", count);
+    }
+
+    /// Get the checkpoints recorded for this tree, or the error from
+    /// decoding them.
+    pub(crate) fn checkpoints(&self) -> io::Result<Checkpoints> {
+        match self.counters.get(&b"Checkpoints"[..]).unwrap() {
+            Some(bytes) => Checkpoints::read(&mut &bytes[..]),
+            None => Ok(Checkpoints::default()),
+        }
+    }
+
+    pub(crate) fn set_checkpoints(&self, checkpoints: &Checkpoints) {
+        let mut bytes = vec![];
+        checkpoints.write(&mut bytes).unwrap();
+        self.counters.insert(&b"Checkpoints"[..], bytes).unwrap();
+    }
+
+    /// Get where the tree starts, if it was synced from subtree roots or
+    /// imported.
+    pub(crate) fn sync_point(&self) -> Option<SyncPoint<T>> {
+        self.counters
+            .get(&b"SyncPoint"[..])
+            .unwrap()
+            .map(|bytes_vec| {
+                let mut bytes = &bytes_vec[..];
+                let size = bytes.read_u64::<BigEndian>().unwrap();
+                let level = bytes.read_u64::<BigEndian>().unwrap() as usize;
+                let node = NodeIndex(bytes.read_u64::<BigEndian>().unwrap());
+                let root_hash = self.hasher.read_hash(&mut bytes).unwrap();
+                let frontier = (0..size.count_ones())
+                    .map(|_| self.hasher.read_hash(&mut bytes).unwrap())
+                    .collect();
+                SyncPoint {
+                    size,
+                    level,
+                    node,
+                    root_hash,
+                    frontier,
+                }
+            })
+    }
+
+    pub(crate) fn set_sync_point(&self, sync_point: Option<&SyncPoint<T>>) {
+        match sync_point {
+            Some(sync_point) => {
+                let mut bytes = vec![];
+                bytes.write_u64::<BigEndian>(sync_point.size).unwrap();
+                bytes
+                    .write_u64::<BigEndian>(sync_point.level as u64)
+                    .unwrap();
+                bytes.write_u64::<BigEndian>(sync_point.node.0).unwrap();
+                self.hasher
+                    .write_hash(&sync_point.root_hash, &mut bytes)
+                    .unwrap();
+                for hash in &sync_point.frontier {
+                    self.hasher.write_hash(hash, &mut bytes).unwrap();
+                }
+                self.counters.insert(&b"SyncPoint"[..], bytes).unwrap();
+            }
+            None => {
+                self.counters.remove(&b"SyncPoint"[..]).unwrap();
+            }
+        }
     }
 
     /// Get the parent of the leaf node at given index.
     pub(crate) fn leaf_parent(&self, index: LeafIndex) -> Option<NodeIndex> {
-        self.get_u32(self.leaves, &index.parent_key())
-            .map(|num| NodeIndex(num))
+        self.get_u64(self.leaves, &index.parent_key())
+            .map(NodeIndex)
     }
 
     /// Set the parent of the given index.
     pub(crate) fn set_leaf_parent(&self, index: LeafIndex, parent: NodeIndex) {
-        self.set_u32(self.leaves, &index.parent_key(), parent.0)
+        self.set_u64(self.leaves, &index.parent_key(), parent.0)
     }
 
     /// Get the hash of the leaf at given index.
@@ -189,6 +302,17 @@ impl<'a, T: MerkleHasher> Sledder<'a, T> {
             })
     }
 
+    /// Mark or unmark the leaf at the given index, so that pruning keeps it.
+    pub(crate) fn set_leaf_marked(&self, index: LeafIndex, marked: bool) {
+        if marked {
+            self.leaves
+                .insert(index.marked_key(), Vec::<u8>::new())
+                .unwrap();
+        } else {
+            self.leaves.remove(index.marked_key()).unwrap();
+        }
+    }
+
     pub(crate) fn set_leaf_element(&self, index: LeafIndex, value: &T::Element) {
         let mut bytes = vec![];
         value.write(&mut bytes).unwrap();
@@ -222,7 +346,7 @@ impl<'a, T: MerkleHasher> Sledder<'a, T> {
                 .map(|bytes_vec| {
                     let mut bytes = &bytes_vec[..];
                     let node_type = bytes.read_u8().unwrap();
-                    let the_other_node = NodeIndex(bytes.read_u32::<BigEndian>().unwrap());
+                    let the_other_node = NodeIndex(bytes.read_u64::<BigEndian>().unwrap());
                     let hash_of_sibling = self.hasher.read_hash(&mut bytes).unwrap();
                     if node_type == 0 {
                         Node::Left {
@@ -255,10 +379,8 @@ impl<'a, T: MerkleHasher> Sledder<'a, T> {
                 },
             ) => {
                 bytes.write_u8(0).unwrap();
-                bytes.write_u32::<BigEndian>(parent.0).unwrap();
-                self.hasher
-                    .write_hash(&hash_of_sibling, &mut bytes)
-                    .unwrap();
+                bytes.write_u64::<BigEndian>(parent.0).unwrap();
+                self.hasher.write_hash(hash_of_sibling, &mut bytes).unwrap();
             }
             (
                 NodeIndex(_),
@@ -268,70 +390,326 @@ impl<'a, T: MerkleHasher> Sledder<'a, T> {
                 },
             ) => {
                 bytes.write_u8(1).unwrap();
-                bytes.write_u32::<BigEndian>(left.0).unwrap();
-                self.hasher
-                    .write_hash(&hash_of_sibling, &mut bytes)
-                    .unwrap();
+                bytes.write_u64::<BigEndian>(left.0).unwrap();
+                self.hasher.write_hash(hash_of_sibling, &mut bytes).unwrap();
             }
         }
         self.nodes.insert(data_key, bytes).unwrap();
     }
 
-    fn get_u32(&self, db: &sled::TransactionalTree, key: &[u8]) -> Option<u32> {
+    fn get_u64(&self, db: &sled::TransactionalTree, key: &[u8]) -> Option<u64> {
         let bytes_option = db.get(key).unwrap();
         bytes_option.map(|bytes_vec| {
-            assert_eq!(bytes_vec.len(), 4);
-            let mut bytes = [0; 4];
-            bytes[0..4].clone_from_slice(&bytes_vec);
-            u32::from_be_bytes(bytes)
+            assert_eq!(bytes_vec.len(), 8);
+            let mut bytes = [0; 8];
+            bytes[0..8].clone_from_slice(&bytes_vec);
+            u64::from_be_bytes(bytes)
         })
     }
 
-    fn set_u32(&self, db: &sled::TransactionalTree, key: &[u8], value: u32) {
+    fn set_u64(&self, db: &sled::TransactionalTree, key: &[u8], value: u64) {
         let bytes = value.to_be_bytes();
         db.insert(key, &bytes).unwrap();
     }
 }
 
 /// Helper method to get the leaf count outside a transaction
-pub(crate) fn num_leaves(db: &sled::Tree) -> u32 {
+pub(crate) fn num_leaves(db: &sled::Tree) -> io::Result<u64> {
     let key = b"LeafCount";
-    db.get(key)
-        .unwrap()
-        .map(|bytes_vec| {
-            assert_eq!(bytes_vec.len(), 4);
-            let mut bytes = [0; 4];
-            bytes[0..4].clone_from_slice(&bytes_vec);
-            u32::from_be_bytes(bytes)
-        })
-        .unwrap_or(0)
+    match db.get(key).map_err(io_error)? {
+        Some(bytes_vec) => (&bytes_vec[..]).read_u64::<BigEndian>(),
+        None => Ok(0),
+    }
+}
+
+/// Leaf key suffixes, as they follow the index in a leaf key.
+const LEAF_KEY_SUFFIXES: [&[u8]; 4] = [b"Parent", b"Element", b"Hash", b"Marked"];
+
+/// How many rewritten keys to apply at once during a migration.
+const MIGRATION_BATCH_SIZE: usize = 10_000;
+
+/// Rewrite a tree created before leaf and node indices were widened to u64.
+/// Such trees have u32 indices in their keys, in leaf parents and node
+/// links, and in the leaf and node counts.
+///
+/// Each rewritten key is inserted in the same batch that removes its old
+/// form, and the new keys are longer than any old one, so a migration that is
+/// interrupted picks up where it left off the next time the tree is opened.
+/// The index width is recorded once everything has been rewritten, and a new
+/// tree records it straight away.
+pub(crate) fn migrate_u32_indices(
+    counters: &sled::Tree,
+    leaves: &sled::Tree,
+    nodes: &sled::Tree,
+) -> io::Result<()> {
+    if counters.get(b"IndexWidth").map_err(io_error)?.is_some() {
+        return Ok(());
+    }
+    rewrite_keys(leaves, |key, value| {
+        if key.len() < 8 || &key[..4] != b"Leaf" || !LEAF_KEY_SUFFIXES.contains(&&key[8..]) {
+            return None;
+        }
+        let mut new_key = b"Leaf".to_vec();
+        new_key.extend(&widen(&key[4..8]));
+        new_key.extend(&key[8..]);
+        let new_value = if &key[8..] == b"Parent" {
+            widen(value).to_vec()
+        } else {
+            value.to_vec()
+        };
+        Some((new_key, new_value))
+    })?;
+    rewrite_keys(nodes, |key, value| {
+        if key.len() != 8 || &key[..4] != b"Node" {
+            return None;
+        }
+        let mut new_key = b"Node".to_vec();
+        new_key.extend(&widen(&key[4..8]));
+        let mut new_value = vec![value[0]];
+        new_value.extend(&widen(&value[1..5]));
+        new_value.extend(&value[5..]);
+        Some((new_key, new_value))
+    })?;
+    for key in [&b"LeafCount"[..], &b"NodeCount"[..]].iter() {
+        if let Some(value) = counters.get(key).map_err(io_error)? {
+            if value.len() == 4 {
+                counters.insert(key, &widen(&value)[..]).map_err(io_error)?;
+            }
+        }
+    }
+    counters
+        .insert(&b"IndexWidth"[..], &[8u8][..])
+        .map_err(io_error)?;
+    Ok(())
+}
+
+/// Replace every key in db for which rewrite returns a new key and value.
+fn rewrite_keys<F: Fn(&[u8], &[u8]) -> Option<(Vec<u8>, Vec<u8>)>>(
+    db: &sled::Tree,
+    rewrite: F,
+) -> io::Result<()> {
+    let mut batch = sled::Batch::default();
+    let mut batch_size = 0;
+    for entry in db.iter() {
+        let (key, value) = entry.map_err(io_error)?;
+        if let Some((new_key, new_value)) = rewrite(&key, &value) {
+            batch.insert(new_key, new_value);
+            batch.remove(key);
+            batch_size += 1;
+            if batch_size == MIGRATION_BATCH_SIZE {
+                db.apply_batch(std::mem::take(&mut batch))
+                    .map_err(io_error)?;
+                batch_size = 0;
+            }
+        }
+    }
+    db.apply_batch(batch).map_err(io_error)
+}
+
+/// Convert a big endian u32 to a big endian u64.
+fn widen(bytes: &[u8]) -> [u8; 8] {
+    let mut u32_bytes = [0; 4];
+    u32_bytes.clone_from_slice(&bytes[..4]);
+    u64::from(u32::from_be_bytes(u32_bytes)).to_be_bytes()
+}
+
+/// Helper method to get the leaf storage mode recorded when the tree was
+/// created, outside a transaction.
+pub(crate) fn leaf_storage(db: &sled::Tree) -> io::Result<Option<LeafStorage>> {
+    let key = b"LeafStorage";
+    match db.get(key).map_err(io_error)?.as_deref() {
+        None => Ok(None),
+        Some([0]) => Ok(Some(LeafStorage::Elements)),
+        Some([1]) => Ok(Some(LeafStorage::HashesOnly)),
+        Some(_) => Err(invalid_data("Unknown leaf storage mode".to_string())),
+    }
+}
+
+/// Helper method to record the leaf storage mode outside a transaction
+pub(crate) fn set_leaf_storage(db: &sled::Tree, leaf_storage: LeafStorage) -> io::Result<()> {
+    let byte = match leaf_storage {
+        LeafStorage::Elements => 0u8,
+        LeafStorage::HashesOnly => 1u8,
+    };
+    db.insert(&b"LeafStorage"[..], &[byte][..])
+        .map_err(io_error)?;
+    Ok(())
+}
+
+/// Helper method to get the depth recorded when the tree was created,
+/// outside a transaction. Trees created before it was recorded have none.
+pub(crate) fn tree_depth(db: &sled::Tree) -> io::Result<Option<u32>> {
+    match db.get(b"TreeDepth").map_err(io_error)? {
+        Some(bytes_vec) => Ok(Some((&bytes_vec[..]).read_u32::<BigEndian>()?)),
+        None => Ok(None),
+    }
+}
+
+/// Helper method to record the depth of the tree outside a transaction
+pub(crate) fn set_tree_depth(db: &sled::Tree, tree_depth: u32) -> io::Result<()> {
+    db.insert(&b"TreeDepth"[..], &tree_depth.to_be_bytes()[..])
+        .map_err(io_error)?;
+    Ok(())
 }
 
 #[cfg(test)]
-pub(crate) fn num_nodes(db: &sled::Tree) -> u32 {
+pub(crate) fn num_nodes(db: &sled::Tree) -> u64 {
     let key = b"NodeCount";
     db.get(key)
         .unwrap()
         .map(|bytes_vec| {
-            assert_eq!(bytes_vec.len(), 4);
-            let mut bytes = [0; 4];
-            bytes[0..4].clone_from_slice(&bytes_vec);
-            u32::from_be_bytes(bytes)
+            assert_eq!(bytes_vec.len(), 8);
+            let mut bytes = [0; 8];
+            bytes[0..8].clone_from_slice(&bytes_vec);
+            u64::from_be_bytes(bytes)
         })
         .unwrap_or(1)
 }
 
-/// Helper method to get leaf element outside a transaction
+/// Helper method to get leaf element outside a transaction. Returns None if
+/// the element isn't stored.
 pub(crate) fn leaf_element<T: MerkleHasher>(
     hasher: Arc<T>,
     db: &sled::Tree,
     index: LeafIndex,
-) -> T::Element {
-    db.get(index.element_key())
-        .unwrap()
-        .map(|bytes_vec| {
-            let mut bytes = &bytes_vec[..];
-            hasher.read_element(&mut bytes).unwrap()
+) -> Option<T::Element> {
+    db.get(index.element_key()).unwrap().map(|bytes_vec| {
+        let mut bytes = &bytes_vec[..];
+        hasher.read_element(&mut bytes).unwrap()
+    })
+}
+
+/// Helper method to get a leaf hash outside a transaction. Returns None if
+/// the leaf has been pruned.
+pub(crate) fn leaf_hash<T: MerkleHasher>(
+    hasher: &T,
+    db: &sled::Tree,
+    index: LeafIndex,
+) -> Option<<T::Element as HashableElement>::Hash> {
+    db.get(index.hash_key()).unwrap().map(|bytes_vec| {
+        let mut bytes = &bytes_vec[..];
+        hasher.read_hash(&mut bytes).unwrap()
+    })
+}
+
+/// Read the position and element out of an entry of the leaves tree, if it
+/// is an element. An entry that can't be read or decoded is skipped, like a
+/// leaf whose element isn't stored.
+pub(crate) fn leaf_element_entry<T: MerkleHasher>(
+    hasher: &T,
+    entry: sled::Result<(sled::IVec, sled::IVec)>,
+) -> Option<(usize, T::Element)> {
+    let (key, value) = entry.ok()?;
+    if !key.ends_with(b"Element") {
+        return None;
+    }
+    let index = LeafIndex::from_key(&key)?;
+    let mut bytes = &value[..];
+    Some((index.0 as usize, hasher.read_element(&mut bytes).ok()?))
+}
+
+/// Read every leaf and node row outside a transaction, for checking. Unlike
+/// everything else here, rows that can't be decoded don't panic; they are
+/// left out and recorded as problems instead.
+pub(crate) fn stored_rows<T: MerkleHasher>(
+    hasher: &T,
+    counters: &sled::Tree,
+    leaves: &sled::Tree,
+    nodes: &sled::Tree,
+) -> io::Result<StoredRows<<T::Element as HashableElement>::Hash>> {
+    let mut problems = vec![];
+    let mut counter = |key: &'static str, default: u64| -> io::Result<u64> {
+        Ok(match counters.get(key).map_err(io_error)? {
+            Some(bytes) if bytes.len() == 8 => (&bytes[..]).read_u64::<BigEndian>()?,
+            Some(_) => {
+                problems.push(IntegrityProblem::Unreadable(Row::Counter(key)));
+                default
+            }
+            None => default,
         })
-        .unwrap()
+    };
+    let mut rows = StoredRows::new(counter("LeafCount", 0)?, counter("NodeCount", 1)?);
+    if let Some(bytes) = counters.get(b"SyncPoint").map_err(io_error)? {
+        let mut bytes = &bytes[..];
+        let sync_point = (|| -> io::Result<(u64, usize, u64)> {
+            let size = bytes.read_u64::<BigEndian>()?;
+            let level = bytes.read_u64::<BigEndian>()? as usize;
+            let node = bytes.read_u64::<BigEndian>()?;
+            Ok((size, level, node))
+        })();
+        match sync_point {
+            Ok(sync_point) => rows.sync_point = Some(sync_point),
+            Err(_) => problems.push(IntegrityProblem::Unreadable(Row::SyncPoint)),
+        }
+    }
+    for entry in leaves.iter() {
+        let (key, value) = entry.map_err(io_error)?;
+        let index = match LeafIndex::from_key(&key) {
+            Some(index) => index,
+            None => continue,
+        };
+        let mut bytes = &value[..];
+        let leaf = rows.leaves.entry(index.0).or_default();
+        let decoded = match &key[12..] {
+            b"Parent" => bytes
+                .read_u64::<BigEndian>()
+                .map(|parent| leaf.parent = Some(parent)),
+            b"Hash" => hasher
+                .read_hash(&mut bytes)
+                .map(|hash| leaf.hash = Some(hash)),
+            b"Element" => hasher
+                .read_element(&mut bytes)
+                .map(|element| leaf.element_hash = Some(element.merkle_hash())),
+            _ => Ok(()),
+        };
+        if decoded.is_err() {
+            problems.push(IntegrityProblem::Unreadable(Row::Leaf(index.0)));
+        }
+    }
+    for entry in nodes.iter() {
+        let (key, value) = entry.map_err(io_error)?;
+        let index = match NodeIndex::from_key(&key) {
+            Some(index) => index,
+            None => continue,
+        };
+        let mut bytes = &value[..];
+        let node = (|| -> io::Result<_> {
+            let node_type = bytes.read_u8()?;
+            let the_other_node = bytes.read_u64::<BigEndian>()?;
+            let hash_of_sibling = hasher.read_hash(&mut bytes)?;
+            Ok(match node_type {
+                0 => StoredNode::Left {
+                    hash_of_sibling,
+                    parent: the_other_node,
+                },
+                1 => StoredNode::Right {
+                    hash_of_sibling,
+                    left: the_other_node,
+                },
+                _ => return Err(invalid_data(format!("Unknown node type {}", node_type))),
+            })
+        })();
+        match node {
+            Ok(node) => {
+                rows.nodes.insert(index.0, node);
+            }
+            Err(_) => problems.push(IntegrityProblem::Unreadable(Row::Node(index.0))),
+        }
+    }
+    rows.problems = problems;
+    Ok(rows)
+}
+
+/// Convert an error from sled, such as a failed read, to an io::Error.
+pub(crate) fn io_error(error: sled::Error) -> io::Error {
+    io::Error::other(error.to_string())
+}
+
+/// Convert the error from a transaction over several trees, which can't
+/// abort with an error of its own, to an io::Error.
+pub(crate) fn transaction_error(error: TransactionError<()>) -> io::Error {
+    match error {
+        TransactionError::Abort(()) => io::Error::other("The transaction was aborted"),
+        TransactionError::Storage(error) => io_error(error),
+    }
 }
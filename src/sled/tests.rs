@@ -1,7 +1,10 @@
 use super::sledder::{num_nodes, LeafIndex, Node, NodeIndex, Sledder};
 use super::SledMerkleTree;
-use crate::{test_helper::StringHasher, MerkleTree, WitnessNode};
+use crate::checkpoint::Checkpointed;
+use crate::test_helper::{copy_database, StringHasher};
+use crate::{LeafStorage, MerkleTree, NotRetained, UpdateError, WitnessNode};
 use sled::Transactional;
+use std::io;
 use tempfile::tempdir;
 
 fn make_tree(characters: &str) -> SledMerkleTree<StringHasher> {
@@ -36,7 +39,7 @@ fn assert_tree(tree: &SledMerkleTree<StringHasher>, characters: &str) {
 
                     assert_eq!(tree_sledder.num_nodes(), expected_sledder.num_nodes());
                     for idx in 0..tree_len {
-                        let index = LeafIndex(idx as u32);
+                        let index = LeafIndex(idx as u64);
                         assert_eq!(
                             tree_sledder.leaf_element(index).unwrap(),
                             expected_sledder.leaf_element(index).unwrap()
@@ -51,7 +54,7 @@ fn assert_tree(tree: &SledMerkleTree<StringHasher>, characters: &str) {
                         );
                     }
                     for idx in 0..tree_sledder.num_nodes() {
-                        let index = NodeIndex(idx as u32);
+                        let index = NodeIndex(idx);
                         assert_eq!(tree_sledder.node(index), expected_sledder.node(index));
                     }
                     Ok(())
@@ -62,17 +65,17 @@ fn assert_tree(tree: &SledMerkleTree<StringHasher>, characters: &str) {
         .unwrap();
 }
 
-fn assert_leaves(tree: &SledMerkleTree<StringHasher>, characters: &str, parents: &[u32]) {
+fn assert_leaves(tree: &SledMerkleTree<StringHasher>, characters: &str, parents: &[u64]) {
     assert_eq!(tree.len(), characters.len());
     assert_eq!(tree.len(), parents.len());
     (&tree.counters, &tree.leaves, &tree.nodes)
         .transaction(|(counters, leaves, nodes)| {
             let sledder = Sledder::new(tree.hasher.clone(), counters, leaves, nodes);
             for (index, (character, parent)) in characters.chars().zip(parents).enumerate() {
-                let leaf_index = LeafIndex(index as u32);
+                let leaf_index = LeafIndex(index as u64);
                 let element = sledder
                     .leaf_element(leaf_index)
-                    .expect(&format!("'{}' element should exist in tree", character));
+                    .unwrap_or_else(|| panic!("'{}' element should exist in tree", character));
                 let leaf_parent = sledder
                     .leaf_parent(leaf_index)
                     .expect("{} metadata should exist in tree");
@@ -90,9 +93,9 @@ fn assert_leaves(tree: &SledMerkleTree<StringHasher>, characters: &str, parents:
 
 fn node_matches(
     tree: &SledMerkleTree<StringHasher>,
-    my_index: u32,
+    my_index: u64,
     is_left: bool,
-    other_index: u32, // parent or left, depending
+    other_index: u64, // parent or left, depending
     expected_hash_of_sibling: &str,
 ) -> bool {
     let node = (&tree.counters, &tree.leaves, &tree.nodes)
@@ -110,7 +113,7 @@ fn node_matches(
         } => {
             is_left
                 && *parent == NodeIndex(other_index)
-                && *hash_of_sibling == expected_hash_of_sibling.to_string()
+                && *hash_of_sibling == expected_hash_of_sibling
         }
         Node::Right {
             left,
@@ -118,7 +121,7 @@ fn node_matches(
         } => {
             !is_left
                 && *left == NodeIndex(other_index)
-                && *hash_of_sibling == expected_hash_of_sibling.to_string()
+                && *hash_of_sibling == expected_hash_of_sibling
         }
     };
     if !is_match {
@@ -267,61 +270,61 @@ fn add() {
 fn truncate() {
     color_backtrace::install();
     let mut tree = make_tree("");
-    tree.truncate(0);
+    tree.truncate(0).unwrap();
     assert_tree(&tree, "");
     tree = make_full_tree();
-    tree.truncate(0);
+    tree.truncate(0).unwrap();
     assert_tree(&tree, "");
     tree = make_full_tree();
-    tree.truncate(1);
+    tree.truncate(1).unwrap();
     assert_tree(&tree, "a");
     tree = make_full_tree();
-    tree.truncate(2);
+    tree.truncate(2).unwrap();
     assert_tree(&tree, "ab");
     tree = make_full_tree();
-    tree.truncate(3);
+    tree.truncate(3).unwrap();
     assert_tree(&tree, "abc");
     tree = make_full_tree();
-    tree.truncate(4);
+    tree.truncate(4).unwrap();
     assert_tree(&tree, "abcd");
     tree = make_full_tree();
-    tree.truncate(5);
+    tree.truncate(5).unwrap();
     assert_tree(&tree, "abcde");
     tree = make_full_tree();
-    tree.truncate(6);
+    tree.truncate(6).unwrap();
     assert_tree(&tree, "abcdef");
     tree = make_full_tree();
-    tree.truncate(7);
+    tree.truncate(7).unwrap();
     assert_tree(&tree, "abcdefg");
     tree = make_full_tree();
-    tree.truncate(8);
+    tree.truncate(8).unwrap();
     assert_tree(&tree, "abcdefgh");
     tree = make_full_tree();
-    tree.truncate(9);
+    tree.truncate(9).unwrap();
     assert_tree(&tree, "abcdefghi");
     tree = make_full_tree();
-    tree.truncate(10);
+    tree.truncate(10).unwrap();
     assert_tree(&tree, "abcdefghij");
     tree = make_full_tree();
-    tree.truncate(11);
+    tree.truncate(11).unwrap();
     assert_tree(&tree, "abcdefghijk");
     tree = make_full_tree();
-    tree.truncate(12);
+    tree.truncate(12).unwrap();
     assert_tree(&tree, "abcdefghijkl");
     tree = make_full_tree();
-    tree.truncate(13);
+    tree.truncate(13).unwrap();
     assert_tree(&tree, "abcdefghijklm");
     tree = make_full_tree();
-    tree.truncate(14);
+    tree.truncate(14).unwrap();
     assert_tree(&tree, "abcdefghijklmn");
     tree = make_full_tree();
-    tree.truncate(15);
+    tree.truncate(15).unwrap();
     assert_tree(&tree, "abcdefghijklmno");
     tree = make_full_tree();
-    tree.truncate(16);
+    tree.truncate(16).unwrap();
     assert_tree(&tree, "abcdefghijklmnop");
     tree = make_full_tree();
-    tree.truncate(17);
+    tree.truncate(17).unwrap();
     assert_tree(&tree, "abcdefghijklmnop");
 }
 
@@ -341,37 +344,37 @@ fn iteration_and_get() {
     color_backtrace::install();
     let mut tree = make_tree("");
     {
-        assert_eq!(tree.get(0), None);
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(0), Ok(None));
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), None);
     }
 
     tree.add("a".to_string());
     {
-        assert_eq!(tree.get(1), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(1), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), None);
     }
 
     tree.add("b".to_string());
     {
-        assert_eq!(tree.get(2), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(2), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), None);
     }
     tree.add("c".to_string());
     {
-        assert_eq!(tree.get(3), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(3), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -379,12 +382,12 @@ fn iteration_and_get() {
     }
     tree.add("d".to_string());
     {
-        assert_eq!(tree.get(4), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        assert_eq!(*tree.get(3).unwrap(), "d".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(4), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        assert_eq!(*tree.get(3).unwrap().unwrap(), "d".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -394,13 +397,13 @@ fn iteration_and_get() {
 
     tree.add("e".to_string());
     {
-        assert_eq!(tree.get(5), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        assert_eq!(*tree.get(3).unwrap(), "d".to_string());
-        assert_eq!(*tree.get(4).unwrap(), "e".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(5), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        assert_eq!(*tree.get(3).unwrap().unwrap(), "d".to_string());
+        assert_eq!(*tree.get(4).unwrap().unwrap(), "e".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -411,14 +414,14 @@ fn iteration_and_get() {
 
     tree.add("f".to_string());
     {
-        assert_eq!(tree.get(6), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        assert_eq!(*tree.get(3).unwrap(), "d".to_string());
-        assert_eq!(*tree.get(4).unwrap(), "e".to_string());
-        assert_eq!(*tree.get(5).unwrap(), "f".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(6), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        assert_eq!(*tree.get(3).unwrap().unwrap(), "d".to_string());
+        assert_eq!(*tree.get(4).unwrap().unwrap(), "e".to_string());
+        assert_eq!(*tree.get(5).unwrap().unwrap(), "f".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -431,7 +434,7 @@ fn iteration_and_get() {
     for i in 0..100 {
         tree.add(i.to_string());
     }
-    let mut iter = tree.iter_notes();
+    let mut iter = tree.iter_notes().map(Result::unwrap);
     for char in ["a", "b", "c", "d", "e", "f"].iter() {
         assert_eq!(iter.next(), Some(char.to_string()));
     }
@@ -685,3 +688,872 @@ fn witness_path() {
         ]
     );
 }
+
+#[test]
+fn past_witness() {
+    let characters = "abcdefghijk";
+    let directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in characters.chars() {
+        tree.add(character.to_string());
+    }
+    assert!(tree.past_witness(0, 0).is_none());
+    assert!(tree.past_witness(3, 3).is_none());
+    assert!(tree.past_witness(0, 12).is_none());
+
+    for past_size in 1..=characters.len() {
+        let past_directory = tempdir().unwrap();
+        let mut past_tree =
+            SledMerkleTree::new_with_size(StringHasher::new(), past_directory.path(), 5);
+        for character in characters[..past_size].chars() {
+            past_tree.add(character.to_string());
+        }
+        for position in 0..past_size {
+            let witness = tree.past_witness(position, past_size).expect("leaf exists");
+            assert_eq!(Some(witness), past_tree.witness(position));
+        }
+    }
+}
+
+#[test]
+fn snapshot() {
+    let directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in "abcde".chars() {
+        tree.add(character.to_string());
+    }
+    let root = tree.root_hash();
+    let witness = tree.witness(2);
+    let snapshot = tree.snapshot().unwrap();
+
+    tree.add("f".to_string());
+    tree.update(2, "z".to_string()).unwrap();
+    tree.mark(0);
+    tree.prune().unwrap();
+    assert_eq!(snapshot.len(), 5);
+    assert_eq!(snapshot.root_hash(), root);
+    assert_eq!(snapshot.witness(2), witness);
+    assert_eq!(snapshot.get(2), Ok(Some("c".to_string())));
+    assert_eq!(snapshot.get(5), Ok(None));
+    assert!(!snapshot.contains(&"f".to_string()));
+
+    tree.truncate(1).unwrap();
+    tree.add("x".to_string());
+    assert_eq!(snapshot.root_hash(), root);
+    assert_eq!(snapshot.witness(2), witness);
+    assert_eq!(
+        snapshot
+            .iter_notes()
+            .collect::<Result<String, _>>()
+            .unwrap(),
+        "abcde"
+    );
+}
+
+#[test]
+fn checkpoints() {
+    let directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in "abc".chars() {
+        tree.add(character.to_string());
+    }
+    tree.checkpoint(b"first").unwrap();
+    let root = tree.root_hash();
+    tree.add("d".to_string());
+    tree.checkpoint(b"second").unwrap();
+    tree.add("e".to_string());
+    tree.checkpoint(b"third").unwrap();
+    assert_eq!(
+        tree.checkpoints().unwrap(),
+        vec![
+            (b"first".to_vec(), 3),
+            (b"second".to_vec(), 4),
+            (b"third".to_vec(), 5)
+        ]
+    );
+
+    assert!(!tree.rewind_to(b"missing").unwrap());
+    assert_eq!(tree.len(), 5);
+    assert!(tree.rewind_to(b"first").unwrap());
+    assert_eq!(tree.len(), 3);
+    assert_eq!(tree.root_hash(), root);
+    assert_eq!(tree.checkpoints().unwrap(), vec![(b"first".to_vec(), 3)]);
+
+    tree.add("x".to_string());
+    tree.checkpoint(b"second").unwrap();
+    tree.truncate(2).unwrap();
+    assert!(tree.checkpoints().unwrap().is_empty());
+
+    tree.set_reorg_depth(1).unwrap();
+    tree.checkpoint(b"first").unwrap();
+    tree.add("y".to_string());
+    tree.checkpoint(b"second").unwrap();
+    assert_eq!(tree.checkpoints().unwrap(), vec![(b"second".to_vec(), 3)]);
+}
+
+#[test]
+fn unreadable_checkpoints() {
+    let directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in "abc".chars() {
+        tree.add(character.to_string());
+    }
+    tree.checkpoint(b"first").unwrap();
+    tree.counters.insert(b"Checkpoints", vec![1, 0]).unwrap();
+    assert!(tree.checkpoints().is_err());
+    assert!(tree.checkpoint(b"second").is_err());
+    assert!(tree.rewind_to(b"first").is_err());
+    assert!(tree.set_reorg_depth(1).is_err());
+    assert!(tree.prune().is_err());
+    assert_eq!(tree.get(0), Ok(Some("a".to_string())));
+
+    // truncating still works, and leaves the checkpoints as they are
+    tree.truncate(1).unwrap();
+    assert_eq!(tree.len(), 1);
+    assert!(tree.checkpoints().is_err());
+}
+
+#[test]
+fn hashes_only() {
+    let directory = tempdir().unwrap();
+    let full_directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_storage(
+        StringHasher::new(),
+        directory.path(),
+        5,
+        LeafStorage::HashesOnly,
+    );
+    let mut full = SledMerkleTree::new_with_size(StringHasher::new(), full_directory.path(), 5);
+    for character in "abcde".chars() {
+        tree.add(character.to_string());
+        full.add(character.to_string());
+    }
+    assert_eq!(tree.root_hash(), full.root_hash());
+    assert_eq!(tree.past_root(3), full.past_root(3));
+    assert_eq!(tree.witness(2), full.witness(2));
+    assert_eq!(tree.past_witness(1, 3), full.past_witness(1, 3));
+    assert!(tree.contains(&"c".to_string()));
+    assert!(!tree.contained(&"e".to_string(), 4));
+
+    assert_eq!(tree.get(2), Err(NotRetained { position: 2 }));
+    assert_eq!(tree.get(5), Ok(None));
+    assert_eq!(full.get(2), Ok(Some("c".to_string())));
+    assert_eq!(
+        tree.iter_notes().collect::<Vec<_>>(),
+        (0..5)
+            .map(|position| Err(NotRetained { position }))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        tree.iter_range(1..3).rev().collect::<Vec<_>>(),
+        vec![
+            Err(NotRetained { position: 2 }),
+            Err(NotRetained { position: 1 })
+        ]
+    );
+
+    tree.truncate(3).unwrap();
+    full.truncate(3).unwrap();
+    tree.add("x".to_string());
+    full.add("x".to_string());
+    assert_eq!(tree.root_hash(), full.root_hash());
+    let directory = copy_database(&tree._db, directory.path());
+    drop(tree);
+
+    let reopened = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    assert_eq!(reopened.get(0), Err(NotRetained { position: 0 }));
+}
+
+#[test]
+#[should_panic(expected = "leaf storage")]
+fn storage_mode_mismatch() {
+    let directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    tree.add("a".to_string());
+    let directory = copy_database(&tree._db, directory.path());
+    drop(tree);
+    SledMerkleTree::new_with_storage(
+        StringHasher::new(),
+        directory.path(),
+        5,
+        LeafStorage::HashesOnly,
+    );
+}
+
+#[test]
+#[should_panic(expected = "created with depth 5, not 6")]
+fn depth_mismatch() {
+    let directory = tempdir().unwrap();
+    let tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    let directory = copy_database(&tree._db, directory.path());
+    drop(tree);
+    SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 6);
+}
+
+#[test]
+fn with_config_mismatch() {
+    use crate::config::{ConfigError, TreeConfig};
+    use crate::fixed::FixedDepth;
+
+    let directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    tree.add("a".to_string());
+    let config_error = |error: io::Error| {
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        *error
+            .into_inner()
+            .unwrap()
+            .downcast::<ConfigError>()
+            .unwrap()
+    };
+    let with_config = |config| {
+        let directory = copy_database(&tree._db, directory.path());
+        SledMerkleTree::with_config(StringHasher::new(), directory.path(), config)
+            .err()
+            .unwrap()
+    };
+
+    let deeper = TreeConfig {
+        depth: 6,
+        ..TreeConfig::default()
+    };
+    assert_eq!(
+        config_error(with_config(deeper)),
+        ConfigError::DepthMismatch {
+            stored: 5,
+            requested: 6
+        }
+    );
+    let hashes_only = TreeConfig {
+        depth: 5,
+        leaf_storage: Some(LeafStorage::HashesOnly),
+        ..TreeConfig::default()
+    };
+    assert_eq!(
+        config_error(with_config(hashes_only)),
+        ConfigError::LeafStorageMismatch {
+            stored: LeafStorage::Elements,
+            requested: LeafStorage::HashesOnly
+        }
+    );
+
+    let copy = copy_database(&tree._db, directory.path());
+    let fixed = FixedDepth::<SledMerkleTree<_>, 4>::open(StringHasher::new(), copy.path()).unwrap();
+    assert_eq!(fixed.len(), 1);
+    let copy = copy_database(&tree._db, directory.path());
+    let error = FixedDepth::<SledMerkleTree<_>, 5>::open(StringHasher::new(), copy.path())
+        .err()
+        .unwrap();
+    assert_eq!(
+        config_error(error),
+        ConfigError::DepthMismatch {
+            stored: 5,
+            requested: 6
+        }
+    );
+}
+
+#[test]
+fn open_existing() {
+    let directory = tempdir().unwrap();
+    let missing = directory.path().join("missing");
+    let error = SledMerkleTree::open_existing(StringHasher::new(), &missing)
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    assert!(!missing.exists());
+
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in "abc".chars() {
+        tree.add(character.to_string());
+    }
+    let copy = copy_database(&tree._db, directory.path());
+    let mut existing = SledMerkleTree::open_existing(StringHasher::new(), copy.path()).unwrap();
+    assert_eq!(existing.tree_depth(), 5);
+    assert_eq!(existing.len(), 3);
+    assert_eq!(existing.root_hash(), tree.root_hash());
+    existing.add("d".to_string());
+    tree.add("d".to_string());
+    assert_eq!(existing.root_hash(), tree.root_hash());
+}
+
+#[test]
+fn prune() {
+    let directory = tempdir().unwrap();
+    let full_directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 6);
+    let mut full = SledMerkleTree::new_with_size(StringHasher::new(), full_directory.path(), 6);
+    for character in "abcdef".chars() {
+        tree.add(character.to_string());
+        full.add(character.to_string());
+    }
+    tree.checkpoint(b"six").unwrap();
+    for character in "ghijk".chars() {
+        tree.add(character.to_string());
+        full.add(character.to_string());
+    }
+    assert!(tree.mark(2));
+    assert!(tree.mark(7));
+    assert!(!tree.mark(11));
+    let leaf_keys = tree.leaves.len();
+    tree.prune().unwrap();
+
+    assert!(tree.leaves.len() < leaf_keys);
+    assert_eq!(tree.len(), 11);
+    assert_eq!(tree.get(2), Ok(Some("c".to_string())));
+    assert_eq!(tree.get(3), Ok(Some("d".to_string())));
+    assert_eq!(tree.get(0), Err(NotRetained { position: 0 }));
+    let pruned_range = vec![
+        Err(NotRetained { position: 0 }),
+        Err(NotRetained { position: 1 }),
+        Ok("c".to_string()),
+        Ok("d".to_string()),
+    ];
+    assert_eq!(tree.iter_range(0..4).collect::<Vec<_>>(), pruned_range);
+    assert_eq!(
+        tree.iter_range(0..4).rev().collect::<Vec<_>>(),
+        pruned_range.into_iter().rev().collect::<Vec<_>>()
+    );
+    assert_eq!(tree.witness(0), None);
+    assert!(!tree.mark(0));
+    assert!(!tree.contains(&"a".to_string()));
+    assert!(tree.contains(&"h".to_string()));
+    assert_eq!(tree.root_hash(), full.root_hash());
+
+    for character in "lmnopqrstu".chars() {
+        tree.add(character.to_string());
+        full.add(character.to_string());
+        assert_eq!(tree.root_hash(), full.root_hash());
+        for position in [2, 3, 6, 7].iter() {
+            assert_eq!(tree.witness(*position), full.witness(*position));
+        }
+    }
+    assert_eq!(tree.past_root(11), full.past_root(11));
+    assert_eq!(tree.past_root(9), None);
+    assert_eq!(tree.truncate(9), Err(NotRetained { position: 8 }));
+    assert_eq!(tree.len(), 21);
+
+    tree.prune().unwrap();
+    assert_eq!(tree.witness(7), full.witness(7));
+    assert!(tree.rewind_to(b"six").unwrap());
+    full.truncate(6).unwrap();
+    assert_eq!(tree.root_hash(), full.root_hash());
+    assert_eq!(tree.witness(2), full.witness(2));
+    tree.add("x".to_string());
+    full.add("x".to_string());
+    assert_eq!(tree.root_hash(), full.root_hash());
+    assert_eq!(tree.witness(2), full.witness(2));
+}
+
+#[test]
+fn update() {
+    use crate::linked::LinkedMerkleTree;
+
+    for size in 1..=9 {
+        let directory = tempdir().unwrap();
+        let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+        let mut characters: Vec<String> = "abcdefghi"[..size]
+            .chars()
+            .map(|character| character.to_string())
+            .collect();
+        for character in characters.iter() {
+            tree.add(character.clone());
+        }
+        for position in 0..size {
+            let replacement = ((b'q' + position as u8) as char).to_string();
+            tree.update(position, replacement.clone()).unwrap();
+            characters[position] = replacement;
+
+            let mut expected = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+            for character in characters.iter() {
+                expected.add(character.clone());
+            }
+            assert_eq!(tree.get(position), expected.get(position));
+            assert_eq!(tree.root_hash(), expected.root_hash());
+            for witnessed in 0..size {
+                assert_eq!(tree.witness(witnessed), expected.witness(witnessed));
+            }
+        }
+        tree.add("z".to_string());
+        let mut expected = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+        for character in characters.iter() {
+            expected.add(character.clone());
+        }
+        expected.add("z".to_string());
+        assert_eq!(tree.root_hash(), expected.root_hash());
+    }
+}
+
+#[test]
+fn update_errors() {
+    let directory = tempdir().unwrap();
+    let full_directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    let mut full = SledMerkleTree::new_with_size(StringHasher::new(), full_directory.path(), 5);
+    for character in "abcdefghijk".chars() {
+        tree.add(character.to_string());
+        full.add(character.to_string());
+    }
+    assert!(tree.mark(7));
+    assert!(tree.mark(9));
+    tree.prune().unwrap();
+    let root_hash = tree.root_hash();
+
+    assert_eq!(
+        tree.update(11, "z".to_string()),
+        Err(UpdateError::OutOfRange {
+            position: 11,
+            len: 11
+        })
+    );
+    assert_eq!(
+        tree.update(0, "z".to_string()),
+        Err(UpdateError::NotRetained(NotRetained { position: 0 }))
+    );
+    assert_eq!(tree.root_hash(), root_hash);
+
+    tree.update(9, "z".to_string()).unwrap();
+    full.update(9, "z".to_string()).unwrap();
+    assert_eq!(tree.root_hash(), full.root_hash());
+    assert_eq!(tree.witness(9), full.witness(9));
+}
+
+#[test]
+fn with_config() {
+    use crate::config::{ConfigError, TreeConfig};
+
+    let directory = tempdir().unwrap();
+    let config = TreeConfig {
+        depth: 5,
+        reorg_depth: Some(1),
+        leaf_storage: Some(LeafStorage::HashesOnly),
+    };
+    let mut tree =
+        SledMerkleTree::with_config(StringHasher::new(), directory.path(), config).unwrap();
+    for character in "abc".chars() {
+        tree.add(character.to_string());
+    }
+    assert_eq!(tree.get(0), Err(NotRetained { position: 0 }));
+    assert_eq!(tree.witness(0).unwrap().auth_path.len(), 4);
+    tree.checkpoint(b"one").unwrap();
+    tree.checkpoint(b"two").unwrap();
+    assert_eq!(tree.checkpoints().unwrap(), vec![(b"two".to_vec(), 3)]);
+
+    // checkpoints that can't be read are an error rather than a panic
+    tree.counters.insert(&b"Checkpoints"[..], &[1][..]).unwrap();
+    drop(tree);
+    assert!(SledMerkleTree::with_config(StringHasher::new(), directory.path(), config).is_err());
+
+    let too_deep = TreeConfig {
+        depth: 65,
+        ..TreeConfig::default()
+    };
+    let other_directory = tempdir().unwrap();
+    let error = SledMerkleTree::with_config(StringHasher::new(), other_directory.path(), too_deep)
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(
+        error.into_inner().unwrap().downcast_ref::<ConfigError>(),
+        Some(&ConfigError::UnsupportedDepth(65))
+    );
+}
+
+#[test]
+fn migrates_u32_indices() {
+    let directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in "abcdefg".chars() {
+        tree.add(character.to_string());
+    }
+    tree.mark(2);
+    let root_hash = tree.root_hash();
+    let witnesses: Vec<_> = (0..7).map(|position| tree.witness(position)).collect();
+    let directory = copy_database(&tree._db, directory.path());
+    drop(tree);
+
+    // Rewrite the database the way it was stored before indices were u64
+    let directory = {
+        let db = sled::open(directory.path()).unwrap();
+        let counters = db.open_tree(b"counters").unwrap();
+        let leaves = db.open_tree(b"leaves").unwrap();
+        let nodes = db.open_tree(b"nodes").unwrap();
+        let entries: Vec<_> = leaves.iter().map(Result::unwrap).collect();
+        for (key, value) in entries {
+            let mut old_key = key[..4].to_vec();
+            old_key.extend(&key[8..]);
+            let old_value = if key.ends_with(b"Parent") {
+                value[4..].to_vec()
+            } else {
+                value.to_vec()
+            };
+            leaves.remove(&key).unwrap();
+            leaves.insert(old_key, old_value).unwrap();
+        }
+        let entries: Vec<_> = nodes.iter().map(Result::unwrap).collect();
+        for (key, value) in entries {
+            let mut old_key = key[..4].to_vec();
+            old_key.extend(&key[8..]);
+            let mut old_value = vec![value[0]];
+            old_value.extend(&value[5..]);
+            nodes.remove(&key).unwrap();
+            nodes.insert(old_key, old_value).unwrap();
+        }
+        for key in [&b"LeafCount"[..], &b"NodeCount"[..]].iter() {
+            let value = counters.get(key).unwrap().unwrap();
+            counters.insert(key, &value[4..]).unwrap();
+        }
+        counters.remove(b"IndexWidth").unwrap();
+        copy_database(&db, directory.path())
+    };
+
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    assert_eq!(tree.len(), 7);
+    assert_eq!(tree.root_hash(), root_hash);
+    for (position, witness) in witnesses.iter().enumerate() {
+        assert_eq!(&tree.witness(position), witness);
+    }
+    assert_eq!(tree.get(6), Ok(Some("g".to_string())));
+    tree.prune().unwrap();
+    assert!(tree.witness(2).is_some());
+    tree.add("h".to_string());
+    assert_eq!(tree.len(), 8);
+}
+
+#[test]
+fn iter_range() {
+    let tree = make_tree("abcdefghijk");
+    let collect = |iter: &mut dyn Iterator<Item = Result<String, NotRetained>>| {
+        iter.collect::<Result<String, _>>().unwrap()
+    };
+    assert_eq!(collect(&mut tree.iter_range(3..7)), "defg");
+    assert_eq!(collect(&mut tree.iter_range(3..7).rev()), "gfed");
+    assert_eq!(collect(&mut tree.iter_range(8..usize::MAX)), "ijk");
+    assert_eq!(tree.iter_range(11..20).count(), 0);
+
+    let mut iter = tree.iter_range(0..3);
+    assert_eq!(iter.next_back(), Some(Ok("c".to_string())));
+    assert_eq!(iter.next(), Some(Ok("a".to_string())));
+    assert_eq!(iter.next(), Some(Ok("b".to_string())));
+    assert_eq!(iter.next_back(), None);
+}
+
+#[test]
+fn iter_leaves() {
+    let tree = make_tree("abcdefghijk");
+    let leaves: Vec<_> = tree.iter_leaves().map(Result::unwrap).collect();
+    assert_eq!(leaves.len(), 11);
+    for (position, leaf) in leaves.iter().enumerate() {
+        assert_eq!(leaf.position, position);
+        assert_eq!(leaf.hash, tree.get(position).unwrap().unwrap());
+        assert_eq!(leaf.element, tree.get(position).unwrap());
+    }
+
+    let directory = tempdir().unwrap();
+    let mut hashes_only = SledMerkleTree::new_with_storage(
+        StringHasher::new(),
+        directory.path(),
+        5,
+        LeafStorage::HashesOnly,
+    );
+    for character in "abc".chars() {
+        hashes_only.add(character.to_string());
+    }
+    hashes_only.truncate(2).unwrap();
+    let leaves: Vec<_> = hashes_only.iter_leaves().map(Result::unwrap).collect();
+    assert_eq!(leaves.len(), 2);
+    assert_eq!(leaves[1].position, 1);
+    assert_eq!(leaves[1].hash, "b".to_string());
+    assert_eq!(leaves[1].element, None);
+
+    // a row that can't be decoded is an error, and ends the iteration
+    let tree = make_tree("abcd");
+    tree.leaves
+        .insert(LeafIndex(1).element_key(), &[100, 0, 0, 0])
+        .unwrap();
+    let mut leaves = tree.iter_leaves();
+    assert_eq!(leaves.next().unwrap().unwrap().position, 0);
+    assert!(leaves.next().unwrap().is_err());
+    assert!(leaves.next().is_none());
+}
+
+#[test]
+fn iterators_during_modification() {
+    let directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in "abcdef".chars() {
+        tree.add(character.to_string());
+    }
+    let reader = tree.handle();
+
+    // leaves added after the iterator was created aren't yielded
+    let leaves = reader.iter_leaves();
+    tree.add("g".to_string());
+    assert_eq!(leaves.count(), 6);
+
+    // truncating below the next leaf ends the iteration
+    let mut notes = reader.iter_notes().map(Result::unwrap);
+    let mut range = reader.iter_range(0..6);
+    assert_eq!(notes.next(), Some("a".to_string()));
+    assert_eq!(range.next_back(), Some(Ok("f".to_string())));
+    tree.truncate(3).unwrap();
+    tree.add("x".to_string());
+    assert_eq!(notes.collect::<String>(), "bc");
+    assert_eq!(range.next_back(), None);
+    assert_eq!(range.next(), None);
+
+    assert_eq!(
+        reader.iter_notes().collect::<Result<String, _>>(),
+        Ok("abcx".to_string())
+    );
+}
+
+#[test]
+fn subtree_root() {
+    use crate::linked::LinkedMerkleTree;
+
+    let directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_storage(
+        StringHasher::new(),
+        directory.path(),
+        5,
+        LeafStorage::HashesOnly,
+    );
+    let mut expected = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in "abcdefghijk".chars() {
+        tree.add(character.to_string());
+        expected.add(character.to_string());
+    }
+    for level in 0..5 {
+        for index in 0..3 {
+            assert_eq!(
+                tree.subtree_root(level, index),
+                expected.subtree_root(level, index)
+            );
+            assert_eq!(
+                tree.subtree_witness(level, index),
+                expected.subtree_witness(level, index)
+            );
+        }
+    }
+    let witness = tree.subtree_witness(2, 1).unwrap();
+    let root = tree.subtree_root(2, 1).unwrap();
+    assert!(witness.verify_subtree(&StringHasher {}, 2, &root));
+}
+
+#[test]
+fn sync_from_subtree_roots() {
+    use crate::linked::LinkedMerkleTree;
+
+    let characters = "abcdefghijklmnopqrstu";
+    let mut full = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    for character in characters.chars() {
+        full.add(character.to_string());
+    }
+    for level in 1..4 {
+        let width = 1 << level;
+        let roots: Vec<String> = (0..characters.len() / width)
+            .map(|index| full.subtree_root(level, index).unwrap())
+            .collect();
+        let sync_size = roots.len() * width;
+        let directory = tempdir().unwrap();
+        let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 6);
+        tree.sync_from_subtree_roots(
+            level,
+            &roots,
+            characters[sync_size..]
+                .chars()
+                .map(|character| character.to_string())
+                .collect(),
+        )
+        .unwrap();
+        assert_eq!(tree.len(), characters.len());
+        assert_eq!(tree.root_hash(), full.root_hash());
+        for past_size in sync_size..=characters.len() {
+            assert_eq!(tree.past_root(past_size), full.past_root(past_size));
+        }
+        assert_eq!(
+            tree.get(sync_size - 1),
+            Err(NotRetained {
+                position: sync_size - 1
+            })
+        );
+        assert_eq!(tree.witness(sync_size - 1), None);
+        for position in sync_size..characters.len() {
+            assert_eq!(tree.get(position), full.get(position));
+            assert_eq!(tree.witness(position), full.witness(position));
+        }
+
+        // the sync point survives reopening and pruning
+        let directory = copy_database(&tree._db, directory.path());
+        drop(tree);
+        let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 6);
+        tree.prune().unwrap();
+        let mut expected = full.clone();
+        for character in "vwxyz".chars() {
+            tree.add(character.to_string());
+            expected.add(character.to_string());
+        }
+        assert_eq!(tree.root_hash(), expected.root_hash());
+        assert_eq!(tree.past_root(sync_size), expected.past_root(sync_size));
+        assert_eq!(
+            tree.witness(characters.len()),
+            expected.witness(characters.len())
+        );
+
+        tree.truncate(0).unwrap();
+        assert_eq!(tree.root_hash(), None);
+        tree.add("a".to_string());
+        assert_eq!(tree.past_root(1), full.past_root(1));
+    }
+}
+
+#[test]
+fn sync_without_leaves() {
+    use crate::linked::LinkedMerkleTree;
+
+    let mut full = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in "abcdefghij".chars() {
+        full.add(character.to_string());
+    }
+    let directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 5);
+    for character in "stuvwxyz".chars() {
+        tree.add(character.to_string());
+    }
+    // the leftover leaves must not pass for the ones the sync skips
+    tree.truncate(0).unwrap();
+    let roots = [full.subtree_root(3, 0).unwrap()];
+    let error = tree.sync_from_subtree_roots(5, &roots, vec![]).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    tree.sync_from_subtree_roots(3, &roots, vec![]).unwrap();
+    assert_eq!(tree.len(), 8);
+    assert_eq!(tree.root_hash(), full.past_root(8));
+    tree.add("i".to_string());
+    tree.add("j".to_string());
+    assert_eq!(tree.root_hash(), full.root_hash());
+    assert_eq!(tree.witness(8), full.witness(8));
+    assert_eq!(tree.witness(9), full.witness(9));
+    assert_eq!(tree.witness(7), None);
+}
+
+#[test]
+fn export_and_import_state() {
+    use crate::linked::LinkedMerkleTree;
+
+    let characters = "abcdefghijklmnopqrstu";
+    let mut full = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    for character in characters.chars() {
+        full.add(character.to_string());
+    }
+    for num_leaves in [0, 1, 7, 8, 13] {
+        let mut expected = full.clone();
+        expected.truncate(num_leaves).unwrap();
+        let mut state = vec![];
+        expected.export_state(&mut state).unwrap();
+
+        let directory = tempdir().unwrap();
+        let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 6);
+        tree.import_state(&mut &state[..]).unwrap();
+        assert_eq!(tree.len(), num_leaves);
+        let report = tree.verify_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(tree.root_hash(), expected.root_hash());
+        let mut exported = vec![];
+        tree.export_state(&mut exported).unwrap();
+        assert_eq!(exported, state);
+
+        // the imported state survives reopening
+        let directory = copy_database(&tree._db, directory.path());
+        drop(tree);
+        let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 6);
+        for character in characters.chars().skip(num_leaves) {
+            tree.add(character.to_string());
+        }
+        assert_eq!(tree.root_hash(), full.root_hash());
+        let error = tree.import_state(&mut &state[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        for past_size in num_leaves.max(1)..=characters.len() {
+            assert_eq!(tree.past_root(past_size), full.past_root(past_size));
+        }
+        for position in num_leaves..characters.len() {
+            assert_eq!(tree.witness(position), full.witness(position));
+        }
+        let report = tree.verify_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        let mut exported = vec![];
+        tree.export_state(&mut exported).unwrap();
+        let mut expected_state = vec![];
+        full.export_state(&mut expected_state).unwrap();
+        assert_eq!(exported, expected_state);
+    }
+}
+
+#[test]
+fn verify_integrity() {
+    use crate::integrity::{IntegrityProblem, Row};
+
+    let directory = tempdir().unwrap();
+    let mut tree = SledMerkleTree::new_with_size(StringHasher::new(), directory.path(), 6);
+    for character in "abcdefghijklm".chars() {
+        tree.add(character.to_string());
+    }
+    tree.mark(4);
+    tree.prune().unwrap();
+    let report = tree.verify_integrity().unwrap();
+    assert!(report.is_ok(), "{:?}", report.problems);
+
+    let parent = (&tree.counters, &tree.leaves, &tree.nodes)
+        .transaction(|(counters, leaves, nodes)| {
+            let sledder = Sledder::new(tree.hasher.clone(), counters, leaves, nodes);
+            let parent = sledder.leaf_parent(LeafIndex(12)).unwrap();
+            if let Node::Left { parent: above, .. } = sledder.node(parent) {
+                sledder.set_node(
+                    parent,
+                    &Node::Left {
+                        hash_of_sibling: "x".to_string(),
+                        parent: above,
+                    },
+                );
+            }
+            Ok(parent)
+        })
+        .unwrap();
+    assert!(tree
+        .verify_integrity()
+        .unwrap()
+        .problems
+        .contains(&IntegrityProblem::HashMismatch(parent.0)));
+
+    // a node row too short to hold an index and a hash
+    tree.nodes
+        .insert(&b"Node\0\0\0\0\0\0\0\x02"[..], &b"\x07"[..])
+        .unwrap();
+    assert!(tree
+        .verify_integrity()
+        .unwrap()
+        .problems
+        .contains(&IntegrityProblem::Unreadable(Row::Node(2))));
+}
+
+#[test]
+fn depth_at_leaf_count() {
+    use super::depth_at_leaf_count;
+    // including counts that f32 can't represent exactly
+    for &(count, depth) in [
+        (0, 0),
+        (1, 1),
+        (2, 2),
+        (3, 3),
+        (4, 3),
+        (5, 4),
+        (1 << 25, 26),
+        ((1 << 25) + 1, 27),
+        (1 << 33, 34),
+        ((1 << 33) + 1, 35),
+    ]
+    .iter()
+    {
+        assert_eq!(depth_at_leaf_count(count), depth, "{} leaves", count);
+    }
+}
@@ -0,0 +1,138 @@
+//! Read-only views of a Merkle tree, pinned at the size it had when the
+//! view was taken.
+//!
+//! Every backend with a `snapshot()` method returns a Snapshot which is
+//! unaffected by anything that happens to the original afterwards. The
+//! in-memory trees hand out a copy-on-write clone of themselves, the rocks
+//! tree hands out a handle that reads from a rocksdb snapshot, and the sled
+//! tree, since sled 0.30 can't take snapshots, copies itself into a
+//! temporary database.
+
+use super::{HashableElement, MerkleHasher, MerkleTree, NotRetained, Witness};
+#[cfg(feature = "sledder")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "sledder")]
+use std::sync::{Arc, Mutex, Weak};
+
+type Element<M> = <<M as MerkleTree>::Hasher as MerkleHasher>::Element;
+type Hash<M> = <Element<M> as HashableElement>::Hash;
+
+/// A read-only view of a tree at a fixed size.
+pub struct Snapshot<M: MerkleTree> {
+    tree: M,
+    size: usize,
+}
+
+impl<M: MerkleTree> Snapshot<M> {
+    /// Wrap a tree that nothing else will modify.
+    pub(crate) fn new(tree: M) -> Self {
+        let size = tree.len();
+        Snapshot { tree, size }
+    }
+
+    /// Get the number of leaf nodes in the snapshot.
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Determine whether the snapshot has no leaf nodes.
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Get a clone of the element at position. A leaf whose element the tree
+    /// doesn't keep is a NotRetained error.
+    pub fn get(&self, position: usize) -> Result<Option<Element<M>>, NotRetained> {
+        if position >= self.size {
+            return Ok(None);
+        }
+        self.tree.get(position)
+    }
+
+    /// Iterate over clones of the leaf notes in the snapshot.
+    pub fn iter_notes<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = Result<Element<M>, NotRetained>> + 'a> {
+        Box::new(self.tree.iter_notes().take(self.size))
+    }
+
+    /// Get the hash of the root element when the snapshot was taken.
+    pub fn root_hash(&self) -> Option<Hash<M>> {
+        self.past_root(self.size)
+    }
+
+    /// Calculate what the root hash was when the tree contained `past_size`
+    /// elements. past_size cannot be greater than the size of the snapshot.
+    pub fn past_root(&self, past_size: usize) -> Option<Hash<M>> {
+        if past_size > self.size {
+            return None;
+        }
+        self.tree.past_root(past_size)
+    }
+
+    /// Determine whether the snapshot contains a value.
+    pub fn contains(&self, value: &Element<M>) -> bool {
+        self.contained(value, self.size)
+    }
+
+    /// Determine whether the tree contained a value when it had a specific
+    /// size, which cannot be greater than the size of the snapshot.
+    pub fn contained(&self, value: &Element<M>, past_size: usize) -> bool {
+        if past_size > self.size {
+            return false;
+        }
+        self.tree.contained(value, past_size)
+    }
+
+    /// Construct the proof that the leaf node at `position` exists in the
+    /// snapshot.
+    pub fn witness(&self, position: usize) -> Option<Witness<M::Hasher>> {
+        self.past_witness(position, self.size)
+    }
+
+    /// Construct the proof that the leaf node at `position` existed when the
+    /// tree contained `past_size` elements, which cannot be greater than the
+    /// size of the snapshot.
+    pub fn past_witness(&self, position: usize, past_size: usize) -> Option<Witness<M::Hasher>> {
+        if past_size > self.size {
+            return None;
+        }
+        self.tree.past_witness(position, past_size)
+    }
+}
+
+/// The iterators over the handles to a persistent tree, so that they can be
+/// told when another handle truncates it below their position.
+#[cfg(feature = "sledder")]
+#[derive(Default)]
+pub(crate) struct SnapshotRegistry {
+    watermarks: Mutex<Vec<Weak<AtomicUsize>>>,
+}
+
+#[cfg(feature = "sledder")]
+impl SnapshotRegistry {
+    /// Create the watermark for a new iterator.
+    pub(crate) fn register(&self) -> Arc<AtomicUsize> {
+        let truncated_to = Arc::new(AtomicUsize::new(usize::MAX));
+        let mut watermarks = self.watermarks.lock().unwrap();
+        watermarks.retain(|watermark| watermark.strong_count() > 0);
+        watermarks.push(Arc::downgrade(&truncated_to));
+        truncated_to
+    }
+
+    /// Tell every live iterator that the tree is about to be truncated to
+    /// `past_size`. Must be called before the truncation is written.
+    pub(crate) fn truncating(&self, past_size: usize) {
+        let mut watermarks = self.watermarks.lock().unwrap();
+        watermarks.retain(|watermark| match watermark.upgrade() {
+            Some(truncated_to) => {
+                truncated_to.fetch_min(past_size, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests;
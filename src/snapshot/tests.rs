@@ -0,0 +1,71 @@
+use crate::linked::LinkedMerkleTree;
+use crate::test_helper::StringHasher;
+use crate::vector::VectorMerkleTree;
+use crate::MerkleTree;
+
+#[test]
+fn linked_snapshot_ignores_later_changes() {
+    let mut tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in "abcde".chars() {
+        tree.add(character.to_string());
+    }
+    let root = tree.root_hash();
+    let root_at_three = tree.past_root(3);
+    let witness = tree.witness(2);
+    let snapshot = tree.snapshot();
+
+    tree.add("f".to_string());
+    tree.truncate(2).unwrap();
+    tree.add("x".to_string());
+    tree.add("y".to_string());
+
+    assert_eq!(snapshot.len(), 5);
+    assert_eq!(snapshot.root_hash(), root);
+    assert_eq!(snapshot.witness(2), witness);
+    assert_eq!(snapshot.get(2), Ok(Some("c".to_string())));
+    assert_eq!(snapshot.get(5), Ok(None));
+    assert!(snapshot.contains(&"e".to_string()));
+    assert!(!snapshot.contains(&"x".to_string()));
+    assert_eq!(
+        snapshot
+            .iter_notes()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap(),
+        vec!["a", "b", "c", "d", "e"]
+    );
+    assert_eq!(snapshot.past_root(3), root_at_three);
+    assert_eq!(snapshot.past_root(6), None);
+    assert!(!snapshot.contained(&"e".to_string(), 6));
+    assert_eq!(tree.get(2), Ok(Some("x".to_string())));
+}
+
+#[test]
+fn vector_snapshot_ignores_later_changes() {
+    let mut tree = VectorMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in "abcde".chars() {
+        tree.add(character.to_string());
+    }
+    let root = tree.root_hash();
+    let witness = tree.witness(4);
+    let snapshot = tree.snapshot();
+
+    tree.truncate(1).unwrap();
+    tree.add("x".to_string());
+
+    assert_eq!(snapshot.len(), 5);
+    assert_eq!(snapshot.root_hash(), root);
+    assert_eq!(snapshot.witness(4), witness);
+    assert_eq!(snapshot.get(1), Ok(Some("b".to_string())));
+    assert_eq!(snapshot.iter_notes().count(), 5);
+    assert_eq!(tree.len(), 2);
+}
+
+#[test]
+fn empty_snapshot() {
+    let tree = LinkedMerkleTree::new_with_size(StringHasher::new(), 5);
+    let snapshot = tree.snapshot();
+    assert!(snapshot.is_empty());
+    assert_eq!(snapshot.root_hash(), None);
+    assert_eq!(snapshot.witness(0), None);
+    assert_eq!(snapshot.iter_notes().count(), 0);
+}
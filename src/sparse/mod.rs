@@ -0,0 +1,275 @@
+//! A sparse Merkle tree with a leaf for every possible 256-bit key, such as
+//! a nullifier set.
+//!
+//! Almost all of the leaves are empty, so only the non-empty leaves and the
+//! internal nodes above them are stored. An empty subtree of a given height
+//! always has the same hash, which is calculated once up front from the hash
+//! of an empty leaf.
+//!
+//! The leaf for a key is found by reading the key as a big endian number:
+//! the least significant bit decides whether the leaf is a left or right
+//! child, the next bit decides the same for its parent, and so on up to the
+//! most significant bit, just below the root.
+
+use super::{HashableElement, MerkleHasher};
+use std::io;
+use std::sync::Arc;
+
+mod storage;
+pub use storage::{MemorySparseStorage, SparseStorage};
+#[cfg(feature = "sledder")]
+mod sled_storage;
+#[cfg(feature = "sledder")]
+pub use sled_storage::SledSparseStorage;
+
+/// The number of levels below the root.
+pub const SPARSE_TREE_DEPTH: usize = 256;
+
+/// The key that a leaf is stored under.
+pub type SparseKey = [u8; 32];
+
+type Hash<H> = <<H as MerkleHasher>::Element as HashableElement>::Hash;
+
+/// A Merkle tree with a leaf for every SparseKey, stored in S.
+///
+/// A key maps to a value if its leaf holds that value, and is absent if its
+/// leaf is empty. Values whose hash equals the empty leaf hash can't be told
+/// apart from an empty leaf, so the empty leaf hash should be something no
+/// value hashes to.
+///
+/// Every method that reads or writes the storage returns its errors. An
+/// insert or remove that fails part way leaves the storage as it was, as
+/// each one is a single storage update.
+pub struct SparseMerkleTree<H: MerkleHasher, S: SparseStorage<H> = MemorySparseStorage<H>> {
+    hasher: Arc<H>,
+    storage: S,
+    /// empty[height] is the hash of an empty subtree of that height; empty[0]
+    /// is the empty leaf hash.
+    empty: Vec<Hash<H>>,
+}
+
+impl<H: MerkleHasher> SparseMerkleTree<H> {
+    /// Construct a new, empty tree held in memory.
+    pub fn new(hasher: Arc<H>, empty_leaf: Hash<H>) -> Self {
+        SparseMerkleTree::with_storage(hasher, empty_leaf, MemorySparseStorage::default())
+    }
+}
+
+impl<H: MerkleHasher, S: SparseStorage<H>> SparseMerkleTree<H, S> {
+    /// Construct a tree on top of the given storage, which may already hold
+    /// a tree. It must have been built with the same empty leaf hash.
+    pub fn with_storage(hasher: Arc<H>, empty_leaf: Hash<H>, storage: S) -> Self {
+        let mut empty = vec![empty_leaf];
+        for height in 0..SPARSE_TREE_DEPTH {
+            let below = &empty[height];
+            let next = hasher.combine_hash(height, below, below);
+            empty.push(next);
+        }
+        SparseMerkleTree {
+            hasher,
+            storage,
+            empty,
+        }
+    }
+
+    /// Expose the hasher
+    pub fn hasher(&self) -> Arc<H> {
+        self.hasher.clone()
+    }
+
+    /// The hash of an empty leaf, needed to verify non-membership proofs.
+    pub fn empty_leaf(&self) -> &Hash<H> {
+        &self.empty[0]
+    }
+
+    /// Get a clone of the value stored under key.
+    pub fn get(&self, key: &SparseKey) -> io::Result<Option<H::Element>> {
+        self.storage.leaf(key)
+    }
+
+    /// Determine whether a value is stored under key.
+    pub fn contains_key(&self, key: &SparseKey) -> io::Result<bool> {
+        Ok(self.storage.node(0, key)?.is_some())
+    }
+
+    /// Store value under key, returning the value it replaced.
+    pub fn insert(&mut self, key: SparseKey, value: H::Element) -> io::Result<Option<H::Element>> {
+        let previous = self.get(&key)?;
+        let leaf_hash = value.merkle_hash();
+        self.update(&key, Some(&value), leaf_hash)?;
+        Ok(previous)
+    }
+
+    /// Empty the leaf for key, returning the value it held.
+    pub fn remove(&mut self, key: &SparseKey) -> io::Result<Option<H::Element>> {
+        let previous = match self.get(key)? {
+            Some(previous) => previous,
+            None => return Ok(None),
+        };
+        let empty_leaf = self.empty[0].clone();
+        self.update(key, None, empty_leaf)?;
+        Ok(Some(previous))
+    }
+
+    /// Get the hash of the root. An empty tree has the hash of an empty
+    /// subtree of the full depth.
+    pub fn root_hash(&self) -> io::Result<Hash<H>> {
+        self.node_hash(SPARSE_TREE_DEPTH, &[0; 32])
+    }
+
+    /// Construct the proof for key. If key has a value, it proves that
+    /// value is stored under key, otherwise it proves key is absent.
+    pub fn prove(&self, key: &SparseKey) -> io::Result<SparseProof<H>> {
+        Ok(SparseProof {
+            key: *key,
+            root_hash: self.root_hash()?,
+            siblings: (0..SPARSE_TREE_DEPTH)
+                .map(|height| self.sibling_hash(height, key))
+                .collect::<io::Result<_>>()?,
+        })
+    }
+
+    /// Get the hash of the node at height whose subtree contains key.
+    fn node_hash(&self, height: usize, key: &SparseKey) -> io::Result<Hash<H>> {
+        Ok(self
+            .storage
+            .node(height, &prefix(key, height))?
+            .unwrap_or_else(|| self.empty[height].clone()))
+    }
+
+    /// Get the hash of the sibling of the node at height on the path to key.
+    fn sibling_hash(&self, height: usize, key: &SparseKey) -> io::Result<Hash<H>> {
+        let mut sibling = *key;
+        flip_bit(&mut sibling, height);
+        self.node_hash(height, &sibling)
+    }
+
+    /// Recalculate the path from the leaf for key up to the root and store
+    /// it, along with the new leaf, in one update. Nodes that are now empty
+    /// are removed from storage.
+    fn update(
+        &mut self,
+        key: &SparseKey,
+        value: Option<&H::Element>,
+        leaf_hash: Hash<H>,
+    ) -> io::Result<()> {
+        let mut nodes = Vec::with_capacity(SPARSE_TREE_DEPTH + 1);
+        let mut current = leaf_hash;
+        for height in 0..SPARSE_TREE_DEPTH {
+            let sibling = self.sibling_hash(height, key)?;
+            let next = if bit(key, height) {
+                self.hasher.combine_hash(height, &sibling, &current)
+            } else {
+                self.hasher.combine_hash(height, &current, &sibling)
+            };
+            nodes.push(self.stored_node(height, key, current));
+            current = next;
+        }
+        nodes.push(self.stored_node(SPARSE_TREE_DEPTH, key, current));
+        self.storage.update(key, value, &nodes)
+    }
+
+    fn stored_node(&self, height: usize, key: &SparseKey, hash: Hash<H>) -> SparseNode<H> {
+        let hash = if hash == self.empty[height] {
+            None
+        } else {
+            Some(hash)
+        };
+        (height, prefix(key, height), hash)
+    }
+}
+
+/// A node to store (or remove, if the hash is None) at a height, identified
+/// by the key of any leaf in its subtree with the bits below that height
+/// cleared. The leaf hashes are the nodes at height 0.
+pub type SparseNode<H> = (usize, SparseKey, Option<Hash<H>>);
+
+/// Proof that a key maps to a value, or that it is absent, in a tree with a
+/// particular root hash.
+pub struct SparseProof<H: MerkleHasher> {
+    pub key: SparseKey,
+    pub root_hash: Hash<H>,
+    /// The hashes of the siblings on the path from the leaf to the root,
+    /// starting with the sibling of the leaf.
+    pub siblings: Vec<Hash<H>>,
+}
+
+impl<H: MerkleHasher> SparseProof<H> {
+    /// Verify that the proof shows value stored under the key.
+    pub fn verify_membership(&self, hasher: &H, value: &H::Element) -> bool {
+        self.verify(hasher, value.merkle_hash())
+    }
+
+    /// Verify that the proof shows the key is absent. empty_leaf must be the
+    /// empty leaf hash the tree was built with.
+    pub fn verify_non_membership(&self, hasher: &H, empty_leaf: &Hash<H>) -> bool {
+        self.verify(hasher, empty_leaf.clone())
+    }
+
+    fn verify(&self, hasher: &H, leaf_hash: Hash<H>) -> bool {
+        if self.siblings.len() != SPARSE_TREE_DEPTH {
+            return false;
+        }
+        let mut current = leaf_hash;
+        for (height, sibling) in self.siblings.iter().enumerate() {
+            current = if bit(&self.key, height) {
+                hasher.combine_hash(height, sibling, &current)
+            } else {
+                hasher.combine_hash(height, &current, sibling)
+            };
+        }
+        current == self.root_hash
+    }
+
+    /// Serialize the proof to a writer. The format is the key, then the
+    /// root hash and each of the siblings, starting from the leaf. Hashes
+    /// are written with `MerkleHasher::write_hash`.
+    pub fn write<W: io::Write>(&self, hasher: &H, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.key)?;
+        hasher.write_hash(&self.root_hash, writer)?;
+        for sibling in self.siblings.iter() {
+            hasher.write_hash(sibling, writer)?;
+        }
+        Ok(())
+    }
+
+    /// Deserialize a proof that was written with `SparseProof::write`.
+    pub fn read<R: io::Read>(hasher: &H, reader: &mut R) -> io::Result<Self> {
+        let mut key = [0; 32];
+        reader.read_exact(&mut key)?;
+        let root_hash = hasher.read_hash(reader)?;
+        let siblings = (0..SPARSE_TREE_DEPTH)
+            .map(|_| hasher.read_hash(reader))
+            .collect::<io::Result<_>>()?;
+        Ok(SparseProof {
+            key,
+            root_hash,
+            siblings,
+        })
+    }
+}
+
+/// Determine whether the node at height on the path to key is a right child.
+fn bit(key: &SparseKey, height: usize) -> bool {
+    (key[31 - height / 8] >> (height % 8)) & 1 == 1
+}
+
+fn flip_bit(key: &mut SparseKey, height: usize) {
+    key[31 - height / 8] ^= 1 << (height % 8);
+}
+
+/// Clear the bits of key below height.
+fn prefix(key: &SparseKey, height: usize) -> SparseKey {
+    let mut prefix = *key;
+    let whole_bytes = height / 8;
+    for byte in prefix[32 - whole_bytes..].iter_mut() {
+        *byte = 0;
+    }
+    if whole_bytes < 32 {
+        prefix[31 - whole_bytes] &= 0xff << (height % 8);
+    }
+    prefix
+}
+
+#[cfg(test)]
+mod tests;
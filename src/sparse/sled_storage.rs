@@ -0,0 +1,88 @@
+use super::{Hash, SparseKey, SparseNode, SparseStorage};
+use crate::{HashableElement, MerkleHasher};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// SparseStorage kept in sled. Leaves are stored under b"Leaf" and the key,
+/// nodes under b"Node", the height as a big endian u16, and the prefix. Each
+/// update is applied as a single batch.
+///
+/// Failed database operations and rows that can't be decoded are returned
+/// as io::Errors.
+pub struct SledSparseStorage<H: MerkleHasher> {
+    hasher: Arc<H>,
+    pub(super) _db: sled::Db,
+    pub(super) tree: sled::Tree,
+}
+
+impl<H: MerkleHasher> SledSparseStorage<H> {
+    /// Open the storage in the given directory, creating it if necessary.
+    pub fn new(hasher: Arc<H>, sled_directory: &Path) -> io::Result<Self> {
+        let _db = sled::open(sled_directory).map_err(io_error)?;
+        let tree = _db.open_tree(b"sparse").map_err(io_error)?;
+        Ok(SledSparseStorage { hasher, _db, tree })
+    }
+}
+
+fn leaf_key(key: &SparseKey) -> Vec<u8> {
+    let mut leaf_key = b"Leaf".to_vec();
+    leaf_key.extend(key);
+    leaf_key
+}
+
+fn node_key(height: usize, prefix: &SparseKey) -> Vec<u8> {
+    let mut node_key = b"Node".to_vec();
+    node_key.extend(&(height as u16).to_be_bytes());
+    node_key.extend(prefix);
+    node_key
+}
+
+/// Convert an error from sled, such as a failed read, to an io::Error.
+fn io_error(error: sled::Error) -> io::Error {
+    io::Error::other(error.to_string())
+}
+
+impl<H: MerkleHasher> SparseStorage<H> for SledSparseStorage<H> {
+    fn leaf(&self, key: &SparseKey) -> io::Result<Option<H::Element>> {
+        match self.tree.get(leaf_key(key)).map_err(io_error)? {
+            Some(bytes) => Ok(Some(self.hasher.read_element(&mut &bytes[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    fn node(&self, height: usize, prefix: &SparseKey) -> io::Result<Option<Hash<H>>> {
+        match self.tree.get(node_key(height, prefix)).map_err(io_error)? {
+            Some(bytes) => Ok(Some(self.hasher.read_hash(&mut &bytes[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    fn update(
+        &mut self,
+        key: &SparseKey,
+        value: Option<&H::Element>,
+        nodes: &[SparseNode<H>],
+    ) -> io::Result<()> {
+        let mut batch = sled::Batch::default();
+        match value {
+            Some(value) => {
+                let mut bytes = vec![];
+                value.write(&mut bytes)?;
+                batch.insert(leaf_key(key), bytes);
+            }
+            None => batch.remove(leaf_key(key)),
+        }
+        for (height, prefix, hash) in nodes.iter() {
+            match hash {
+                Some(hash) => {
+                    let mut bytes = vec![];
+                    self.hasher.write_hash(hash, &mut bytes)?;
+                    batch.insert(node_key(*height, prefix), bytes);
+                }
+                None => batch.remove(node_key(*height, prefix)),
+            }
+        }
+        self.tree.apply_batch(batch).map_err(io_error)
+    }
+}
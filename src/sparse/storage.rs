@@ -0,0 +1,71 @@
+use super::{Hash, SparseKey, SparseNode};
+use crate::MerkleHasher;
+use std::collections::HashMap;
+use std::io;
+
+/// Where a SparseMerkleTree keeps its non-empty leaves and nodes.
+///
+/// Storage that can fail, such as a database, returns its errors, including
+/// stored values that can't be decoded, rather than panicking.
+pub trait SparseStorage<H: MerkleHasher> {
+    /// Get a clone of the value stored under key.
+    fn leaf(&self, key: &SparseKey) -> io::Result<Option<H::Element>>;
+
+    /// Get the hash of the node at height identified by prefix, if it isn't
+    /// empty.
+    fn node(&self, height: usize, prefix: &SparseKey) -> io::Result<Option<Hash<H>>>;
+
+    /// Apply the changes from one insert or remove: store value under key,
+    /// or remove it if value is None, and store or remove each of the nodes.
+    /// All of the changes should be applied, or none of them.
+    fn update(
+        &mut self,
+        key: &SparseKey,
+        value: Option<&H::Element>,
+        nodes: &[SparseNode<H>],
+    ) -> io::Result<()>;
+}
+
+/// SparseStorage that keeps everything in memory.
+pub struct MemorySparseStorage<H: MerkleHasher> {
+    pub(super) leaves: HashMap<SparseKey, H::Element>,
+    pub(super) nodes: HashMap<(usize, SparseKey), Hash<H>>,
+}
+
+impl<H: MerkleHasher> Default for MemorySparseStorage<H> {
+    fn default() -> Self {
+        MemorySparseStorage {
+            leaves: HashMap::new(),
+            nodes: HashMap::new(),
+        }
+    }
+}
+
+impl<H: MerkleHasher> SparseStorage<H> for MemorySparseStorage<H> {
+    fn leaf(&self, key: &SparseKey) -> io::Result<Option<H::Element>> {
+        Ok(self.leaves.get(key).cloned())
+    }
+
+    fn node(&self, height: usize, prefix: &SparseKey) -> io::Result<Option<Hash<H>>> {
+        Ok(self.nodes.get(&(height, *prefix)).cloned())
+    }
+
+    fn update(
+        &mut self,
+        key: &SparseKey,
+        value: Option<&H::Element>,
+        nodes: &[SparseNode<H>],
+    ) -> io::Result<()> {
+        match value {
+            Some(value) => self.leaves.insert(*key, value.clone()),
+            None => self.leaves.remove(key),
+        };
+        for (height, prefix, hash) in nodes.iter() {
+            match hash {
+                Some(hash) => self.nodes.insert((*height, *prefix), hash.clone()),
+                None => self.nodes.remove(&(*height, *prefix)),
+            };
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use super::{SparseKey, SparseMerkleTree, SparseProof, SPARSE_TREE_DEPTH};
+use crate::test_helper::MixHasher;
+
+const EMPTY: u64 = 0;
+
+fn key(last_byte: u8, first_byte: u8) -> SparseKey {
+    let mut key = [0; 32];
+    key[0] = first_byte;
+    key[31] = last_byte;
+    key
+}
+
+#[test]
+fn empty_tree() {
+    let tree = SparseMerkleTree::new(MixHasher::new(), EMPTY);
+    let other = SparseMerkleTree::new(MixHasher::new(), EMPTY);
+    assert_eq!(tree.root_hash().unwrap(), other.root_hash().unwrap());
+    assert_eq!(tree.get(&key(1, 0)).unwrap(), None);
+    assert!(!tree.contains_key(&key(1, 0)).unwrap());
+    let proof = tree.prove(&key(1, 0)).unwrap();
+    assert_eq!(proof.siblings.len(), SPARSE_TREE_DEPTH);
+    assert!(proof.verify_non_membership(&MixHasher {}, &EMPTY));
+    assert!(!proof.verify_membership(&MixHasher {}, &5));
+}
+
+#[test]
+fn insert_and_remove() {
+    let mut tree = SparseMerkleTree::new(MixHasher::new(), EMPTY);
+    let empty_root = tree.root_hash().unwrap();
+    assert_eq!(tree.insert(key(1, 0), 10).unwrap(), None);
+    let one_root = tree.root_hash().unwrap();
+    assert_ne!(one_root, empty_root);
+    assert_eq!(tree.insert(key(2, 0), 20).unwrap(), None);
+    assert_eq!(tree.insert(key(1, 0x80), 30).unwrap(), None);
+    assert_eq!(tree.get(&key(2, 0)).unwrap(), Some(20));
+    assert!(tree.contains_key(&key(1, 0x80)).unwrap());
+    assert!(!tree.contains_key(&key(3, 0)).unwrap());
+
+    assert_eq!(tree.insert(key(2, 0), 21).unwrap(), Some(20));
+    assert_eq!(tree.get(&key(2, 0)).unwrap(), Some(21));
+
+    assert_eq!(tree.remove(&key(3, 0)).unwrap(), None);
+    assert_eq!(tree.remove(&key(2, 0)).unwrap(), Some(21));
+    assert_eq!(tree.remove(&key(1, 0x80)).unwrap(), Some(30));
+    assert_eq!(tree.root_hash().unwrap(), one_root);
+    assert_eq!(tree.remove(&key(1, 0)).unwrap(), Some(10));
+    assert_eq!(tree.root_hash().unwrap(), empty_root);
+    assert!(tree.storage.nodes.is_empty());
+    assert!(tree.storage.leaves.is_empty());
+}
+
+#[test]
+fn root_is_independent_of_insertion_order() {
+    let mut forward = SparseMerkleTree::new(MixHasher::new(), EMPTY);
+    let mut backward = SparseMerkleTree::new(MixHasher::new(), EMPTY);
+    for value in 1..20u8 {
+        forward.insert(key(value, value * 7), value as u64).unwrap();
+    }
+    for value in (1..20u8).rev() {
+        backward
+            .insert(key(value, value * 7), value as u64)
+            .unwrap();
+    }
+    assert_eq!(forward.root_hash().unwrap(), backward.root_hash().unwrap());
+}
+
+#[test]
+fn proofs() {
+    let hasher = MixHasher {};
+    let mut tree = SparseMerkleTree::new(MixHasher::new(), EMPTY);
+    tree.insert(key(1, 0), 10).unwrap();
+    tree.insert(key(3, 0), 30).unwrap();
+    tree.insert(key(1, 0xff), 40).unwrap();
+
+    let membership = tree.prove(&key(3, 0)).unwrap();
+    assert!(membership.verify_membership(&hasher, &30));
+    assert!(!membership.verify_membership(&hasher, &31));
+    assert!(!membership.verify_non_membership(&hasher, &EMPTY));
+
+    let non_membership = tree.prove(&key(2, 0)).unwrap();
+    assert!(non_membership.verify_non_membership(&hasher, &EMPTY));
+    assert!(!non_membership.verify_membership(&hasher, &30));
+
+    // the proof is tied to its key
+    let mut moved = tree.prove(&key(1, 0)).unwrap();
+    moved.key = key(1, 0xff);
+    assert!(!moved.verify_membership(&hasher, &10));
+
+    // and to the root it was made from, which changes with the tree
+    tree.insert(key(2, 0), 20).unwrap();
+    assert_ne!(non_membership.root_hash, tree.root_hash().unwrap());
+    assert!(tree
+        .prove(&key(2, 0))
+        .unwrap()
+        .verify_membership(&hasher, &20));
+}
+
+#[test]
+fn proof_serialization() {
+    let hasher = MixHasher {};
+    let mut tree = SparseMerkleTree::new(MixHasher::new(), EMPTY);
+    tree.insert(key(9, 9), 90).unwrap();
+    let proof = tree.prove(&key(9, 9)).unwrap();
+    let mut bytes = vec![];
+    proof.write(&hasher, &mut bytes).unwrap();
+    assert_eq!(bytes.len(), 32 + 8 * (SPARSE_TREE_DEPTH + 1));
+
+    let read_back = SparseProof::read(&hasher, &mut bytes[..].as_ref()).unwrap();
+    assert_eq!(read_back.key, proof.key);
+    assert_eq!(read_back.root_hash, proof.root_hash);
+    assert_eq!(read_back.siblings, proof.siblings);
+    assert!(read_back.verify_membership(&hasher, &90));
+    assert!(SparseProof::read(&hasher, &mut bytes[..bytes.len() - 1].as_ref()).is_err());
+}
+
+#[cfg(feature = "sledder")]
+#[test]
+fn sled_storage() {
+    use super::SledSparseStorage;
+    use crate::test_helper::copy_database;
+    use tempfile::tempdir;
+
+    let directory = tempdir().unwrap();
+    let mut memory = SparseMerkleTree::new(MixHasher::new(), EMPTY);
+    let mut sled = SparseMerkleTree::with_storage(
+        MixHasher::new(),
+        EMPTY,
+        SledSparseStorage::new(MixHasher::new(), directory.path()).unwrap(),
+    );
+    for value in 1..10u8 {
+        memory.insert(key(value, value), value as u64).unwrap();
+        sled.insert(key(value, value), value as u64).unwrap();
+    }
+    sled.remove(&key(4, 4)).unwrap();
+    memory.remove(&key(4, 4)).unwrap();
+    assert_eq!(sled.root_hash().unwrap(), memory.root_hash().unwrap());
+    assert_eq!(sled.get(&key(5, 5)).unwrap(), Some(5));
+    assert_eq!(sled.get(&key(4, 4)).unwrap(), None);
+    assert!(sled
+        .prove(&key(4, 4))
+        .unwrap()
+        .verify_non_membership(&MixHasher {}, &EMPTY));
+
+    // a row that can't be decoded is an error, not a panic
+    let copy = copy_database(&sled.storage._db, directory.path());
+    let mut leaf_key = b"Leaf".to_vec();
+    leaf_key.extend(&key(5, 5));
+    sled.storage.tree.insert(&leaf_key, vec![1, 2]).unwrap();
+    // the leaf hash of key(6, 6) is the sibling of the leaf for key(7, 6)
+    let mut node_key = b"Node".to_vec();
+    node_key.extend(&0u16.to_be_bytes());
+    node_key.extend(&key(6, 6));
+    sled.storage.tree.insert(&node_key, vec![3]).unwrap();
+    assert!(sled.get(&key(5, 5)).is_err());
+    assert!(sled.prove(&key(7, 6)).is_err());
+    assert!(sled.insert(key(7, 6), 7).is_err());
+    drop(sled);
+
+    let reopened = SparseMerkleTree::with_storage(
+        MixHasher::new(),
+        EMPTY,
+        SledSparseStorage::new(MixHasher::new(), copy.path()).unwrap(),
+    );
+    assert_eq!(reopened.root_hash().unwrap(), memory.root_hash().unwrap());
+
+    // a directory that can't hold a database fails to open
+    let file = copy.path().join("not a directory");
+    std::fs::write(&file, b"").unwrap();
+    assert!(SledSparseStorage::<MixHasher>::new(MixHasher::new(), &file).is_err());
+}
@@ -1,8 +1,13 @@
+use crate::indexed::{IndexedLeaf, IndexedValue};
+use crate::kary::MerkleHasherN;
 use crate::{HashableElement, MerkleHasher};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io;
-use std::io::Read;
 use std::sync::Arc;
+#[cfg(feature = "sledder")]
+use std::{fs, path::Path};
+#[cfg(feature = "sledder")]
+use tempfile::{tempdir, TempDir};
 
 /// Fake hashable element that just concatenates strings so it is easy to
 /// test that the correct values are output. It's weird cause the hashes are
@@ -38,13 +43,8 @@ impl MerkleHasher for StringHasher {
 
     fn read_element<R: io::Read>(&self, reader: &mut R) -> io::Result<String> {
         let str_size = reader.read_u8()?;
-        // There has GOT to be a better way to do this
-        // (read str_size bytes into a string)
-        let bytes = reader
-            .take(str_size as u64)
-            .bytes()
-            .map(|b| b.unwrap())
-            .collect::<Vec<u8>>();
+        let mut bytes = vec![0u8; str_size as usize];
+        reader.read_exact(&mut bytes)?;
         match String::from_utf8(bytes) {
             Ok(s) => Ok(s),
             Err(_) => Err(io::Error::new(
@@ -68,6 +68,22 @@ impl MerkleHasher for StringHasher {
     }
 }
 
+impl MerkleHasherN for StringHasher {
+    fn combine_many(&self, depth: usize, hashes: &[String]) -> String {
+        "<".to_string() + &hashes.join("|") + "-" + &depth.to_string() + ">"
+    }
+}
+
+/// Chains combine_hash over the children, so that two children hash the
+/// same way they do in a binary tree.
+impl MerkleHasherN for MixHasher {
+    fn combine_many(&self, depth: usize, hashes: &[u64]) -> u64 {
+        hashes[1..].iter().fold(hashes[0], |hash, child| {
+            self.combine_hash(depth, &hash, child)
+        })
+    }
+}
+
 /// Fake hashable element that just counts the number of levels.
 /// I made this because man, 32 levels of StringHasher is a lot of bytes.
 /// Like, crashed my computer bytes.
@@ -110,3 +126,111 @@ impl MerkleHasher for CountHasher {
         panic!("Not needed for the unit test suite");
     }
 }
+
+/// Hasher for u64 elements that mixes both children and the depth, for
+/// tests of trees too deep for StringHasher where CountHasher, which
+/// ignores the right child, can't tell different trees apart.
+#[derive(Debug, PartialEq)]
+pub(crate) struct MixHasher {}
+
+impl MixHasher {
+    pub fn new() -> Arc<MixHasher> {
+        Arc::new(MixHasher {})
+    }
+}
+
+impl MerkleHasher for MixHasher {
+    type Element = u64;
+    fn combine_hash(&self, depth: usize, left: &u64, right: &u64) -> u64 {
+        left.rotate_left(17)
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            .wrapping_add(*right ^ (depth as u64))
+    }
+
+    fn read_element<R: io::Read>(&self, reader: &mut R) -> io::Result<u64> {
+        reader.read_u64::<LittleEndian>()
+    }
+
+    fn read_hash<R: io::Read>(&self, reader: &mut R) -> io::Result<u64> {
+        reader.read_u64::<LittleEndian>()
+    }
+
+    fn write_hash<W: io::Write>(&self, hash: &u64, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(*hash)
+    }
+}
+
+impl IndexedValue for u64 {
+    type Hash = u64;
+    fn hash_leaf(value: &u64, next_index: usize, next_value: &u64) -> u64 {
+        value
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            .rotate_left(23)
+            .wrapping_add((next_index as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f))
+            .rotate_left(29)
+            ^ *next_value
+    }
+
+    fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(*self)
+    }
+
+    fn read<R: io::Read>(reader: &mut R) -> io::Result<u64> {
+        reader.read_u64::<LittleEndian>()
+    }
+}
+
+/// MixHasher for the leaves of an indexed tree of u64 values.
+#[derive(Debug)]
+pub(crate) struct IndexedMixHasher {}
+
+impl IndexedMixHasher {
+    pub fn new() -> Arc<IndexedMixHasher> {
+        Arc::new(IndexedMixHasher {})
+    }
+}
+
+impl MerkleHasher for IndexedMixHasher {
+    type Element = IndexedLeaf<u64>;
+    fn combine_hash(&self, depth: usize, left: &u64, right: &u64) -> u64 {
+        MixHasher {}.combine_hash(depth, left, right)
+    }
+
+    fn read_element<R: io::Read>(&self, reader: &mut R) -> io::Result<IndexedLeaf<u64>> {
+        IndexedLeaf::read(reader)
+    }
+
+    fn read_hash<R: io::Read>(&self, reader: &mut R) -> io::Result<u64> {
+        reader.read_u64::<LittleEndian>()
+    }
+
+    fn write_hash<W: io::Write>(&self, hash: &u64, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(*hash)
+    }
+}
+
+/// Flush a database and copy its files to a new directory, to open it again
+/// there. Sled holds on to the files of a dropped database until its
+/// background threads are done with them, so opening the same directory again
+/// straight away can fail to get the lock.
+#[cfg(feature = "sledder")]
+pub(crate) fn copy_database(db: &sled::Db, directory: &Path) -> TempDir {
+    db.flush().unwrap();
+    let copy = tempdir().unwrap();
+    copy_directory(directory, copy.path());
+    copy
+}
+
+#[cfg(feature = "sledder")]
+fn copy_directory(from: &Path, to: &Path) {
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            fs::create_dir(&target).unwrap();
+            copy_directory(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
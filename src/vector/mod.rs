@@ -2,8 +2,15 @@
 /// a vector (actually, I used a deque, it's not quite as inefficient)
 /// as a complete binary tree. This is dreadfully inefficient, but
 /// it was a quick way to get an API implementation up and running.
-use super::{HashableElement, MerkleHasher, MerkleTree, Witness, WitnessNode};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use super::checkpoint::{Checkpointed, Checkpoints};
+use super::config::{ConfigError, TreeConfig};
+#[cfg(feature = "serde")]
+use super::serialize_tree;
+use super::snapshot::Snapshot;
+use super::{
+    read_tree_header, write_tree_header, HashableElement, MerkleHasher, MerkleTree, NotRetained,
+    Witness, WitnessNode,
+};
 use std::collections::VecDeque;
 use std::io;
 use std::sync::Arc;
@@ -17,6 +24,16 @@ enum Node<T: MerkleHasher> {
     Empty,
 }
 
+impl<T: MerkleHasher> Clone for Node<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Node::Leaf(element) => Node::Leaf(element.clone()),
+            Node::Internal(hash) => Node::Internal(hash.clone()),
+            Node::Empty => Node::Empty,
+        }
+    }
+}
+
 // Iterator over references to the elements in the tree. Only the leaf
 // nodes are iterated.
 pub struct VectorLeafIterator<'a, T: MerkleHasher> {
@@ -26,7 +43,7 @@ pub struct VectorLeafIterator<'a, T: MerkleHasher> {
 impl<'a, T: MerkleHasher> VectorLeafIterator<'a, T> {
     // Construct a new iterator using a reference to the nodes in a VectorMerkleTree
     fn new(nodes: &'a VecDeque<Node<T>>) -> VectorLeafIterator<'a, T> {
-        let first_leaf_index = if nodes.len() > 0 {
+        let first_leaf_index = if !nodes.is_empty() {
             first_leaf(nodes.len())
         } else {
             0
@@ -76,10 +93,30 @@ impl<'a, T: MerkleHasher> Iterator for VectorLeafIterator<'a, T> {
 ///  *  nearly half the tree will usually contain empty nodes
 ///  *  related nodes for a given authentication path are scattered throughout
 ///     the array
+///
+/// The deque is shared copy-on-write between clones of the tree, so cloning
+/// is cheap. The first modification after a clone copies the deque.
+///
+/// Unlike the other trees, this one can't `import_state`. Every internal
+/// node is recomputed from the leaves below it whenever the tree grows a
+/// level, so it needs every leaf, and an imported state has none of the
+/// leaves before it.
 pub struct VectorMerkleTree<T: MerkleHasher> {
-    nodes: VecDeque<Node<T>>,
+    nodes: Arc<VecDeque<Node<T>>>,
     tree_depth: usize,
     hasher: Arc<T>,
+    checkpoints: Checkpoints,
+}
+
+impl<T: MerkleHasher> Clone for VectorMerkleTree<T> {
+    fn clone(&self) -> Self {
+        VectorMerkleTree {
+            nodes: self.nodes.clone(),
+            tree_depth: self.tree_depth,
+            hasher: self.hasher.clone(),
+            checkpoints: self.checkpoints.clone(),
+        }
+    }
 }
 
 impl<T: MerkleHasher> VectorMerkleTree<T> {
@@ -90,24 +127,47 @@ impl<T: MerkleHasher> VectorMerkleTree<T> {
     }
 
     /// Used for simpler unit tests
-    fn new_with_size(hasher: Arc<T>, tree_depth: usize) -> Box<Self> {
+    pub(crate) fn new_with_size(hasher: Arc<T>, tree_depth: usize) -> Box<Self> {
         Box::new(VectorMerkleTree {
-            nodes: VecDeque::new(),
+            nodes: Arc::new(VecDeque::new()),
             tree_depth,
             hasher,
+            checkpoints: Checkpoints::default(),
         })
     }
 
+    /// Construct a new, empty tree as described by config.
+    pub fn with_config(hasher: Arc<T>, config: TreeConfig) -> Result<Box<Self>, ConfigError> {
+        config.validate_in_memory()?;
+        let mut tree = VectorMerkleTree::new_with_size(hasher, config.depth);
+        if let Some(reorg_depth) = config.reorg_depth {
+            tree.checkpoints.set_reorg_depth(reorg_depth);
+        }
+        Ok(tree)
+    }
+
+    /// Take a read-only view of the tree at its current size. Later changes
+    /// to this tree are not visible through the snapshot.
+    pub fn snapshot(&self) -> Snapshot<Self> {
+        Snapshot::new(self.clone())
+    }
+
+    /// Get a mutable reference to the nodes, copying them first if they are
+    /// shared with a clone of the tree.
+    fn nodes_mut(&mut self) -> &mut VecDeque<Node<T>> {
+        Arc::make_mut(&mut self.nodes)
+    }
+
     /// Called when a new leaf was added to a complete binary tree, meaning
     /// that everything needs to be moved around and hashes need to be
     /// recalculated. The garbage in this method is the whole reason a vector
     /// based complete binary tree implementation is inefficient.
     fn add_leaf_rehash(&mut self, element: T::Element) {
         let old_leaf_start = first_leaf(self.nodes.len());
-        self.nodes.push_back(Node::Leaf(element));
+        self.nodes_mut().push_back(Node::Leaf(element));
 
         for _ in 0..old_leaf_start {
-            self.nodes.pop_front();
+            self.nodes_mut().pop_front();
         }
 
         self.rehash_all_levels();
@@ -149,7 +209,7 @@ impl<T: MerkleHasher> VectorMerkleTree<T> {
                 ),
                 (_, _) => panic!("Invalid tree structure"),
             };
-            self.nodes.push_front(new_node);
+            self.nodes_mut().push_front(new_node);
 
             if index_being_added == 0 {
                 break;
@@ -184,17 +244,13 @@ impl<T: MerkleHasher> VectorMerkleTree<T> {
                 }
             };
 
-            self.nodes[parent_position] = Node::Internal(parent_hash);
+            self.nodes_mut()[parent_position] = Node::Internal(parent_hash);
 
             depth += 1;
             current_position = parent_position;
         }
     }
 
-    fn is_empty(&self) -> bool {
-        self.nodes.len() == 0
-    }
-
     /// Extract the hash from a leaf or internal node.
     ///
     /// Returns None if the position is invalid or empty
@@ -208,13 +264,20 @@ impl<T: MerkleHasher> VectorMerkleTree<T> {
     }
 }
 
+/// Serialized as the bytes `write` writes. Read it back with a `TreeSeed`.
+#[cfg(feature = "serde")]
+impl<T: MerkleHasher> serde::Serialize for VectorMerkleTree<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_tree(self, serializer)
+    }
+}
+
 impl<T: MerkleHasher> MerkleTree for VectorMerkleTree<T> {
     type Hasher = T;
     /// Load a merkle tree from a reader and return a box pointer to it
     fn read<R: io::Read>(hasher: Arc<T>, reader: &mut R) -> io::Result<Box<Self>> {
-        let tree_depth = reader.read_u8()?;
-        let num_nodes = reader.read_u32::<LittleEndian>()?;
-        let mut tree = VectorMerkleTree::new_with_size(hasher, tree_depth as usize);
+        let (tree_depth, num_nodes) = read_tree_header(reader)?;
+        let mut tree = VectorMerkleTree::new_with_size(hasher, tree_depth);
         for _ in 0..num_nodes {
             tree.add(tree.hasher.read_element(reader)?);
         }
@@ -236,33 +299,36 @@ impl<T: MerkleHasher> MerkleTree for VectorMerkleTree<T> {
     ///      *  append an element and update all its parent hashes
     fn add(&mut self, element: T::Element) {
         if self.is_empty() {
-            self.nodes.push_back(Node::Leaf(element));
+            self.nodes_mut().push_back(Node::Leaf(element));
         } else if is_complete(self.nodes.len()) {
             if depth_at_index(self.nodes.len()) == self.tree_depth + 1 {
                 panic!("Tree is full!");
             }
             self.add_leaf_rehash(element);
         } else {
-            self.nodes.push_back(Node::Leaf(element));
+            self.nodes_mut().push_back(Node::Leaf(element));
             self.rehash_leaf_path();
         }
     }
 
     /// Get the leaf note at a specific position
-    fn get(&self, position: usize) -> Option<<Self::Hasher as MerkleHasher>::Element> {
-        if self.nodes.len() == 0 {
-            return None;
+    fn get(
+        &self,
+        position: usize,
+    ) -> Result<Option<<Self::Hasher as MerkleHasher>::Element>, NotRetained> {
+        if self.nodes.is_empty() {
+            return Ok(None);
         }
         let position = first_leaf(self.nodes.len()) + position;
         match self.nodes.get(position) {
-            Some(Node::Leaf(element)) => Some(element.clone()),
-            _ => None,
+            Some(Node::Leaf(element)) => Ok(Some(element.clone())),
+            _ => Ok(None),
         }
     }
 
     /// Get the number of leaf nodes in the tree
     fn len(&self) -> usize {
-        if self.nodes.len() == 0 {
+        if self.nodes.is_empty() {
             0
         } else {
             self.nodes.len() - first_leaf(self.nodes.len())
@@ -270,34 +336,37 @@ impl<T: MerkleHasher> MerkleTree for VectorMerkleTree<T> {
     }
 
     /// Truncate the tree to when it was a specific past size.
-    fn truncate(&mut self, past_size: usize) {
+    fn truncate(&mut self, past_size: usize) -> Result<(), NotRetained> {
         if past_size >= self.len() {
-            return;
+            return Ok(());
         }
+        self.checkpoints.truncated(past_size);
         if past_size == 0 {
-            self.nodes.clear();
-            return;
+            self.nodes_mut().clear();
+            return Ok(());
         }
 
         let old_leaf_start = first_leaf(self.nodes.len());
 
         for _ in 0..self.len() - past_size {
-            self.nodes.pop_back();
+            self.nodes_mut().pop_back();
         }
 
         for _ in 0..old_leaf_start {
-            self.nodes.pop_front();
+            self.nodes_mut().pop_front();
         }
 
         self.rehash_all_levels();
+        Ok(())
     }
 
     /// Iterate over clones of all leaf notes in the tree, without consuming
     /// the tree.
     fn iter_notes<'a>(
         &'a self,
-    ) -> Box<dyn Iterator<Item = <Self::Hasher as MerkleHasher>::Element> + 'a> {
-        Box::new(VectorLeafIterator::new(&self.nodes))
+    ) -> Box<dyn Iterator<Item = Result<<Self::Hasher as MerkleHasher>::Element, NotRetained>> + 'a>
+    {
+        Box::new(VectorLeafIterator::new(&self.nodes).map(Ok))
     }
 
     /// The current root hash of the tree.
@@ -313,7 +382,7 @@ impl<T: MerkleHasher> MerkleTree for VectorMerkleTree<T> {
 
     /// What was the root of the tree when it had past_size leaf nodes
     fn past_root(&self, past_size: usize) -> Option<<T::Element as HashableElement>::Hash> {
-        if self.nodes.len() == 0 || past_size > self.len() {
+        if self.nodes.is_empty() || past_size > self.len() || past_size == 0 {
             return None;
         }
         let mut cur = first_leaf(self.nodes.len()) + past_size - 1;
@@ -346,14 +415,14 @@ impl<T: MerkleHasher> MerkleTree for VectorMerkleTree<T> {
                 .combine_hash(depth, &current_hash, &current_hash);
             depth += 1;
         }
-        return Some(current_hash);
+        Some(current_hash)
     }
 
     /// Did the tree contain the given element when it was the given size?
     ///
     /// Uses a slow linear scan. Not... efficient.
     fn contained(&self, value: &T::Element, past_size: usize) -> bool {
-        for (idx, candidate) in self.iter_notes().enumerate() {
+        for (idx, candidate) in VectorLeafIterator::new(&self.nodes).enumerate() {
             if idx == past_size {
                 break;
             }
@@ -416,17 +485,46 @@ impl<T: MerkleHasher> MerkleTree for VectorMerkleTree<T> {
             tree_size: self.len(),
         })
     }
+
     /// Write the vector to an array
     fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
-        writer.write_u8(self.tree_depth as u8)?;
-        writer.write_u32::<LittleEndian>(self.len() as u32)?;
-        for element in self.iter_notes() {
+        write_tree_header(writer, self.tree_depth, self.len())?;
+        for element in VectorLeafIterator::new(&self.nodes) {
             element.write(writer)?;
         }
         Ok(())
     }
 }
 
+impl<T: MerkleHasher> Checkpointed for VectorMerkleTree<T> {
+    /// Record the current size under id. Checkpoints of in-memory trees are
+    /// not included when the tree is written.
+    fn checkpoint(&mut self, id: &[u8]) -> io::Result<()> {
+        let size = self.len();
+        self.checkpoints.record(id, size);
+        Ok(())
+    }
+
+    fn rewind_to(&mut self, id: &[u8]) -> io::Result<bool> {
+        match self.checkpoints.rewind(id) {
+            Some(size) => {
+                self.truncate(size).expect("The tree keeps every leaf");
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn checkpoints(&self) -> io::Result<Vec<(Vec<u8>, usize)>> {
+        Ok(self.checkpoints.list())
+    }
+
+    fn set_reorg_depth(&mut self, reorg_depth: usize) -> io::Result<()> {
+        self.checkpoints.set_reorg_depth(reorg_depth);
+        Ok(())
+    }
+}
+
 /// Is it a complete binary tree that would need a new level if we added
 /// a node? (It's complete if the number of nodes is a power of two)
 fn is_complete(num_nodes: usize) -> bool {
@@ -446,7 +544,7 @@ fn is_leftmost_path(my_index: usize) -> bool {
 ///
 /// floor(log2(num_nodes)) + 1
 fn depth_at_index(index: usize) -> usize {
-    ((index + 1) as f32).log2() as usize + 1
+    (usize::BITS - (index + 1).leading_zeros()) as usize
 }
 
 /// What is the index of the first leaf a tree with num_nodes elements
@@ -455,7 +553,7 @@ fn first_leaf(num_nodes: usize) -> usize {
     if num_nodes == 0 {
         panic!("Tree is empty");
     }
-    (1 << depth_at_index(num_nodes - 1) - 1) - 1
+    (1 << (depth_at_index(num_nodes - 1) - 1)) - 1
 }
 
 /// What is the index of the first leaf of a tree with num_leaves leaves
@@ -486,4 +584,6 @@ fn is_left_child(my_index: usize) -> bool {
     my_index % 2 != 0
 }
 #[cfg(test)]
+// The tests compare against owned strings throughout
+#[allow(clippy::cmp_owned)]
 mod tests;
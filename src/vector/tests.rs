@@ -2,6 +2,8 @@ use super::{
     depth_at_index, first_leaf, first_leaf_by_num_leaves, is_complete, is_left_child, parent_index,
     Node, VectorMerkleTree,
 };
+use crate::checkpoint::Checkpointed;
+use crate::linked::LinkedMerkleTree;
 use crate::test_helper::{CountHasher, StringHasher};
 use crate::{MerkleTree, WitnessNode};
 
@@ -161,6 +163,7 @@ fn root_hash_functions() {
         );
     assert_eq!(tree.past_root(1), Some("<<<<a|a-0>|<a|a-0>-1>|<<a|a-0>|<a|a-0>-1>-2>|<<<a|a-0>|<a|a-0>-1>|<<a|a-0>|<a|a-0>-1>-2>-3>".to_string()));
     assert_eq!(tree.past_root(2), None);
+    assert_eq!(tree.past_root(0), None);
     tree.add("b".to_string());
     assert_eq!(tree.root_hash(), Some("<<<<a|b-0>|<a|b-0>-1>|<<a|b-0>|<a|b-0>-1>-2>|<<<a|b-0>|<a|b-0>-1>|<<a|b-0>|<a|b-0>-1>-2>-3>".to_string()));
     assert_eq!(tree.past_root(1), Some("<<<<a|a-0>|<a|a-0>-1>|<<a|a-0>|<a|a-0>-1>-2>|<<<a|a-0>|<a|a-0>-1>|<<a|a-0>|<a|a-0>-1>-2>-3>".to_string()));
@@ -382,29 +385,29 @@ fn witness_path() {
 #[test]
 fn test_truncate() {
     let mut tree = VectorMerkleTree::new_with_size(StringHasher::new(), 5);
-    tree.truncate(0);
-    tree.truncate(1);
+    tree.truncate(0).unwrap();
+    tree.truncate(1).unwrap();
 
     tree.add("a".to_string());
-    tree.truncate(1);
+    tree.truncate(1).unwrap();
     assert_eq!(tree.len(), 1);
     assert_eq!(tree.nodes.len(), 1);
-    assert_eq!(tree.iter_notes().next(), Some("a".to_string()));
+    assert_eq!(tree.iter_notes().next(), Some(Ok("a".to_string())));
     assert_eq!(tree.root_hash(), Some("<<<<a|a-0>|<a|a-0>-1>|<<a|a-0>|<a|a-0>-1>-2>|<<<a|a-0>|<a|a-0>-1>|<<a|a-0>|<a|a-0>-1>-2>-3>".to_string()));
-    tree.truncate(0);
+    tree.truncate(0).unwrap();
     assert_eq!(tree.len(), 0);
     assert_eq!(tree.nodes.len(), 0);
     assert!(tree.root_hash().is_none());
 
     tree.add("a".to_string());
     tree.add("b".to_string());
-    tree.truncate(2);
+    tree.truncate(2).unwrap();
     assert_eq!(tree.len(), 2);
     assert_eq!(tree.nodes.len(), 3);
     assert_eq!(tree.root_hash(), Some("<<<<a|b-0>|<a|b-0>-1>|<<a|b-0>|<a|b-0>-1>-2>|<<<a|b-0>|<a|b-0>-1>|<<a|b-0>|<a|b-0>-1>-2>-3>".to_string()));
-    tree.truncate(1);
+    tree.truncate(1).unwrap();
     assert_eq!(tree.len(), 1);
-    assert_eq!(tree.iter_notes().next(), Some("a".to_string()));
+    assert_eq!(tree.iter_notes().next(), Some(Ok("a".to_string())));
     assert_eq!(tree.root_hash(), Some("<<<<a|a-0>|<a|a-0>-1>|<<a|a-0>|<a|a-0>-1>-2>|<<<a|a-0>|<a|a-0>-1>|<<a|a-0>|<a|a-0>-1>-2>-3>".to_string()));
 
     tree.add("b".to_string());
@@ -415,9 +418,9 @@ fn test_truncate() {
     tree.add("g".to_string());
     tree.add("h".to_string());
     tree.add("i".to_string());
-    tree.truncate(5); // abcde
+    tree.truncate(5).unwrap(); // abcde
     assert_eq!(tree.len(), 5);
-    let mut iter = tree.iter_notes();
+    let mut iter = tree.iter_notes().map(Result::unwrap);
     assert_eq!(iter.next(), Some("a".to_string()));
     assert_eq!(iter.next(), Some("b".to_string()));
     assert_eq!(iter.next(), Some("c".to_string()));
@@ -431,36 +434,36 @@ fn iteration_and_get() {
     color_backtrace::install();
     let mut tree = VectorMerkleTree::new(StringHasher::new());
     {
-        assert_eq!(tree.get(0), None);
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(0), Ok(None));
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), None);
     }
 
     tree.add("a".to_string());
     {
-        assert_eq!(tree.get(1), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(1), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), None);
     }
     tree.add("b".to_string());
     {
-        assert_eq!(tree.get(2), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(2), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), None);
     }
     tree.add("c".to_string());
     {
-        assert_eq!(tree.get(3), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(3), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -469,12 +472,12 @@ fn iteration_and_get() {
 
     tree.add("d".to_string());
     {
-        assert_eq!(tree.get(4), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        assert_eq!(*tree.get(3).unwrap(), "d".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(4), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        assert_eq!(*tree.get(3).unwrap().unwrap(), "d".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -484,13 +487,13 @@ fn iteration_and_get() {
 
     tree.add("e".to_string());
     {
-        assert_eq!(tree.get(5), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        assert_eq!(*tree.get(3).unwrap(), "d".to_string());
-        assert_eq!(*tree.get(4).unwrap(), "e".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(5), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        assert_eq!(*tree.get(3).unwrap().unwrap(), "d".to_string());
+        assert_eq!(*tree.get(4).unwrap().unwrap(), "e".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -501,14 +504,14 @@ fn iteration_and_get() {
 
     tree.add("f".to_string());
     {
-        assert_eq!(tree.get(6), None);
-        assert_eq!(*tree.get(0).unwrap(), "a".to_string());
-        assert_eq!(*tree.get(1).unwrap(), "b".to_string());
-        assert_eq!(*tree.get(2).unwrap(), "c".to_string());
-        assert_eq!(*tree.get(3).unwrap(), "d".to_string());
-        assert_eq!(*tree.get(4).unwrap(), "e".to_string());
-        assert_eq!(*tree.get(5).unwrap(), "f".to_string());
-        let mut iter = tree.iter_notes();
+        assert_eq!(tree.get(6), Ok(None));
+        assert_eq!(*tree.get(0).unwrap().unwrap(), "a".to_string());
+        assert_eq!(*tree.get(1).unwrap().unwrap(), "b".to_string());
+        assert_eq!(*tree.get(2).unwrap().unwrap(), "c".to_string());
+        assert_eq!(*tree.get(3).unwrap().unwrap(), "d".to_string());
+        assert_eq!(*tree.get(4).unwrap().unwrap(), "e".to_string());
+        assert_eq!(*tree.get(5).unwrap().unwrap(), "f".to_string());
+        let mut iter = tree.iter_notes().map(Result::unwrap);
         assert_eq!(iter.next(), Some("a".to_string()));
         assert_eq!(iter.next(), Some("b".to_string()));
         assert_eq!(iter.next(), Some("c".to_string()));
@@ -521,7 +524,7 @@ fn iteration_and_get() {
     for i in 0..100 {
         tree.add(i.to_string());
     }
-    let mut iter = tree.iter_notes();
+    let mut iter = tree.iter_notes().map(Result::unwrap);
     for char in ["a", "b", "c", "d", "e", "f"].iter() {
         assert_eq!(iter.next(), Some(char.to_string()));
     }
@@ -553,6 +556,44 @@ fn serialization() {
     assert_eq!(bytes, bytes_again);
 }
 
+#[test]
+fn read_rejects_malformed_input() {
+    let read = |bytes: &[u8]| {
+        VectorMerkleTree::read(StringHasher::new(), &mut { bytes }).map(|tree| tree.len())
+    };
+    // depth zero would underflow when computing the authentication path
+    assert!(read(&[0, 0, 0, 0, 0]).is_err());
+    assert!(read(&[65, 0, 0, 0, 0]).is_err());
+    // a depth 3 tree can only hold four leaves
+    assert!(read(&[3, 5, 0, 0, 0]).is_err());
+    // the header promises more leaves than the stream contains
+    assert!(read(&[3, 2, 0, 0, 0, 1, b'a']).is_err());
+    assert_eq!(read(&[3, 1, 0, 0, 0, 1, b'a']).unwrap(), 1);
+}
+
+#[test]
+fn past_witness() {
+    let characters = "abcdefghijk";
+    let mut tree = VectorMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in characters.chars() {
+        tree.add(character.to_string());
+    }
+    assert!(tree.past_witness(0, 0).is_none());
+    assert!(tree.past_witness(3, 3).is_none());
+    assert!(tree.past_witness(0, 12).is_none());
+
+    for past_size in 1..=characters.len() {
+        let mut past_tree = VectorMerkleTree::new_with_size(StringHasher::new(), 5);
+        for character in characters[..past_size].chars() {
+            past_tree.add(character.to_string());
+        }
+        for position in 0..past_size {
+            let witness = tree.past_witness(position, past_size).expect("leaf exists");
+            assert_eq!(Some(witness), past_tree.witness(position));
+        }
+    }
+}
+
 #[test]
 fn test_depth_at_index() {
     assert_eq!(depth_at_index(0), 1);
@@ -704,3 +745,77 @@ fn default_authpath_len() {
     assert_eq!(witness.root_hash, 33);
     assert_eq!(witness.auth_path.len(), 32);
 }
+
+#[test]
+fn checkpoints() {
+    let mut tree = VectorMerkleTree::new_with_size(StringHasher::new(), 5);
+    for character in "abc".chars() {
+        tree.add(character.to_string());
+    }
+    tree.checkpoint(b"first").unwrap();
+    let root = tree.root_hash();
+    tree.add("d".to_string());
+    tree.checkpoint(b"second").unwrap();
+    tree.add("e".to_string());
+    tree.checkpoint(b"third").unwrap();
+    assert_eq!(
+        tree.checkpoints().unwrap(),
+        vec![
+            (b"first".to_vec(), 3),
+            (b"second".to_vec(), 4),
+            (b"third".to_vec(), 5)
+        ]
+    );
+
+    assert!(!tree.rewind_to(b"missing").unwrap());
+    assert_eq!(tree.len(), 5);
+    assert!(tree.rewind_to(b"first").unwrap());
+    assert_eq!(tree.len(), 3);
+    assert_eq!(tree.root_hash(), root);
+    assert_eq!(tree.checkpoints().unwrap(), vec![(b"first".to_vec(), 3)]);
+
+    tree.add("x".to_string());
+    tree.checkpoint(b"second").unwrap();
+    tree.truncate(2).unwrap();
+    assert!(tree.checkpoints().unwrap().is_empty());
+
+    tree.set_reorg_depth(1).unwrap();
+    tree.checkpoint(b"first").unwrap();
+    tree.add("y".to_string());
+    tree.checkpoint(b"second").unwrap();
+    assert_eq!(tree.checkpoints().unwrap(), vec![(b"second".to_vec(), 3)]);
+}
+
+#[test]
+fn export_state() {
+    let mut tree = VectorMerkleTree::new_with_size(StringHasher::new(), 6);
+    let mut linked = LinkedMerkleTree::new_with_size(StringHasher::new(), 6);
+    for character in "abcdefghijklmnopqrstu".chars() {
+        let mut state = vec![];
+        tree.export_state(&mut state).unwrap();
+        let mut expected = vec![];
+        linked.export_state(&mut expected).unwrap();
+        assert_eq!(state, expected);
+        tree.add(character.to_string());
+        linked.add(character.to_string());
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde() {
+    use crate::TreeSeed;
+    use serde::de::DeserializeSeed;
+
+    let mut tree = VectorMerkleTree::new_with_size(StringHasher::new(), 6);
+    for character in "abcde".chars() {
+        tree.add(character.to_string());
+    }
+    let json = serde_json::to_string(&tree).unwrap();
+    let read_back: Box<VectorMerkleTree<StringHasher>> = TreeSeed::new(StringHasher::new())
+        .deserialize(&mut serde_json::Deserializer::from_str(&json))
+        .unwrap();
+    assert_eq!(read_back.len(), 5);
+    assert_eq!(read_back.root_hash(), tree.root_hash());
+    assert_eq!(read_back.witness(2), tree.witness(2));
+}